@@ -0,0 +1,63 @@
+//! Embeds the git revision and build date, shown by `--version`.
+
+#![allow(non_snake_case)]
+
+use std::{
+    process::Command,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+fn main() {
+    let gitSha = Command::new("git")
+        .args(["rev-parse", "--short=10", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|sha| sha.trim().to_string())
+        .unwrap_or_else(|| "unknown".into());
+    let dirty = Command::new("git")
+        .args(["status", "--porcelain", "--untracked-files=no"])
+        .output()
+        .is_ok_and(|output| output.status.success() && !output.stdout.is_empty());
+    let dirty = if dirty { "-dirty" } else { "" };
+    println!("cargo:rustc-env=CASEPROXY_GIT_SHA={gitSha}{dirty}");
+
+    // honour reproducible builds
+    let timestamp = std::env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|epoch| epoch.parse().ok())
+        .unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|time| time.as_secs())
+                .unwrap_or(0)
+        });
+    println!(
+        "cargo:rustc-env=CASEPROXY_BUILD_DATE={}",
+        format_date(timestamp)
+    );
+
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/index");
+}
+
+/// Formats a unix timestamp as `YYYY-MM-DD`, using Howard Hinnant's
+/// `civil_from_days`.
+fn format_date(timestamp: u64) -> String {
+    let days = (timestamp / 86400) as i64 + 719468;
+    let era = days.div_euclid(146097);
+    let dayOfEra = days.rem_euclid(146097);
+    let yearOfEra = (dayOfEra - dayOfEra / 1460 + dayOfEra / 36524 - dayOfEra / 146096) / 365;
+    let dayOfYear = dayOfEra - (365 * yearOfEra + yearOfEra / 4 - yearOfEra / 100);
+    let monthIndex = (5 * dayOfYear + 2) / 153;
+    let day = dayOfYear - (153 * monthIndex + 2) / 5 + 1;
+    let month = if monthIndex < 10 {
+        monthIndex + 3
+    } else {
+        monthIndex - 9
+    };
+    let year = yearOfEra + era * 400 + (month <= 2) as i64;
+    format!("{year:04}-{month:02}-{day:02}")
+}
@@ -0,0 +1,28 @@
+#![no_main]
+
+use std::ffi::OsStr;
+
+use caseproxy::{osstr_chars, CharOrByte};
+use libfuzzer_sys::fuzz_target;
+
+// `osstr_chars` must split any bytes into characters and stray bytes which
+// re-encode to exactly the original bytes.
+fuzz_target!(|data: &[u8]| {
+    // unix `OsStr`s are arbitrary bytes
+    let str = unsafe { OsStr::from_encoded_bytes_unchecked(data) };
+    let mut encoded = Vec::with_capacity(data.len());
+    for item in osstr_chars(str) {
+        match item {
+            CharOrByte::Char(c) => encoded.extend(c.encode_utf8(&mut [0; 4]).as_bytes()),
+            CharOrByte::Byte(byte) => {
+                // anything valid should have been decoded
+                assert!(
+                    std::str::from_utf8(&[byte]).is_err(),
+                    "{byte:#x} returned as a byte in {data:x?}"
+                );
+                encoded.push(byte);
+            }
+        }
+    }
+    assert_eq!(encoded, data);
+});
@@ -0,0 +1,35 @@
+#![no_main]
+
+use std::{
+    ffi::OsStr,
+    path::{Component, Path},
+};
+
+use caseproxy::resolve_parents;
+use libfuzzer_sys::fuzz_target;
+
+// `..` must never climb above the root or a leading `.`, nor survive
+// resolution, as request paths are joined onto the served root.
+fuzz_target!(|data: &[u8]| {
+    let path = Path::new(unsafe { OsStr::from_encoded_bytes_unchecked(data) });
+    let resolved = resolve_parents(path);
+
+    assert!(
+        !resolved
+            .components()
+            .any(|component| component == Component::ParentDir),
+        "{path:?} resolved to {resolved:?}"
+    );
+    for prefix in [Path::new("/"), Path::new(".")] {
+        if path.starts_with(prefix) {
+            assert!(
+                resolved.starts_with(prefix),
+                "{path:?} resolved to {resolved:?}, outside {prefix:?}"
+            );
+        }
+    }
+    // as the server resolves requests
+    let rooted = resolve_parents(&Path::new("/").join(path));
+    assert!(rooted.has_root(), "{path:?} escaped the root as {rooted:?}");
+    assert_eq!(rooted, resolve_parents(&rooted));
+});
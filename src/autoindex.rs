@@ -0,0 +1,108 @@
+//! HTML listings of directories without an index file, for browsing the tree
+//! under `--autoindex`.
+
+use std::{collections::HashMap, fmt::Write, path::Path};
+
+use caseproxy::{AResult, InsensitiveOsString};
+
+use crate::{html_escape, is_hidden, percent_encode_path};
+
+/// Builds a listing of `dir`, relative to `root` and served under `urlPrefix`.
+/// Directories come first, then files, each ordered as names are compared.
+///
+/// Entries whose names differ only in case are flagged, as only one of them
+/// can be reached case-insensitively.
+pub fn listing(root: &Path, dir: &Path, urlPrefix: &str, skipHidden: bool) -> AResult<String> {
+    let relative = dir.strip_prefix(root)?;
+    let dirHref = |relative: &Path| {
+        let mut href = format!(
+            "{urlPrefix}{}",
+            percent_encode_path(relative.as_os_str().as_encoded_bytes())
+        );
+        if !href.ends_with('/') {
+            href.push('/');
+        }
+        href
+    };
+    let href = dirHref(relative);
+
+    let mut entries = vec![];
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        if skipHidden && is_hidden(&relative.join(&name)) {
+            continue;
+        }
+        // follows symlinks, as serving them would
+        let Ok(metadata) = std::fs::metadata(entry.path()) else {
+            continue;
+        };
+        entries.push((!metadata.is_dir(), InsensitiveOsString(name)));
+    }
+    entries.sort();
+    let mut variants: HashMap<&InsensitiveOsString, usize> = HashMap::new();
+    for (_, name) in &entries {
+        *variants.entry(name).or_default() += 1;
+    }
+
+    let title = html_escape(&format!("Index of {href}"));
+    let mut res = format!(
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>{title}</title></head>\n<body><h1>{title}</h1>\n<ul>\n"
+    );
+    if let Some(parent) = relative.parent() {
+        let parentHref = html_escape(&dirHref(parent));
+        writeln!(res, "<li><a href=\"{parentHref}\">../</a></li>").unwrap();
+    }
+    for (isFile, name) in &entries {
+        let slash = if *isFile { "" } else { "/" };
+        let entryHref = format!(
+            "{href}{}{slash}",
+            percent_encode_path(name.0.as_encoded_bytes())
+        );
+        let collision = match variants[name] {
+            1 => "",
+            _ => " <em class=\"collision\" title=\"another entry differs only in case\">(case collision)</em>",
+        };
+        let name = html_escape(&name.0.to_string_lossy());
+        writeln!(
+            res,
+            "<li><a href=\"{}\">{name}{slash}</a>{collision}</li>",
+            html_escape(&entryHref)
+        )
+        .unwrap();
+    }
+    res.push_str("</ul>\n</body></html>\n");
+    Ok(res)
+}
+
+#[test]
+fn test_case_collisions() -> AResult<()> {
+    let (root, removeRoot) = caseproxy::make_temp_dir()?;
+    std::fs::write(root.join("Foo.txt"), "")?;
+    std::fs::write(root.join("foo.txt"), "")?;
+    std::fs::write(root.join("bar.txt"), "")?;
+    std::fs::create_dir(root.join("Docs"))?;
+    std::fs::write(root.join("docs"), "")?;
+    std::fs::create_dir(root.join("Other"))?;
+
+    let listing = listing(&root, &root, "/", false)?;
+    let entries: Vec<_> = listing
+        .lines()
+        .filter(|line| line.starts_with("<li>"))
+        .map(|line| (line.contains("(case collision)"), line))
+        .collect();
+    let flagged = |name: &str| {
+        entries
+            .iter()
+            .find(|(_, line)| line.contains(&format!(">{name}</a>")))
+            .map(|(flagged, _)| *flagged)
+    };
+    assert_eq!(flagged("Foo.txt"), Some(true));
+    assert_eq!(flagged("foo.txt"), Some(true));
+    // a directory and a file collide too
+    assert_eq!(flagged("Docs/"), Some(true));
+    assert_eq!(flagged("docs"), Some(true));
+    assert_eq!(flagged("bar.txt"), Some(false));
+    assert_eq!(flagged("Other/"), Some(false));
+    Ok(())
+}
@@ -1,16 +1,18 @@
-#![allow(non_snake_case)]
+#![allow(unused, non_snake_case, non_upper_case_globals)]
 
 use std::{
-    collections::{HashMap, VecDeque},
-    fmt::Write,
-    io::Read,
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    fs::File,
+    io::{BufWriter, Write},
     path::{Path, PathBuf},
 };
 
 use anyhow::anyhow;
-use caseproxy::{AResult, InsensitivePath};
+use caseproxy::{
+    hashing::{hex, HashAlgorithm, HashCache},
+    AResult, FileId, InsensitiveOsString, InsensitivePath,
+};
 use clap::Parser;
-use sha3::Digest;
 
 #[derive(Debug, Parser)]
 struct Args {
@@ -19,12 +21,67 @@ struct Args {
     /// Path to save an HTML report to
     #[arg(long)]
     html: Option<PathBuf>,
+
+    /// Compare one directory (and its case variants) at a time, reporting
+    /// duplicates as they're found, rather than listing the whole tree up front.
+    ///
+    /// Memory use is then proportional to the largest directory plus the
+    /// directories still to be visited, instead of every path in the tree,
+    /// at the cost of reports being in traversal order rather than grouped
+    /// after the scan. Throughput is about the same.
+    #[arg(long)]
+    streaming: bool,
+
+    /// File to keep hashes in between runs, so files unchanged since (by size
+    /// and mtime) aren't read again.
+    #[arg(long, value_name = "FILE")]
+    hashCache: Option<PathBuf>,
+
+    /// Note the target of each duplicate that's a symlink, so links into
+    /// other parts of the tree can be told apart from copies.
+    #[arg(long)]
+    dereferenceReport: bool,
 }
 
+const maxCachedHashes: usize = 1_000_000;
+
 fn main() -> AResult<()> {
     let args = Args::parse();
+    let hashCache = match &args.hashCache {
+        Some(store) => HashCache::open(store, maxCachedHashes)?,
+        None => HashCache::new(maxCachedHashes),
+    };
 
-    let files = find_all_files(&args.rootDir)?;
+    let mut report = match &args.html {
+        Some(htmlPath) => Report::html(BufWriter::new(File::create(htmlPath)?))?,
+        None => Report::Text,
+    };
+    let linkTargets = |instances: &[PathBuf]| match args.dereferenceReport {
+        true => symlink_targets(instances),
+        false => HashMap::new(),
+    };
+    if args.streaming {
+        stream_duplicate_sets(&args.rootDir, |path, instances| {
+            report.write_set(
+                &path,
+                &instances,
+                &hash_files(&hashCache, &instances),
+                &linkTargets(&instances),
+            )
+        })?;
+    } else {
+        let duplicateSets = find_duplicate_sets(&args.rootDir)?;
+        let fileHashes = hash_files(&hashCache, duplicateSets.values().flatten());
+        for (path, instances) in &duplicateSets {
+            report.write_set(&path.0, instances, &fileHashes, &linkTargets(instances))?;
+        }
+    }
+    report.finish()?;
+    hashCache.save()
+}
+
+fn find_duplicate_sets(root: &Path) -> AResult<HashMap<InsensitivePath, Vec<PathBuf>>> {
+    let files = find_all_files(root)?;
     let mut files: Vec<_> = files.into_iter().map(InsensitivePath).collect();
     files.sort();
 
@@ -35,29 +92,20 @@ fn main() -> AResult<()> {
             .and_modify(|v| v.push(file.0.clone()))
             .or_insert_with(|| vec![file.0]);
     }
-    duplicateSets.retain(|_, v| v.len() > 1);
-
-    let mut fileHashes = HashMap::new();
-    for file in duplicateSets.values().flat_map(std::convert::identity) {
-        let hash = match hash_file(file) {
-            Ok(v) => v,
-            Err(err) => {
-                eprintln!("couldn't read {file:?} for hashing: {err:?}");
-                fileHashes.insert(file.to_path_buf(), "error".to_string());
-                continue;
-            }
-        };
-        fileHashes.insert(file.to_path_buf(), hash);
-    }
-
-    if let Some(htmlPath) = args.html {
-        let report = create_html_report(&duplicateSets, &fileHashes)?;
-        std::fs::write(htmlPath, report)?;
-    } else {
-        print_text_report(&duplicateSets, &fileHashes);
+    for instances in duplicateSets.values_mut() {
+        skip_hardlinks(instances);
     }
+    duplicateSets.retain(|_, v| v.len() > 1);
+    Ok(duplicateSets)
+}
 
-    Ok(())
+/// Hardlinks are the same file, not duplicates of it.
+fn skip_hardlinks(instances: &mut Vec<PathBuf>) {
+    let mut seen = HashSet::new();
+    instances.retain(|path| match FileId::of(path) {
+        Ok(id) => seen.insert(id),
+        Err(_) => true,
+    });
 }
 
 fn find_all_files(root: &Path) -> AResult<Vec<PathBuf>> {
@@ -86,68 +134,236 @@ fn find_all_files(root: &Path) -> AResult<Vec<PathBuf>> {
     Ok(files)
 }
 
-fn hash_file(file: &Path) -> AResult<String> {
-    let mut hasher = sha3::Sha3_256::new();
-    let mut file = std::fs::OpenOptions::new().read(true).open(file)?;
-    let mut chunk = [0u8; 8192];
-    loop {
-        let len = file.read(&mut chunk)?;
-        if len == 0 {
-            break;
+/// Finds the same duplicate sets as `find_duplicate_sets`, passing each to
+/// `report` as soon as it's found.
+///
+/// Paths can only collide if their parent directories do, so directories are
+/// visited in groups of case variants (e.g. `Dir` and `dir` together), and only
+/// one group's listing is held at a time.
+fn stream_duplicate_sets(
+    root: &Path,
+    mut report: impl FnMut(PathBuf, Vec<PathBuf>) -> AResult<()>,
+) -> AResult<()> {
+    if !root.is_dir() {
+        return Err(anyhow!("given root path must be a directory"));
+    }
+
+    let mut stack = vec![vec![root.to_path_buf()]];
+    while let Some(dirs) = stack.pop() {
+        let mut files: BTreeMap<InsensitiveOsString, Vec<PathBuf>> = BTreeMap::new();
+        let mut subdirs: BTreeMap<InsensitiveOsString, Vec<PathBuf>> = BTreeMap::new();
+        for dir in dirs {
+            for entry in std::fs::read_dir(dir)? {
+                let entry = entry?;
+                let group = if entry.file_type()?.is_dir() {
+                    &mut subdirs
+                } else {
+                    &mut files
+                };
+                group
+                    .entry(InsensitiveOsString(entry.file_name()))
+                    .or_default()
+                    .push(entry.path());
+            }
         }
 
-        let slice = &chunk[..len];
-        hasher.update(slice);
+        for (_, mut instances) in files {
+            skip_hardlinks(&mut instances);
+            if instances.len() > 1 {
+                instances.sort();
+                report(instances[0].clone(), instances)?;
+            }
+        }
+        // reversed so directories are visited in order
+        stack.extend(subdirs.into_values().rev());
     }
+    Ok(())
+}
+
+#[test]
+fn test_stream_duplicate_sets() -> AResult<()> {
+    use caseproxy::make_temp_dir;
 
-    let mut digest = String::new();
-    for byte in hasher.finalize() {
-        write!(&mut digest, "{:02X}", byte)?;
+    let (tempdir, removeTempdir) = make_temp_dir()?;
+
+    // a few thousand files, with collisions within directories, across case
+    // variants of directories, and hardlinks
+    for dir in 0..40 {
+        let dirName = match dir % 4 {
+            0 => format!("Dir{dir}"),
+            _ => format!("dir{dir}"),
+        };
+        for file in 0..50 {
+            let path = tempdir.join(&dirName).join(format!("file{file}.txt"));
+            std::fs::create_dir_all(path.parent().unwrap())?;
+            std::fs::write(&path, "")?;
+        }
+        std::fs::write(tempdir.join(&dirName).join("FILE0.txt"), "")?;
+        std::fs::hard_link(
+            tempdir.join(&dirName).join("file1.txt"),
+            tempdir.join(&dirName).join("File1.txt"),
+        )?;
+        std::fs::create_dir_all(tempdir.join(format!("DIR{dir}/nested")))?;
+        std::fs::write(tempdir.join(format!("DIR{dir}/File2.TXT")), "")?;
     }
-    Ok(digest)
+
+    let mut streamed = vec![];
+    stream_duplicate_sets(&tempdir, |path, instances| {
+        streamed.push(instances);
+        Ok(())
+    })?;
+    let mut expected: Vec<_> = find_duplicate_sets(&tempdir)?
+        .into_values()
+        .map(|mut instances| {
+            instances.sort();
+            instances
+        })
+        .collect();
+    expected.sort();
+    streamed.sort();
+
+    // FILE0/file0 in every directory, plus File2/file2 across case variants
+    assert_eq!(streamed.len(), 80);
+    assert_eq!(streamed, expected);
+    assert!(streamed.iter().all(|instances| instances.len() == 2));
+
+    Ok(())
 }
 
-fn print_text_report(
-    duplicateSets: &HashMap<InsensitivePath, Vec<PathBuf>>,
-    hashes: &HashMap<PathBuf, String>,
-) {
-    for (path, instances) in duplicateSets {
-        println!("{:?}", path.0);
-        for instance in instances {
-            let hash = hashes
-                .get(instance)
-                .map(String::as_str)
-                .unwrap_or("missing");
-            println!(" => {instance:?} {hash}");
-        }
+fn hash_files<'a>(
+    cache: &HashCache,
+    files: impl IntoIterator<Item = &'a PathBuf>,
+) -> HashMap<PathBuf, String> {
+    let mut fileHashes = HashMap::new();
+    for file in files {
+        let hash = match cache
+            .get(file, HashAlgorithm::Sha3_256)
+            .map(|hash| hex(&hash))
+        {
+            Ok(v) => v,
+            Err(err) => {
+                eprintln!("couldn't read {file:?} for hashing: {err:?}");
+                fileHashes.insert(file.to_path_buf(), "error".to_string());
+                continue;
+            }
+        };
+        fileHashes.insert(file.to_path_buf(), hash);
     }
+    fileHashes
+}
+
+/// Targets of those `instances` which are symlinks, as stored in the link.
+fn symlink_targets(instances: &[PathBuf]) -> HashMap<PathBuf, PathBuf> {
+    instances
+        .iter()
+        .filter(|instance| instance.is_symlink())
+        .filter_map(|instance| match std::fs::read_link(instance) {
+            Ok(target) => Some((instance.clone(), target)),
+            Err(err) => {
+                eprintln!("couldn't read symlink {instance:?}: {err:?}");
+                None
+            }
+        })
+        .collect()
+}
+
+#[test]
+fn test_symlink_targets() -> AResult<()> {
+    use caseproxy::make_temp_dir;
+
+    let (tempdir, removeTempdir) = make_temp_dir()?;
+
+    std::fs::create_dir_all(tempdir.join("other"))?;
+    std::fs::write(tempdir.join("file.txt"), "")?;
+    std::fs::write(tempdir.join("other/real.txt"), "")?;
+    std::os::unix::fs::symlink("other/real.txt", tempdir.join("FILE.txt"))?;
+
+    let duplicateSets = find_duplicate_sets(&tempdir)?;
+    let instances = &duplicateSets[&InsensitivePath(tempdir.join("file.txt"))];
+    assert_eq!(instances.len(), 2);
+    assert_eq!(
+        symlink_targets(instances),
+        HashMap::from([(tempdir.join("FILE.txt"), PathBuf::from("other/real.txt"))])
+    );
+
+    Ok(())
+}
+
+enum Report {
+    Text,
+    Html(BufWriter<File>),
 }
 
-fn create_html_report(
-    duplicateSets: &HashMap<InsensitivePath, Vec<PathBuf>>,
-    hashes: &HashMap<PathBuf, String>,
-) -> AResult<String> {
-    let mut res = String::new();
-    writeln!(&mut res, "<style>")?;
-    writeln!(
-        &mut res,
-        "table {{ border-collapse: collapse; width: 100%; }}"
-    )?;
-    writeln!(&mut res, "td:first-child {{ width: 100%; }}")?;
-    writeln!(&mut res, "table, tr, th, td {{ border: 1px solid black; }}")?;
-    writeln!(&mut res, "</style>")?;
-    for (path, instances) in duplicateSets {
-        writeln!(&mut res, "<h3>{:?}</h3>", path.0)?;
-        writeln!(&mut res, "<table>")?;
-        writeln!(&mut res, "<tr><th>path</th><th>hash</th></tr>")?;
-        for instance in instances {
-            let hash = hashes
+impl Report {
+    fn html(mut out: BufWriter<File>) -> AResult<Self> {
+        writeln!(&mut out, "<style>")?;
+        writeln!(
+            &mut out,
+            "table {{ border-collapse: collapse; width: 100%; }}"
+        )?;
+        writeln!(&mut out, "td:first-child {{ width: 100%; }}")?;
+        writeln!(&mut out, "table, tr, th, td {{ border: 1px solid black; }}")?;
+        writeln!(&mut out, "</style>")?;
+        Ok(Self::Html(out))
+    }
+
+    fn write_set(
+        &mut self,
+        path: &Path,
+        instances: &[PathBuf],
+        hashes: &HashMap<PathBuf, String>,
+        linkTargets: &HashMap<PathBuf, PathBuf>,
+    ) -> AResult<()> {
+        let hash = |instance| {
+            hashes
                 .get(instance)
                 .map(String::as_str)
-                .unwrap_or("missing");
-            writeln!(&mut res, "<tr><td>{instance:?}</td>\n<td>{hash}</td></tr>")?;
+                .unwrap_or("missing")
+        };
+        let target = |instance| match linkTargets.get(instance) {
+            Some(target) => format!("symlink → {target:?}"),
+            None => String::new(),
+        };
+        match self {
+            Report::Text => {
+                println!("{path:?}");
+                for instance in instances {
+                    match linkTargets.get(instance) {
+                        Some(_) => {
+                            println!(" => {instance:?} {} ({})", hash(instance), target(instance))
+                        }
+                        None => println!(" => {instance:?} {}", hash(instance)),
+                    }
+                }
+            }
+            Report::Html(out) => {
+                writeln!(out, "<h3>{path:?}</h3>")?;
+                writeln!(out, "<table>")?;
+                match linkTargets.is_empty() {
+                    true => writeln!(out, "<tr><th>path</th><th>hash</th></tr>")?,
+                    false => writeln!(out, "<tr><th>path</th><th>hash</th><th>link</th></tr>")?,
+                }
+                for instance in instances {
+                    write!(
+                        out,
+                        "<tr><td>{instance:?}</td>\n<td>{}</td>",
+                        hash(instance)
+                    )?;
+                    if !linkTargets.is_empty() {
+                        write!(out, "\n<td>{}</td>", target(instance))?;
+                    }
+                    writeln!(out, "</tr>")?;
+                }
+                writeln!(out, "</table>")?;
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(self) -> AResult<()> {
+        if let Report::Html(mut out) = self {
+            out.flush()?;
         }
-        writeln!(&mut res, "</table>")?;
+        Ok(())
     }
-    Ok(res)
 }
@@ -0,0 +1,52 @@
+#![allow(non_snake_case)]
+
+use std::path::PathBuf;
+
+use caseproxy::{
+    manifest::{build_manifest, manifest_to_binary, manifest_to_json},
+    AResult,
+};
+use clap::Parser;
+
+/// Generates a manifest for `caseproxy --manifest`, mapping every path under a
+/// directory to its real casing.
+#[derive(Debug, Parser)]
+struct Args {
+    rootDir: PathBuf,
+
+    /// Path to save the manifest to, instead of printing it
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+
+    /// Write the compact binary format rather than JSON, which loads without
+    /// parsing and so starts up faster with large trees
+    #[arg(long, requires = "output")]
+    binary: bool,
+}
+
+fn main() -> AResult<()> {
+    let args = Args::parse();
+
+    let manifest = build_manifest(&args.rootDir)?;
+    for path in &manifest.collisions {
+        eprintln!("{path:?} differs only in case from another path, leaving it out");
+    }
+    for path in &manifest.skipped {
+        eprintln!("{path:?} isn't valid UTF-8, leaving it out");
+    }
+
+    if args.binary {
+        // `requires = "output"` keeps binary data off the terminal
+        let output = args.output.as_deref().unwrap();
+        std::fs::write(output, manifest_to_binary(&manifest.entries))?;
+        return Ok(());
+    }
+    let json = manifest_to_json(&manifest.entries);
+    if let Some(output) = args.output {
+        std::fs::write(output, json)?;
+    } else {
+        print!("{json}");
+    }
+
+    Ok(())
+}
@@ -0,0 +1,150 @@
+//! `Content-Disposition` headers naming the file being sent, so clients save
+//! downloads under their real name rather than however they were requested.
+
+use std::ffi::OsStr;
+
+/// Precomposed Latin-1 and Latin Extended-A letters, by combining mark: the
+/// base letters each composes with, and what they compose to, in that order.
+const compositions: &[(char, &str, &str)] = &[
+    ('\u{300}', "AEIOUaeiou", "ÀÈÌÒÙàèìòù"),
+    (
+        '\u{301}',
+        "AEIOUYaeiouyCcLlNnRrSsZz",
+        "ÁÉÍÓÚÝáéíóúýĆćĹĺŃńŔŕŚśŹź",
+    ),
+    (
+        '\u{302}',
+        "AEIOUaeiouCcGgHhJjSsWwYy",
+        "ÂÊÎÔÛâêîôûĈĉĜĝĤĥĴĵŜŝŴŵŶŷ",
+    ),
+    ('\u{303}', "ANOanoIiUu", "ÃÑÕãñõĨĩŨũ"),
+    ('\u{304}', "AaEeIiOoUu", "ĀāĒēĪīŌōŪū"),
+    ('\u{306}', "AaEeGgIiOoUu", "ĂăĔĕĞğĬĭŎŏŬŭ"),
+    ('\u{307}', "CcEeGgIZz", "ĊċĖėĠġİŻż"),
+    ('\u{308}', "AEIOUaeiouyY", "ÄËÏÖÜäëïöüÿŸ"),
+    ('\u{30a}', "AaUu", "ÅåŮů"),
+    ('\u{30b}', "OoUu", "ŐőŰű"),
+    ('\u{30c}', "CcDdEeLlNnRrSsTtZz", "ČčĎďĚěĽľŇňŘřŠšŤťŽž"),
+    ('\u{327}', "CcGgKkLlNnRrSsTt", "ÇçĢģĶķĻļŅņŖŗŞşŢţ"),
+    ('\u{328}', "AaEeIiUu", "ĄąĘęĮįŲų"),
+];
+
+fn compose(base: char, mark: char) -> Option<char> {
+    let (_, bases, composed) = compositions.iter().find(|(m, _, _)| *m == mark)?;
+    let index = bases.chars().position(|c| c == base)?;
+    composed.chars().nth(index)
+}
+
+/// The base letter of a precomposed letter from `compositions`.
+fn decompose(c: char) -> Option<char> {
+    compositions.iter().find_map(|(_, bases, composed)| {
+        let index = composed.chars().position(|composed| composed == c)?;
+        bases.chars().nth(index)
+    })
+}
+
+/// Composes letters followed by a combining mark into the precomposed letter,
+/// as NFC would, for names from filesystems storing them decomposed (e.g.
+/// macOS). Only covers Latin letters in `compositions`.
+pub fn compose_latin(name: &str) -> String {
+    let mut res = String::with_capacity(name.len());
+    for c in name.chars() {
+        let composed = res.chars().next_back().and_then(|base| compose(base, c));
+        match composed {
+            Some(composed) => {
+                res.pop();
+                res.push(composed);
+            }
+            None => res.push(c),
+        }
+    }
+    res
+}
+
+#[test]
+fn test_compose_latin() {
+    assert_eq!(compose_latin("Cafe\u{301}.txt"), "Café.txt");
+    assert_eq!(compose_latin("Café.txt"), "Café.txt");
+    assert_eq!(compose_latin("S\u{30c}koda"), "Škoda");
+    assert_eq!(
+        compose_latin("n\u{303}a\u{308}\u{301}"),
+        "ñä\u{301}",
+        "only the first mark composes"
+    );
+    assert_eq!(compose_latin("\u{301}x"), "\u{301}x");
+    assert_eq!(compose_latin("\u{3b1}\u{301}"), "\u{3b1}\u{301}");
+}
+
+/// An ASCII stand-in for `name`, for clients not understanding `filename*`:
+/// accented letters lose their accents, and anything else not printable ASCII,
+/// or needing escaping in a quoted string, becomes `_`.
+fn ascii_fallback(name: &str) -> String {
+    name.chars()
+        .filter(|c| !('\u{300}'..='\u{36f}').contains(c))
+        .map(|c| match decompose(c).unwrap_or(c) {
+            '"' | '\\' => '_',
+            c if c.is_ascii_graphic() || c == ' ' => c,
+            _ => '_',
+        })
+        .collect()
+}
+
+/// Percent-encodes `name` as an RFC 5987 `ext-value`'s `value-chars`.
+fn rfc5987_encode(name: &str) -> String {
+    let mut res = String::with_capacity(name.len());
+    for &byte in name.as_bytes() {
+        if byte.is_ascii_alphanumeric() || b"!#$&+-.^_`|~".contains(&byte) {
+            res.push(byte as char);
+        } else {
+            res.push_str(&format!("%{byte:02X}"));
+        }
+    }
+    res
+}
+
+/// `Content-Disposition: attachment` value naming `name`, in NFC. Names that
+/// aren't plain ASCII are also given in full as `filename*`, unless they
+/// aren't valid UTF-8, in which case only the ASCII stand-in is given.
+pub fn attachment(name: &OsStr) -> String {
+    let Some(name) = name.to_str() else {
+        let fallback = ascii_fallback(&name.to_string_lossy());
+        return format!("attachment; filename=\"{fallback}\"");
+    };
+    let name = compose_latin(name);
+    let fallback = ascii_fallback(&name);
+    match fallback == name {
+        true => format!("attachment; filename=\"{fallback}\""),
+        false => format!(
+            "attachment; filename=\"{fallback}\"; filename*=UTF-8''{}",
+            rfc5987_encode(&name)
+        ),
+    }
+}
+
+#[test]
+fn test_attachment() {
+    use std::os::unix::ffi::OsStrExt;
+
+    let attachment = |name: &[u8]| attachment(OsStr::from_bytes(name));
+    assert_eq!(
+        attachment(b"Report.pdf"),
+        "attachment; filename=\"Report.pdf\""
+    );
+    assert_eq!(
+        attachment(b"My \"quoted\" file.txt"),
+        "attachment; filename=\"My _quoted_ file.txt\"; filename*=UTF-8''My%20%22quoted%22%20file.txt"
+    );
+    // decomposed and precomposed forms give the same header
+    let expected = "attachment; filename=\"Cafe.txt\"; filename*=UTF-8''Caf%C3%A9.txt";
+    assert_eq!(attachment("Café.txt".as_bytes()), expected);
+    assert_eq!(attachment("Cafe\u{301}.txt".as_bytes()), expected);
+    assert_eq!(
+        attachment("\u{65e5}\u{672c}.txt".as_bytes()),
+        "attachment; filename=\"__.txt\"; filename*=UTF-8''%E6%97%A5%E6%9C%AC.txt"
+    );
+    // not UTF-8
+    assert_eq!(
+        attachment(b"caf\xe9.txt"),
+        "attachment; filename=\"caf_.txt\""
+    );
+}
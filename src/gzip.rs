@@ -0,0 +1,482 @@
+//! Minimal streaming gzip encoder for `--compress`: LZ77 over a 32KiB window,
+//! coded with deflate's fixed Huffman codes. Not as tight as dynamic codes, but
+//! small and cheap, and text still shrinks severalfold. Pieces that wouldn't
+//! shrink are stored as they are, so nothing grows by more than a few bytes.
+
+use futures_util::{Stream, StreamExt};
+use hyper::body::Bytes;
+
+const windowSize: usize = 1 << 15;
+const hashBits: u32 = 15;
+const minMatch: usize = 3;
+const maxMatch: usize = 258;
+/// How many earlier positions with the same hash are tried for a match.
+const maxChain: usize = 32;
+/// Most bytes in one stored block.
+const maxStored: usize = 0xffff;
+
+const lengthBases: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const lengthExtra: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const distanceBases: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const distanceExtra: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+
+/// Collects bits least significant first, as deflate packs them.
+#[derive(Default)]
+struct BitWriter {
+    out: Vec<u8>,
+    pending: u64,
+    count: u32,
+}
+
+impl BitWriter {
+    fn write(&mut self, value: u32, bits: u32) {
+        self.pending |= (value as u64) << self.count;
+        self.count += bits;
+        while self.count >= 8 {
+            self.out.push(self.pending as u8);
+            self.pending >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    /// Writes a Huffman code, which unlike other fields is packed most
+    /// significant bit first.
+    fn write_code(&mut self, code: u32, bits: u32) {
+        self.write(code.reverse_bits() >> (32 - bits), bits);
+    }
+
+    /// Pads to a whole byte.
+    fn align(&mut self) {
+        if self.count > 0 {
+            self.write(0, 8 - self.count);
+        }
+    }
+
+    /// Takes the whole bytes written so far.
+    fn take(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.out)
+    }
+}
+
+/// A piece of input as LZ77 codes it.
+enum Token {
+    Literal(u8),
+    Match { length: usize, distance: usize },
+}
+
+/// Bits the fixed Huffman code for `symbol` takes.
+fn literal_bits(symbol: u32) -> usize {
+    match symbol {
+        0..=143 => 8,
+        144..=255 => 9,
+        256..=279 => 7,
+        _ => 8,
+    }
+}
+
+/// Bits `token` takes in a fixed Huffman block.
+fn token_bits(token: &Token) -> usize {
+    match *token {
+        Token::Literal(byte) => literal_bits(byte as u32),
+        Token::Match { length, distance } => {
+            let code = lengthBases.partition_point(|&base| base as usize <= length) - 1;
+            let distanceCode = distanceBases.partition_point(|&base| base as usize <= distance) - 1;
+            literal_bits(257 + code as u32)
+                + lengthExtra[code] as usize
+                + 5
+                + distanceExtra[distanceCode] as usize
+        }
+    }
+}
+
+fn write_literal(bits: &mut BitWriter, symbol: u32) {
+    match symbol {
+        0..=143 => bits.write_code(0x30 + symbol, 8),
+        144..=255 => bits.write_code(0x190 + symbol - 144, 9),
+        256..=279 => bits.write_code(symbol - 256, 7),
+        _ => bits.write_code(0xc0 + symbol - 280, 8),
+    }
+}
+
+fn write_match(bits: &mut BitWriter, length: usize, distance: usize) {
+    let code = lengthBases.partition_point(|&base| base as usize <= length) - 1;
+    write_literal(bits, 257 + code as u32);
+    bits.write(
+        (length - lengthBases[code] as usize) as u32,
+        lengthExtra[code] as u32,
+    );
+    let code = distanceBases.partition_point(|&base| base as usize <= distance) - 1;
+    bits.write_code(code as u32, 5);
+    bits.write(
+        (distance - distanceBases[code] as usize) as u32,
+        distanceExtra[code] as u32,
+    );
+}
+
+fn crc32(mut crc: u32, data: &[u8]) -> u32 {
+    crc = !crc;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xedb88320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+/// Compresses data written in pieces into one gzip member, each piece coded
+/// as its own block so it can be sent before the next arrives. Matches may
+/// reach back into earlier pieces.
+pub struct Encoder {
+    bits: BitWriter,
+    /// The last `windowSize` bytes written, and the piece being coded.
+    window: Vec<u8>,
+    /// Position in the whole input of `window[0]`.
+    windowStart: usize,
+    /// Latest position + 1 of each hash, or 0 for none.
+    head: Vec<usize>,
+    /// The previous position + 1 with the same hash, by position in the window.
+    prev: Vec<usize>,
+    crc: u32,
+    length: usize,
+}
+
+impl Encoder {
+    pub fn new() -> Self {
+        let mut bits = BitWriter::default();
+        // magic, deflate, no flags, no mtime, no extra flags, unknown OS
+        for byte in [0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 255] {
+            bits.write(byte, 8);
+        }
+        Self {
+            bits,
+            window: Vec::with_capacity(windowSize * 2),
+            windowStart: 0,
+            head: vec![0; 1 << hashBits],
+            prev: vec![0; windowSize],
+            crc: 0,
+            length: 0,
+        }
+    }
+
+    fn hash(data: &[u8]) -> usize {
+        let value = u32::from_le_bytes([data[0], data[1], data[2], 0]);
+        (value.wrapping_mul(0x9e3779b1) >> (32 - hashBits)) as usize
+    }
+
+    /// Notes that the three bytes at `pos` (in the whole input) start there.
+    fn insert(&mut self, pos: usize) {
+        let index = pos - self.windowStart;
+        if index + minMatch > self.window.len() {
+            return;
+        }
+        let hash = Self::hash(&self.window[index..]);
+        self.prev[pos % windowSize] = self.head[hash];
+        self.head[hash] = pos + 1;
+    }
+
+    /// Longest earlier match for the bytes at `pos`, as its length and distance.
+    fn longest_match(&self, pos: usize) -> Option<(usize, usize)> {
+        let index = pos - self.windowStart;
+        let limit = maxMatch.min(self.window.len() - index);
+        if limit < minMatch {
+            return None;
+        }
+        let mut best = None;
+        let mut bestLength = minMatch - 1;
+        let mut candidate = self.head[Self::hash(&self.window[index..])];
+        for _ in 0..maxChain {
+            // positions are stored plus one, so zero means none
+            let Some(start) = candidate.checked_sub(1) else {
+                break;
+            };
+            if start >= pos || pos - start > windowSize || start < self.windowStart {
+                break;
+            }
+            let earlier = &self.window[start - self.windowStart..];
+            let length = earlier
+                .iter()
+                .zip(&self.window[index..index + limit])
+                .take_while(|(a, b)| a == b)
+                .count();
+            if length > bestLength {
+                bestLength = length;
+                best = Some((length, pos - start));
+                if length == limit {
+                    break;
+                }
+            }
+            let next = self.prev[start % windowSize];
+            if next > candidate {
+                // overwritten by a later position, so the chain ends here
+                break;
+            }
+            candidate = next;
+        }
+        best
+    }
+
+    /// Compresses `data`, returning the compressed bytes completed so far.
+    pub fn write(&mut self, data: &[u8]) -> Vec<u8> {
+        if data.is_empty() {
+            return self.bits.take();
+        }
+        self.crc = crc32(self.crc, data);
+        self.length += data.len();
+        let start = self.windowStart + self.window.len();
+        self.window.extend_from_slice(data);
+        // any earlier bytes too near the end to hash can now be
+        for pos in start.saturating_sub(minMatch - 1).max(self.windowStart)..start {
+            self.insert(pos);
+        }
+
+        let end = self.windowStart + self.window.len();
+        let mut tokens = vec![];
+        let mut pos = start;
+        while pos < end {
+            match self.longest_match(pos) {
+                Some((length, distance)) => {
+                    tokens.push(Token::Match { length, distance });
+                    for pos in pos..pos + length {
+                        self.insert(pos);
+                    }
+                    pos += length;
+                }
+                None => {
+                    tokens.push(Token::Literal(self.window[pos - self.windowStart]));
+                    self.insert(pos);
+                    pos += 1;
+                }
+            }
+        }
+
+        // headers, end of block, and padding to a byte for stored blocks
+        let fixedBits = 3 + tokens.iter().map(token_bits).sum::<usize>() + 7;
+        let storedBits = data.len().div_ceil(maxStored).max(1) * (3 + 7 + 32) + data.len() * 8;
+        if storedBits < fixedBits {
+            for piece in data.chunks(maxStored) {
+                // a stored block, not the last
+                self.bits.write(0b000, 3);
+                self.bits.align();
+                self.bits.write(piece.len() as u32, 16);
+                self.bits.write(!piece.len() as u32 & 0xffff, 16);
+                self.bits.out.extend_from_slice(piece);
+            }
+        } else {
+            // a fixed Huffman block, not the last
+            self.bits.write(0b010, 3);
+            for token in tokens {
+                match token {
+                    Token::Literal(byte) => write_literal(&mut self.bits, byte as u32),
+                    Token::Match { length, distance } => {
+                        write_match(&mut self.bits, length, distance)
+                    }
+                }
+            }
+            write_literal(&mut self.bits, 256);
+        }
+
+        if self.window.len() > windowSize {
+            let excess = self.window.len() - windowSize;
+            self.window.drain(..excess);
+            self.windowStart += excess;
+        }
+        self.bits.take()
+    }
+
+    /// Ends the stream, returning the rest of it.
+    pub fn finish(mut self) -> Vec<u8> {
+        // an empty last block
+        self.bits.write(0b011, 3);
+        write_literal(&mut self.bits, 256);
+        self.bits.align();
+        self.bits.write(self.crc, 32);
+        self.bits.write(self.length as u32, 32);
+        self.bits.take()
+    }
+}
+
+/// Gzips `chunks` as they arrive.
+pub fn compress_stream<E>(
+    chunks: impl Stream<Item = Result<Bytes, E>>,
+) -> impl Stream<Item = Result<Bytes, E>> {
+    futures_util::stream::unfold(
+        (Box::pin(chunks), Some(Encoder::new())),
+        |(mut chunks, mut encoder)| async move {
+            loop {
+                let compressed = match chunks.next().await {
+                    Some(Ok(chunk)) => encoder.as_mut()?.write(&chunk),
+                    Some(Err(err)) => return Some((Err(err), (chunks, encoder))),
+                    None => encoder.take()?.finish(),
+                };
+                // pieces too small to complete a byte are held back
+                if !compressed.is_empty() {
+                    return Some((Ok(Bytes::from(compressed)), (chunks, encoder)));
+                }
+            }
+        },
+    )
+}
+
+/// Decompresses a gzip stream using only fixed Huffman and stored blocks, as
+/// `Encoder` writes, to check what it writes.
+#[cfg(test)]
+pub fn decompress(data: &[u8]) -> caseproxy::AResult<Vec<u8>> {
+    use anyhow::{anyhow, ensure};
+
+    struct BitReader<'a> {
+        data: &'a [u8],
+        pos: usize,
+    }
+
+    impl BitReader<'_> {
+        fn bits(&mut self, count: u32) -> caseproxy::AResult<u32> {
+            let mut value = 0;
+            for index in 0..count {
+                let byte = self
+                    .data
+                    .get(self.pos / 8)
+                    .ok_or_else(|| anyhow!("truncated"))?;
+                value |= ((*byte as u32 >> (self.pos % 8)) & 1) << index;
+                self.pos += 1;
+            }
+            Ok(value)
+        }
+
+        /// Reads a Huffman code of `count` bits onto `code`, most significant
+        /// bit first.
+        fn code(&mut self, mut code: u32, count: u32) -> caseproxy::AResult<u32> {
+            for _ in 0..count {
+                code = (code << 1) | self.bits(1)?;
+            }
+            Ok(code)
+        }
+
+        fn align(&mut self) {
+            self.pos = self.pos.next_multiple_of(8);
+        }
+    }
+
+    ensure!(
+        data.starts_with(&[0x1f, 0x8b, 8, 0]),
+        "not a plain gzip stream"
+    );
+    let mut reader = BitReader { data, pos: 10 * 8 };
+    let mut out = vec![];
+    loop {
+        let last = reader.bits(1)? == 1;
+        match reader.bits(2)? {
+            0 => {
+                reader.align();
+                let length = reader.bits(16)?;
+                ensure!(reader.bits(16)? == !length & 0xffff, "bad stored length");
+                let start = reader.pos / 8;
+                let stored = data
+                    .get(start..start + length as usize)
+                    .ok_or_else(|| anyhow!("truncated"))?;
+                out.extend_from_slice(stored);
+                reader.pos += length as usize * 8;
+            }
+            1 => loop {
+                let symbol = reader.code(0, 7)?;
+                let symbol = match symbol {
+                    0..=0x17 => symbol + 256,
+                    _ => match reader.code(symbol, 1)? {
+                        code @ 0x30..=0xbf => code - 0x30,
+                        code @ 0xc0..=0xc7 => code - 0xc0 + 280,
+                        code => reader.code(code, 1)? - 0x190 + 144,
+                    },
+                };
+                match symbol {
+                    0..=255 => out.push(symbol as u8),
+                    256 => break,
+                    _ => {
+                        let code = (symbol - 257) as usize;
+                        let length = lengthBases[code] as usize
+                            + reader.bits(lengthExtra[code] as u32)? as usize;
+                        let code = reader.code(0, 5)? as usize;
+                        let distance = distanceBases[code] as usize
+                            + reader.bits(distanceExtra[code] as u32)? as usize;
+                        ensure!(distance <= out.len(), "distance too far back");
+                        for _ in 0..length {
+                            out.push(out[out.len() - distance]);
+                        }
+                    }
+                }
+            },
+            _ => return Err(anyhow!("not a fixed Huffman or stored block")),
+        }
+        if last {
+            break;
+        }
+    }
+    let trailer = &data[reader.pos.div_ceil(8)..];
+    ensure!(trailer.len() == 8, "bad trailer length");
+    ensure!(
+        trailer[..4] == crc32(0, &out).to_le_bytes(),
+        "checksum mismatch"
+    );
+    ensure!(
+        trailer[4..] == (out.len() as u32).to_le_bytes(),
+        "length mismatch"
+    );
+    Ok(out)
+}
+
+#[test]
+fn test_gzip() -> caseproxy::AResult<()> {
+    assert_eq!(crc32(0, b"123456789"), 0xcbf43926);
+
+    let text: Vec<u8> = (0..2000)
+        .flat_map(|i| format!("line {} of some repetitive text\n", i % 37).into_bytes())
+        .collect();
+    // pieces of awkward sizes, so matches cross them
+    let mut encoder = Encoder::new();
+    let mut compressed = vec![];
+    for piece in text.chunks(1000).chain([&[][..]]) {
+        compressed.extend(encoder.write(piece));
+    }
+    compressed.extend(encoder.finish());
+    assert!(compressed.len() < text.len() / 5, "{}", compressed.len());
+    assert_eq!(decompress(&compressed)?, text);
+
+    // every byte value, and nothing at all
+    let bytes: Vec<u8> = (0..=255).cycle().take(100_000).collect();
+    for data in [&bytes[..], b""] {
+        let mut encoder = Encoder::new();
+        let mut compressed = encoder.write(data);
+        compressed.extend(encoder.finish());
+        assert_eq!(decompress(&compressed)?, data);
+    }
+
+    // noise doesn't compress, so is stored rather than growing
+    let mut state = 0x2545f491u32;
+    let noise: Vec<u8> = (0..200_000)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state as u8
+        })
+        .collect();
+    let mut encoder = Encoder::new();
+    let mut compressed = vec![];
+    for piece in noise.chunks(70_000) {
+        compressed.extend(encoder.write(piece));
+    }
+    compressed.extend(encoder.finish());
+    assert!(compressed.len() < noise.len() + 64, "{}", compressed.len());
+    assert_eq!(decompress(&compressed)?, noise);
+    Ok(())
+}
@@ -0,0 +1,549 @@
+//! File hashing for `dupe-finder` and `Digest` headers.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::Write,
+    io::{BufRead, BufReader, Read},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{anyhow, Context, Ok};
+use sha3::Digest;
+
+use crate::{json, AResult};
+
+/// Feeds the contents of `file` to `update`, a chunk at a time.
+pub fn read_chunks(file: &Path, mut update: impl FnMut(&[u8])) -> AResult<()> {
+    let mut file = std::fs::OpenOptions::new().read(true).open(file)?;
+    let mut chunk = [0u8; 8192];
+    loop {
+        let len = file.read(&mut chunk)?;
+        if len == 0 {
+            break;
+        }
+        update(&chunk[..len]);
+    }
+    Ok(())
+}
+
+pub fn sha3_256_file(file: &Path) -> AResult<[u8; 32]> {
+    let mut hasher = sha3::Sha3_256::new();
+    read_chunks(file, |chunk| hasher.update(chunk))?;
+    Ok(hasher.finalize().into())
+}
+
+pub fn sha256_file(file: &Path) -> AResult<[u8; 32]> {
+    let mut hasher = Sha256::new();
+    read_chunks(file, |chunk| hasher.update(chunk))?;
+    Ok(hasher.finalize())
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum HashAlgorithm {
+    /// For `Digest` headers.
+    Sha256,
+    /// For `dupe-finder`.
+    Sha3_256,
+}
+
+impl HashAlgorithm {
+    fn name(self) -> &'static str {
+        match self {
+            HashAlgorithm::Sha256 => "sha-256",
+            HashAlgorithm::Sha3_256 => "sha3-256",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        [HashAlgorithm::Sha256, HashAlgorithm::Sha3_256]
+            .into_iter()
+            .find(|algorithm| algorithm.name() == name)
+    }
+}
+
+pub fn content_hash(file: &Path, algorithm: HashAlgorithm) -> AResult<[u8; 32]> {
+    match algorithm {
+        HashAlgorithm::Sha256 => sha256_file(file),
+        HashAlgorithm::Sha3_256 => sha3_256_file(file),
+    }
+}
+
+/// Hashes of files, reused while their size and mtime are unchanged. Can be
+/// kept on disk, so hashes outlive the process and are shared between the
+/// server and `dupe-finder`.
+///
+/// Files are keyed by their canonical path, so however they're reached (e.g.
+/// relative to `dupe-finder`'s working directory, or through a symlink) they
+/// share an entry.
+pub struct HashCache {
+    store: Option<PathBuf>,
+    limit: usize,
+    entries: Mutex<HashEntries>,
+}
+
+type HashKey = (PathBuf, HashAlgorithm);
+
+#[derive(Default)]
+struct HashEntries {
+    hashes: HashMap<HashKey, CachedHash>,
+    /// Keys oldest first, the order they're evicted in. Keys hashed again
+    /// leave their earlier places behind, told apart by insertion.
+    order: VecDeque<(HashKey, u64)>,
+    insertions: u64,
+}
+
+#[derive(Clone, Copy)]
+struct CachedHash {
+    size: u64,
+    modified: SystemTime,
+    hash: [u8; 32],
+    insertion: u64,
+}
+
+impl HashEntries {
+    /// Adds `cached` as the newest entry, evicting the oldest to stay within
+    /// `limit`.
+    fn insert(&mut self, key: HashKey, mut cached: CachedHash, limit: usize) {
+        let Self {
+            hashes,
+            order,
+            insertions,
+        } = self;
+        if hashes.len() >= limit && !hashes.contains_key(&key) {
+            while let Some((oldest, insertion)) = order.pop_front() {
+                if hashes
+                    .get(&oldest)
+                    .is_some_and(|entry| entry.insertion == insertion)
+                {
+                    hashes.remove(&oldest);
+                    break;
+                }
+            }
+        }
+        *insertions += 1;
+        cached.insertion = *insertions;
+        hashes.insert(key.clone(), cached);
+        order.push_back((key, *insertions));
+        // drops places left behind, rarely enough to cost O(1) per insertion
+        if order.len() > limit * 2 {
+            order.retain(|(key, insertion)| {
+                hashes
+                    .get(key)
+                    .is_some_and(|entry| entry.insertion == *insertion)
+            });
+        }
+    }
+
+    /// Live entries, oldest first.
+    fn oldest_first(&self) -> impl Iterator<Item = (&HashKey, &CachedHash)> {
+        self.order.iter().filter_map(|(key, insertion)| {
+            let cached = self.hashes.get(key)?;
+            (cached.insertion == *insertion).then_some((key, cached))
+        })
+    }
+}
+
+impl HashCache {
+    /// A cache of at most `limit` hashes, kept in memory only.
+    pub fn new(limit: usize) -> Self {
+        Self {
+            store: None,
+            limit,
+            entries: Mutex::new(HashEntries::default()),
+        }
+    }
+
+    /// A cache loaded from `store`, if it exists yet, and written back to it
+    /// by `save`. Entries that can't be read are skipped, and the newest
+    /// `limit` kept.
+    pub fn open(store: &Path, limit: usize) -> AResult<Self> {
+        let mut res = Self::new(limit);
+        res.store = Some(store.to_path_buf());
+        let file = match std::fs::File::open(store) {
+            Result::Ok(file) => file,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(res),
+            Err(err) => return Err(err).with_context(|| format!("couldn't open {store:?}")),
+        };
+
+        let entries = res.entries.get_mut().unwrap();
+        for line in BufReader::new(file).lines() {
+            let parse = || -> AResult<_> {
+                let mut entry = json::parse_string_map(&line?)?;
+                let mut field = |name| {
+                    entry
+                        .remove(name)
+                        .ok_or_else(|| anyhow!("missing {name:?}"))
+                };
+                let path = PathBuf::from(field("path")?);
+                let algorithm = HashAlgorithm::from_name(&field("algorithm")?)
+                    .ok_or_else(|| anyhow!("unknown algorithm"))?;
+                let (secs, nanos) = field("modified")?
+                    .split_once('.')
+                    .map(|(secs, nanos)| Ok((secs.parse()?, nanos.parse()?)))
+                    .ok_or_else(|| anyhow!("invalid mtime"))??;
+                let cached = CachedHash {
+                    size: field("size")?.parse()?,
+                    modified: SystemTime::UNIX_EPOCH + Duration::new(secs, nanos),
+                    hash: unhex(&field("hash")?).ok_or_else(|| anyhow!("invalid hash"))?,
+                    insertion: 0,
+                };
+                Ok(((path, algorithm), cached))
+            };
+            if let Result::Ok((key, cached)) = parse() {
+                entries.insert(key, cached, limit);
+            }
+        }
+        Ok(res)
+    }
+
+    /// Hashes `file`, unless it was already with the same size and mtime.
+    pub fn get(&self, file: &Path, algorithm: HashAlgorithm) -> AResult<[u8; 32]> {
+        let file = std::fs::canonicalize(file)?;
+        let metadata = std::fs::metadata(&file)?;
+        let size = metadata.len();
+        let modified = metadata.modified()?;
+        let key = (file, algorithm);
+        if let Some(cached) = self.entries.lock().unwrap().hashes.get(&key) {
+            if cached.size == size && cached.modified == modified {
+                return Ok(cached.hash);
+            }
+        }
+
+        let hash = content_hash(&key.0, algorithm)?;
+        let cached = CachedHash {
+            size,
+            modified,
+            hash,
+            insertion: 0,
+        };
+        self.entries.lock().unwrap().insert(key, cached, self.limit);
+        Ok(hash)
+    }
+
+    /// Writes the cache back to its store, if it has one. Files whose paths
+    /// aren't valid UTF-8 are left out.
+    pub fn save(&self) -> AResult<()> {
+        let Some(store) = &self.store else {
+            return Ok(());
+        };
+        let mut contents = String::new();
+        // oldest first, so they're evicted in the same order once reloaded
+        for ((path, algorithm), cached) in self.entries.lock().unwrap().oldest_first() {
+            let Some(path) = path.to_str() else {
+                continue;
+            };
+            let modified = cached.modified.duration_since(SystemTime::UNIX_EPOCH)?;
+            writeln!(
+                &mut contents,
+                "{{\"path\":{},\"algorithm\":\"{}\",\"size\":\"{}\",\"modified\":\"{}.{:09}\",\"hash\":\"{}\"}}",
+                json::quote(path),
+                algorithm.name(),
+                cached.size,
+                modified.as_secs(),
+                modified.subsec_nanos(),
+                hex(&cached.hash)
+            )?;
+        }
+        // replaced whole, so a concurrent reader never sees half of it
+        let partial = store.with_extension(format!("{}.partial", std::process::id()));
+        std::fs::write(&partial, contents)?;
+        std::fs::rename(&partial, store).with_context(|| format!("couldn't write {store:?}"))
+    }
+}
+
+#[test]
+fn test_hash_cache() -> AResult<()> {
+    use std::fs::File;
+
+    use crate::make_temp_dir;
+
+    let (tempdir, removeTempdir) = make_temp_dir()?;
+    let file = tempdir.join("file.txt");
+    let store = tempdir.join("hashes.jsonl");
+    let mtime = SystemTime::UNIX_EPOCH + Duration::new(1_700_000_000, 123);
+    // changes the contents while keeping the size and mtime, so only a cache
+    // hit still gives the old hash
+    let rewrite = |contents: &str| -> AResult<()> {
+        std::fs::write(&file, contents)?;
+        File::options()
+            .write(true)
+            .open(&file)?
+            .set_modified(mtime)?;
+        Ok(())
+    };
+    let sha256 = |data: &[u8]| {
+        let mut hasher = Sha256::new();
+        hasher.update(data);
+        hasher.finalize()
+    };
+
+    rewrite("abc")?;
+    let cache = HashCache::open(&store, 10)?;
+    assert_eq!(cache.get(&file, HashAlgorithm::Sha256)?, sha256(b"abc"));
+    assert_eq!(
+        cache.get(&file, HashAlgorithm::Sha3_256)?,
+        sha3_256_file(&file)?
+    );
+    rewrite("xyz")?;
+    assert_eq!(cache.get(&file, HashAlgorithm::Sha256)?, sha256(b"abc"));
+    cache.save()?;
+
+    // hits after reloading, too
+    let cache = HashCache::open(&store, 10)?;
+    assert_eq!(cache.get(&file, HashAlgorithm::Sha256)?, sha256(b"abc"));
+
+    // missed once modified
+    std::fs::write(&file, "xyz")?;
+    assert_eq!(cache.get(&file, HashAlgorithm::Sha256)?, sha256(b"xyz"));
+    std::fs::write(&file, "abcd")?;
+    assert_eq!(cache.get(&file, HashAlgorithm::Sha256)?, sha256(b"abcd"));
+
+    // keyed by the file, however it's reached
+    let cache = HashCache::new(10);
+    std::os::unix::fs::symlink("file.txt", tempdir.join("link.txt"))?;
+    std::fs::create_dir(tempdir.join("sub"))?;
+    for path in ["file.txt", "./file.txt", "link.txt", "sub/../file.txt"] {
+        cache.get(&tempdir.join(path), HashAlgorithm::Sha256)?;
+    }
+    assert_eq!(cache.entries.lock().unwrap().hashes.len(), 1);
+
+    // bounded, evicting the oldest first
+    let cache = HashCache::new(2);
+    let hashed = |cache: &HashCache| -> Vec<PathBuf> {
+        let entries = cache.entries.lock().unwrap();
+        let mut paths: Vec<_> = entries
+            .hashes
+            .keys()
+            .map(|(path, _)| path.clone())
+            .collect();
+        paths.sort();
+        paths
+    };
+    let canonical = |name: &str| std::fs::canonicalize(tempdir.join(name)).unwrap();
+    for name in ["a.txt", "b.txt", "c.txt"] {
+        std::fs::write(tempdir.join(name), name)?;
+    }
+    cache.get(&tempdir.join("a.txt"), HashAlgorithm::Sha256)?;
+    cache.get(&tempdir.join("b.txt"), HashAlgorithm::Sha256)?;
+    cache.get(&tempdir.join("a.txt"), HashAlgorithm::Sha256)?;
+    cache.get(&tempdir.join("c.txt"), HashAlgorithm::Sha256)?;
+    assert_eq!(hashed(&cache), [canonical("b.txt"), canonical("c.txt")]);
+    // and keeping the newest when reloaded
+    let store = tempdir.join("bounded.jsonl");
+    let cache = HashCache::open(&store, 3)?;
+    for name in ["a.txt", "b.txt", "c.txt"] {
+        cache.get(&tempdir.join(name), HashAlgorithm::Sha256)?;
+    }
+    cache.save()?;
+    let cache = HashCache::open(&store, 2)?;
+    assert_eq!(hashed(&cache), [canonical("b.txt"), canonical("c.txt")]);
+
+    Ok(())
+}
+
+/// SHA-256 (FIPS 180-4), as `Digest` headers can't name SHA-3.
+pub struct Sha256 {
+    state: [u32; 8],
+    block: [u8; 64],
+    blockLength: usize,
+    totalLength: u64,
+}
+
+const roundConstants: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+impl Sha256 {
+    pub fn new() -> Self {
+        Self {
+            state: [
+                0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab,
+                0x5be0cd19,
+            ],
+            block: [0; 64],
+            blockLength: 0,
+            totalLength: 0,
+        }
+    }
+
+    pub fn update(&mut self, mut data: &[u8]) {
+        self.totalLength += data.len() as u64;
+        while !data.is_empty() {
+            let take = data.len().min(64 - self.blockLength);
+            self.block[self.blockLength..self.blockLength + take].copy_from_slice(&data[..take]);
+            self.blockLength += take;
+            data = &data[take..];
+            if self.blockLength == 64 {
+                self.compress();
+                self.blockLength = 0;
+            }
+        }
+    }
+
+    pub fn finalize(mut self) -> [u8; 32] {
+        let bitLength = self.totalLength * 8;
+        self.update(&[0x80]);
+        while self.blockLength != 56 {
+            self.update(&[0]);
+        }
+        self.update(&bitLength.to_be_bytes());
+
+        let mut res = [0; 32];
+        for (bytes, word) in res.chunks_exact_mut(4).zip(self.state) {
+            bytes.copy_from_slice(&word.to_be_bytes());
+        }
+        res
+    }
+
+    fn compress(&mut self) {
+        let mut schedule = [0u32; 64];
+        for (word, bytes) in schedule.iter_mut().zip(self.block.chunks_exact(4)) {
+            *word = u32::from_be_bytes(bytes.try_into().unwrap());
+        }
+        for i in 16..64 {
+            let s0 = schedule[i - 15].rotate_right(7)
+                ^ schedule[i - 15].rotate_right(18)
+                ^ (schedule[i - 15] >> 3);
+            let s1 = schedule[i - 2].rotate_right(17)
+                ^ schedule[i - 2].rotate_right(19)
+                ^ (schedule[i - 2] >> 10);
+            schedule[i] = schedule[i - 16]
+                .wrapping_add(s0)
+                .wrapping_add(schedule[i - 7])
+                .wrapping_add(s1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = self.state;
+        for (constant, word) in roundConstants.iter().zip(schedule) {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let choice = (e & f) ^ (!e & g);
+            let temp1 = h
+                .wrapping_add(s1)
+                .wrapping_add(choice)
+                .wrapping_add(*constant)
+                .wrapping_add(word);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let majority = (a & b) ^ (a & c) ^ (b & c);
+            let temp2 = s0.wrapping_add(majority);
+
+            h = g;
+            g = f;
+            f = e;
+            e = d.wrapping_add(temp1);
+            d = c;
+            c = b;
+            b = a;
+            a = temp1.wrapping_add(temp2);
+        }
+        for (state, value) in self.state.iter_mut().zip([a, b, c, d, e, f, g, h]) {
+            *state = state.wrapping_add(value);
+        }
+    }
+}
+
+impl Default for Sha256 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[test]
+fn test_sha256() {
+    let sha256 = |data: &[u8]| {
+        let mut hasher = Sha256::new();
+        hasher.update(data);
+        hex(&hasher.finalize())
+    };
+    assert_eq!(
+        sha256(b""),
+        "E3B0C44298FC1C149AFBF4C8996FB92427AE41E4649B934CA495991B7852B855"
+    );
+    assert_eq!(
+        sha256(b"abc"),
+        "BA7816BF8F01CFEA414140DE5DAE2223B00361A396177A9CB410FF61F20015AD"
+    );
+    // crosses a block boundary during padding
+    assert_eq!(
+        sha256(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"),
+        "248D6A61D20638B8E5C026930C3E6039A33CE45964FF2167F6ECEDD419DB06C1"
+    );
+
+    // fed in pieces of awkward sizes
+    let data = vec![b'a'; 1000];
+    let mut hasher = Sha256::new();
+    for chunk in data.chunks(63) {
+        hasher.update(chunk);
+    }
+    assert_eq!(hex(&hasher.finalize()), sha256(&data));
+}
+
+/// Formats `bytes` as uppercase hex.
+pub fn hex(bytes: &[u8]) -> String {
+    let mut res = String::with_capacity(bytes.len() * 2);
+    for byte in bytes {
+        write!(&mut res, "{byte:02X}").unwrap();
+    }
+    res
+}
+
+/// Parses uppercase or lowercase hex, as formatted by `hex`.
+fn unhex<const N: usize>(hex: &str) -> Option<[u8; N]> {
+    if hex.len() != N * 2 || !hex.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        return None;
+    }
+    let mut res = [0; N];
+    for (byte, digits) in res.iter_mut().zip(hex.as_bytes().chunks_exact(2)) {
+        *byte = u8::from_str_radix(std::str::from_utf8(digits).ok()?, 16).ok()?;
+    }
+    Some(res)
+}
+
+#[test]
+fn test_unhex() {
+    assert_eq!(unhex::<2>("0AfF"), Some([0x0a, 0xff]));
+    assert_eq!(unhex(&hex(&[1, 2, 3])), Some([1, 2, 3]));
+    assert_eq!(unhex::<2>("0AF"), None);
+    assert_eq!(unhex::<1>("0G"), None);
+    assert_eq!(unhex::<1>("+1"), None);
+}
+
+/// Formats `bytes` as standard, padded base64.
+pub fn base64(bytes: &[u8]) -> String {
+    const alphabet: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut res = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let value = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |value, (index, &byte)| {
+                value | (byte as u32) << (16 - index * 8)
+            });
+        for index in 0..4 {
+            if index <= chunk.len() {
+                res.push(alphabet[(value >> (18 - index * 6)) as usize & 63] as char);
+            } else {
+                res.push('=');
+            }
+        }
+    }
+    res
+}
+
+#[test]
+fn test_base64() {
+    assert_eq!(base64(b""), "");
+    assert_eq!(base64(b"f"), "Zg==");
+    assert_eq!(base64(b"fo"), "Zm8=");
+    assert_eq!(base64(b"foo"), "Zm9v");
+    assert_eq!(base64(b"foob"), "Zm9vYg==");
+    assert_eq!(base64(&[0xfb, 0xff]), "+/8=");
+}
@@ -0,0 +1,173 @@
+//! Just enough JSON for manifests and diagnostic output.
+
+use std::{collections::BTreeMap, fmt::Write};
+
+use anyhow::{anyhow, Ok};
+
+use crate::AResult;
+
+/// Formats `str` as a quoted JSON string.
+pub fn quote(str: &str) -> String {
+    let mut res = String::with_capacity(str.len() + 2);
+    res.push('"');
+    for char in str.chars() {
+        match char {
+            '"' => res.push_str("\\\""),
+            '\\' => res.push_str("\\\\"),
+            '\n' => res.push_str("\\n"),
+            '\r' => res.push_str("\\r"),
+            '\t' => res.push_str("\\t"),
+            '\u{0}'..='\u{1f}' => write!(&mut res, "\\u{:04x}", char as u32).unwrap(),
+            _ => res.push(char),
+        }
+    }
+    res.push('"');
+    res
+}
+
+#[test]
+fn test_quote() {
+    assert_eq!(quote("plain"), r#""plain""#);
+    assert_eq!(quote("a\"b\\c"), r#""a\"b\\c""#);
+    assert_eq!(quote("tab\there\n"), r#""tab\there\n""#);
+    assert_eq!(quote("\u{1}caf\u{e9}"), "\"\\u0001caf\u{e9}\"");
+}
+
+/// Parses a JSON object whose values are all strings.
+pub fn parse_string_map(json: &str) -> AResult<BTreeMap<String, String>> {
+    let mut parser = Parser {
+        chars: json.chars().peekable(),
+    };
+    let mut res = BTreeMap::new();
+
+    parser.expect('{')?;
+    if parser.peek() == Some('}') {
+        parser.expect('}')?;
+    } else {
+        loop {
+            let key = parser.string()?;
+            parser.expect(':')?;
+            let value = parser.string()?;
+            res.insert(key, value);
+
+            match parser.next() {
+                Some(',') => continue,
+                Some('}') => break,
+                other => return Err(anyhow!("expected `,` or `}}`, found {other:?}")),
+            }
+        }
+    }
+    if let Some(trailing) = parser.next() {
+        return Err(anyhow!("unexpected {trailing:?} after object"));
+    }
+    Ok(res)
+}
+
+struct Parser<'a> {
+    chars: std::iter::Peekable<std::str::Chars<'a>>,
+}
+
+impl Parser<'_> {
+    fn skip_whitespace(&mut self) {
+        while self
+            .chars
+            .next_if(|c| matches!(c, ' ' | '\t' | '\n' | '\r'))
+            .is_some()
+        {}
+    }
+
+    fn peek(&mut self) -> Option<char> {
+        self.skip_whitespace();
+        self.chars.peek().copied()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        self.skip_whitespace();
+        self.chars.next()
+    }
+
+    fn expect(&mut self, expected: char) -> AResult<()> {
+        match self.next() {
+            Some(c) if c == expected => Ok(()),
+            other => Err(anyhow!("expected `{expected}`, found {other:?}")),
+        }
+    }
+
+    fn string(&mut self) -> AResult<String> {
+        self.expect('"')?;
+        let mut res = String::new();
+        loop {
+            match self.chars.next() {
+                None => return Err(anyhow!("unterminated string")),
+                Some('"') => return Ok(res),
+                Some('\\') => match self.chars.next() {
+                    Some('"') => res.push('"'),
+                    Some('\\') => res.push('\\'),
+                    Some('/') => res.push('/'),
+                    Some('b') => res.push('\u{8}'),
+                    Some('f') => res.push('\u{c}'),
+                    Some('n') => res.push('\n'),
+                    Some('r') => res.push('\r'),
+                    Some('t') => res.push('\t'),
+                    Some('u') => {
+                        let high = self.hex4()?;
+                        let code = if (0xD800..0xDC00).contains(&high) {
+                            if self.chars.next() != Some('\\') || self.chars.next() != Some('u') {
+                                return Err(anyhow!("unpaired surrogate in string"));
+                            }
+                            let low = self.hex4()?;
+                            if !(0xDC00..0xE000).contains(&low) {
+                                return Err(anyhow!("unpaired surrogate in string"));
+                            }
+                            0x10000 + ((high - 0xD800) << 10) + (low - 0xDC00)
+                        } else {
+                            high
+                        };
+                        res.push(
+                            char::from_u32(code)
+                                .ok_or_else(|| anyhow!("invalid escape \\u{code:04x}"))?,
+                        );
+                    }
+                    other => return Err(anyhow!("invalid escape {other:?}")),
+                },
+                Some(c) => res.push(c),
+            }
+        }
+    }
+
+    fn hex4(&mut self) -> AResult<u32> {
+        let mut res = 0;
+        for _ in 0..4 {
+            let digit = self
+                .chars
+                .next()
+                .and_then(|c| c.to_digit(16))
+                .ok_or_else(|| anyhow!("invalid \\u escape"))?;
+            res = res * 16 + digit;
+        }
+        Ok(res)
+    }
+}
+
+#[test]
+fn test_parse_string_map() -> AResult<()> {
+    let map = parse_string_map(
+        r#" {
+            "a": "A",
+            "escaped\"\\\/": "\n\t\u00e9\ud83d\ude00"
+        } "#,
+    )?;
+    assert_eq!(map.len(), 2);
+    assert_eq!(map["a"], "A");
+    assert_eq!(map["escaped\"\\/"], "\n\t\u{e9}\u{1f600}");
+
+    assert!(parse_string_map("{}")?.is_empty());
+    assert!(parse_string_map(r#"{"a": "b",}"#).is_err());
+    assert!(parse_string_map(r#"{"a": 1}"#).is_err());
+    assert!(parse_string_map(r#"{"a": "b"} x"#).is_err());
+    assert!(parse_string_map(r#"{"a": "\ud800"}"#).is_err());
+
+    let roundtrip = format!("{{{}: {}}}", quote("k\u{1}\""), quote("v\\"));
+    assert_eq!(parse_string_map(&roundtrip)?["k\u{1}\""], "v\\");
+    Ok(())
+}
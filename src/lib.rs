@@ -2,33 +2,140 @@
 
 use std::{
     cmp::Ordering,
-    collections::VecDeque,
+    collections::{HashMap, VecDeque},
     ffi::{OsStr, OsString},
     fs::read_dir,
     hash::{DefaultHasher, Hash, Hasher},
     ops::{Deref, DerefMut},
     path::{Component, Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU8, Ordering as AtomicOrdering},
+        Arc, RwLock,
+    },
+    time::SystemTime,
 };
 
 pub use anyhow::Result as AResult;
 use anyhow::{anyhow, Ok};
 
+pub mod hashing;
+pub mod json;
+pub mod manifest;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum)]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+static logLevel: AtomicU8 = AtomicU8::new(LogLevel::Warn as u8);
+
+pub fn set_log_level(level: LogLevel) {
+    logLevel.store(level as u8, AtomicOrdering::Relaxed);
+}
+
+pub fn log_enabled(level: LogLevel) -> bool {
+    level as u8 <= logLevel.load(AtomicOrdering::Relaxed)
+}
+
+/// Prints a message to stderr if `level` is enabled. Arguments are not
+/// evaluated otherwise.
+#[macro_export]
+macro_rules! log {
+    ($level:expr, $($arg:tt)+) => {
+        if $crate::log_enabled($level) {
+            eprintln!("[{:?}] {}", $level, format_args!($($arg)+));
+        }
+    };
+}
+
+/// Tweaks to how `InsensitivePath::find_matching_files_with` matches path components.
+#[derive(Clone, Copy, Default)]
+pub struct MatchOptions<'a> {
+    /// Look up directory entries through this index rather than scanning every
+    /// directory along the way.
+    pub index: Option<&'a DirectoryIndex>,
+
+    /// Match directories and the stem of the filename exactly, and only the
+    /// filename's extension case-insensitively.
+    pub insensitiveExtOnly: bool,
+
+    /// Ignore trailing dots and spaces in filenames, which Windows trims, so
+    /// e.g. `file.txt.` and `file.txt` match each other. Only applies to the
+    /// last component, which is then scanned for even with an `index`.
+    pub trimTrailingDots: bool,
+
+    /// Stop the walk once this is set, failing each directory lookup still
+    /// to be made.
+    pub cancelled: Option<&'a AtomicBool>,
+
+    /// Also match accented Latin letters against their unaccented forms, so
+    /// `cafe` matches `café`. Only affects matching against the filesystem;
+    /// `InsensitivePath` and `InsensitiveOsString` still compare by case alone.
+    pub deaccent: bool,
+}
+
+/// As `MatchOptions`, sharing the index and cancellation flag rather than
+/// borrowing them, so matching can be moved onto other threads.
+#[derive(Clone, Default)]
+pub struct SharedMatchOptions {
+    pub index: Option<Arc<DirectoryIndex>>,
+    pub insensitiveExtOnly: bool,
+    pub trimTrailingDots: bool,
+    pub cancelled: Option<Arc<AtomicBool>>,
+    pub deaccent: bool,
+}
+
+impl SharedMatchOptions {
+    pub fn borrow(&self) -> MatchOptions<'_> {
+        MatchOptions {
+            index: self.index.as_deref(),
+            insensitiveExtOnly: self.insensitiveExtOnly,
+            trimTrailingDots: self.trimTrailingDots,
+            cancelled: self.cancelled.as_deref(),
+            deaccent: self.deaccent,
+        }
+    }
+}
+
 #[derive(Clone, Debug, Eq)]
 pub struct InsensitivePath(pub PathBuf);
 
 impl InsensitivePath {
     pub fn find_matching_files(&self, root: Option<&Path>) -> AResult<Vec<PathBuf>> {
+        self.find_matching_files_with(root, MatchOptions::default())
+    }
+
+    /// As `find_matching_files_with`, pairing each match with its file
+    /// identity so that hardlinks to the same file can be told apart from
+    /// distinct files.
+    pub fn find_matching_files_with_ids(
+        &self,
+        root: Option<&Path>,
+        options: MatchOptions,
+    ) -> AResult<Vec<(PathBuf, FileId)>> {
+        self.find_matching_files_with(root, options)?
+            .into_iter()
+            .map(|path| {
+                let id = FileId::of(&path)?;
+                Ok((path, id))
+            })
+            .collect()
+    }
+
+    pub fn find_matching_files_with(
+        &self,
+        root: Option<&Path>,
+        options: MatchOptions,
+    ) -> AResult<Vec<PathBuf>> {
         let root = root.unwrap_or(Path::new("."));
         let mut matchingFiles = Vec::new();
         let mut queue = VecDeque::new();
-        queue.push_back((
-            PathBuf::from(""),
-            if root == Path::new(".") {
-                self.to_path_buf()
-            } else {
-                self.strip_prefix(root)?.to_path_buf()
-            },
-        ));
+        queue.push_back((PathBuf::from(""), self.relative_to(root)?.to_path_buf()));
+        let mut firstError = None;
 
         while let Some((mut prefix, mut remaining)) = queue.pop_front() {
             let headPath = {
@@ -50,38 +157,772 @@ impl InsensitivePath {
             let mut fullPath = PathBuf::new();
             fullPath.push(root);
             fullPath.push(&prefix);
-            if remaining.components().next().is_none() {
-                // head component is filename
-                for entry in read_dir(&fullPath)? {
-                    let entry = entry?;
-                    let filename = entry.file_name();
-                    if compare_osstr_case_insensitive(&filename, &headPath) == Ordering::Equal {
-                        fullPath.push(filename);
-                        matchingFiles.push(fullPath.to_path_buf());
-                        fullPath.pop();
-                    }
+            let isDirectory = remaining.components().next().is_some();
+            // another case variant of this directory may still be readable
+            let candidates = match component_candidates(&fullPath, &headPath, isDirectory, options)
+            {
+                std::result::Result::Ok(candidates) => candidates,
+                Err(err) => {
+                    log!(LogLevel::Debug, "skipping unreadable {fullPath:?}: {err}");
+                    firstError.get_or_insert(err);
+                    continue;
+                }
+            };
+            if isDirectory {
+                for filename in candidates {
+                    let mut relativePath = PathBuf::new();
+                    relativePath.push(&prefix);
+                    relativePath.push(filename);
+                    queue.push_back((relativePath, remaining.clone()));
                 }
             } else {
-                // head component is a directory
-                for entry in read_dir(&fullPath)? {
-                    let entry = entry?;
-                    if !entry.file_type()?.is_dir() {
-                        continue;
-                    }
+                // head component is filename
+                for filename in candidates {
+                    fullPath.push(filename);
+                    matchingFiles.push(fullPath.to_path_buf());
+                    fullPath.pop();
+                }
+            }
+        }
 
-                    let filename = entry.file_name();
-                    if compare_osstr_case_insensitive(&filename, &headPath) == Ordering::Equal {
-                        let mut relativePath = PathBuf::new();
-                        relativePath.push(&prefix);
-                        relativePath.push(filename);
-                        queue.push_back((relativePath, remaining.clone()));
-                    }
+        if let (true, Some(err)) = (matchingFiles.is_empty(), firstError) {
+            return Err(err);
+        }
+
+        // `read_dir` order is filesystem-dependent, so sort for stable results
+        matchingFiles.sort_by(|l, r| compare_path_case_insensitive(l, r).then_with(|| l.cmp(r)));
+        Ok(matchingFiles)
+    }
+
+    /// As `find_matching_files_with`, scanning up to `concurrency` directories
+    /// at once on tokio's blocking pool rather than one after another, so paths
+    /// through many case variants of directories resolve sooner on slow (e.g.
+    /// network) storage. Results are sorted the same way.
+    pub async fn find_matching_files_concurrent(
+        &self,
+        root: Option<&Path>,
+        options: SharedMatchOptions,
+        concurrency: usize,
+    ) -> AResult<Vec<PathBuf>> {
+        use futures_util::stream::{FuturesUnordered, StreamExt};
+
+        let root = root.unwrap_or(Path::new("."));
+        let components = self
+            .relative_to(root)?
+            .components()
+            .map(|component| match component {
+                Component::Normal(name) => Ok(name.to_os_string()),
+                _ => Err(anyhow!("unexpected path component {component:?}")),
+            })
+            .collect::<AResult<Vec<_>>>()?;
+        if components.is_empty() {
+            return Err(anyhow!("no path components to match"));
+        }
+
+        let options = Arc::new(options);
+        let mut matchingFiles = Vec::new();
+        let mut firstError = None;
+        // directories still to scan, relative to the root, and the index of
+        // the component to look for in each
+        let mut queue = VecDeque::from([(PathBuf::new(), 0)]);
+        let mut scanning = FuturesUnordered::new();
+        loop {
+            while scanning.len() < concurrency.max(1) {
+                let Some((prefix, depth)) = queue.pop_front() else {
+                    break;
+                };
+                let dir = root.join(&prefix);
+                let name = components[depth].clone();
+                let isDirectory = depth + 1 < components.len();
+                let options = options.clone();
+                let scan = tokio::task::spawn_blocking(move || {
+                    component_candidates(&dir, &name, isDirectory, options.borrow())
+                });
+                scanning.push(async move {
+                    let candidates = match scan.await {
+                        std::result::Result::Ok(candidates) => candidates,
+                        Err(err) => Err(err.into()),
+                    };
+                    (prefix, depth, candidates)
+                });
+            }
+            let Some((prefix, depth, candidates)) = scanning.next().await else {
+                break;
+            };
+            // another case variant of this directory may still be readable
+            let candidates = match candidates {
+                std::result::Result::Ok(candidates) => candidates,
+                Err(err) => {
+                    log!(
+                        LogLevel::Debug,
+                        "skipping unreadable {:?}: {err}",
+                        root.join(&prefix)
+                    );
+                    firstError.get_or_insert(err);
+                    continue;
+                }
+            };
+            for filename in candidates {
+                let relativePath = prefix.join(filename);
+                if depth + 1 < components.len() {
+                    queue.push_back((relativePath, depth + 1));
+                } else {
+                    matchingFiles.push(root.join(relativePath));
                 }
             }
         }
 
+        if let (true, Some(err)) = (matchingFiles.is_empty(), firstError) {
+            return Err(err);
+        }
+        matchingFiles.sort_by(|l, r| compare_path_case_insensitive(l, r).then_with(|| l.cmp(r)));
         Ok(matchingFiles)
     }
+
+    /// Finds the first of `find_matching_files_with`'s matches, without
+    /// listing the rest.
+    ///
+    /// Case variants of each directory are descended one at a time in sorted
+    /// order, moving on to the next only if the first doesn't contain the rest
+    /// of the path. So where several components are ambiguous, resolving
+    /// usually costs one directory lookup per component rather than one per
+    /// combination of variants.
+    pub fn find_first_matching_file_with(
+        &self,
+        root: Option<&Path>,
+        options: MatchOptions,
+    ) -> AResult<Option<PathBuf>> {
+        fn descend(
+            dir: &mut PathBuf,
+            components: &[&OsStr],
+            options: MatchOptions,
+            firstError: &mut Option<anyhow::Error>,
+        ) -> Option<PathBuf> {
+            let [head, rest @ ..] = components else {
+                return None;
+            };
+            let isDirectory = !rest.is_empty();
+            let mut candidates = match component_candidates(dir, head, isDirectory, options) {
+                std::result::Result::Ok(candidates) => candidates,
+                Err(err) => {
+                    log!(LogLevel::Debug, "skipping unreadable {dir:?}: {err}");
+                    firstError.get_or_insert(err);
+                    return None;
+                }
+            };
+            // candidates are all equal case-insensitively, so this is the
+            // order `find_matching_files_with` sorts them in
+            candidates.sort();
+            if !isDirectory {
+                return candidates.first().map(|filename| dir.join(filename));
+            }
+            for filename in candidates {
+                dir.push(filename);
+                let found = descend(dir, rest, options, firstError);
+                dir.pop();
+                if found.is_some() {
+                    return found;
+                }
+            }
+            None
+        }
+
+        let root = root.unwrap_or(Path::new("."));
+        let components = self
+            .relative_to(root)?
+            .components()
+            .map(|component| match component {
+                Component::Normal(name) => Ok(name),
+                _ => Err(anyhow!("unexpected path component {component:?}")),
+            })
+            .collect::<AResult<Vec<_>>>()?;
+
+        let mut firstError = None;
+        let found = descend(
+            &mut root.to_path_buf(),
+            &components,
+            options,
+            &mut firstError,
+        );
+        match (found, firstError) {
+            (None, Some(err)) => Err(err),
+            (found, _) => Ok(found),
+        }
+    }
+
+    /// The deepest directory along this path that exists, in its real case,
+    /// and how many components of the path it spans. The next component is
+    /// where resolution fails, if it does. Where there are several case
+    /// variants of a directory, whichever leads deepest is taken.
+    pub fn deepest_match(
+        &self,
+        root: Option<&Path>,
+        options: MatchOptions,
+    ) -> AResult<(PathBuf, usize)> {
+        fn descend(
+            dir: &mut PathBuf,
+            components: &[&OsStr],
+            depth: usize,
+            options: MatchOptions,
+            deepest: &mut (PathBuf, usize),
+        ) {
+            if depth > deepest.1 {
+                *deepest = (dir.clone(), depth);
+            }
+            let [head, rest @ ..] = components else {
+                return;
+            };
+            let std::result::Result::Ok(mut candidates) =
+                component_candidates(dir, head, !rest.is_empty(), options)
+            else {
+                return;
+            };
+            candidates.sort();
+            for filename in candidates {
+                dir.push(filename);
+                descend(dir, rest, depth + 1, options, deepest);
+                dir.pop();
+                if deepest.1 == depth + 1 + rest.len() {
+                    return;
+                }
+            }
+        }
+
+        let root = root.unwrap_or(Path::new("."));
+        let components: Vec<_> = self
+            .relative_to(root)?
+            .components()
+            .map(|component| match component {
+                Component::Normal(name) => Ok(name),
+                _ => Err(anyhow!("unexpected path component {component:?}")),
+            })
+            .collect::<AResult<_>>()?;
+
+        let mut deepest = (root.to_path_buf(), 0);
+        descend(
+            &mut root.to_path_buf(),
+            &components,
+            0,
+            options,
+            &mut deepest,
+        );
+        Ok(deepest)
+    }
+
+    /// Why this path doesn't resolve, or `None` if it does. Follows
+    /// `deepest_match`, telling a component matching only files (so the rest
+    /// of the path can't be inside it) apart from one matching nothing.
+    pub fn unresolved(
+        &self,
+        root: Option<&Path>,
+        options: MatchOptions,
+    ) -> AResult<Option<ResolveError>> {
+        let (dir, depth) = self.deepest_match(root, options)?;
+        let components: Vec<_> = self
+            .relative_to(root.unwrap_or(Path::new(".")))?
+            .components()
+            .collect();
+        let Some(Component::Normal(next)) = components.get(depth) else {
+            return Ok(None);
+        };
+        if depth + 1 < components.len() {
+            let mut files = component_candidates(&dir, next, false, options).unwrap_or_default();
+            files.sort();
+            if let Some(file) = files.into_iter().next() {
+                let file = dir.join(file);
+                return Ok(Some(ResolveError::NotADirectory { file, depth }));
+            }
+        }
+        Ok(Some(ResolveError::NoMatch { dir, depth }))
+    }
+
+    fn relative_to(&self, root: &Path) -> AResult<&Path> {
+        match self.strip_prefix(root) {
+            std::result::Result::Ok(relative) => Ok(relative),
+            // paths relative to the working directory may omit the leading `./`
+            Err(_) if root == Path::new(".") => Ok(self),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+/// Lists entries of `dir` which the path component `name` may refer to under
+/// `options`, in `read_dir` order. With `directoriesOnly`, as for components
+/// other than the last, only directories are listed.
+///
+/// This is the single step `InsensitivePath`'s searches are built from, for
+/// walking paths some other way.
+pub fn component_candidates(
+    dir: &Path,
+    name: &OsStr,
+    directoriesOnly: bool,
+    options: MatchOptions,
+) -> AResult<Vec<OsString>> {
+    if options
+        .cancelled
+        .is_some_and(|cancelled| cancelled.load(AtomicOrdering::Relaxed))
+    {
+        return Err(anyhow!("resolution cancelled"));
+    }
+    let deaccent = options.deaccent;
+    if too_long_to_exist(dir, name, deaccent) {
+        return Ok(vec![]);
+    }
+    let trimmed = options.trimTrailingDots && !directoriesOnly;
+    let mut candidates = match options.index {
+        _ if trimmed => scan_directory_trimmed(dir, name, deaccent)?,
+        Some(index) => index.lookup(dir, name, directoriesOnly, deaccent)?,
+        None => scan_directory(dir, name, directoriesOnly, deaccent)?,
+    };
+    if options.insensitiveExtOnly {
+        if directoriesOnly {
+            candidates.retain(|candidate| candidate == name);
+        } else if trimmed {
+            candidates.retain(|candidate| {
+                extensions_only_differ_in_case(
+                    trim_trailing_dots(candidate),
+                    trim_trailing_dots(name),
+                    deaccent,
+                )
+            });
+        } else {
+            candidates
+                .retain(|candidate| extensions_only_differ_in_case(candidate, name, deaccent));
+        }
+    }
+    Ok(candidates)
+}
+
+#[test]
+fn test_deepest_match() -> AResult<()> {
+    let (tempdir, removeTempdir) = make_temp_dir()?;
+
+    std::fs::create_dir_all(tempdir.join("A/b"))?;
+    std::fs::create_dir_all(tempdir.join("a/B/c"))?;
+    std::fs::write(tempdir.join("A/b/file.txt"), "")?;
+
+    let deepest = |path: &str| {
+        InsensitivePath(tempdir.join(path)).deepest_match(Some(&tempdir), MatchOptions::default())
+    };
+    assert_eq!(deepest("missing/x")?, (tempdir.clone(), 0));
+    assert_eq!(deepest("a/missing/x")?, (tempdir.join("A"), 1));
+    // the variant leading deeper wins, even if sorted later
+    assert_eq!(deepest("a/b/C/missing")?, (tempdir.join("a/B/c"), 3));
+    assert_eq!(deepest("a/b/missing.txt")?, (tempdir.join("A/b"), 2));
+    // a file in the middle of the path isn't a directory to descend into
+    assert_eq!(deepest("a/b/file.txt/x")?, (tempdir.join("A/b"), 2));
+    assert_eq!(deepest("a/b/FILE.txt")?, (tempdir.join("A/b/file.txt"), 3));
+    Ok(())
+}
+
+/// How a path failed to resolve, from `InsensitivePath::unresolved`. `depth`
+/// is how many of its components did resolve.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ResolveError {
+    /// Nothing in `dir` matches the next component.
+    NoMatch { dir: PathBuf, depth: usize },
+    /// The next component only matches `file`, with more of the path after it.
+    NotADirectory { file: PathBuf, depth: usize },
+}
+
+impl ResolveError {
+    pub fn depth(&self) -> usize {
+        match self {
+            ResolveError::NoMatch { depth, .. } | ResolveError::NotADirectory { depth, .. } => {
+                *depth
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for ResolveError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ResolveError::NoMatch { dir, .. } => write!(f, "no match in {dir:?}"),
+            ResolveError::NotADirectory { file, .. } => write!(f, "{file:?} is not a directory"),
+        }
+    }
+}
+
+impl std::error::Error for ResolveError {}
+
+#[test]
+fn test_unresolved() -> AResult<()> {
+    let (tempdir, removeTempdir) = make_temp_dir()?;
+    std::fs::create_dir_all(tempdir.join("Docs"))?;
+    std::fs::write(tempdir.join("Docs/Readme.txt"), "")?;
+
+    let unresolved = |path: &str| {
+        InsensitivePath(tempdir.join(path)).unresolved(Some(&tempdir), MatchOptions::default())
+    };
+    assert_eq!(unresolved("docs/readme.TXT")?, None);
+    assert_eq!(
+        unresolved("docs/readme.txt/more")?,
+        Some(ResolveError::NotADirectory {
+            file: tempdir.join("Docs/Readme.txt"),
+            depth: 1
+        })
+    );
+    assert_eq!(
+        unresolved("docs/missing/more")?,
+        Some(ResolveError::NoMatch {
+            dir: tempdir.join("Docs"),
+            depth: 1
+        })
+    );
+    // a missing file is just a miss, not a directory expected
+    assert_eq!(
+        unresolved("docs/missing.txt")?,
+        Some(ResolveError::NoMatch {
+            dir: tempdir.join("Docs"),
+            depth: 1
+        })
+    );
+    assert_eq!(
+        unresolved("docs/readme.txt/more")?.unwrap().to_string(),
+        format!("{:?} is not a directory", tempdir.join("Docs/Readme.txt"))
+    );
+    Ok(())
+}
+
+#[test]
+fn test_cancelled_walk() -> AResult<()> {
+    let (tempdir, removeTempdir) = make_temp_dir()?;
+    std::fs::create_dir_all(tempdir.join("Dir"))?;
+    std::fs::write(tempdir.join("Dir/File.txt"), "")?;
+
+    let path = InsensitivePath(tempdir.join("dir/file.txt"));
+    let cancelled = AtomicBool::new(false);
+    let options = MatchOptions {
+        cancelled: Some(&cancelled),
+        ..Default::default()
+    };
+    assert_eq!(
+        path.find_first_matching_file_with(Some(&tempdir), options)?,
+        Some(tempdir.join("Dir/File.txt"))
+    );
+    cancelled.store(true, AtomicOrdering::Relaxed);
+    assert!(path
+        .find_first_matching_file_with(Some(&tempdir), options)
+        .is_err());
+    assert!(path
+        .find_matching_files_with(Some(&tempdir), options)
+        .is_err());
+    Ok(())
+}
+
+#[test]
+fn test_component_candidates() -> AResult<()> {
+    let (tempdir, removeTempdir) = make_temp_dir()?;
+
+    std::fs::create_dir_all(tempdir.join("Name.txt"))?;
+    std::fs::write(tempdir.join("name.txt"), "")?;
+    std::fs::write(tempdir.join("NAME.TXT"), "")?;
+    std::fs::write(tempdir.join("other.txt"), "")?;
+
+    let index = DirectoryIndex::new();
+    let candidates = |directoriesOnly, options| -> AResult<Vec<OsString>> {
+        let mut candidates =
+            component_candidates(&tempdir, OsStr::new("name.TXT"), directoriesOnly, options)?;
+        candidates.sort();
+        Ok(candidates)
+    };
+    for options in [
+        MatchOptions::default(),
+        MatchOptions {
+            index: Some(&index),
+            ..Default::default()
+        },
+    ] {
+        assert_eq!(
+            candidates(false, options)?,
+            vec!["NAME.TXT", "Name.txt", "name.txt"]
+        );
+        assert_eq!(candidates(true, options)?, vec!["Name.txt"]);
+    }
+
+    let extOnly = MatchOptions {
+        insensitiveExtOnly: true,
+        ..Default::default()
+    };
+    assert_eq!(candidates(false, extOnly)?, vec!["name.txt"]);
+    assert_eq!(candidates(true, extOnly)?, Vec::<OsString>::new());
+
+    let cancelled = AtomicBool::new(true);
+    let options = MatchOptions {
+        cancelled: Some(&cancelled),
+        ..Default::default()
+    };
+    assert!(candidates(false, options).is_err());
+    assert!(component_candidates(
+        &tempdir.join("missing"),
+        OsStr::new("x"),
+        false,
+        MatchOptions::default()
+    )
+    .is_err());
+    Ok(())
+}
+
+/// Whether no entry of `dir` could match `name`, as any such entry would be
+/// longer than the filesystem allows, so scanning `dir` can be skipped.
+fn too_long_to_exist(dir: &Path, name: &OsStr, deaccent: bool) -> bool {
+    const defaultNameMax: usize = 255;
+    // a name never folds to more characters than it has bytes, so it can't
+    // be too long unless its own bytes are
+    if name.len() <= defaultNameMax {
+        return false;
+    }
+    // and a matching entry has at least as many bytes as `name` has folded
+    // characters
+    let foldedLength = osstr_chars_folded(name, deaccent).count();
+    foldedLength > name_max(dir).unwrap_or(defaultNameMax)
+}
+
+/// The longest name, in bytes, entries of `dir` may have.
+fn name_max(dir: &Path) -> Option<usize> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let dir = std::ffi::CString::new(dir.as_os_str().as_bytes()).ok()?;
+    let max = unsafe { libc::pathconf(dir.as_ptr(), libc::_PC_NAME_MAX) };
+    usize::try_from(max).ok()
+}
+
+#[test]
+fn test_too_long_to_exist() -> AResult<()> {
+    let dir = std::env::temp_dir();
+    let long = "a".repeat(name_max(&dir).unwrap_or(255));
+    assert!(!too_long_to_exist(&dir, OsStr::new(&long), false));
+    assert!(too_long_to_exist(
+        &dir,
+        OsStr::new(&format!("{long}a")),
+        false
+    ));
+    // the Kelvin sign is three bytes, but matches the single byte `k`
+    assert!(!too_long_to_exist(
+        &dir,
+        OsStr::new(&"\u{212a}".repeat(100)),
+        false
+    ));
+    // and a decomposed accent matches nothing at all once deaccented
+    let decomposed = format!("{long}\u{301}");
+    assert!(too_long_to_exist(&dir, OsStr::new(&decomposed), false));
+    assert!(!too_long_to_exist(&dir, OsStr::new(&decomposed), true));
+
+    // never scanned, so even a missing directory has no matches
+    let missing = dir.join("caseproxy_missing_dir");
+    let name = OsString::from("x".repeat(1000));
+    assert_eq!(
+        component_candidates(&missing, &name, false, MatchOptions::default())?,
+        Vec::<OsString>::new()
+    );
+    let path = InsensitivePath(missing.join(&name));
+    assert_eq!(
+        path.find_matching_files(Some(&missing))?,
+        Vec::<PathBuf>::new()
+    );
+    Ok(())
+}
+
+/// Whether `left` and `right` have exactly equal stems and case-insensitively
+/// equal extensions. As with `Path::extension`, a leading dot doesn't begin an
+/// extension.
+fn extensions_only_differ_in_case(left: &OsStr, right: &OsStr, deaccent: bool) -> bool {
+    fn split(name: &OsStr) -> (&[u8], &[u8]) {
+        let bytes = name.as_encoded_bytes();
+        match bytes.iter().rposition(|&byte| byte == b'.') {
+            Some(0) | None => (bytes, &[]),
+            Some(index) => (&bytes[..index], &bytes[index..]),
+        }
+    }
+
+    let (leftStem, leftExt) = split(left);
+    let (rightStem, rightExt) = split(right);
+    // splitting on an ASCII byte leaves both halves valid encoded strings
+    let (leftExt, rightExt) = unsafe {
+        (
+            OsStr::from_encoded_bytes_unchecked(leftExt),
+            OsStr::from_encoded_bytes_unchecked(rightExt),
+        )
+    };
+    leftStem == rightStem && compare_osstr_folded(leftExt, rightExt, deaccent) == Ordering::Equal
+}
+
+/// Lists entries of `dir` matching `name` case-insensitively, and ignoring
+/// accents if `deaccent`.
+fn scan_directory(
+    dir: &Path,
+    name: &OsStr,
+    directoriesOnly: bool,
+    deaccent: bool,
+) -> AResult<Vec<OsString>> {
+    let mut matches = Vec::new();
+    for entry in read_dir(dir)? {
+        let entry = entry?;
+        if directoriesOnly && !entry.file_type()?.is_dir() {
+            continue;
+        }
+
+        let filename = entry.file_name();
+        if compare_osstr_folded(&filename, name, deaccent) == Ordering::Equal {
+            matches.push(filename);
+        }
+    }
+    Ok(matches)
+}
+
+/// As `scan_directory` for files, ignoring trailing dots and spaces.
+fn scan_directory_trimmed(dir: &Path, name: &OsStr, deaccent: bool) -> AResult<Vec<OsString>> {
+    let name = trim_trailing_dots(name);
+    let mut matches = Vec::new();
+    for entry in read_dir(dir)? {
+        let filename = entry?.file_name();
+        if compare_osstr_folded(trim_trailing_dots(&filename), name, deaccent) == Ordering::Equal {
+            matches.push(filename);
+        }
+    }
+    Ok(matches)
+}
+
+/// `name` without trailing dots and spaces, as Windows would store it. Names
+/// made up entirely of them are left alone.
+fn trim_trailing_dots(name: &OsStr) -> &OsStr {
+    let bytes = name.as_encoded_bytes();
+    match bytes.iter().rposition(|&byte| byte != b'.' && byte != b' ') {
+        // SAFETY: only ASCII is removed from the end, so `bytes` is still
+        // split on a character boundary
+        Some(end) => unsafe { OsStr::from_encoded_bytes_unchecked(&bytes[..=end]) },
+        None => name,
+    }
+}
+
+#[test]
+fn test_trim_trailing_dots() -> AResult<()> {
+    assert_eq!(trim_trailing_dots(OsStr::new("file.txt. . ")), "file.txt");
+    assert_eq!(trim_trailing_dots(OsStr::new("file.txt")), "file.txt");
+    assert_eq!(trim_trailing_dots(OsStr::new(" .x")), " .x");
+    assert_eq!(trim_trailing_dots(OsStr::new("..")), "..");
+
+    let (tempdir, removeTempdir) = make_temp_dir()?;
+    std::fs::create_dir_all(tempdir.join("Dir."))?;
+    std::fs::write(tempdir.join("Dir./Report.txt"), "")?;
+    std::fs::write(tempdir.join("Dir./notes.md. "), "")?;
+
+    let index = DirectoryIndex::new();
+    for options in [
+        MatchOptions {
+            trimTrailingDots: true,
+            ..Default::default()
+        },
+        MatchOptions {
+            trimTrailingDots: true,
+            index: Some(&index),
+            ..Default::default()
+        },
+    ] {
+        let find = |path: &str| {
+            InsensitivePath(tempdir.join(path)).find_matching_files_with(Some(&tempdir), options)
+        };
+        assert_eq!(
+            find("dir./report.TXT.")?,
+            vec![tempdir.join("Dir./Report.txt")]
+        );
+        assert_eq!(
+            find("dir./report.txt ")?,
+            vec![tempdir.join("Dir./Report.txt")]
+        );
+        assert_eq!(
+            find("dir./NOTES.md")?,
+            vec![tempdir.join("Dir./notes.md. ")]
+        );
+        // directories are still matched as named
+        assert_eq!(find("dir/report.txt")?, Vec::<PathBuf>::new());
+    }
+
+    let find = |path: &str| InsensitivePath(tempdir.join(path)).find_matching_files(Some(&tempdir));
+    assert_eq!(find("dir./report.txt.")?, Vec::<PathBuf>::new());
+    Ok(())
+}
+
+/// Cache of case-folded directory listings, turning per-component lookups in
+/// very wide directories into a hash lookup rather than a linear scan.
+///
+/// A directory's listing is built on first access, and rebuilt whenever its
+/// mtime changes.
+#[derive(Default)]
+pub struct DirectoryIndex {
+    directories: RwLock<HashMap<PathBuf, Arc<IndexedDirectory>>>,
+}
+
+struct IndexedDirectory {
+    modified: SystemTime,
+    /// Keyed by names folded as with `deaccent`, which groups every name that
+    /// could match with or without it.
+    entries: HashMap<Vec<CharOrByte>, Vec<IndexedEntry>>,
+}
+
+struct IndexedEntry {
+    name: OsString,
+    isDirectory: bool,
+}
+
+impl DirectoryIndex {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Lists entries of `dir` matching `name` case-insensitively, and ignoring
+    /// accents if `deaccent`.
+    pub fn lookup(
+        &self,
+        dir: &Path,
+        name: &OsStr,
+        directoriesOnly: bool,
+        deaccent: bool,
+    ) -> AResult<Vec<OsString>> {
+        let listing = self.listing(dir)?;
+        let key: Vec<_> = osstr_chars_folded(name, true).collect();
+        let Some(entries) = listing.entries.get(&key) else {
+            return Ok(vec![]);
+        };
+        Ok(entries
+            .iter()
+            .filter(|entry| !directoriesOnly || entry.isDirectory)
+            .filter(|entry| {
+                deaccent || compare_osstr_case_insensitive(&entry.name, name) == Ordering::Equal
+            })
+            .map(|entry| entry.name.clone())
+            .collect())
+    }
+
+    fn listing(&self, dir: &Path) -> AResult<Arc<IndexedDirectory>> {
+        let modified = dir.metadata()?.modified()?;
+        if let Some(listing) = self.directories.read().unwrap().get(dir) {
+            if listing.modified == modified {
+                return Ok(listing.clone());
+            }
+        }
+
+        let mut entries: HashMap<_, Vec<_>> = HashMap::new();
+        for entry in read_dir(dir)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            entries
+                .entry(osstr_chars_folded(&name, true).collect())
+                .or_default()
+                .push(IndexedEntry {
+                    name,
+                    isDirectory: entry.file_type()?.is_dir(),
+                });
+        }
+
+        let listing = Arc::new(IndexedDirectory { modified, entries });
+        self.directories
+            .write()
+            .unwrap()
+            .insert(dir.to_path_buf(), listing.clone());
+        Ok(listing)
+    }
 }
 
 impl Deref for InsensitivePath {
@@ -98,121 +939,606 @@ impl DerefMut for InsensitivePath {
     }
 }
 
-impl PartialEq for InsensitivePath {
-    fn eq(&self, other: &Self) -> bool {
-        self.cmp(other) == Ordering::Equal
+impl PartialEq for InsensitivePath {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl PartialOrd for InsensitivePath {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for InsensitivePath {
+    fn cmp(&self, other: &Self) -> Ordering {
+        compare_path_case_insensitive(self, other)
+    }
+}
+
+fn compare_path_case_insensitive(left: &Path, right: &Path) -> Ordering {
+    let mut leftComponents = left.components();
+    let mut rightComponents = right.components();
+    loop {
+        let it = (leftComponents.next(), rightComponents.next());
+        match it {
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (None, None) => return Ordering::Equal,
+            (Some(l), Some(r)) => match (l, r) {
+                (Component::Normal(l), Component::Normal(r)) => {
+                    let order = compare_osstr_case_insensitive(l, r);
+                    if order != Ordering::Equal {
+                        return order;
+                    }
+                }
+                _ => {
+                    let order = l.cmp(&r);
+                    if order != Ordering::Equal {
+                        return order;
+                    }
+                }
+            },
+        }
+    }
+}
+
+impl Hash for InsensitivePath {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        for item in osstr_chars_lowercased(self.0.as_os_str()) {
+            match item {
+                CharOrByte::Char(char) => state.write_u32(char as u32),
+                CharOrByte::Byte(byte) => state.write_u8(byte),
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, Eq)]
+pub struct InsensitiveOsString(pub OsString);
+
+impl Deref for InsensitiveOsString {
+    type Target = OsString;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl PartialEq for InsensitiveOsString {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl PartialOrd for InsensitiveOsString {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for InsensitiveOsString {
+    fn cmp(&self, other: &Self) -> Ordering {
+        compare_osstr_case_insensitive(self, other)
+    }
+}
+
+impl Hash for InsensitiveOsString {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        for item in osstr_chars_lowercased(&self.0) {
+            match item {
+                CharOrByte::Char(char) => state.write_u32(char as u32),
+                CharOrByte::Byte(byte) => state.write_u8(byte),
+            }
+        }
+    }
+}
+
+#[test]
+fn test_insensitive_path() {
+    let a = InsensitivePath(PathBuf::from("foo"));
+    let b = InsensitivePath(PathBuf::from("Foo"));
+    assert_eq!(a, b);
+
+    let aHash = {
+        let mut hasher = DefaultHasher::new();
+        a.hash(&mut hasher);
+        hasher.finish()
+    };
+    let bHash = {
+        let mut hasher = DefaultHasher::new();
+        b.hash(&mut hasher);
+        hasher.finish()
+    };
+    assert_eq!(aHash, bHash);
+
+    let a = InsensitivePath(PathBuf::from("abc"));
+    let b = InsensitivePath(PathBuf::from("def"));
+    assert_ne!(a, b);
+    assert!(a < b);
+    assert!(b > a);
+
+    let aHash = {
+        let mut hasher = DefaultHasher::new();
+        a.hash(&mut hasher);
+        hasher.finish()
+    };
+    let bHash = {
+        let mut hasher = DefaultHasher::new();
+        b.hash(&mut hasher);
+        hasher.finish()
+    };
+    assert_ne!(aHash, bHash);
+}
+
+/// Scope guard running a closure when dropped, e.g. to clean up a temporary
+/// file however the scope is left.
+///
+/// Bind it to a named variable: `let _ = Deferred::new(..)` drops it, and so
+/// runs the closure, immediately.
+#[must_use = "the closure runs as soon as the guard is dropped"]
+pub struct Deferred<Func: FnOnce()>(Option<Func>);
+
+impl<Func: FnOnce()> Deferred<Func> {
+    pub fn new(func: Func) -> Self {
+        Self(Some(func))
+    }
+
+    /// Drops the guard without running the closure, for when cleanup turns out
+    /// to be unnecessary.
+    pub fn cancel(mut self) {
+        self.0 = None;
+    }
+}
+
+impl<Func: FnOnce()> Drop for Deferred<Func> {
+    fn drop(&mut self) {
+        if let Some(func) = self.0.take() {
+            func();
+        }
+    }
+}
+
+/// Creates a uniquely named directory under the system temp directory, along
+/// with a guard removing it again. A test fixture, public so the binaries'
+/// tests can share it.
+#[doc(hidden)]
+pub fn make_temp_dir() -> AResult<(PathBuf, Deferred<impl FnOnce()>)> {
+    use std::{
+        collections::hash_map::RandomState,
+        hash::{BuildHasher, Hasher},
+    };
+
+    // randomly keyed per process, so good enough for a unique name without
+    // pulling in `rand` outside of tests
+    let unique = RandomState::new().build_hasher().finish();
+    let mut tempdir = std::env::temp_dir();
+    tempdir.push(format!("caseproxy_tmp_{unique:016x}"));
+    std::fs::create_dir_all(&tempdir)?;
+
+    let path = tempdir.clone();
+    let removeTempdir = Deferred::new(move || {
+        if let Err(err) = std::fs::remove_dir_all(&path) {
+            eprintln!("unable to remove temp directory {path:?}");
+        }
+    });
+    Ok((tempdir, removeTempdir))
+}
+
+#[test]
+fn test_deferred() {
+    use std::cell::Cell;
+
+    let ran = Cell::new(0);
+    {
+        let guard = Deferred::new(|| ran.set(ran.get() + 1));
+        assert_eq!(ran.get(), 0);
+    }
+    assert_eq!(ran.get(), 1);
+
+    Deferred::new(|| ran.set(ran.get() + 1)).cancel();
+    assert_eq!(ran.get(), 1);
+
+    // runs when unwinding too
+    let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        let guard = Deferred::new(|| ran.set(ran.get() + 1));
+        panic!("unwinding");
+    }));
+    assert!(res.is_err());
+    assert_eq!(ran.get(), 2);
+}
+
+#[test]
+fn test_insensitive_path_searching() -> AResult<()> {
+    let (tempdir, removeTempdir) = make_temp_dir()?;
+
+    let file = |path: &str| -> AResult<()> {
+        let fullPath = tempdir.join(path);
+        std::fs::create_dir_all(fullPath.parent().unwrap())?;
+        std::fs::write(fullPath, "")?;
+        Ok(())
+    };
+    let find = |path: &str| -> AResult<Vec<PathBuf>> {
+        let fullPath = tempdir.join(path);
+        InsensitivePath(fullPath).find_matching_files(Some(&tempdir))
+    };
+
+    file("normal.txt");
+    assert_eq!(find("normal.txt")?, vec![tempdir.join("normal.txt"),]);
+    assert_eq!(
+        InsensitivePath(PathBuf::from("./src/LIB.rs")).find_matching_files(None)?,
+        vec![PathBuf::from("./src/lib.rs")]
+    );
+    assert_eq!(
+        InsensitivePath(PathBuf::from("src/LIB.rs")).find_matching_files(None)?,
+        vec![PathBuf::from("./src/lib.rs")]
+    );
+
+    file("abc.txt");
+    file("Abc.txt");
+    assert_eq!(
+        find("abc.txt")?,
+        vec![tempdir.join("Abc.txt"), tempdir.join("abc.txt"),]
+    );
+
+    file("nested/normal.txt");
+    file("nested/abc.txt");
+    file("nested/Abc.txt");
+    assert_eq!(
+        find("nested/normal.txt")?,
+        vec![tempdir.join("nested/normal.txt"),]
+    );
+    assert_eq!(
+        find("nested/abc.txt")?,
+        vec![
+            tempdir.join("nested/Abc.txt"),
+            tempdir.join("nested/abc.txt"),
+        ]
+    );
+
+    file("deeply/nested/abc.txt");
+    file("deeply/nested/Abc.txt");
+    file("deeply/Nested/abc.txt");
+    file("deeply/Nested/Abc.txt");
+    assert_eq!(
+        find("Deeply/Nested/abc.txt")?,
+        vec![
+            tempdir.join("deeply/Nested/Abc.txt"),
+            tempdir.join("deeply/Nested/abc.txt"),
+            tempdir.join("deeply/nested/Abc.txt"),
+            tempdir.join("deeply/nested/abc.txt"),
+        ]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_find_first_matching_file() -> AResult<()> {
+    use rand::{seq::SliceRandom, thread_rng};
+
+    let (tempdir, removeTempdir) = make_temp_dir()?;
+
+    let file = |path: &str| -> AResult<()> {
+        let fullPath = tempdir.join(path);
+        std::fs::create_dir_all(fullPath.parent().unwrap())?;
+        std::fs::write(fullPath, "")?;
+        Ok(())
+    };
+    let check = |path: &str, options: MatchOptions| -> AResult<Option<PathBuf>> {
+        let path = InsensitivePath(tempdir.join(path));
+        let first = path.find_first_matching_file_with(Some(&tempdir), options)?;
+        let all = path.find_matching_files_with(Some(&tempdir), options)?;
+        assert_eq!(first.as_ref(), all.first(), "{path:?}");
+        Ok(first)
+    };
+
+    file("deeply/nested/abc.txt")?;
+    file("deeply/nested/Abc.txt")?;
+    file("deeply/Nested/abc.txt")?;
+    file("deeply/Nested/Abc.txt")?;
+    assert_eq!(
+        check("Deeply/Nested/abc.txt", MatchOptions::default())?,
+        Some(tempdir.join("deeply/Nested/Abc.txt"))
+    );
+
+    // the first variant of a directory doesn't have the file, so the next is tried
+    file("A/b/c/other.txt")?;
+    file("a/B/C/target.txt")?;
+    file("a/b/C/Target.txt")?;
+    assert_eq!(
+        check("a/b/c/target.txt", MatchOptions::default())?,
+        Some(tempdir.join("a/B/C/target.txt"))
+    );
+    assert_eq!(check("a/b/c/missing.txt", MatchOptions::default())?, None);
+
+    // many ambiguous components, with files scattered among the combinations
+    let mut rng = thread_rng();
+    let variants = ["x", "X"];
+    let mut paths = vec![];
+    for _ in 0..100 {
+        let mut path = PathBuf::from("wide");
+        for _ in 0..6 {
+            path.push(variants.choose(&mut rng).unwrap());
+        }
+        path.push(["f.txt", "F.txt", "f.TXT"].choose(&mut rng).unwrap());
+        file(path.to_str().unwrap())?;
+        paths.push(path);
+    }
+    let index = DirectoryIndex::new();
+    let optionSets = [
+        MatchOptions::default(),
+        MatchOptions {
+            index: Some(&index),
+            ..Default::default()
+        },
+        MatchOptions {
+            insensitiveExtOnly: true,
+            ..Default::default()
+        },
+    ];
+    for options in optionSets {
+        for path in &paths {
+            assert!(check(path.to_str().unwrap(), options)?.is_some());
+        }
+        check("wide/x/x/x/x/x/x/g.txt", options)?;
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_find_matching_files_concurrent() -> AResult<()> {
+    let (tempdir, removeTempdir) = make_temp_dir()?;
+
+    // case variants of `ab/cd`, each with a few variants of the file, and a
+    // file named like the directories, which isn't descended into
+    for first in ["ab", "aB", "Ab"] {
+        for second in ["cd", "cD", "Cd", "CD"] {
+            let dir = tempdir.join(first).join(second);
+            std::fs::create_dir_all(&dir)?;
+            std::fs::write(dir.join("file.txt"), "")?;
+            std::fs::write(dir.join("FILE.txt"), "")?;
+            std::fs::write(dir.join("file.TXT. "), "")?;
+            std::fs::write(dir.join("other.txt"), "")?;
+        }
+    }
+    std::fs::write(tempdir.join("AB"), "")?;
+
+    let optionSets = [
+        SharedMatchOptions::default(),
+        SharedMatchOptions {
+            index: Some(Arc::new(DirectoryIndex::new())),
+            ..Default::default()
+        },
+        SharedMatchOptions {
+            insensitiveExtOnly: true,
+            ..Default::default()
+        },
+        SharedMatchOptions {
+            trimTrailingDots: true,
+            ..Default::default()
+        },
+    ];
+    for options in optionSets {
+        for path in [
+            "ab/cd/file.txt",
+            "AB/Cd/Other.TXT",
+            "ab/cd",
+            "ab/missing.txt",
+            "missing/x",
+        ] {
+            let path = InsensitivePath(tempdir.join(path));
+            let serial = path.find_matching_files_with(Some(&tempdir), options.borrow())?;
+            for concurrency in [1, 4, 64] {
+                let concurrent = path
+                    .find_matching_files_concurrent(Some(&tempdir), options.clone(), concurrency)
+                    .await?;
+                assert_eq!(
+                    concurrent, serial,
+                    "{path:?} with concurrency {concurrency}"
+                );
+            }
+        }
+    }
+    let matches = InsensitivePath(tempdir.join("ab/cd/file.txt"))
+        .find_matching_files_concurrent(Some(&tempdir), Default::default(), 8)
+        .await?;
+    assert_eq!(matches.len(), 24);
+
+    assert!(InsensitivePath(tempdir.join("missing/x"))
+        .find_matching_files_concurrent(Some(&tempdir.join("missing")), Default::default(), 8)
+        .await
+        .is_err());
+    let cancelled = SharedMatchOptions {
+        cancelled: Some(Arc::new(AtomicBool::new(true))),
+        ..Default::default()
+    };
+    assert!(InsensitivePath(tempdir.join("ab/cd/file.txt"))
+        .find_matching_files_concurrent(Some(&tempdir), cancelled, 8)
+        .await
+        .is_err());
+    Ok(())
+}
+
+#[test]
+fn test_unreadable_directory() -> AResult<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let (tempdir, removeTempdir) = make_temp_dir()?;
+    // declared after the removal guard so it runs first, as removal needs the
+    // directory readable again
+    let restorePermissions = Deferred::new(|| {
+        let _ = std::fs::set_permissions(tempdir.join("Dir"), PermissionsExt::from_mode(0o755));
+    });
+
+    std::fs::create_dir_all(tempdir.join("Dir"))?;
+    std::fs::create_dir_all(tempdir.join("dir"))?;
+    std::fs::write(tempdir.join("Dir/file.txt"), "")?;
+    std::fs::write(tempdir.join("dir/file.txt"), "")?;
+    std::fs::set_permissions(tempdir.join("Dir"), PermissionsExt::from_mode(0o000))?;
+    if read_dir(tempdir.join("Dir")).is_ok() {
+        eprintln!(
+            "skipping test_unreadable_directory, permissions aren't enforced (running as root?)"
+        );
+        return Ok(());
     }
-}
 
-impl PartialOrd for InsensitivePath {
-    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        Some(self.cmp(other))
+    let index = DirectoryIndex::new();
+    for options in [
+        MatchOptions::default(),
+        MatchOptions {
+            index: Some(&index),
+            ..Default::default()
+        },
+    ] {
+        let find = |path: &str| {
+            InsensitivePath(tempdir.join(path)).find_matching_files_with(Some(&tempdir), options)
+        };
+        assert_eq!(find("DIR/File.txt")?, vec![tempdir.join("dir/file.txt")]);
+        // nothing else could match, so the error is reported
+        assert!(find("Dir/missing.txt").is_err());
     }
+
+    Ok(())
 }
 
-impl Ord for InsensitivePath {
-    fn cmp(&self, other: &Self) -> Ordering {
-        let mut leftComponents = self.components();
-        let mut rightComponents = other.components();
-        let mut rbuf = String::new();
-        loop {
-            let it = (leftComponents.next(), rightComponents.next());
-            match it {
-                (None, Some(_)) => return Ordering::Less,
-                (Some(_), None) => return Ordering::Greater,
-                (None, None) => return Ordering::Equal,
-                (Some(l), Some(r)) => match (l, r) {
-                    (Component::Normal(l), Component::Normal(r)) => {
-                        let order = compare_osstr_case_insensitive(l, r);
-                        if order != Ordering::Equal {
-                            return order;
-                        }
-                    }
-                    _ => {
-                        let order = l.cmp(&r);
-                        if order != Ordering::Equal {
-                            return order;
-                        }
-                    }
-                },
-            }
-        }
-    }
+/// Identifies a file on disk: paths with equal IDs are hardlinks to (or
+/// otherwise name) the same file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct FileId {
+    pub dev: u64,
+    pub ino: u64,
 }
 
-impl Hash for InsensitivePath {
-    fn hash<H: Hasher>(&self, state: &mut H) {
-        for item in osstr_chars_lowercased(self.0.as_os_str()) {
-            match item {
-                CharOrByte::Char(char) => state.write_u32(char as u32),
-                CharOrByte::Byte(byte) => state.write_u8(byte),
-            }
-        }
+impl FileId {
+    /// The identity of the file `path` refers to, following symlinks.
+    pub fn of(path: &Path) -> AResult<Self> {
+        use std::os::unix::fs::MetadataExt;
+
+        let metadata = path.metadata()?;
+        Ok(Self {
+            dev: metadata.dev(),
+            ino: metadata.ino(),
+        })
     }
 }
 
 #[test]
-fn test_insensitive_path() {
-    let a = InsensitivePath(PathBuf::from("foo"));
-    let b = InsensitivePath(PathBuf::from("Foo"));
-    assert_eq!(a, b);
+fn test_file_id() -> AResult<()> {
+    let (tempdir, removeTempdir) = make_temp_dir()?;
+    std::fs::write(tempdir.join("file.txt"), "same")?;
+    std::fs::hard_link(tempdir.join("file.txt"), tempdir.join("FILE.txt"))?;
+    std::fs::write(tempdir.join("File.txt"), "same")?;
+    std::os::unix::fs::symlink("file.txt", tempdir.join("link.txt"))?;
 
-    let aHash = {
-        let mut hasher = DefaultHasher::new();
-        a.hash(&mut hasher);
-        hasher.finish()
-    };
-    let bHash = {
-        let mut hasher = DefaultHasher::new();
-        b.hash(&mut hasher);
-        hasher.finish()
-    };
-    assert_eq!(aHash, bHash);
+    let original = FileId::of(&tempdir.join("file.txt"))?;
+    assert_eq!(FileId::of(&tempdir.join("FILE.txt"))?, original);
+    assert_eq!(FileId::of(&tempdir.join("link.txt"))?, original);
+    assert_ne!(FileId::of(&tempdir.join("File.txt"))?, original);
+    assert!(FileId::of(&tempdir.join("missing.txt")).is_err());
 
-    let a = InsensitivePath(PathBuf::from("abc"));
-    let b = InsensitivePath(PathBuf::from("def"));
-    assert_ne!(a, b);
-    assert!(a < b);
-    assert!(b > a);
+    Ok(())
+}
 
-    let aHash = {
-        let mut hasher = DefaultHasher::new();
-        a.hash(&mut hasher);
-        hasher.finish()
-    };
-    let bHash = {
-        let mut hasher = DefaultHasher::new();
-        b.hash(&mut hasher);
-        hasher.finish()
+#[test]
+fn test_find_matching_files_with_ids() -> AResult<()> {
+    let (tempdir, removeTempdir) = make_temp_dir()?;
+    std::fs::write(tempdir.join("file.txt"), "same")?;
+    std::fs::hard_link(tempdir.join("file.txt"), tempdir.join("FILE.txt"))?;
+    std::fs::write(tempdir.join("File.txt"), "same")?;
+
+    let matches = InsensitivePath(tempdir.join("file.TXT"))
+        .find_matching_files_with_ids(Some(&tempdir), MatchOptions::default())?;
+    let paths: Vec<_> = matches.iter().map(|(path, _)| path.clone()).collect();
+    assert_eq!(
+        paths,
+        vec![
+            tempdir.join("FILE.txt"),
+            tempdir.join("File.txt"),
+            tempdir.join("file.txt"),
+        ]
+    );
+    let [(_, hardlink), (_, copy), (_, original)] = matches[..] else {
+        unreachable!()
     };
-    assert_ne!(aHash, bHash);
-}
+    assert_eq!(hardlink, original);
+    assert_ne!(copy, original);
 
-pub struct Deferred<Func: FnOnce()>(Option<Func>);
+    Ok(())
+}
 
-impl<Func: FnOnce()> Deferred<Func> {
-    pub fn new(func: Func) -> Self {
-        Self(Some(func))
+/// Finds the real casing of `path`, relative to `root`, returning it joined
+/// onto `root`. Where several paths differ only in case, one matching `path`
+/// exactly is preferred, otherwise the first in sorted order.
+///
+/// Returns `None` if nothing matches, and only errors on I/O failure.
+pub fn canonical_case(path: &Path, root: &Path) -> AResult<Option<PathBuf>> {
+    let relative = resolve_parents(path);
+    let relative = relative.strip_prefix("/").unwrap_or(&relative);
+    let fullPath = root.join(relative);
+    if relative
+        .components()
+        .all(|component| component == Component::CurDir)
+    {
+        return Ok(root.exists().then(|| root.to_path_buf()));
     }
-}
 
-impl<Func: FnOnce()> Drop for Deferred<Func> {
-    fn drop(&mut self) {
-        self.0.take().unwrap()();
+    let matches = InsensitivePath(fullPath.clone()).find_matching_files(Some(root))?;
+    if matches.contains(&fullPath) {
+        return Ok(Some(fullPath));
     }
+    Ok(matches.into_iter().next())
 }
 
 #[test]
-fn test_insensitive_path_searching() -> AResult<()> {
-    use rand::{thread_rng, Rng};
+fn test_canonical_case() -> AResult<()> {
+    let (tempdir, removeTempdir) = make_temp_dir()?;
 
-    let mut tempdir = std::env::temp_dir();
-    tempdir.push(&format!("caseproxy_tmp_{:05}", thread_rng().gen::<u16>()));
-    let removeTempdir = Deferred::new(|| {
-        if let Err(err) = std::fs::remove_dir_all(&tempdir) {
-            eprintln!("unable to remove temp directory {tempdir:?}");
-        }
-    });
+    let file = |path: &str| -> AResult<()> {
+        let fullPath = tempdir.join(path);
+        std::fs::create_dir_all(fullPath.parent().unwrap())?;
+        std::fs::write(fullPath, "")?;
+        Ok(())
+    };
+    file("Src/Main.rs")?;
+    file("dupe/ABC.txt")?;
+    file("dupe/abc.txt")?;
+
+    let canonical = |path: &str| canonical_case(Path::new(path), &tempdir);
+    assert_eq!(canonical("src/main.RS")?, Some(tempdir.join("Src/Main.rs")));
+    assert_eq!(
+        canonical("/SRC/./x/../main.rs")?,
+        Some(tempdir.join("Src/Main.rs"))
+    );
+    assert_eq!(canonical("src")?, Some(tempdir.join("Src")));
+    assert_eq!(canonical("")?, Some(tempdir.clone()));
+
+    // exact case preferred, otherwise the first sorted
+    assert_eq!(
+        canonical("dupe/abc.txt")?,
+        Some(tempdir.join("dupe/abc.txt"))
+    );
+    assert_eq!(
+        canonical("dupe/ABC.txt")?,
+        Some(tempdir.join("dupe/ABC.txt"))
+    );
+    assert_eq!(
+        canonical("dupe/Abc.txt")?,
+        Some(tempdir.join("dupe/ABC.txt"))
+    );
+
+    assert_eq!(canonical("src/missing.rs")?, None);
+    assert_eq!(canonical("missing/main.rs")?, None);
+    assert!(canonical_case(Path::new("foo"), &tempdir.join("missing")).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_directory_index() -> AResult<()> {
+    let (tempdir, removeTempdir) = make_temp_dir()?;
 
     let file = |path: &str| -> AResult<()> {
         let fullPath = tempdir.join(path);
@@ -220,59 +1546,292 @@ fn test_insensitive_path_searching() -> AResult<()> {
         std::fs::write(fullPath, "")?;
         Ok(())
     };
+    let index = DirectoryIndex::new();
     let find = |path: &str| -> AResult<Vec<PathBuf>> {
-        let fullPath = tempdir.join(path);
-        InsensitivePath(fullPath).find_matching_files(Some(&tempdir))
+        let fullPath = InsensitivePath(tempdir.join(path));
+        let options = MatchOptions {
+            index: Some(&index),
+            ..Default::default()
+        };
+        let indexed = fullPath.find_matching_files_with(Some(&tempdir), options)?;
+        assert_eq!(indexed, fullPath.find_matching_files(Some(&tempdir))?);
+        Ok(indexed)
     };
 
-    file("normal.txt");
-    assert_eq!(find("normal.txt")?, vec![tempdir.join("normal.txt"),]);
-
     file("abc.txt");
     file("Abc.txt");
+    file("deeply/nested/abc.txt");
+    file("Deeply/nested.txt/abc.txt");
     assert_eq!(
-        find("abc.txt")?,
-        vec![tempdir.join("abc.txt"), tempdir.join("Abc.txt"),]
+        find("ABC.txt")?,
+        vec![tempdir.join("Abc.txt"), tempdir.join("abc.txt"),]
     );
-
-    file("nested/normal.txt");
-    file("nested/abc.txt");
-    file("nested/Abc.txt");
     assert_eq!(
-        find("nested/normal.txt")?,
-        vec![tempdir.join("nested/normal.txt"),]
+        find("deeply/nested/abc.txt")?,
+        vec![tempdir.join("deeply/nested/abc.txt"),]
     );
+    assert_eq!(find("missing.txt")?, Vec::<PathBuf>::new());
+
+    // listings are rebuilt once the directory changes
+    file("aBC.txt");
     assert_eq!(
-        find("nested/abc.txt")?,
+        find("abc.txt")?,
         vec![
-            tempdir.join("nested/abc.txt"),
-            tempdir.join("nested/Abc.txt"),
+            tempdir.join("Abc.txt"),
+            tempdir.join("aBC.txt"),
+            tempdir.join("abc.txt"),
         ]
     );
 
-    file("deeply/nested/abc.txt");
-    file("deeply/nested/Abc.txt");
-    file("deeply/Nested/abc.txt");
-    file("deeply/Nested/Abc.txt");
+    Ok(())
+}
+
+#[test]
+fn test_deaccent() -> AResult<()> {
+    let (tempdir, removeTempdir) = make_temp_dir()?;
+
+    let file = |path: &str| -> AResult<()> {
+        let fullPath = tempdir.join(path);
+        std::fs::create_dir_all(fullPath.parent().unwrap())?;
+        std::fs::write(fullPath, "")?;
+        Ok(())
+    };
+    // precomposed, and decomposed as macOS would
+    file("Caf\u{e9}.html")?;
+    file("Re\u{301}sume\u{301}/Na\u{ef}ve.txt")?;
+    // one index serves lookups with and without accents ignored
+    let index = DirectoryIndex::new();
+    let findWith = |path: &str, deaccent: bool| -> AResult<Vec<PathBuf>> {
+        let path = InsensitivePath(tempdir.join(path));
+        let options = MatchOptions {
+            deaccent,
+            ..Default::default()
+        };
+        let found = path.find_matching_files_with(Some(&tempdir), options)?;
+        let indexed = MatchOptions {
+            index: Some(&index),
+            ..options
+        };
+        assert_eq!(
+            path.find_matching_files_with(Some(&tempdir), indexed)?,
+            found
+        );
+        Ok(found)
+    };
+    let find = |path: &str| findWith(path, true);
+    let same = |left: &str, right: &str| {
+        compare_osstr_folded(left.as_ref(), right.as_ref(), true) == Ordering::Equal
+    };
+
+    assert!(findWith("cafe.html", false)?.is_empty());
     assert_eq!(
-        find("Deeply/Nested/abc.txt")?,
-        vec![
-            tempdir.join("deeply/nested/abc.txt"),
-            tempdir.join("deeply/nested/Abc.txt"),
-            tempdir.join("deeply/Nested/abc.txt"),
-            tempdir.join("deeply/Nested/Abc.txt"),
-        ]
+        findWith("CAF\u{c9}.html", false)?,
+        vec![tempdir.join("Caf\u{e9}.html")]
+    );
+    // comparisons outside of matching aren't affected
+    assert_ne!(
+        InsensitiveOsString("cafe".into()),
+        InsensitiveOsString("CAF\u{c9}".into())
+    );
+
+    assert_eq!(find("cafe.HTML")?, vec![tempdir.join("Caf\u{e9}.html")]);
+    assert_eq!(
+        find("caf\u{e8}.html")?,
+        vec![tempdir.join("Caf\u{e9}.html")]
+    );
+    assert_eq!(
+        find("resume/naive.txt")?,
+        vec![tempdir.join("Re\u{301}sume\u{301}/Na\u{ef}ve.txt")]
     );
+    assert_eq!(
+        find("R\u{c9}SUM\u{c9}/NA\u{cf}VE.TXT")?,
+        vec![tempdir.join("Re\u{301}sume\u{301}/Na\u{ef}ve.txt")]
+    );
+    assert!(find("cafe.htm")?.is_empty());
+
+    for (left, right) in [
+        ("cafe", "CAF\u{c9}"),
+        ("e\u{301}", "\u{e9}"),
+        ("\u{141}od\u{17a}", "lodz"),
+    ] {
+        assert!(same(left, right), "{left} {right}");
+    }
+    // letters that aren't accented versions of others are left alone
+    assert!(!same("\u{df}", "s"));
+    assert!(!same("\u{3b1}", "a"));
+
+    Ok(())
+}
+
+#[test]
+fn test_insensitive_ext_only() -> AResult<()> {
+    let (tempdir, removeTempdir) = make_temp_dir()?;
+
+    let file = |path: &str| -> AResult<()> {
+        let fullPath = tempdir.join(path);
+        std::fs::create_dir_all(fullPath.parent().unwrap())?;
+        std::fs::write(fullPath, "")?;
+        Ok(())
+    };
+    let find = |path: &str| -> AResult<Vec<PathBuf>> {
+        let options = MatchOptions {
+            insensitiveExtOnly: true,
+            ..Default::default()
+        };
+        InsensitivePath(tempdir.join(path)).find_matching_files_with(Some(&tempdir), options)
+    };
+
+    file("Photo.jpg");
+    assert_eq!(find("Photo.JPG")?, vec![tempdir.join("Photo.jpg")]);
+    assert_eq!(find("photo.jpg")?, Vec::<PathBuf>::new());
+
+    file("archive.Tar.gz");
+    assert_eq!(
+        find("archive.Tar.GZ")?,
+        vec![tempdir.join("archive.Tar.gz")]
+    );
+    assert_eq!(find("archive.tar.gz")?, Vec::<PathBuf>::new());
+
+    file("README");
+    assert_eq!(find("README")?, vec![tempdir.join("README")]);
+    assert_eq!(find("readme")?, Vec::<PathBuf>::new());
+
+    file(".Hidden");
+    assert_eq!(find(".hidden")?, Vec::<PathBuf>::new());
+
+    file("Dir/file.txt");
+    assert_eq!(find("Dir/file.TXT")?, vec![tempdir.join("Dir/file.txt")]);
+    assert_eq!(find("dir/file.txt")?, Vec::<PathBuf>::new());
+
+    Ok(())
+}
+
+/// Whether two paths refer to the same file when compared case-insensitively.
+pub fn paths_collide(a: &Path, b: &Path) -> bool {
+    compare_path_case_insensitive(a, b) == Ordering::Equal
+}
+
+/// Whether creating `name` in `dir` would collide with an existing entry
+/// differing only in case. An entry named exactly `name` is not a collision.
+pub fn would_collide_in_dir(dir: &Path, name: &OsStr) -> AResult<bool> {
+    Ok(
+        component_candidates(dir, name, false, MatchOptions::default())?
+            .iter()
+            .any(|existing| existing != name),
+    )
+}
+
+#[test]
+fn test_collisions() -> AResult<()> {
+    assert!(paths_collide(
+        Path::new("foo/bar.txt"),
+        Path::new("Foo/BAR.txt")
+    ));
+    assert!(paths_collide(
+        Path::new("foo/bar.txt"),
+        Path::new("foo/bar.txt")
+    ));
+    assert!(!paths_collide(
+        Path::new("foo/bar.txt"),
+        Path::new("foo/baz.txt")
+    ));
+    assert!(!paths_collide(
+        Path::new("foo/bar.txt"),
+        Path::new("bar.txt")
+    ));
+
+    let (tempdir, removeTempdir) = make_temp_dir()?;
+    std::fs::create_dir_all(&tempdir)?;
+    std::fs::write(tempdir.join("abc.txt"), "")?;
+
+    assert!(would_collide_in_dir(&tempdir, OsStr::new("ABC.txt"))?);
+    assert!(!would_collide_in_dir(&tempdir, OsStr::new("abc.txt"))?);
+    assert!(!would_collide_in_dir(&tempdir, OsStr::new("def.txt"))?);
 
     Ok(())
 }
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// Levenshtein distance between `left` and `right` after case folding, or
+/// `None` if it exceeds `maxDistance`.
+pub fn case_folded_distance(left: &OsStr, right: &OsStr, maxDistance: usize) -> Option<usize> {
+    let left: Vec<_> = osstr_chars_lowercased(left).collect();
+    let right: Vec<_> = osstr_chars_lowercased(right).collect();
+    if left.len().abs_diff(right.len()) > maxDistance {
+        return None;
+    }
+
+    let mut previous: Vec<usize> = (0..=right.len()).collect();
+    let mut current = vec![0; right.len() + 1];
+    for (i, l) in left.iter().enumerate() {
+        current[0] = i + 1;
+        for (j, r) in right.iter().enumerate() {
+            let substitution = previous[j] + (l != r) as usize;
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        if current.iter().min().unwrap() > &maxDistance {
+            return None;
+        }
+        std::mem::swap(&mut previous, &mut current);
+    }
+
+    let distance = previous[right.len()];
+    (distance <= maxDistance).then_some(distance)
+}
+
+#[test]
+fn test_case_folded_distance() {
+    let distance = |l: &str, r: &str, max| case_folded_distance(OsStr::new(l), OsStr::new(r), max);
+    assert_eq!(distance("abc", "ABC", 0), Some(0));
+    assert_eq!(distance("abc", "abd", 2), Some(1));
+    assert_eq!(distance("report.txt", "Reprot.TXT", 2), Some(2));
+    assert_eq!(distance("report.txt", "report.txt.bak", 4), Some(4));
+    assert_eq!(distance("report.txt", "report.txt.bak", 3), None);
+    assert_eq!(distance("abc", "xyz", 2), None);
+    assert_eq!(distance("", "ab", 2), Some(2));
+}
+
+/// Finds up to `maxResults` entries of `dir` whose names are within `maxDistance`
+/// edits of `name` (after case folding), closest first. At most `maxEntries`
+/// directory entries are considered, to bound the cost in huge directories.
+pub fn find_similar_names(
+    dir: &Path,
+    name: &OsStr,
+    maxDistance: usize,
+    maxResults: usize,
+    maxEntries: usize,
+) -> AResult<Vec<OsString>> {
+    let mut similar = vec![];
+    for entry in read_dir(dir)?.take(maxEntries) {
+        let filename = entry?.file_name();
+        if let Some(distance) = case_folded_distance(&filename, name, maxDistance) {
+            similar.push((distance, filename));
+        }
+    }
+    similar.sort_by(|(ld, l), (rd, r)| {
+        ld.cmp(rd)
+            .then_with(|| compare_osstr_case_insensitive(l, r))
+    });
+    Ok(similar
+        .into_iter()
+        .take(maxResults)
+        .map(|(_, filename)| filename)
+        .collect())
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub enum CharOrByte {
     Char(char),
     Byte(u8),
 }
 
+/// Splits `str` into characters where its encoded bytes are valid UTF-8, and
+/// single bytes where they aren't.
+///
+/// Names that aren't Unicode are encoded differently per platform: arbitrary
+/// bytes on Unix, and on Windows WTF-8, where a lone surrogate takes the three
+/// bytes it would in UTF-8 if that allowed surrogates. Either way the invalid
+/// part comes out as its bytes, with a lone surrogate as three `Byte`s (never
+/// a `Char`), so names order the same everywhere given the same bytes.
 pub fn osstr_chars(str: &OsStr) -> impl '_ + Iterator<Item = CharOrByte> {
     let mut index = 0;
     std::iter::from_fn(move || {
@@ -283,14 +1842,17 @@ pub fn osstr_chars(str: &OsStr) -> impl '_ + Iterator<Item = CharOrByte> {
         let headByte = str.as_encoded_bytes()[index];
         let charLen = if headByte & 0b1000_0000 == 0 {
             1
-        } else if headByte & 0b1100_0000 == 0b1100_0000 {
+        } else if headByte & 0b1110_0000 == 0b1100_0000 {
             2
-        } else if headByte & 0b1110_0000 == 0b1110_0000 {
+        } else if headByte & 0b1111_0000 == 0b1110_0000 {
             3
-        } else if headByte & 0b1111_0000 == 0b1111_0000 {
+        } else if headByte & 0b1111_1000 == 0b1111_0000 {
             4
         } else {
-            unreachable!()
+            // continuation bytes and bytes that never appear in UTF-8
+            let byte = str.as_encoded_bytes()[index];
+            index += 1;
+            return Some(CharOrByte::Byte(byte));
         };
         if index + charLen > str.len() {
             let byte = str.as_encoded_bytes()[index];
@@ -300,22 +1862,61 @@ pub fn osstr_chars(str: &OsStr) -> impl '_ + Iterator<Item = CharOrByte> {
         let slice = &str.as_encoded_bytes()[index..index + charLen];
         if let std::result::Result::Ok(utf8) = std::str::from_utf8(slice) {
             index += charLen;
-            return utf8.chars().next().map(CharOrByte::Char);
+            utf8.chars().next().map(CharOrByte::Char)
         } else {
             let byte = str.as_encoded_bytes()[index];
             index += 1;
-            return Some(CharOrByte::Byte(byte));
+            Some(CharOrByte::Byte(byte))
         }
     })
 }
 
 pub fn osstr_chars_lowercased(str: &OsStr) -> impl '_ + Iterator<Item = CharOrByte> {
-    osstr_chars(str).flat_map(|v| -> smallvec::SmallVec<[CharOrByte; 16]> {
-        match v {
-            CharOrByte::Char(c) => c.to_lowercase().map(CharOrByte::Char).collect(),
-            _ => smallvec::smallvec![v],
-        }
-    })
+    osstr_chars_folded(str, false)
+}
+
+/// As `osstr_chars_lowercased`, with accents also removed if `deaccent`.
+pub fn osstr_chars_folded(str: &OsStr, deaccent: bool) -> impl '_ + Iterator<Item = CharOrByte> {
+    osstr_chars(str)
+        .flat_map(|v| -> smallvec::SmallVec<[CharOrByte; 16]> {
+            match v {
+                CharOrByte::Char(c) => c.to_lowercase().map(CharOrByte::Char).collect(),
+                _ => smallvec::smallvec![v],
+            }
+        })
+        .filter_map(move |v| match v {
+            CharOrByte::Char(c) if deaccent => deaccent_char(c).map(CharOrByte::Char),
+            _ => Some(v),
+        })
+}
+
+/// Folds a lowercase Latin letter with a diacritic to its base letter, and
+/// drops combining diacritics (as in decomposed names, e.g. from macOS).
+fn deaccent_char(c: char) -> Option<char> {
+    let base = match c {
+        '\u{300}'..='\u{36f}' => return None,
+        'à'..='å' | 'ā' | 'ă' | 'ą' => 'a',
+        'ç' | 'ć' | 'ĉ' | 'ċ' | 'č' => 'c',
+        'ď' | 'đ' => 'd',
+        'è'..='ë' | 'ē' | 'ĕ' | 'ė' | 'ę' | 'ě' => 'e',
+        'ĝ' | 'ğ' | 'ġ' | 'ģ' => 'g',
+        'ĥ' | 'ħ' => 'h',
+        'ì'..='ï' | 'ĩ' | 'ī' | 'ĭ' | 'į' => 'i',
+        'ĵ' => 'j',
+        'ķ' => 'k',
+        'ĺ' | 'ļ' | 'ľ' | 'ŀ' | 'ł' => 'l',
+        'ñ' | 'ń' | 'ņ' | 'ň' => 'n',
+        'ò'..='ö' | 'ø' | 'ō' | 'ŏ' | 'ő' => 'o',
+        'ŕ' | 'ŗ' | 'ř' => 'r',
+        'ś' | 'ŝ' | 'ş' | 'š' => 's',
+        'ţ' | 'ť' | 'ŧ' => 't',
+        'ù'..='ü' | 'ũ' | 'ū' | 'ŭ' | 'ů' | 'ű' | 'ų' => 'u',
+        'ŵ' => 'w',
+        'ý' | 'ÿ' | 'ŷ' => 'y',
+        'ź' | 'ż' | 'ž' => 'z',
+        _ => c,
+    };
+    Some(base)
 }
 
 #[test]
@@ -338,14 +1939,57 @@ fn test_osstr_chars() {
         ]
     );
 
+    // multibyte characters, and stray continuation bytes
+    let str =
+        unsafe { OsStr::from_encoded_bytes_unchecked(b"\xe2\x82\xac\xf0\x9f\xa6\x80\x80\xe2\x82") };
+    let chars: Vec<_> = osstr_chars(str).collect();
+    assert_eq!(
+        chars,
+        vec![
+            Char('\u{20ac}'),
+            Char('\u{1f980}'),
+            Byte(b'\x80'),
+            Byte(b'\xe2'),
+            Byte(b'\x82'),
+        ]
+    );
+
     let str = OsString::from("Ab");
     let chars: Vec<_> = osstr_chars_lowercased(&str).collect();
     assert_eq!(chars, vec![Char('a'), Char('b'),]);
 }
 
+/// Orders names by their lowercased forms, as `osstr_chars_lowercased` yields
+/// them, compared an element at a time. Characters compare by code point, and
+/// sort before invalid bytes, including Windows' lone surrogates (see
+/// `osstr_chars`), which compare as their encoded bytes.
+///
+/// Characters lowercasing to several (e.g. `İ` to `i` and U+0307) are compared
+/// as that sequence, so equal those characters written out separately, and a
+/// name is never compared by how it was split into characters. Only lowercase
+/// mappings are used, without full case folding: titlecase `ǅ` equals `ǆ`,
+/// but `ß` doesn't equal `ss`, nor a ligature like `ﬁ` equal `fi`. This makes
+/// it a lexicographic order over sequences of totally ordered elements, and so
+/// a total order itself, which `Hash` agrees with.
 fn compare_osstr_case_insensitive(left: &OsStr, right: &OsStr) -> Ordering {
-    let mut left = osstr_chars_lowercased(left);
-    let mut right = osstr_chars_lowercased(right);
+    compare_osstr_folded(left, right, false)
+}
+
+/// As `compare_osstr_case_insensitive`, comparing names as
+/// `osstr_chars_folded` yields them.
+fn compare_osstr_folded(left: &OsStr, right: &OsStr, deaccent: bool) -> Ordering {
+    if log_enabled(LogLevel::Trace) {
+        // invaluable when names look identical but differ in combining chars or invalid bytes
+        let leftChars: Vec<_> = osstr_chars_folded(left, deaccent).collect();
+        let rightChars: Vec<_> = osstr_chars_folded(right, deaccent).collect();
+        log!(
+            LogLevel::Trace,
+            "comparing {left:?} as {leftChars:?} with {right:?} as {rightChars:?}"
+        );
+    }
+
+    let mut left = osstr_chars_folded(left, deaccent);
+    let mut right = osstr_chars_folded(right, deaccent);
     loop {
         let pair = (left.next(), right.next());
         match pair {
@@ -391,6 +2035,108 @@ fn test_osstr_case_insensitive() {
     assert_eq!(compare_osstr_case_insensitive(&b, &a), Ordering::Greater);
 }
 
+#[test]
+fn test_osstr_lone_surrogates() {
+    use CharOrByte::*;
+
+    // WTF-8, as `as_encoded_bytes` gives on Windows
+    let wtf8 = |bytes: &'static [u8]| unsafe { OsStr::from_encoded_bytes_unchecked(bytes) };
+    let highSurrogate = wtf8(b"A\xed\xa0\x80");
+    let lowSurrogate = wtf8(b"a\xed\xb0\x80");
+    assert_eq!(
+        osstr_chars_lowercased(highSurrogate).collect::<Vec<_>>(),
+        [Char('a'), Byte(0xed), Byte(0xa0), Byte(0x80)]
+    );
+
+    let cmp = compare_osstr_case_insensitive;
+    // after every valid char, even those UTF-16 puts after surrogates
+    assert_eq!(cmp(OsStr::new("a\u{10000}"), highSurrogate), Ordering::Less);
+    assert_eq!(cmp(OsStr::new("a\u{ffff}"), highSurrogate), Ordering::Less);
+    // between themselves, in code unit order, as their encodings sort that way
+    assert_eq!(cmp(highSurrogate, lowSurrogate), Ordering::Less);
+    assert_eq!(cmp(lowSurrogate, highSurrogate), Ordering::Greater);
+    // only the valid part has its case ignored
+    assert_eq!(cmp(highSurrogate, wtf8(b"a\xed\xa0\x80")), Ordering::Equal);
+    assert_eq!(cmp(highSurrogate, wtf8(b"a\xed\xa0\x81")), Ordering::Less);
+    // the same as any other invalid bytes
+    assert_eq!(cmp(highSurrogate, wtf8(b"a\xed\xa0")), Ordering::Greater);
+    assert_eq!(cmp(highSurrogate, wtf8(b"a\xff")), Ordering::Less);
+}
+
+#[test]
+fn test_osstr_case_insensitive_expanding() {
+    use std::hash::{BuildHasher, RandomState};
+
+    let cmp = |a: &str, b: &str| compare_osstr_case_insensitive(a.as_ref(), b.as_ref());
+    assert_eq!(cmp("İ", "i\u{307}"), Ordering::Equal);
+    assert_eq!(cmp("Xİy", "xi\u{307}Y"), Ordering::Equal);
+    assert_eq!(cmp("ǅ", "ǆ"), Ordering::Equal);
+    assert_eq!(cmp("Ǆ", "ǆ"), Ordering::Equal);
+    assert_eq!(cmp("ẞ", "ß"), Ordering::Equal);
+    assert_ne!(cmp("ß", "ss"), Ordering::Equal);
+    assert_ne!(cmp("ﬁ", "fi"), Ordering::Equal);
+    // by the expansion's first char, not İ's own code point
+    assert_eq!(cmp("İ", "j"), Ordering::Less);
+    assert_eq!(cmp("İ", "i"), Ordering::Greater);
+
+    let names = [
+        "",
+        "i",
+        "I",
+        "İ",
+        "i\u{307}",
+        "i\u{307}a",
+        "İa",
+        "ia",
+        "j",
+        "ß",
+        "ẞ",
+        "ss",
+        "SS",
+        "ﬁ",
+        "fi",
+        "FI",
+        "ǅ",
+        "ǆ",
+        "Ǆ",
+        "dž",
+        "a\u{ff}",
+        "a\u{ff}b",
+    ];
+    let mut names: Vec<OsString> = names.iter().map(OsString::from).collect();
+    // invalid bytes, including the lead byte of U+0307 alone
+    for bytes in [&b"i\xff"[..], b"\xcc", b"i\xcc"] {
+        names.push(<OsStr as std::os::unix::ffi::OsStrExt>::from_bytes(bytes).into());
+    }
+
+    let hasher = RandomState::new();
+    let hash = |name: &OsString| hasher.hash_one(InsensitivePath(name.into()));
+    for a in &names {
+        assert_eq!(compare_osstr_case_insensitive(a, a), Ordering::Equal);
+        for b in &names {
+            let ab = compare_osstr_case_insensitive(a, b);
+            assert_eq!(
+                ab,
+                compare_osstr_case_insensitive(b, a).reverse(),
+                "{a:?} {b:?}"
+            );
+            if ab == Ordering::Equal {
+                assert_eq!(hash(a), hash(b), "{a:?} {b:?}");
+            }
+            for c in &names {
+                let bc = compare_osstr_case_insensitive(b, c);
+                if ab == bc {
+                    assert_eq!(
+                        compare_osstr_case_insensitive(a, c),
+                        ab,
+                        "{a:?} {b:?} {c:?}"
+                    );
+                }
+            }
+        }
+    }
+}
+
 pub fn resolve_parents(path: &Path) -> PathBuf {
     let mut res = PathBuf::new();
     for component in path.components() {
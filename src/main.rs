@@ -2,42 +2,177 @@
 
 use std::{
     cell::OnceCell,
+    collections::{HashMap, VecDeque},
     convert::Infallible,
+    ffi::{OsStr, OsString},
+    net::{IpAddr, SocketAddr},
+    os::unix::ffi::OsStringExt,
     path::{Path, PathBuf},
-    sync::OnceLock,
+    process::ExitCode,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering as AtomicOrdering},
+        Arc, Mutex, RwLock,
+    },
+    time::{Duration, Instant, SystemTime},
 };
 
 use anyhow::{anyhow, Context};
-use caseproxy::{resolve_parents, AResult, Deferred, InsensitivePath};
-use clap::Parser;
-use futures_util::TryStreamExt;
+use caseproxy::{
+    canonical_case, component_candidates, find_similar_names,
+    hashing::{base64, HashAlgorithm, HashCache},
+    json, log,
+    manifest::Manifest,
+    resolve_parents, AResult, Deferred, DirectoryIndex, InsensitiveOsString, InsensitivePath,
+    LogLevel, MatchOptions, ResolveError,
+};
+use clap::{Parser, ValueEnum};
+use futures_util::{future::Either, Stream, TryStreamExt};
 use http_body_util::{combinators::BoxBody, BodyExt, Full, StreamBody};
 use hyper::{
     body::{Bytes, Frame},
-    header::HeaderValue,
+    header::{
+        HeaderName, HeaderValue, ACCEPT, ACCEPT_ENCODING, ACCEPT_RANGES, ALLOW, AUTHORIZATION,
+        CONTENT_DISPOSITION, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, ETAG,
+        HOST, IF_MODIFIED_SINCE, IF_NONE_MATCH, IF_RANGE, LAST_MODIFIED, LOCATION, RANGE, TE,
+        TRAILER, VARY,
+    },
     server::conn::http1,
     service::service_fn,
     Request, Response, StatusCode,
 };
 use hyper_util::rt::TokioIo;
-use tokio::net::{TcpListener, UnixListener};
+use mime::MimeTypes;
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::{
+    net::{TcpListener, UnixListener},
+    sync::{OwnedSemaphorePermit, Semaphore},
+};
 use tokio_util::io::ReaderStream;
+use upstream::{CacheBudget, Upstream};
+
+mod autoindex;
+mod disposition;
+mod gzip;
+mod mime;
+#[cfg(feature = "record")]
+mod record;
+mod sniff;
+mod tar;
+mod upstream;
+mod webdav;
+
+const version: &str = concat!(
+    env!("CARGO_PKG_VERSION"),
+    " (",
+    env!("CASEPROXY_GIT_SHA"),
+    ", built ",
+    env!("CASEPROXY_BUILD_DATE"),
+    ")"
+);
 
 /// A static file server that matches paths case-insensitively.
 #[derive(Debug, Parser)]
+#[command(version = version)]
 struct Config {
     /// TCP port to listen on.
     #[arg(short, long, conflicts_with = "socketPath")]
-    port: Option<i16>,
+    port: Option<u16>,
 
     /// Host to listen on when using TCP.
     #[arg(short = 'H', long, requires = "port", default_value = "localhost")]
     host: String,
 
+    /// Which address family to listen on when the host resolves to several
+    /// addresses.
+    #[arg(long, requires = "port", value_enum, default_value_t = AddressFamily::Auto)]
+    addressFamily: AddressFamily,
+
+    /// Maximum number of pending TCP connections queued by the OS.
+    #[arg(
+        long,
+        requires = "port",
+        default_value_t = 1024,
+        value_parser = clap::value_parser!(i32).range(1..)
+    )]
+    listenBacklog: i32,
+
+    /// Set `SO_REUSEPORT` on the TCP socket, allowing several processes to
+    /// listen on the same port, e.g. for zero-downtime restarts.
+    ///
+    /// On Linux, incoming connections are load balanced between all listening
+    /// processes (which must run as the same user). On BSDs and macOS, only the
+    /// most recently bound socket receives new connections.
+    #[arg(long, requires = "port")]
+    reusePort: bool,
+
+    /// Trust `X-Forwarded-*` headers set by a reverse proxy: `X-Forwarded-For`
+    /// to identify clients in logs, and `X-Forwarded-Proto`/`X-Forwarded-Host`
+    /// to make redirects absolute URLs on the proxy.
+    ///
+    /// Only use this when all requests pass through a proxy which sets the
+    /// headers, otherwise clients can claim to be anyone.
+    #[arg(long)]
+    trustProxy: bool,
+
+    /// Answer `/_caseproxy/info` with the effective configuration as JSON, so
+    /// a running instance's settings can be checked over HTTP.
+    ///
+    /// As it reveals filesystem paths, it's only answered for requests
+    /// carrying `Authorization: Bearer TOKEN`. Give this in an argument file
+    /// to keep the token out of process listings.
+    #[arg(long = "info-endpoint", value_name = "TOKEN")]
+    infoToken: Option<String>,
+
+    /// Include query strings in logged requests.
+    ///
+    /// Query strings never affect which file is served.
+    #[arg(long)]
+    logQuery: bool,
+
+    /// Hold on to the root directory itself from startup, rather than going
+    /// through its path, so that replacing the path (e.g. swapping a symlink
+    /// to a new release) can't change or escape what is served.
+    ///
+    /// Done by making the root the working directory, so relative paths given
+    /// to other options are only valid until then. A `--chroot` does the same.
+    #[arg(long)]
+    pinRoot: bool,
+
+    /// Once listening, confine the server to the root directory with
+    /// `chroot`, so that no path can reach outside it.
+    ///
+    /// Needs root (or `CAP_SYS_CHROOT`), so should be paired with `--user`
+    /// to then drop those privileges. Files named by other options are read
+    /// before entering the jail, but `--upstream` hostnames can't be looked up
+    /// from inside it.
+    #[arg(long)]
+    chroot: bool,
+
+    /// Once listening (and after `--chroot`), switch to this user, by name or
+    /// numeric ID, so e.g. port 80 can be bound without serving as root.
+    ///
+    /// Supplementary groups are dropped. `--socket-path` is handed over to the
+    /// user, but its directory must be writable by them for it to be removed
+    /// on exit.
+    #[arg(long, value_name = "USER")]
+    user: Option<String>,
+
+    /// Group to switch to along with `--user`, by name or numeric ID, rather
+    /// than the user's own.
+    #[arg(long, value_name = "GROUP", requires = "user")]
+    group: Option<String>,
+
     /// Path to Unix socket to listen on.
     #[arg(short, long, conflicts_with = "port")]
     socketPath: Option<PathBuf>,
 
+    /// Name of an abstract Unix socket to listen on, which (unlike
+    /// `--socket-path`) has no file to clean up afterwards.
+    ///
+    /// Linux only; an error on other platforms.
+    #[arg(long, value_name = "NAME", conflicts_with_all = ["port", "socketPath"])]
+    abstractSocket: Option<String>,
+
     /// Root directory to serve files from.
     #[arg(short, long, default_value = ".")]
     rootPath: PathBuf,
@@ -47,11 +182,199 @@ struct Config {
     #[arg(short, long, default_value = "/")]
     urlPrefix: String,
 
+    /// Match `--url-prefix` case-insensitively too, like the paths after it.
+    #[arg(long)]
+    insensitivePrefix: bool,
+
+    /// Cache case-folded listings of visited directories, making lookups in
+    /// directories with very many entries much faster.
+    #[arg(long)]
+    indexDirs: bool,
+
+    /// Remember what request paths resolved to, so requests for them in any
+    /// case skip resolving again, until the file is gone or the entry expires.
+    ///
+    /// Unavailable with `--insensitive-ext-only`, or `--on-ambiguous`
+    /// `exact-preferred` or `newest`, where paths differing in case can
+    /// resolve differently.
+    #[arg(long, conflicts_with = "insensitiveExtOnly")]
+    resolveCache: bool,
+
+    /// Maximum number of paths kept by `--resolve-cache`.
+    #[arg(long, requires = "resolveCache", default_value_t = 10_000)]
+    resolveCacheSize: usize,
+
+    /// Seconds `--resolve-cache` keeps a path for.
+    #[arg(
+        long,
+        value_name = "SECS",
+        requires = "resolveCache",
+        default_value_t = 60
+    )]
+    resolveCacheTtl: u64,
+
+    /// Every this many seconds, check a batch of `--resolve-cache`'s entries
+    /// in the background, dropping those whose file is gone or has been
+    /// renamed, e.g. to another case.
+    ///
+    /// Requests already skip such entries, so this only catches them sooner,
+    /// e.g. on network mounts where changes are easily missed.
+    #[arg(
+        long,
+        value_name = "SECS",
+        requires = "resolveCache",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    cacheSweepInterval: Option<u64>,
+
+    /// Answer `/_caseproxy/cache` with how full `--resolve-cache` is, its hits
+    /// and misses, and the paths it most recently resolved, as JSON, to see
+    /// how well it's working.
+    ///
+    /// As with `--info-endpoint`, only requests carrying
+    /// `Authorization: Bearer TOKEN` are answered.
+    #[arg(
+        long = "cache-endpoint",
+        value_name = "TOKEN",
+        requires = "resolveCache"
+    )]
+    cacheToken: Option<String>,
+
+    /// Match paths exactly, except for the case of the filename's extension.
+    ///
+    /// For example, `Photo.JPG` will match `Photo.jpg` but not `photo.jpg`.
+    #[arg(long)]
+    insensitiveExtOnly: bool,
+
+    /// Ignore trailing dots and spaces on filenames, which Windows trims from
+    /// names, so `/file.txt.` finds `file.txt` and vice versa.
+    #[arg(long)]
+    trimTrailingDots: bool,
+
+    /// Serve the file matching a request's case exactly when there is one,
+    /// only falling back to case-insensitive matching when not.
+    ///
+    /// Otherwise `--on-ambiguous` chooses between case variants, which
+    /// unless `exact-preferred` may pass over one matching exactly.
+    #[arg(long)]
+    preferExact: bool,
+
+    /// Which file to serve when several match a request.
+    #[arg(
+        long,
+        alias = "resolve-strategy",
+        value_enum,
+        default_value_t = Ambiguity::First
+    )]
+    onAmbiguous: Ambiguity,
+
+    /// Choose between matches under one directory by another strategy than
+    /// `--on-ambiguous`, as `/dir=STRATEGY`, e.g. `/legacy=newest`. May be
+    /// given several times.
+    ///
+    /// Directories are relative to `--url-prefix` and matched against the
+    /// request case-insensitively, the longest one containing it winning.
+    #[arg(
+        long = "on-ambiguous-under",
+        value_name = "DIR=STRATEGY",
+        value_parser = parse_ambiguity_prefix
+    )]
+    ambiguityPrefixes: Vec<(PathBuf, Ambiguity)>,
+
+    /// Also match accented letters against their unaccented forms, so
+    /// `/cafe.html` finds `café.html`.
+    ///
+    /// This changes which names count as the same, so is off by default. It
+    /// doesn't apply to `--manifest` lookups.
+    #[arg(long)]
+    deaccent: bool,
+
+    /// Resolve paths from a manifest generated by `make-manifest` before
+    /// searching the filesystem. Either its JSON or `--binary` format.
+    #[arg(long)]
+    manifest: Option<PathBuf>,
+
+    /// Only serve paths found in the manifest.
+    #[arg(long, requires = "manifest")]
+    manifestOnly: bool,
+
+    /// Allow downloading directories as tar archives, by requesting them with
+    /// `Accept: application/x-tar` or a `format=tar` query parameter.
+    #[arg(long)]
+    allowTar: bool,
+
+    /// Send files with `Content-Disposition: attachment`, naming them as
+    /// they're named on disk (composed to NFC), so they're saved under their
+    /// real name rather than however they were requested.
+    #[arg(long)]
+    attachments: bool,
+
+    /// Answer WebDAV `PROPFIND` requests with the properties of files and the
+    /// entries of directories, so the tree can be mounted by file managers.
+    ///
+    /// Strictly read-only: methods other than `GET`, `HEAD`, `OPTIONS` and
+    /// `PROPFIND` are refused with `405`. Listings never go deeper than one
+    /// level, whatever `Depth` is asked for.
+    #[arg(long)]
+    webdav: bool,
+
+    /// Refuse to serve files whose path includes a component beginning with a
+    /// dot, except for the `.well-known` directory at the root.
+    #[arg(long)]
+    denyHidden: bool,
+
+    /// Refuse to serve a file that is itself a symlink, with `403`, rather than
+    /// following it to its target.
+    #[arg(long)]
+    noFollowFileLinks: bool,
+
+    /// Redirect with `301` when a request's directories are cased differently
+    /// than on disk, so directory URLs settle on one spelling. Files are still
+    /// served in place whatever their case.
+    #[arg(long)]
+    redirectDirs: bool,
+
+    /// Serve the first of these files found in a requested directory, tried in
+    /// order and matched case-insensitively, e.g. `index.html,default.htm`.
+    /// Directories with none of them are `404`. Given bare, `index.html`.
+    ///
+    /// Directories asked for without a trailing slash are first redirected to
+    /// the URL with one, so that relative links in their index work.
+    #[arg(
+        long,
+        value_name = "NAMES",
+        value_delimiter = ',',
+        num_args = 0..=1,
+        default_missing_value = "index.html"
+    )]
+    index: Vec<String>,
+
+    /// List the entries of requested directories without an index file as an
+    /// HTML page of links, rather than answering `404`.
+    #[arg(long)]
+    autoindex: bool,
+
+    /// When a file isn't found, suggest similarly named files in the same
+    /// directory, in the response body and an `X-Caseproxy-Suggestions` header.
+    #[arg(long)]
+    suggest: bool,
+
+    /// When a path isn't found, name the first component of it that didn't
+    /// match in an `X-Caseproxy-Failed-At` header, following its deepest
+    /// existing directory, to help diagnose broken deep links. Whether it
+    /// matched nothing, or only a file where a directory was needed, is given
+    /// in `X-Caseproxy-Failure` as `no match` or `not a directory`.
+    #[arg(long)]
+    failedAtHeader: bool,
+
     /**
         Whether to use `X-Sendfile` header.
 
         Signals the proxying httpd to serve the resolved file directly.
         Only supported by Apache and lighttpd.
+
+        The httpd answers the client's original request, so `Range` and
+        conditional headers are handled by it rather than caseproxy.
     */
     #[arg(long, conflicts_with = "nginxUrl")]
     sendfile: bool,
@@ -74,6 +397,8 @@ location /files {
     }
 }
 ```
+nginx serves the redirect with the client's original request headers, so
+`Range` requests are handled by it rather than caseproxy.
     */
     #[arg(
         long = "nginx",
@@ -82,51 +407,517 @@ location /files {
         help = "URL prefix to use with `X-Accel-Redirect` header"
     )]
     nginxUrl: Option<String>,
+
+    /// Answer `HEAD` requests offloaded with `--sendfile` or `--nginx` with
+    /// `200` and the file's `Content-Length`, rather than `204`, so its size
+    /// can be learned without the httpd. Costs a `stat` per such request.
+    #[arg(long)]
+    offloadContentLength: bool,
+
+    /// Detect the `Content-Type` of files without an extension from their
+    /// first few bytes, e.g. PNG or PDF signatures.
+    #[arg(long)]
+    sniffContent: bool,
+
+    /// Gzip text files (and text-based formats like JSON and SVG) for clients
+    /// accepting it, sending them without a `Content-Length`. Other types are
+    /// sent as they are, as are range requests.
+    #[arg(long)]
+    compress: bool,
+
+    /// Extensions `--compress` leaves alone, comma-separated and matched
+    /// case-insensitively, as files with them are compressed already even
+    /// where their type is text-based, e.g. `.svgz`. Replaces the defaults.
+    #[arg(
+        long,
+        value_name = "EXTS",
+        value_delimiter = ',',
+        requires = "compress",
+        default_value = "svgz,gz,tgz,br,zst,bz2,xz,zip,7z,woff,woff2,png,jpg,jpeg,webp"
+    )]
+    incompressible: Vec<String>,
+
+    /// Send `<file>.br`, `<file>.zst` or `<file>.gz` in place of a file, if
+    /// there is one and the client accepts that encoding, with the file's own
+    /// `Content-Type`. The encoding with the highest `q` wins, in that order
+    /// among equals.
+    #[arg(long)]
+    precompressed: bool,
+
+    /// Read extra `Content-Type`s by extension from this file, in the format
+    /// of `/etc/mime.types`, overriding built-in ones for the same extension.
+    #[arg(long, value_name = "PATH")]
+    mimeTypes: Option<PathBuf>,
+
+    /// Serve this file from memory for `/favicon.ico`, without looking it up
+    /// under the root. Read once at startup.
+    #[arg(long, value_name = "PATH", conflicts_with = "favicon204")]
+    favicon: Option<PathBuf>,
+
+    /// Answer `/favicon.ico` with an empty `204` rather than looking it up.
+    #[arg(long = "favicon-204")]
+    favicon204: bool,
+
+    /// Strip a leading UTF-8 byte order mark from files served as `text/*`,
+    /// which some browsers otherwise show or fail to parse.
+    #[arg(long)]
+    stripBom: bool,
+
+    /// Maximum number of files streamed at once, each holding a file
+    /// descriptor until its download completes. Further requests get a `503`
+    /// rather than risking the process running out of descriptors.
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    maxOpenFiles: Option<u32>,
+
+    /// Most requests handled at once, each counting until its response has
+    /// been sent.
+    ///
+    /// Further requests wait up to `--admission-timeout` for one to finish,
+    /// and are otherwise turned away with a `503`, so a flood of requests
+    /// can't pile up unbounded work. Requests pipelined on one connection
+    /// are answered in turn, so only count once the previous one is sent.
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    maxInflight: Option<u32>,
+
+    /// Milliseconds a request waits for a `--max-inflight` slot before being
+    /// turned away.
+    #[arg(long, value_name = "MS", default_value_t = 1000)]
+    admissionTimeout: u64,
+
+    /// Most requests waiting for a `--max-inflight` slot at once. Beyond
+    /// this, requests are turned away with a `503` immediately, rather than
+    /// after `--admission-timeout`.
+    #[arg(long, requires = "maxInflight", value_parser = clap::value_parser!(u32).range(1..))]
+    maxQueued: Option<u32>,
+
+    /// Most bytes per second to send each file at, so a few large downloads
+    /// can't saturate the link. Responses on a connection are sent one at a
+    /// time, so this also limits each connection.
+    ///
+    /// Doesn't apply with `--sendfile` or `--nginx`, where the httpd sends files.
+    #[arg(long, value_name = "BYTES", value_parser = clap::value_parser!(u64).range(1..))]
+    maxRate: Option<u64>,
+
+    /// Milliseconds to spend resolving a path before giving up with a `504`,
+    /// for roots on network filesystems where listing a directory can stall.
+    ///
+    /// The walk is abandoned at its next directory lookup, so a stalled one
+    /// still holds its blocking thread until it returns.
+    #[arg(long, value_name = "MS", value_parser = clap::value_parser!(u64).range(1..))]
+    resolveTimeout: Option<u64>,
+
+    /// Send a `Digest: sha-256=...` header with files, for clients to verify
+    /// downloads against. Digests are cached until a file's size or mtime changes.
+    #[arg(long)]
+    digestHeader: bool,
+
+    /// What files' `ETag`s are derived from. Conditional requests matching
+    /// them, or `If-Modified-Since` the file's mtime, are answered with `304`.
+    #[arg(long, value_enum, default_value_t = ETagMode::Mtime)]
+    etag: ETagMode,
+
+    /// File to keep content digests in between runs, for `--digest-header`
+    /// and `--etag content`, loaded on startup and written back on shutdown.
+    #[arg(long, value_name = "FILE")]
+    hashCache: Option<PathBuf>,
+
+    /// Send the file each request resolved to, relative to the root, in an
+    /// `X-Caseproxy-Resolved-Path` trailer after the body.
+    ///
+    /// Only clients sending `TE: trailers` get the trailer, and their
+    /// responses are always chunked, as trailers can't follow a body of
+    /// declared length.
+    #[arg(long)]
+    resolutionTrailers: bool,
+
+    /// Origin to fetch files from when they aren't found locally, e.g.
+    /// `http://origin.example.com/assets`. Only plain HTTP is supported.
+    ///
+    /// If the origin doesn't have the path as requested, it is retried in
+    /// lowercase.
+    #[arg(long, value_parser = Upstream::parse)]
+    upstream: Option<Upstream>,
+
+    /// Save files fetched from `--upstream` into the root, so later requests
+    /// are served locally.
+    ///
+    /// Only responses with a known length are cached.
+    #[arg(long, requires = "upstream")]
+    upstreamCache: bool,
+
+    /// Maximum total bytes to cache from `--upstream` while running.
+    #[arg(long, requires = "upstreamCache", default_value_t = 1 << 30)]
+    upstreamCacheSize: u64,
+
+    /// Milliseconds `--upstream` has to start responding, and then to send
+    /// each further part of the body, before the request is abandoned.
+    #[arg(
+        long,
+        value_name = "MS",
+        requires = "upstream",
+        default_value_t = 30_000,
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    upstreamTimeout: u64,
+
+    /// Extra header to send with every successful response, as `Name: Value`,
+    /// replacing any header of the same name caseproxy would send. May be
+    /// given several times.
+    #[arg(long = "header", value_name = "HEADER", value_parser = parse_header)]
+    headers: Vec<(HeaderName, HeaderValue)>,
+
+    /// Serve requests for one path from another, as `/from=/to`, e.g.
+    /// `/latest.zip=/releases/v2.3.1.zip`. May be given several times.
+    ///
+    /// Paths are relative to `--url-prefix` and the root respectively, and
+    /// both are matched case-insensitively. Targets must stay within the root.
+    #[arg(long = "alias", value_name = "FROM=TO", value_parser = parse_alias)]
+    aliases: Vec<(PathBuf, PathBuf)>,
+
+    /// HTTP versions to accept requests in, comma-separated, e.g. `1.1` to
+    /// turn away HTTP/1.0 clients. Others get `505 HTTP Version Not
+    /// Supported`. Only HTTP/1 is served, so `2` can't be given.
+    #[arg(
+        long,
+        value_name = "VERSIONS",
+        value_delimiter = ',',
+        value_parser = parse_http_version,
+        default_value = "1.0,1.1"
+    )]
+    httpVersions: Vec<hyper::Version>,
+
+    /// Also send `--header` headers with error responses.
+    #[arg(long, requires = "headers")]
+    headersAlways: bool,
+
+    /// Format of the body of error responses.
+    #[arg(long, value_enum, default_value_t = ErrorFormat::Text)]
+    errorFormat: ErrorFormat,
+
+    /// Append each request's path, status and resolved file to
+    /// `requests.jsonl` in this directory, for `--replay`.
+    #[cfg(feature = "record")]
+    #[arg(long, value_name = "DIR")]
+    record: Option<PathBuf>,
+
+    /// Re-issue `GET` requests from a `--record` recording against a running
+    /// caseproxy at `--replay-url`, report any which now resolve differently,
+    /// and exit.
+    #[cfg(feature = "record")]
+    #[arg(long, value_name = "RECORDING", requires = "replayUrl")]
+    replay: Option<PathBuf>,
+
+    /// URL of the server to replay against, e.g. `http://localhost:8080/`.
+    #[cfg(feature = "record")]
+    #[arg(long, value_name = "URL", value_parser = Upstream::parse, requires = "replay")]
+    replayUrl: Option<Upstream>,
+
+    /// Most verbose level of messages to print.
+    ///
+    /// The `trace` level includes the exact character sequences compared when
+    /// matching filenames.
+    #[arg(long, value_enum, default_value_t = LogLevel::Warn)]
+    logLevel: LogLevel,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum AddressFamily {
+    /// Prefer IPv4, falling back to IPv6.
+    Auto,
+    /// Only listen on IPv4 addresses.
+    V4,
+    /// Only listen on IPv6 addresses.
+    V6,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum Ambiguity {
+    /// The first in sorted order, found without listing the rest.
+    First,
+    /// The one with the shortest path, in characters, then the first of
+    /// those in sorted order. Every match is listed to find it.
+    Shortest,
+    /// The one matching the request's case exactly, if any, otherwise the
+    /// first. Correctly cased requests then never see the ambiguity.
+    ExactPreferred,
+    /// The most recently modified, then the first of those in sorted order.
+    Newest,
+    /// None, answering `409 Conflict` instead.
+    Error,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum ETagMode {
+    /// The file's size and mtime, which only need a `stat`.
+    Mtime,
+    /// A hash of the file's contents, so rebuilding or redeploying it
+    /// unchanged keeps its `ETag`. Hashes are cached as for `--digest-header`.
+    Content,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum ErrorFormat {
+    /// Plain text status message.
+    Text,
+    /// Minimal HTML page, for browsers.
+    Html,
+    /// `{"error": <status message>, "status": <status code>}`
+    Json,
+}
+
+impl Config {
+    /// Canonicalizes values given on the command line.
+    fn normalize(&mut self) {
+        // request paths have `..` resolved, so the root must too for prefix checks to work
+        self.rootPath = resolve_parents(&self.rootPath);
+        if self.rootPath.as_os_str().is_empty() {
+            self.rootPath = PathBuf::from(".");
+        }
+
+        if !self.urlPrefix.starts_with("/") {
+            self.urlPrefix.insert(0, '/');
+        }
+        if !self.urlPrefix.ends_with("/") {
+            self.urlPrefix.push('/');
+        }
+
+        if let Some(url) = &mut self.nginxUrl {
+            if !url.starts_with("/") {
+                url.insert(0, '/');
+            }
+            if !url.ends_with("/") {
+                url.push('/');
+            }
+        }
+
+        // written on exit, after `--pin-root` may have changed directory
+        if let Some(store) = &mut self.hashCache {
+            if let Ok(absolute) = std::path::absolute(&store) {
+                *store = absolute;
+            }
+        }
+    }
+}
+
+/// State shared by all connections.
+struct AppState {
+    config: Config,
+    directoryIndex: Option<DirectoryIndex>,
+    manifest: Option<Manifest>,
+    upstreamCache: Option<CacheBudget>,
+    digestCache: Option<HashCache>,
+    admission: Admission,
+    favicon: Option<Bytes>,
+    mimeTypes: MimeTypes,
+    resolutionStats: ResolutionStats,
+    resolveCache: Option<ResolveCache>,
+    resolvedHook: Option<ResolvedHook>,
+    #[cfg(feature = "record")]
+    recorder: Option<record::Recorder>,
+    /// Stalls each resolution, standing in for a slow filesystem.
+    #[cfg(test)]
+    resolveDelay: Duration,
 }
 
-static serverConfig: OnceLock<Config> = OnceLock::new();
+/// Called with each resolved file, to serve another in its place, or refuse it
+/// with a `404` by returning `None`. Lets embedders layer their own policies
+/// over case-insensitive resolution, e.g. serving shadow copies.
+type ResolvedHook = Box<dyn Fn(&Path) -> Option<PathBuf> + Send + Sync>;
+
+// bounds memory use, the oldest digests are evicted to make room
+const maxCachedDigests: usize = 10_000;
+
+impl AppState {
+    fn new(config: Config) -> AResult<Self> {
+        let directoryIndex = config.indexDirs.then(DirectoryIndex::new);
+        let manifest = config.manifest.as_deref().map(Manifest::load).transpose()?;
+        let upstreamCache = config
+            .upstreamCache
+            .then(|| CacheBudget::new(config.upstreamCacheSize));
+        let hashing = config.digestHeader || config.etag == ETagMode::Content;
+        let digestCache = match (&config.hashCache, hashing) {
+            (Some(store), _) => Some(HashCache::open(store, maxCachedDigests)?),
+            (None, true) => Some(HashCache::new(maxCachedDigests)),
+            (None, false) => None,
+        };
+        #[cfg(feature = "record")]
+        let recorder = config
+            .record
+            .as_deref()
+            .map(record::Recorder::open)
+            .transpose()?;
+        let favicon = config
+            .favicon
+            .as_ref()
+            .map(|path| {
+                std::fs::read(path).with_context(|| format!("couldn't read favicon {path:?}"))
+            })
+            .transpose()?
+            .map(Bytes::from);
+        let admission = Admission::new(&config);
+        let mimeTypes = MimeTypes::new(config.mimeTypes.as_deref())?;
+        let uncacheable = std::iter::once(config.onAmbiguous)
+            .chain(
+                config
+                    .ambiguityPrefixes
+                    .iter()
+                    .map(|(_, strategy)| *strategy),
+            )
+            .find(|strategy| matches!(strategy, Ambiguity::ExactPreferred | Ambiguity::Newest));
+        if let (true, Some(strategy)) = (config.resolveCache, uncacheable) {
+            let strategy = strategy.to_possible_value().unwrap();
+            return Err(anyhow!(
+                "--resolve-cache can't be used with --on-ambiguous {}",
+                strategy.get_name()
+            ));
+        }
+        let resolveCache = config.resolveCache.then(|| {
+            ResolveCache::new(
+                config.resolveCacheSize,
+                Duration::from_secs(config.resolveCacheTtl),
+            )
+        });
+        Ok(Self {
+            config,
+            directoryIndex,
+            manifest,
+            upstreamCache,
+            digestCache,
+            admission,
+            favicon,
+            mimeTypes,
+            resolutionStats: ResolutionStats::default(),
+            resolveCache,
+            resolvedHook: None,
+            #[cfg(feature = "record")]
+            recorder,
+            #[cfg(test)]
+            resolveDelay: Duration::ZERO,
+        })
+    }
+}
 
 #[tokio::main]
-async fn main() -> AResult<()> {
-    let expanded = argfile::expand_args(argfile::parse_fromfile, argfile::PREFIX)?;
+async fn main() -> ExitCode {
+    let expanded = match argfile::expand_args(argfile::parse_fromfile, argfile::PREFIX) {
+        Ok(expanded) => expanded,
+        Err(err) => {
+            eprintln!("Error: couldn't read argument file: {err}");
+            return ExitCode::from(Failure::Config.exit_code());
+        }
+    };
     let mut config = match Config::try_parse_from(expanded) {
         Ok(config) => config,
+        // exits successfully for `--help` and `--version`, and with 2
+        // (`Failure::Config`) otherwise
+        Err(err) => err.exit(),
+    };
+
+    config.normalize();
+    eprintln!("caseproxy {version}");
+    dbg!(&config);
+    caseproxy::set_log_level(config.logLevel);
+
+    match run(config, None).await {
+        Ok(()) => ExitCode::SUCCESS,
         Err(err) => {
-            err.print();
-            std::process::exit(1)
+            eprintln!("Error: {err:?}");
+            ExitCode::from(exit_code(&err))
         }
-    };
+    }
+}
+
+/// Why the server couldn't start, attached as context to the error so that
+/// `main` can exit with a code saying so.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Failure {
+    /// Invalid or contradictory options, or files they name being unreadable.
+    Config,
+    /// Couldn't listen on the given address or socket.
+    Bind,
+    /// The root directory doesn't exist or can't be read.
+    RootInaccessible,
+}
 
-    if !config.urlPrefix.starts_with("/") {
-        config.urlPrefix.insert(0, '/');
+impl Failure {
+    fn exit_code(self) -> u8 {
+        match self {
+            Failure::Config => 2,
+            Failure::Bind => 3,
+            Failure::RootInaccessible => 4,
+        }
     }
-    if !config.urlPrefix.ends_with("/") {
-        config.urlPrefix.push('/');
+}
+
+impl std::fmt::Display for Failure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Failure::Config => "invalid configuration",
+            Failure::Bind => "couldn't listen",
+            Failure::RootInaccessible => "root directory is inaccessible",
+        })
     }
+}
 
-    if let Some(url) = &mut config.nginxUrl {
-        if !url.starts_with("/") {
-            url.insert(0, '/');
-        }
-        if !url.ends_with("/") {
-            url.push('/');
+/// 0 is a clean shutdown, and 1 any failure not covered by `Failure`.
+fn exit_code(err: &anyhow::Error) -> u8 {
+    err.downcast_ref::<Failure>()
+        .map_or(1, |failure| failure.exit_code())
+}
+
+/// Serves until interrupted, passing resolved files through `resolvedHook` if
+/// one is given.
+async fn run(config: Config, resolvedHook: Option<ResolvedHook>) -> AResult<()> {
+    #[cfg(feature = "record")]
+    if let (Some(recording), Some(url)) = (&config.replay, &config.replayUrl) {
+        let mismatches = record::replay(recording, url).await?;
+        if mismatches > 0 {
+            return Err(anyhow!("{mismatches} requests resolved differently"));
         }
+        return Ok(());
     }
 
-    serverConfig.set(config).unwrap();
-    let config = serverConfig.get().unwrap();
-    dbg!(config);
-
     if matches!(
         config,
         Config {
             port: None,
             socketPath: None,
+            abstractSocket: None,
             ..
         }
     ) {
-        return Err(anyhow!("One of --port or --socket-path must be given"));
+        return Err(
+            anyhow!("One of --port, --socket-path or --abstract-socket must be given")
+                .context(Failure::Config),
+        );
+    }
+    std::fs::read_dir(&config.rootPath)
+        .with_context(|| format!("couldn't read {:?}", config.rootPath))
+        .context(Failure::RootInaccessible)?;
+
+    // looked up now, as the user database is out of reach once chrooted
+    let runAs = match &config.user {
+        Some(user) => Some(RunAs::resolve(user, config.group.as_deref()).context(Failure::Config)?),
+        None => None,
+    };
+    let mut state = AppState::new(config).context(Failure::Config)?;
+    state.resolvedHook = resolvedHook;
+    let listener = listen(&state.config).await.context(Failure::Bind)?;
+    let removeSocket = match &state.config.socketPath {
+        Some(socketPath) => Some(socket_cleanup(socketPath, runAs).context(Failure::Bind)?),
+        None => None,
+    };
+    // only once listening, as binding may need privileges too
+    if state.config.chroot {
+        enter_chroot(&mut state.config).context(Failure::RootInaccessible)?;
+    } else if state.config.pinRoot {
+        pin_root(&mut state.config).context(Failure::RootInaccessible)?;
+    }
+    if let Some(runAs) = runAs {
+        runAs.switch_to().context(Failure::Config)?;
     }
+    let state: &'static AppState = Box::leak(Box::new(state));
+    tokio::spawn(sweep_resolve_cache(state));
 
     macro_rules! main_loop {
         ($listener:ident) => {
@@ -136,126 +927,5718 @@ async fn main() -> AResult<()> {
                     _ = tokio::signal::ctrl_c() => { break }
                 };
                 let io = TokioIo::new(client);
+                let peer = clientAddr.peer_ip();
                 tokio::task::spawn(async move {
                     let res = http1::Builder::new()
-                        .serve_connection(io, service_fn(handle_request))
+                        .serve_connection(io, service_fn(|req| handle_request(state, peer, req)))
                         .await;
                     if let Err(err) = res {
-                        eprintln!("Failed serving connection from {clientAddr:?}: {err:?}");
+                        log_connection_error(&format!("{clientAddr:?}"), &err);
                     }
                 });
             }
         };
     }
 
-    if let Some(port) = config.port {
-        let host = &format!("{}:{}", config.host, port);
+    match listener {
+        Listener::Tcp(mut listener) => main_loop!(listener),
+        Listener::Unix(mut listener) => main_loop!(listener),
+    }
 
-        let mut candidateAddresses = tokio::net::lookup_host(host)
-            .await
-            .context(format!("invalid host address {host:?}"))?
-            .collect::<Vec<_>>();
-        if candidateAddresses.is_empty() {
-            return Err(anyhow!(
-                "lookup of hostname {host:?} yields zero addresses?!"
-            ));
+    log!(LogLevel::Info, "{}", state.resolutionStats.summary());
+    if let Some(digests) = &state.digestCache {
+        if let Err(err) = digests.save() {
+            log!(LogLevel::Warn, "couldn't save hash cache: {err:#}");
+        }
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_exit_codes() -> AResult<()> {
+    let (root, removeRoot) = make_temp_dir()?;
+    let root = root.to_str().unwrap();
+    let run_with = |args: &[&str]| {
+        let mut config = Config::try_parse_from(
+            ["caseproxy", "--root-path", root]
+                .iter()
+                .chain(args)
+                .copied(),
+        )
+        .unwrap();
+        config.normalize();
+        run(config, None)
+    };
+
+    let err = run_with(&[]).await.unwrap_err();
+    assert_eq!(exit_code(&err), Failure::Config.exit_code());
+    let err = run_with(&["--socket-path", "x", "--favicon", "/nonexistent"])
+        .await
+        .unwrap_err();
+    assert_eq!(exit_code(&err), Failure::Config.exit_code());
+    for strategy in ["exact-preferred", "newest"] {
+        let err = run_with(&[
+            "--socket-path",
+            "x",
+            "--resolve-cache",
+            "--on-ambiguous",
+            strategy,
+        ])
+        .await
+        .unwrap_err();
+        assert_eq!(exit_code(&err), Failure::Config.exit_code(), "{strategy}");
+    }
+
+    let taken = TcpListener::bind("127.0.0.1:0").await?;
+    let port = taken.local_addr()?.port().to_string();
+    let err = run_with(&["--host", "127.0.0.1", "--port", &port])
+        .await
+        .unwrap_err();
+    assert_eq!(exit_code(&err), Failure::Bind.exit_code());
+    let err = run_with(&["--socket-path", "/nonexistent/caseproxy.sock"])
+        .await
+        .unwrap_err();
+    assert_eq!(exit_code(&err), Failure::Bind.exit_code());
+
+    let missing = format!("{root}/missing");
+    let mut config =
+        Config::try_parse_from(["caseproxy", "--root-path", &missing, "--port", "1"]).unwrap();
+    config.normalize();
+    let err = run(config, None).await.unwrap_err();
+    assert_eq!(exit_code(&err), Failure::RootInaccessible.exit_code());
+
+    assert_eq!(exit_code(&anyhow!("anything else")), 1);
+    Ok(())
+}
+
+/// Why serving a connection failed.
+#[derive(Debug, PartialEq, Eq)]
+enum ConnectionError {
+    /// The client went away, which is routine.
+    ClientGone,
+    /// A file being sent couldn't be read.
+    ReadFailed(std::io::ErrorKind),
+    Other,
+}
+
+fn classify_connection_error(err: &hyper::Error) -> ConnectionError {
+    use std::io::ErrorKind;
+
+    if err.is_canceled() || err.is_incomplete_message() {
+        return ConnectionError::ClientGone;
+    }
+    let mut source = std::error::Error::source(err);
+    let ioError = std::iter::from_fn(|| {
+        let current = source?;
+        source = current.source();
+        Some(current)
+    })
+    .find_map(|err| err.downcast_ref::<std::io::Error>());
+    match (ioError.map(std::io::Error::kind), err.is_user()) {
+        (
+            Some(ErrorKind::BrokenPipe | ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted),
+            _,
+        ) => ConnectionError::ClientGone,
+        // errors from the response body are ours, rather than the connection's
+        (Some(kind), true) => ConnectionError::ReadFailed(kind),
+        _ => ConnectionError::Other,
+    }
+}
+
+fn log_connection_error(client: &str, err: &hyper::Error) {
+    match classify_connection_error(err) {
+        ConnectionError::ClientGone => {
+            log!(LogLevel::Debug, "{client} disconnected: {err}")
         }
-        // prefer ipv4
-        candidateAddresses.sort_by(|l, r| l.is_ipv6().cmp(&r.is_ipv6()));
+        ConnectionError::ReadFailed(kind) => log!(
+            LogLevel::Error,
+            "Failed sending file to {client} ({kind:?}): {err:?}"
+        ),
+        ConnectionError::Other => {
+            log!(
+                LogLevel::Warn,
+                "Failed serving connection from {client}: {err:?}"
+            )
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_read_error_mid_stream() -> AResult<()> {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let (root, removeRoot) = make_temp_dir()?;
+    // directories open fine, then fail to read once the response is underway
+    std::fs::create_dir(root.join("dir"))?;
+    let state = test_state(&root, &[]);
+
+    let (mut client, server) = tokio::io::duplex(1 << 16);
+    let connection = tokio::task::spawn(http1::Builder::new().serve_connection(
+        TokioIo::new(server),
+        service_fn(|req| handle_request(state, None, req)),
+    ));
+    client
+        .write_all(b"GET /dir HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+        .await?;
+    // the head may or may not be flushed before the connection is dropped
+    client.read_to_end(&mut vec![]).await?;
+
+    let err = connection.await?.unwrap_err();
+    assert_eq!(
+        classify_connection_error(&err),
+        ConnectionError::ReadFailed(std::io::ErrorKind::IsADirectory)
+    );
+
+    // whereas a client hanging up partway is routine
+    std::fs::write(root.join("big.bin"), vec![0; 1 << 20])?;
+    let (mut client, server) = tokio::io::duplex(1 << 12);
+    let connection = tokio::task::spawn(http1::Builder::new().serve_connection(
+        TokioIo::new(server),
+        service_fn(|req| handle_request(state, None, req)),
+    ));
+    client
+        .write_all(b"GET /big.bin HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+        .await?;
+    let mut head = [0; 12];
+    client.read_exact(&mut head).await?;
+    drop(client);
+    let err = connection.await?.unwrap_err();
+    assert_eq!(classify_connection_error(&err), ConnectionError::ClientGone);
 
-        let mut listener = TcpListener::bind(candidateAddresses.first().unwrap()).await?;
-        main_loop!(listener);
+    Ok(())
+}
+
+enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+/// Binds whichever of `--port`, `--socket-path` or `--abstract-socket` was
+/// given.
+async fn listen(config: &Config) -> AResult<Listener> {
+    if let Some(port) = config.port {
+        Ok(Listener::Tcp(listen_tcp(config, port).await?))
     } else if let Some(socketPath) = &config.socketPath {
-        let mut listener = UnixListener::bind(socketPath)?;
-        let removeSocket = Deferred::new(|| match std::fs::remove_file(socketPath) {
-            Ok(_) => {}
-            Err(err) => {
-                eprintln!("couldn't remove server socket {socketPath:?}: {err:#?}");
-            }
-        });
-        main_loop!(listener);
+        let listener = UnixListener::bind(socketPath)
+            .with_context(|| format!("couldn't bind {socketPath:?}"))?;
+        Ok(Listener::Unix(listener))
+    } else if let Some(name) = &config.abstractSocket {
+        Ok(Listener::Unix(bind_abstract(name)?))
     } else {
         unreachable!()
     }
+}
+
+/// Confines the process to the root directory, which then becomes `/`.
+fn enter_chroot(config: &mut Config) -> AResult<()> {
+    use std::os::unix::ffi::OsStrExt;
 
+    let root = config.rootPath.canonicalize()?;
+    let path = std::ffi::CString::new(root.as_os_str().as_bytes())?;
+    if unsafe { libc::chroot(path.as_ptr()) } != 0 {
+        return Err(std::io::Error::last_os_error())
+            .with_context(|| format!("couldn't chroot into {root:?}"));
+    }
+    // the working directory would otherwise still be outside
+    std::env::set_current_dir("/")?;
+    config.rootPath = PathBuf::from("/");
+    log!(LogLevel::Info, "chrooted into {root:?}");
     Ok(())
 }
 
-type ABody = BoxBody<Bytes, anyhow::Error>;
+/// Moves into the root directory, which then becomes `.`. Paths are then
+/// resolved from the directory itself, however its path later changes.
+fn pin_root(config: &mut Config) -> AResult<()> {
+    std::env::set_current_dir(&config.rootPath)
+        .with_context(|| format!("couldn't enter {:?}", config.rootPath))?;
+    log!(LogLevel::Info, "pinned root {:?}", config.rootPath);
+    config.rootPath = PathBuf::from(".");
+    Ok(())
+}
 
-async fn handle_request(req: Request<impl hyper::body::Body>) -> AResult<Response<ABody>> {
-    let config = serverConfig.get().unwrap();
+#[test]
+fn test_pin_root() -> AResult<()> {
+    const rootVar: &str = "CASEPROXY_TEST_PIN_ROOT";
 
-    let reqPath = Path::new(req.uri().path()).strip_prefix(&config.urlPrefix)?;
-    let fullPath = resolve_parents(&config.rootPath.join(reqPath));
-    let file = resolve_path(InsensitivePath(fullPath.clone())).await;
-    match file {
-        Err(err) => Ok(status_response(StatusCode::NOT_FOUND)),
-        Ok(file) => {
-            // this check is technically unnecessary as it is sufficiently handled by prefix
-            // stripping in `find_matching_files`, but just in case that ever changes
-            if !file.starts_with(&config.rootPath) {
-                return Ok(status_response(StatusCode::FORBIDDEN));
+    // the test binary runs this again in a process of its own, as the working
+    // directory is shared with other tests
+    if let Some(root) = std::env::var_os(rootVar) {
+        let root = PathBuf::from(root);
+        let mut config = Config::try_parse_from([
+            "caseproxy",
+            "--root-path",
+            root.join("current").to_str().unwrap(),
+            "--pin-root",
+        ])?;
+        config.normalize();
+        pin_root(&mut config)?;
+        // a new release swapped in, and then the old one's path replaced
+        std::os::unix::fs::symlink("v2", root.join("next"))?;
+        std::fs::rename(root.join("next"), root.join("current"))?;
+        std::fs::rename(root.join("v1"), root.join("old"))?;
+        std::os::unix::fs::symlink("..", root.join("v1"))?;
+
+        let found = InsensitivePath(config.rootPath.join("dir/file.txt"))
+            .find_first_matching_file_with(Some(&config.rootPath), MatchOptions::default())?
+            .ok_or_else(|| anyhow!("not found"))?;
+        assert_eq!(std::fs::read(&found)?, b"v1");
+        let escaped = InsensitivePath(config.rootPath.join("secret.txt"))
+            .find_first_matching_file_with(Some(&config.rootPath), MatchOptions::default())?;
+        assert_eq!(escaped, None);
+        return Ok(());
+    }
+
+    let (root, removeRoot) = make_temp_dir()?;
+    std::fs::create_dir_all(root.join("v1/Dir"))?;
+    std::fs::write(root.join("v1/Dir/File.txt"), "v1")?;
+    std::fs::create_dir_all(root.join("v2/Dir"))?;
+    std::fs::write(root.join("v2/Dir/File.txt"), "v2")?;
+    std::fs::write(root.join("secret.txt"), "secret")?;
+    std::os::unix::fs::symlink("v1", root.join("current"))?;
+
+    let status = std::process::Command::new(std::env::current_exe()?)
+        .args(["--exact", "test_pin_root"])
+        .env(rootVar, &root)
+        .status()?;
+    assert!(status.success(), "pinned test failed: {status}");
+    Ok(())
+}
+
+/// User and group to switch to, see `--user`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct RunAs {
+    uid: libc::uid_t,
+    gid: libc::gid_t,
+}
+
+impl RunAs {
+    /// Looks up `user` and `group` by name, falling back to parsing them as
+    /// numeric IDs. Without a group, that of the user is used.
+    fn resolve(user: &str, group: Option<&str>) -> AResult<Self> {
+        let name = std::ffi::CString::new(user)?;
+        // only called before serving, so the static buffer isn't shared
+        let entry = unsafe { libc::getpwnam(name.as_ptr()) };
+        let (uid, userGid) = match unsafe { entry.as_ref() } {
+            Some(entry) => (entry.pw_uid, Some(entry.pw_gid)),
+            None => {
+                let uid = user.parse().map_err(|_| anyhow!("no such user {user:?}"))?;
+                let entry = unsafe { libc::getpwuid(uid).as_ref() };
+                (uid, entry.map(|entry| entry.pw_gid))
             }
+        };
 
-            if config.sendfile {
-                let file = file.canonicalize()?;
-                let body = Bytes::new();
-                let body = Full::new(body).map_err(|e| match e {}).boxed();
-                let response = Response::builder()
-                    .status(StatusCode::NO_CONTENT)
-                    .header(
-                        "X-Sendfile",
-                        HeaderValue::from_bytes(file.as_os_str().as_encoded_bytes())?,
-                    )
-                    .body(body)?;
-                Ok(response)
-            } else if let Some(nginxUrl) = &config.nginxUrl {
-                let file = file.strip_prefix(&config.rootPath)?;
-                let body = Bytes::new();
-                let body = Full::new(body).map_err(|e| match e {}).boxed();
-                let mut fullUrl = Vec::new();
-                fullUrl.extend(nginxUrl.as_bytes());
-                fullUrl.extend(file.as_os_str().as_encoded_bytes());
-                let response = Response::builder()
-                    .status(StatusCode::NO_CONTENT)
-                    .header("X-Accel-Redirect", HeaderValue::from_bytes(&fullUrl)?)
-                    .body(body)?;
-                Ok(response)
-            } else {
-                let file = tokio::fs::File::open(file).await?;
-                let length = file.metadata().await?.len();
-                let fileStream = ReaderStream::new(file).map_ok(Frame::data);
-                let body = StreamBody::new(fileStream);
-                let body = BodyExt::map_err(body, |e| anyhow!(e)).boxed();
-                let response = Response::builder()
-                    .status(StatusCode::OK)
-                    .header("Content-Length", format!("{length}"))
-                    .body(body)?;
-                Ok(response)
+        let gid = match group {
+            Some(group) => {
+                let name = std::ffi::CString::new(group)?;
+                match unsafe { libc::getgrnam(name.as_ptr()).as_ref() } {
+                    Some(entry) => entry.gr_gid,
+                    None => group
+                        .parse()
+                        .map_err(|_| anyhow!("no such group {group:?}"))?,
+                }
             }
+            None => userGid.ok_or_else(|| {
+                anyhow!("user {user:?} has no group of its own, --group must be given")
+            })?,
+        };
+        Ok(Self { uid, gid })
+    }
+
+    /// Drops all privileges for those of this user and group, for good.
+    fn switch_to(self) -> AResult<()> {
+        let check = |res: libc::c_int, what: &str| match res {
+            0 => Ok(()),
+            _ => Err(std::io::Error::last_os_error()).context(format!("couldn't {what}")),
+        };
+        unsafe {
+            check(
+                libc::setgroups(0, std::ptr::null()),
+                "clear supplementary groups",
+            )?;
+            check(libc::setgid(self.gid), "set group ID")?;
+            check(libc::setuid(self.uid), "set user ID")?;
+        }
+        // regaining root would defeat the point
+        if self.uid != 0 && unsafe { libc::setuid(0) } == 0 {
+            return Err(anyhow!("privileges were not dropped"));
         }
+        Ok(())
     }
 }
 
-async fn resolve_path(path: InsensitivePath) -> AResult<PathBuf> {
-    let config = serverConfig.get().unwrap();
-    let files =
-        tokio::task::spawn_blocking(move || path.find_matching_files(Some(&config.rootPath)))
-            .await??;
-    // TODO: other strategies
-    // TODO: caching
-    Ok(files
-        .into_iter()
-        .next()
-        .ok_or_else(|| anyhow!("not found"))?)
+#[test]
+fn test_resolve_run_as() {
+    let root = RunAs { uid: 0, gid: 0 };
+    assert_eq!(RunAs::resolve("root", None).unwrap(), root);
+    assert_eq!(RunAs::resolve("0", None).unwrap(), root);
+    assert_eq!(RunAs::resolve("root", Some("root")).unwrap(), root);
+    assert_eq!(
+        RunAs::resolve("0", Some("100")).unwrap(),
+        RunAs { uid: 0, gid: 100 }
+    );
+    assert_eq!(
+        RunAs::resolve("4000000", Some("4000001")).unwrap(),
+        RunAs {
+            uid: 4000000,
+            gid: 4000001
+        }
+    );
+    // an unknown ID has no group to default to
+    assert!(RunAs::resolve("4000000", None).is_err());
+    assert!(RunAs::resolve("no such user", None).is_err());
+    assert!(RunAs::resolve("root", Some("no such group")).is_err());
 }
 
-fn status_response(code: StatusCode) -> Response<ABody> {
-    let message = code.canonical_reason().unwrap_or("unknown");
-    let body = Bytes::from_static(message.as_bytes());
-    let body = Full::new(body).map_err(|e| match e {}).boxed();
-    let mut res = Response::new(body);
-    *res.status_mut() = code;
-    res
+/// Removes the `--socket-path` socket once dropped, handing it over to
+/// `runAs` in the meantime. The socket's directory is opened now, so removal
+/// still works from inside `--chroot`.
+fn socket_cleanup(socketPath: &Path, runAs: Option<RunAs>) -> AResult<Deferred<impl FnOnce()>> {
+    use std::os::{fd::AsRawFd, unix::ffi::OsStrExt};
+
+    if let Some(runAs) = runAs {
+        std::os::unix::fs::chown(socketPath, Some(runAs.uid), Some(runAs.gid))
+            .with_context(|| format!("couldn't hand {socketPath:?} over"))?;
+    }
+    let dir = match socketPath.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let dir = std::fs::File::open(dir)?;
+    let name = std::ffi::CString::new(socketPath.file_name().unwrap_or_default().as_bytes())?;
+    let socketPath = socketPath.to_path_buf();
+    Ok(Deferred::new(move || {
+        if unsafe { libc::unlinkat(dir.as_raw_fd(), name.as_ptr(), 0) } != 0 {
+            let err = std::io::Error::last_os_error();
+            eprintln!("couldn't remove server socket {socketPath:?}: {err:#?}");
+        }
+    }))
+}
+
+#[tokio::test]
+async fn test_socket_cleanup() -> AResult<()> {
+    let (root, removeRoot) = make_temp_dir()?;
+    let socketPath = root.join("caseproxy.sock");
+    let listener = UnixListener::bind(&socketPath)?;
+    let cleanup = socket_cleanup(&socketPath, None)?;
+    assert!(socketPath.exists());
+    drop(cleanup);
+    assert!(!socketPath.exists());
+    drop(listener);
+    Ok(())
+}
+
+#[test]
+fn test_chroot() -> AResult<()> {
+    const jailVar: &str = "CASEPROXY_TEST_JAIL";
+
+    // the test binary runs this again in a process of its own, so the jail
+    // doesn't contain the other tests too
+    if let Some(jail) = std::env::var_os(jailVar) {
+        let mut config = Config::try_parse_from([
+            OsStr::new("caseproxy"),
+            OsStr::new("--root-path"),
+            &jail,
+            OsStr::new("--chroot"),
+        ])?;
+        config.normalize();
+        enter_chroot(&mut config)?;
+        RunAs::resolve("65534", Some("65534"))?.switch_to()?;
+        assert_eq!(config.rootPath, Path::new("/"));
+        assert_eq!(std::fs::read("/inside.txt")?, b"inside");
+        assert!(std::fs::metadata("/../outside.txt").is_err());
+        assert_eq!(unsafe { libc::geteuid() }, 65534);
+        return Ok(());
+    }
+
+    if unsafe { libc::geteuid() } != 0 {
+        eprintln!("skipping, chroot needs root");
+        return Ok(());
+    }
+    let (root, removeRoot) = make_temp_dir()?;
+    std::fs::create_dir(root.join("jail"))?;
+    std::fs::write(root.join("jail/inside.txt"), "inside")?;
+    std::fs::write(root.join("outside.txt"), "outside")?;
+
+    let status = std::process::Command::new(std::env::current_exe()?)
+        .args(["--exact", "test_chroot"])
+        .env(jailVar, root.join("jail"))
+        .status()?;
+    assert!(status.success(), "jailed test failed: {status}");
+    Ok(())
+}
+
+/// Resolves `--host` and binds to `port` on it.
+async fn listen_tcp(config: &Config, port: u16) -> AResult<TcpListener> {
+    let host = &format!("{}:{}", config.host, port);
+
+    let candidateAddresses = tokio::net::lookup_host(host)
+        .await
+        .context(format!("invalid host address {host:?}"))?
+        .collect::<Vec<_>>();
+    if candidateAddresses.is_empty() {
+        return Err(anyhow!(
+            "lookup of hostname {host:?} yields zero addresses?!"
+        ));
+    }
+    let Some(address) = select_bind_address(candidateAddresses, config.addressFamily) else {
+        return Err(anyhow!(
+            "lookup of hostname {host:?} yields no addresses of family {:?}",
+            config.addressFamily
+        ));
+    };
+
+    bind_tcp(address, config.listenBacklog, config.reusePort)
+        .with_context(|| format!("couldn't bind {address}"))
+}
+
+/// Picks the address to listen on from the results of a hostname lookup.
+fn select_bind_address(
+    mut candidates: Vec<SocketAddr>,
+    family: AddressFamily,
+) -> Option<SocketAddr> {
+    match family {
+        AddressFamily::Auto => candidates.sort_by_key(|addr| addr.is_ipv6()),
+        AddressFamily::V4 => candidates.retain(SocketAddr::is_ipv4),
+        AddressFamily::V6 => candidates.retain(SocketAddr::is_ipv6),
+    }
+    candidates.first().copied()
+}
+
+#[test]
+fn test_select_bind_address() {
+    let v4: SocketAddr = "127.0.0.1:8080".parse().unwrap();
+    let v6: SocketAddr = "[::1]:8080".parse().unwrap();
+
+    assert_eq!(
+        select_bind_address(vec![v6, v4], AddressFamily::Auto),
+        Some(v4)
+    );
+    assert_eq!(select_bind_address(vec![v6], AddressFamily::Auto), Some(v6));
+    assert_eq!(
+        select_bind_address(vec![v6, v4], AddressFamily::V4),
+        Some(v4)
+    );
+    assert_eq!(select_bind_address(vec![v6], AddressFamily::V4), None);
+    assert_eq!(
+        select_bind_address(vec![v4, v6], AddressFamily::V6),
+        Some(v6)
+    );
+    assert_eq!(select_bind_address(vec![v4], AddressFamily::V6), None);
+    assert_eq!(select_bind_address(vec![], AddressFamily::Auto), None);
+}
+
+type ABody = BoxBody<Bytes, anyhow::Error>;
+
+/// Creates a listener like `TcpListener::bind`, with a configurable backlog.
+fn bind_tcp(address: SocketAddr, backlog: i32, reusePort: bool) -> AResult<TcpListener> {
+    let socket = Socket::new(
+        Domain::for_address(address),
+        Type::STREAM,
+        Some(Protocol::TCP),
+    )?;
+    // as tokio does, so restarts needn't wait for old connections to time out
+    socket.set_reuse_address(true)?;
+    if reusePort {
+        socket.set_reuse_port(true)?;
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&address.into())?;
+    socket.listen(backlog)?;
+    Ok(TcpListener::from_std(socket.into())?)
+}
+
+#[tokio::test]
+async fn test_bind_tcp() -> AResult<()> {
+    let listener = bind_tcp("127.0.0.1:0".parse()?, 1, false)?;
+    let address = listener.local_addr()?;
+    let client = tokio::net::TcpStream::connect(address).await?;
+    let (server, peer) = listener.accept().await?;
+    assert_eq!(peer, client.local_addr()?);
+    assert!(bind_tcp(address, 1, false).is_err());
+
+    let first = bind_tcp("127.0.0.1:0".parse()?, 1, true)?;
+    let address = first.local_addr()?;
+    let second = bind_tcp(address, 1, true)?;
+    assert!(bind_tcp(address, 1, false).is_err());
+    Ok(())
+}
+
+/// Creates a listener on the abstract Unix socket `name`, which goes away
+/// with the last reference to it.
+#[cfg(target_os = "linux")]
+fn bind_abstract(name: &str) -> AResult<UnixListener> {
+    use std::os::linux::net::SocketAddrExt;
+
+    let address = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+    let listener = std::os::unix::net::UnixListener::bind_addr(&address)
+        .with_context(|| format!("couldn't bind abstract socket {name:?}"))?;
+    listener.set_nonblocking(true)?;
+    Ok(UnixListener::from_std(listener)?)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn bind_abstract(name: &str) -> AResult<UnixListener> {
+    Err(anyhow!("abstract sockets are only supported on Linux"))
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn test_bind_abstract() -> AResult<()> {
+    use std::os::linux::net::SocketAddrExt;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let name = format!("caseproxy_test_{}", std::process::id());
+    let listener = bind_abstract(&name)?;
+    // already taken
+    assert!(bind_abstract(&name).is_err());
+
+    let address = std::os::unix::net::SocketAddr::from_abstract_name(&name)?;
+    let client = std::os::unix::net::UnixStream::connect_addr(&address)?;
+    client.set_nonblocking(true)?;
+    let mut client = tokio::net::UnixStream::from_std(client)?;
+    let (mut server, _) = listener.accept().await?;
+    client.write_all(b"ping").await?;
+    let mut buf = [0; 4];
+    server.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"ping");
+
+    // nothing is left behind once closed
+    drop((listener, server, client));
+    bind_abstract(&name)?;
+    Ok(())
+}
+
+trait PeerIp {
+    /// IP address of the connected client, if it has one.
+    fn peer_ip(&self) -> Option<IpAddr>;
+}
+
+impl PeerIp for SocketAddr {
+    fn peer_ip(&self) -> Option<IpAddr> {
+        Some(self.ip())
+    }
+}
+
+impl PeerIp for tokio::net::unix::SocketAddr {
+    fn peer_ip(&self) -> Option<IpAddr> {
+        None
+    }
+}
+
+/// The first value of an `X-Forwarded-*` header, i.e. that added by the proxy
+/// nearest the client.
+fn forwarded_header<'req>(
+    req: &'req Request<impl hyper::body::Body>,
+    name: &str,
+) -> Option<&'req str> {
+    req.headers()
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(',').next())
+        .map(str::trim)
+        .filter(|value| !value.is_empty())
+}
+
+/// Describes who a request came from, for logging. Only when `trustProxy` is
+/// set is the client given by `X-Forwarded-For` believed over the peer address.
+fn client_address(
+    req: &Request<impl hyper::body::Body>,
+    peer: Option<IpAddr>,
+    trustProxy: bool,
+) -> String {
+    let forwarded = forwarded_header(req, "X-Forwarded-For");
+    match (forwarded, peer) {
+        (Some(client), _) if trustProxy => client.to_string(),
+        (_, Some(peer)) => peer.to_string(),
+        (_, None) => "unix".to_string(),
+    }
+}
+
+#[test]
+fn test_client_address() {
+    let peer = Some(IpAddr::from([10, 0, 0, 1]));
+    let plain = Request::new(Full::new(Bytes::new()));
+    let forwarded = Request::builder()
+        .header("X-Forwarded-For", "203.0.113.7, 10.0.0.1")
+        .body(Full::new(Bytes::new()))
+        .unwrap();
+
+    assert_eq!(client_address(&plain, peer, false), "10.0.0.1");
+    assert_eq!(client_address(&plain, peer, true), "10.0.0.1");
+    assert_eq!(client_address(&plain, None, false), "unix");
+    assert_eq!(client_address(&forwarded, peer, false), "10.0.0.1");
+    assert_eq!(client_address(&forwarded, peer, true), "203.0.113.7");
+    assert_eq!(client_address(&forwarded, None, true), "203.0.113.7");
+}
+
+async fn handle_request(
+    state: &'static AppState,
+    peer: Option<IpAddr>,
+    req: Request<impl hyper::body::Body>,
+) -> AResult<Response<ABody>> {
+    let client = client_address(&req, peer, state.config.trustProxy);
+    let requestLine = match req.uri().query() {
+        Some(query) if state.config.logQuery => {
+            format!("{} {}?{query}", req.method(), req.uri().path())
+        }
+        _ => format!("{} {}", req.method(), req.uri().path()),
+    };
+    #[cfg(feature = "record")]
+    let (method, path) = (req.method().to_string(), req.uri().path().to_string());
+    let mut res = match state.admission.admit().await {
+        Ok(_) if state.config.infoToken.is_some() && req.uri().path() == infoPath => {
+            Ok(info_response(&state.config, &req))
+        }
+        Ok(_) if state.config.cacheToken.is_some() && req.uri().path() == cachePath => {
+            Ok(cache_response(state, &req))
+        }
+        Ok(permit) => serve_request(state, req)
+            .await
+            .map(|res| hold_until_sent(res, permit)),
+        Err(()) => Ok(overloaded_response(&state.config)),
+    };
+    #[cfg(feature = "record")]
+    if let Ok(response) = &mut res {
+        record_response(state, &method, &path, response);
+    }
+    if let Ok(response) = &mut res {
+        let config = &state.config;
+        let failed = response.status().is_client_error() || response.status().is_server_error();
+        if !failed || config.headersAlways {
+            for (name, value) in &config.headers {
+                response.headers_mut().insert(name, value.clone());
+            }
+        }
+    }
+    match &res {
+        Ok(response) => log!(
+            LogLevel::Info,
+            "{client} \"{requestLine}\" {}",
+            response.status().as_u16()
+        ),
+        Err(err) => log!(LogLevel::Warn, "{client} \"{requestLine}\" failed: {err:#}"),
+    }
+    res
+}
+
+/// Bounds the work taken on at once: requests in flight, requests queued for a
+/// slot, and files held open while streaming. Anything over a limit is shed
+/// with a `503`.
+struct Admission {
+    inflight: Option<Arc<Semaphore>>,
+    queued: AtomicUsize,
+    maxQueued: Option<usize>,
+    timeout: Duration,
+    openFiles: Option<Arc<Semaphore>>,
+}
+
+impl Admission {
+    fn new(config: &Config) -> Self {
+        let semaphore = |max: Option<u32>| max.map(|max| Arc::new(Semaphore::new(max as usize)));
+        Self {
+            inflight: semaphore(config.maxInflight),
+            queued: AtomicUsize::new(0),
+            maxQueued: config.maxQueued.map(|max| max as usize),
+            timeout: Duration::from_millis(config.admissionTimeout),
+            openFiles: semaphore(config.maxOpenFiles),
+        }
+    }
+
+    /// Waits for a `--max-inflight` slot, if limited, unless the queue for one
+    /// is full. The slot is freed when the permit is dropped.
+    async fn admit(&self) -> Result<Option<OwnedSemaphorePermit>, ()> {
+        let Some(inflight) = &self.inflight else {
+            return Ok(None);
+        };
+        if let Ok(permit) = inflight.clone().try_acquire_owned() {
+            return Ok(Some(permit));
+        }
+        let queued = self.queued.fetch_add(1, AtomicOrdering::Relaxed);
+        let dequeue = Deferred::new(|| {
+            self.queued.fetch_sub(1, AtomicOrdering::Relaxed);
+        });
+        if self.maxQueued.is_some_and(|max| queued >= max) {
+            return Err(());
+        }
+        let permit = tokio::time::timeout(self.timeout, inflight.clone().acquire_owned()).await;
+        drop(dequeue);
+        match permit {
+            Ok(Ok(permit)) => Ok(Some(permit)),
+            _ => Err(()),
+        }
+    }
+
+    /// Takes a `--max-open-files` slot for streaming a file, if limited,
+    /// without waiting for one.
+    fn open_file(&self) -> Result<Option<OwnedSemaphorePermit>, ()> {
+        match &self.openFiles {
+            Some(openFiles) => openFiles
+                .clone()
+                .try_acquire_owned()
+                .map(Some)
+                .map_err(|_| ()),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Keeps `permit` until `res`'s body has been sent or abandoned.
+fn hold_until_sent(res: Response<ABody>, permit: Option<OwnedSemaphorePermit>) -> Response<ABody> {
+    let Some(permit) = permit else {
+        return res;
+    };
+    res.map(|body| {
+        body.map_frame(move |frame| {
+            let _ = &permit;
+            frame
+        })
+        .boxed()
+    })
+}
+
+#[tokio::test]
+async fn test_max_inflight() -> AResult<()> {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let (root, removeRoot) = make_temp_dir()?;
+    std::fs::write(root.join("big.bin"), vec![0; 1 << 20])?;
+    std::fs::write(root.join("small.txt"), "small")?;
+    let state = test_state(
+        &root,
+        &["--max-inflight", "2", "--admission-timeout", "200"],
+    );
+    let inflight = state.admission.inflight.as_ref().unwrap();
+
+    // slow clients stall partway through downloads, holding both slots
+    let mut slowClients = vec![];
+    for _ in 0..2 {
+        let (mut slowClient, server) = tokio::io::duplex(1 << 12);
+        tokio::task::spawn(http1::Builder::new().serve_connection(
+            TokioIo::new(server),
+            service_fn(|req| handle_request(state, None, req)),
+        ));
+        slowClient
+            .write_all(b"GET /big.bin HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await?;
+        let mut head = [0; 12];
+        slowClient.read_exact(&mut head).await?;
+        assert_eq!(&head, b"HTTP/1.1 200");
+        slowClients.push(slowClient);
+    }
+    assert_eq!(inflight.available_permits(), 0);
+
+    // a flood is shed after waiting, without any more being let in
+    let flood: Vec<_> = (0..200)
+        .map(|_| tokio::spawn(test_get(state, "/small.txt")))
+        .collect();
+    for res in flood {
+        let res = res.await??;
+        assert_eq!(res.status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(res.headers["Retry-After"], "1");
+        assert_eq!(inflight.available_permits(), 0);
+    }
+
+    // a request waiting for a slot gets it once one frees up
+    let waiting = tokio::spawn(test_get(state, "/small.txt"));
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    drop(slowClients.pop());
+    let res = waiting.await??;
+    assert_eq!(res.status, StatusCode::OK);
+    assert_eq!(res.body, b"small");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_max_queued() -> AResult<()> {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let (root, removeRoot) = make_temp_dir()?;
+    std::fs::write(root.join("big.bin"), vec![0; 1 << 20])?;
+    std::fs::write(root.join("small.txt"), "small")?;
+    let state = test_state(
+        &root,
+        &[
+            "--max-inflight",
+            "1",
+            "--max-queued",
+            "2",
+            "--admission-timeout",
+            "10000",
+        ],
+    );
+    let admission = &state.admission;
+
+    let (mut slowClient, server) = tokio::io::duplex(1 << 12);
+    tokio::task::spawn(http1::Builder::new().serve_connection(
+        TokioIo::new(server),
+        service_fn(|req| handle_request(state, None, req)),
+    ));
+    slowClient
+        .write_all(b"GET /big.bin HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+        .await?;
+    let mut head = [0; 12];
+    slowClient.read_exact(&mut head).await?;
+    assert_eq!(&head, b"HTTP/1.1 200");
+
+    let queued: Vec<_> = (0..2)
+        .map(|_| tokio::spawn(test_get(state, "/small.txt")))
+        .collect();
+    while admission.queued.load(AtomicOrdering::Relaxed) < 2 {
+        tokio::time::sleep(Duration::from_millis(1)).await;
+    }
+
+    // with the queue full, the rest are shed without waiting out the timeout
+    let start = Instant::now();
+    let flood: Vec<_> = (0..100)
+        .map(|_| tokio::spawn(test_get(state, "/small.txt")))
+        .collect();
+    for res in flood {
+        let res = res.await??;
+        assert_eq!(res.status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(res.headers["Retry-After"], "1");
+    }
+    assert!(start.elapsed() < Duration::from_secs(5));
+    assert_eq!(admission.queued.load(AtomicOrdering::Relaxed), 2);
+
+    drop(slowClient);
+    for res in queued {
+        let res = res.await??;
+        assert_eq!(res.status, StatusCode::OK);
+        assert_eq!(res.body, b"small");
+    }
+    assert_eq!(admission.queued.load(AtomicOrdering::Relaxed), 0);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_pipelining_under_limits() -> AResult<()> {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let (root, removeRoot) = make_temp_dir()?;
+    std::fs::write(root.join("Small.txt"), "small")?;
+    let state = test_state(&root, &["--max-inflight", "1", "--max-open-files", "1"]);
+
+    // hyper answers pipelined requests in order, one at a time, so each
+    // releases its permits once sent rather than blocking the next
+    let (client, server) = tokio::io::duplex(1 << 12);
+    tokio::task::spawn(http1::Builder::new().serve_connection(
+        TokioIo::new(server),
+        service_fn(|req| handle_request(state, None, req)),
+    ));
+    let mut requests = "GET /small.txt HTTP/1.1\r\nHost: localhost\r\n\r\n".repeat(99);
+    requests.push_str("GET /small.txt HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n");
+    let (mut reader, mut writer) = tokio::io::split(client);
+    let write = tokio::spawn(async move { writer.write_all(requests.as_bytes()).await });
+
+    let mut raw = vec![];
+    tokio::time::timeout(Duration::from_secs(10), reader.read_to_end(&mut raw))
+        .await
+        .map_err(|_| anyhow!("pipelined requests deadlocked"))??;
+    write.await??;
+    let raw = String::from_utf8(raw)?;
+    assert_eq!(raw.matches("HTTP/1.1 200 OK").count(), 100);
+    assert_eq!(raw.matches("\r\n\r\nsmall").count(), 100);
+    let admission = &state.admission;
+    assert_eq!(admission.inflight.as_ref().unwrap().available_permits(), 1);
+    assert_eq!(admission.openFiles.as_ref().unwrap().available_permits(), 1);
+    Ok(())
+}
+
+fn parse_header(header: &str) -> AResult<(HeaderName, HeaderValue)> {
+    let (name, value) = header
+        .split_once(':')
+        .ok_or_else(|| anyhow!("expected `Name: Value`"))?;
+    let name = HeaderName::from_bytes(name.trim().as_bytes())
+        .with_context(|| format!("invalid header name {:?}", name.trim()))?;
+    let value = HeaderValue::from_str(value.trim())
+        .with_context(|| format!("invalid header value {:?}", value.trim()))?;
+    Ok((name, value))
+}
+
+#[tokio::test]
+async fn test_custom_headers() -> AResult<()> {
+    let (root, removeRoot) = make_temp_dir()?;
+    std::fs::write(root.join("file.txt"), "file")?;
+    let args = [
+        "--header",
+        "X-Frame-Options: DENY",
+        "--header",
+        "x-content-type-options:nosniff",
+        "--sniff-content",
+        "--header",
+        "Content-Type: text/x-custom",
+    ];
+
+    let state = test_state(&root, &args);
+    let res = test_get(state, "/File.txt").await?;
+    assert_eq!(res.headers["X-Frame-Options"], "DENY");
+    assert_eq!(res.headers["X-Content-Type-Options"], "nosniff");
+    assert_eq!(res.headers[CONTENT_TYPE], "text/x-custom");
+    let res = test_get(state, "/missing").await?;
+    assert!(!res.headers.contains_key("X-Frame-Options"));
+
+    let state = test_state(&root, &[&args[..], &["--headers-always"]].concat());
+    let res = test_get(state, "/missing").await?;
+    assert_eq!(res.status, StatusCode::NOT_FOUND);
+    assert_eq!(res.headers["X-Frame-Options"], "DENY");
+
+    for invalid in [
+        "X-Missing-Colon",
+        "Bad Name: value",
+        "X-Bad-Value: a\nb",
+        ": value",
+    ] {
+        assert!(parse_header(invalid).is_err(), "{invalid:?}");
+    }
+    assert!(Config::try_parse_from(["caseproxy", "--header", "nope"]).is_err());
+
+    Ok(())
+}
+
+/// Tells `--replay` which file `response` served, and records it if enabled.
+#[cfg(feature = "record")]
+fn record_response(
+    state: &'static AppState,
+    method: &str,
+    path: &str,
+    response: &mut Response<ABody>,
+) {
+    let config = &state.config;
+    let file = response
+        .extensions()
+        .get::<ResolvedFile>()
+        .map(|file| file.relative(&config.rootPath));
+    if let Some(file) = &file {
+        if let Ok(value) = HeaderValue::from_str(file) {
+            response
+                .headers_mut()
+                .insert(record::resolvedFileHeader, value);
+        }
+    }
+    if let Some(recorder) = &state.recorder {
+        let status = response.status().as_u16();
+        if let Err(err) = recorder.record(method, path, status, file.as_deref()) {
+            log!(LogLevel::Warn, "failed to record request: {err:#}");
+        }
+    }
+}
+
+#[cfg(feature = "record")]
+#[tokio::test]
+async fn test_record_replay() -> AResult<()> {
+    let (root, removeRoot) = make_temp_dir()?;
+    std::fs::create_dir_all(root.join("tree/Dir"))?;
+    std::fs::write(root.join("tree/Dir/File.txt"), "file")?;
+    std::fs::write(root.join("tree/other.txt"), "other")?;
+    let recordDir = root.join("recording");
+
+    let state = test_state(
+        &root.join("tree"),
+        &["--record", recordDir.to_str().unwrap()],
+    );
+    for path in ["/dir/file.TXT", "/OTHER.txt", "/missing.txt"] {
+        test_get(state, path).await?;
+    }
+    // the server keeps recording while being replayed against
+    let recording = root.join("replay.jsonl");
+    std::fs::copy(recordDir.join(record::recordingName), &recording)?;
+    let recorded = record::read_recording(&recording)?;
+    let summary: Vec<_> = recorded
+        .iter()
+        .map(|entry| (entry.path.as_str(), entry.status, entry.file.as_str()))
+        .collect();
+    assert_eq!(
+        summary,
+        vec![
+            ("/dir/file.TXT", 200, "Dir/File.txt"),
+            ("/OTHER.txt", 200, "other.txt"),
+            ("/missing.txt", 404, ""),
+        ]
+    );
+
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let url = Upstream::parse(&format!("http://{}/", listener.local_addr()?))?;
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let service = service_fn(|req| handle_request(state, None, req));
+            tokio::spawn(http1::Builder::new().serve_connection(TokioIo::new(stream), service));
+        }
+    });
+    assert_eq!(record::replay(&recording, &url).await?, 0);
+
+    // a new case variant now wins, and a missing file appears
+    std::fs::create_dir_all(root.join("tree/DIR"))?;
+    std::fs::write(root.join("tree/DIR/file.txt"), "")?;
+    std::fs::write(root.join("tree/missing.txt"), "")?;
+    assert_eq!(record::replay(&recording, &url).await?, 2);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_query_ignored() -> AResult<()> {
+    let (root, removeRoot) = make_temp_dir()?;
+    std::fs::write(root.join("file.txt"), "file")?;
+    std::fs::write(root.join("other?x=1"), "other")?;
+    let state = test_state(&root, &["--log-query"]);
+
+    let res = test_get(state, "/File.txt?foo=bar").await?;
+    assert_eq!(res.status, StatusCode::OK);
+    assert_eq!(res.body, b"file");
+
+    let res = test_get(state, "/file.txt?/../other%3Fx=1").await?;
+    assert_eq!(res.body, b"file");
+
+    let res = test_get(state, "/other?x=1").await?;
+    assert_eq!(res.status, StatusCode::NOT_FOUND);
+
+    Ok(())
+}
+
+async fn serve_request(
+    state: &'static AppState,
+    req: Request<impl hyper::body::Body>,
+) -> AResult<Response<ABody>> {
+    let config = &state.config;
+
+    if let Some(res) = non_origin_form_response(config, &req) {
+        return Ok(res);
+    }
+    if !config.httpVersions.contains(&req.version()) {
+        return Ok(status_response(
+            config,
+            StatusCode::HTTP_VERSION_NOT_SUPPORTED,
+        ));
+    }
+    let propfind = config.webdav && req.method() == "PROPFIND";
+    if config.webdav {
+        match req.method().as_str() {
+            "OPTIONS" => return Ok(webdav_options_response()),
+            method if !webdav::is_read_only(method) => {
+                let mut res = status_response(config, StatusCode::METHOD_NOT_ALLOWED);
+                res.headers_mut()
+                    .insert(ALLOW, HeaderValue::from_static(webdav::allowedMethods));
+                return Ok(res);
+            }
+            _ => {}
+        }
+    }
+
+    let uriPath = collapse_slashes(req.uri().path());
+    let reqPath = match config.insensitivePrefix {
+        true => strip_url_prefix_insensitive(&uriPath, &config.urlPrefix),
+        false => strip_url_prefix(&uriPath, &config.urlPrefix),
+    };
+    let Some(reqPath) = reqPath else {
+        return Ok(status_response(config, StatusCode::NOT_FOUND));
+    };
+    if reqPath == Path::new("favicon.ico") {
+        if let Some(response) = favicon_response(state) {
+            return Ok(response);
+        }
+    }
+    let Some(reqPath) = decode_request_path(reqPath.as_os_str().as_encoded_bytes()) else {
+        return Ok(status_response(config, StatusCode::BAD_REQUEST));
+    };
+    // `..` stops at the root, as it does in aliases
+    let reqPath = resolve_parents(&Path::new("/").join(reqPath))
+        .strip_prefix("/")?
+        .to_path_buf();
+    let reqPath = resolve_alias(config, reqPath);
+    let fullPath = resolve_parents(&config.rootPath.join(reqPath));
+    let file = resolve_path(state, InsensitivePath(fullPath.clone())).await;
+    match file {
+        Err(err) if err.is::<ResolveTimeout>() => {
+            Ok(status_response(config, StatusCode::GATEWAY_TIMEOUT))
+        }
+        Err(err) if err.is::<AmbiguousMatch>() => {
+            log!(LogLevel::Debug, "{fullPath:?} is ambiguous: {err}");
+            Ok(status_response(config, StatusCode::CONFLICT))
+        }
+        // upstream can't be asked for properties
+        Err(err) if propfind => Ok(status_response(config, StatusCode::NOT_FOUND)),
+        Err(err) => {
+            let mut res = not_found_response(state, &fullPath).await?;
+            if config.failedAtHeader && res.status() == StatusCode::NOT_FOUND {
+                if let Some((failedAt, reason)) = failed_at(state, &fullPath).await? {
+                    log!(LogLevel::Debug, "{fullPath:?} didn't resolve: {reason}");
+                    let failure = match reason {
+                        ResolveError::NoMatch { .. } => "no match",
+                        ResolveError::NotADirectory { .. } => "not a directory",
+                    };
+                    let headers = res.headers_mut();
+                    headers.insert("X-Caseproxy-Failed-At", HeaderValue::from_str(&failedAt)?);
+                    headers.insert("X-Caseproxy-Failure", HeaderValue::from_static(failure));
+                }
+            }
+            Ok(res)
+        }
+        Ok(file) => {
+            // before any redirect gives away how the file is really named
+            if is_forbidden(config, &file) {
+                return Ok(status_response(config, StatusCode::FORBIDDEN));
+            }
+            let file = match &state.resolvedHook {
+                Some(hook) => match hook(&file) {
+                    Some(file) => file,
+                    None => return Ok(status_response(config, StatusCode::NOT_FOUND)),
+                },
+                None => file,
+            };
+            if config.redirectDirs {
+                if let Some(location) =
+                    canonical_dir_location(config, &req, &fullPath, &file).await?
+                {
+                    return Ok(redirect_response(&external_location(
+                        config, &req, &location,
+                    )));
+                }
+            }
+            let file = match directory_index(state, &req, file).await? {
+                IndexTarget::File(file) => file,
+                IndexTarget::Redirect(location) => {
+                    return Ok(redirect_response(&external_location(
+                        config, &req, &location,
+                    )))
+                }
+                IndexTarget::Missing(dir) if config.autoindex => {
+                    return autoindex_response(state, &req, dir).await
+                }
+                IndexTarget::Missing(_) => {
+                    return Ok(status_response(config, StatusCode::NOT_FOUND))
+                }
+            };
+            let mut res = serve_file(state, &req, file.clone()).await?;
+            let file = ResolvedFile(file);
+            if config.resolutionTrailers && res.status() == StatusCode::OK && accepts_trailers(&req)
+            {
+                res = with_resolution_trailer(res, &file.relative(&config.rootPath))?;
+            }
+            res.extensions_mut().insert(file);
+            Ok(res)
+        }
+    }
+}
+
+/// What to serve for a request that resolved to a directory.
+enum IndexTarget {
+    /// The resolved file, or the directory's index file.
+    File(PathBuf),
+    /// The directory, which has no index file.
+    Missing(PathBuf),
+    /// The directory's URL with a trailing slash, which it was asked for
+    /// without, so that relative links in its index resolve within it.
+    Redirect(String),
+}
+
+/// With `--index` or `--autoindex`, what to serve for `file` if it's a
+/// directory. Directories asked for as archives or collections are left to be
+/// served as such.
+async fn directory_index(
+    state: &'static AppState,
+    req: &Request<impl hyper::body::Body>,
+    file: PathBuf,
+) -> AResult<IndexTarget> {
+    let config = &state.config;
+    let wantsDir =
+        (config.allowTar && wants_tar(req)) || (config.webdav && req.method() == "PROPFIND");
+    let indexing = !config.index.is_empty() || config.autoindex;
+    if !indexing || wantsDir || !tokio::fs::metadata(&file).await?.is_dir() {
+        return Ok(IndexTarget::File(file));
+    }
+    if !req.uri().path().ends_with('/') {
+        let mut location = format!("{}/", req.uri().path());
+        if let Some(query) = req.uri().query() {
+            location.push('?');
+            location.push_str(query);
+        }
+        return Ok(IndexTarget::Redirect(location));
+    }
+    tokio::task::spawn_blocking(move || {
+        let options = MatchOptions {
+            index: state.directoryIndex.as_ref(),
+            insensitiveExtOnly: config.insensitiveExtOnly,
+            deaccent: config.deaccent,
+            ..Default::default()
+        };
+        for name in &config.index {
+            let mut candidates = component_candidates(&file, OsStr::new(name), false, options)?;
+            candidates.sort();
+            let found = candidates
+                .into_iter()
+                .map(|candidate| file.join(candidate))
+                .find(|candidate| candidate.is_file());
+            if let Some(found) = found {
+                return Ok(IndexTarget::File(found));
+            }
+        }
+        Ok(IndexTarget::Missing(file))
+    })
+    .await?
+}
+
+#[tokio::test]
+async fn test_directory_index() -> AResult<()> {
+    let (root, removeRoot) = make_temp_dir()?;
+    std::fs::create_dir_all(root.join("Both"))?;
+    std::fs::write(root.join("Both/Index.HTM"), "htm")?;
+    std::fs::write(root.join("Both/INDEX.html"), "html")?;
+    std::fs::create_dir_all(root.join("Fallback"))?;
+    std::fs::write(root.join("Fallback/default.html"), "default")?;
+    std::fs::create_dir_all(root.join("None/index.html"))?;
+    std::fs::write(root.join("None/other.html"), "other")?;
+
+    // tried in order, whatever their case
+    let state = test_state(&root, &["--index", "index.html,index.htm,default.html"]);
+    let res = test_get(state, "/both/").await?;
+    assert_eq!(res.status, StatusCode::OK);
+    assert_eq!(res.body, b"html");
+    let res = test_get(state, "/fallback/").await?;
+    assert_eq!(res.body, b"default");
+    // directories asked for without a trailing slash are sent to it first
+    let res = test_get(state, "/fallback?x=1").await?;
+    assert_eq!(res.status, StatusCode::MOVED_PERMANENTLY);
+    assert_eq!(res.headers[LOCATION], "/fallback/?x=1");
+    assert!(res.body.is_empty());
+    // falling through to a 404, directories named like an index not counting
+    let res = test_get(state, "/none/").await?;
+    assert_eq!(res.status, StatusCode::NOT_FOUND);
+    assert_eq!(test_get(state, "/none/OTHER.html").await?.body, b"other");
+
+    let state = test_state(&root, &["--index", "index.htm,index.html"]);
+    assert_eq!(test_get(state, "/both/").await?.body, b"htm");
+
+    // bare, only `index.html`
+    let state = test_state(&root, &["--index"]);
+    assert_eq!(test_get(state, "/both/").await?.body, b"html");
+    assert_eq!(
+        test_get(state, "/fallback/").await?.status,
+        StatusCode::NOT_FOUND
+    );
+
+    // archives of directories aren't affected
+    let state = test_state(&root, &["--index", "--allow-tar"]);
+    let res = test_get(state, "/both?format=tar").await?;
+    assert_eq!(res.headers[CONTENT_TYPE], "application/x-tar");
+
+    // hidden directories are refused before being redirected
+    std::fs::create_dir_all(root.join(".Hidden"))?;
+    std::fs::write(root.join(".Hidden/index.html"), "hidden")?;
+    let state = test_state(&root, &["--index", "--deny-hidden"]);
+    let res = test_get(state, "/.hidden").await?;
+    assert_eq!(res.status, StatusCode::FORBIDDEN);
+    assert!(!res.headers.contains_key(LOCATION));
+    Ok(())
+}
+
+/// A listing of `dir` for `--autoindex`, validated by the directory's mtime,
+/// which changes whenever entries are added, removed or renamed.
+async fn autoindex_response(
+    state: &'static AppState,
+    req: &Request<impl hyper::body::Body>,
+    dir: PathBuf,
+) -> AResult<Response<ABody>> {
+    let config = &state.config;
+    if config.denyHidden && is_hidden(dir.strip_prefix(&config.rootPath)?) {
+        return Ok(status_response(config, StatusCode::FORBIDDEN));
+    }
+    let metadata = tokio::fs::metadata(&dir).await?;
+    let etag = mtime_etag(&metadata);
+    let modified = metadata.modified().ok();
+    if not_modified(req, &etag, modified) {
+        return Ok(not_modified_response(&etag, modified, false));
+    }
+    let listing = tokio::task::spawn_blocking(move || {
+        autoindex::listing(&config.rootPath, &dir, &config.urlPrefix, config.denyHidden)
+    })
+    .await??;
+    let body = Full::new(Bytes::from(listing))
+        .map_err(|e| match e {})
+        .boxed();
+    let mut response = Response::builder()
+        .header(CONTENT_TYPE, "text/html; charset=utf-8")
+        .header(ETAG, &etag);
+    if let Some(modified) = modified {
+        response = response.header(LAST_MODIFIED, httpdate::fmt_http_date(modified));
+    }
+    Ok(response.body(body)?)
+}
+
+#[tokio::test]
+async fn test_autoindex() -> AResult<()> {
+    let (root, removeRoot) = make_temp_dir()?;
+    std::fs::create_dir_all(root.join("Share/sub dir"))?;
+    std::fs::create_dir_all(root.join("Share/.hidden"))?;
+    std::fs::create_dir_all(root.join("Share/Alpha"))?;
+    std::fs::write(root.join("Share/beta.txt"), "")?;
+    std::fs::write(root.join("Share/ALPHA.txt"), "")?;
+    std::fs::write(root.join("Share/a&b.txt"), "")?;
+    std::fs::create_dir_all(root.join("Site"))?;
+    std::fs::write(root.join("Site/index.html"), "index")?;
+
+    let state = test_state(&root, &["--autoindex", "--index", "--deny-hidden"]);
+    let res = test_get(state, "/share").await?;
+    assert_eq!(res.status, StatusCode::MOVED_PERMANENTLY);
+    assert_eq!(res.headers[LOCATION], "/share/");
+    let res = test_get(state, "/share/").await?;
+    assert_eq!(res.status, StatusCode::OK);
+    assert_eq!(res.headers[CONTENT_TYPE], "text/html; charset=utf-8");
+    let body = String::from_utf8(res.body)?;
+    let links: Vec<_> = body
+        .lines()
+        .filter_map(|line| line.strip_prefix("<li>"))
+        .collect();
+    // directories first, each group ordered case-insensitively
+    assert_eq!(
+        links,
+        [
+            "<a href=\"/\">../</a></li>",
+            "<a href=\"/Share/Alpha/\">Alpha/</a></li>",
+            "<a href=\"/Share/sub%20dir/\">sub dir/</a></li>",
+            "<a href=\"/Share/a%26b.txt\">a&amp;b.txt</a></li>",
+            "<a href=\"/Share/ALPHA.txt\">ALPHA.txt</a></li>",
+            "<a href=\"/Share/beta.txt\">beta.txt</a></li>",
+        ]
+    );
+    // index files are still preferred
+    assert_eq!(test_get(state, "/SITE/").await?.body, b"index");
+
+    // listings are revalidated by the directory's mtime
+    let res = test_get(state, "/share/").await?;
+    let etag = res.headers[ETAG].to_str()?.to_string();
+    assert!(res.headers.contains_key(LAST_MODIFIED));
+    let revalidate = |etag: &str| {
+        format!("GET /share/ HTTP/1.1\r\nHost: localhost\r\nIf-None-Match: {etag}\r\nConnection: close\r\n\r\n")
+    };
+    let res = send_test_request(state, revalidate(&etag).as_bytes()).await?;
+    assert_eq!(res.status, StatusCode::NOT_MODIFIED);
+    assert_eq!(res.headers[ETAG], etag.as_str());
+    std::fs::write(root.join("Share/gamma.txt"), "")?;
+    let modified = SystemTime::now() + Duration::from_secs(10);
+    std::fs::File::open(root.join("Share"))?.set_modified(modified)?;
+    let res = send_test_request(state, revalidate(&etag).as_bytes()).await?;
+    assert_eq!(res.status, StatusCode::OK);
+    assert!(String::from_utf8(res.body)?.contains("gamma.txt"));
+
+    // and directories aren't listed unless asked
+    let state = test_state(&root, &["--index"]);
+    assert_eq!(
+        test_get(state, "/share/").await?.status,
+        StatusCode::NOT_FOUND
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_resolved_hook() -> AResult<()> {
+    let (root, removeRoot) = make_temp_dir()?;
+    std::fs::create_dir_all(root.join("shadow"))?;
+    std::fs::write(root.join("File.txt"), "original")?;
+    std::fs::write(root.join("shadow/File.txt"), "shadow")?;
+    std::fs::write(root.join("Private.txt"), "private")?;
+    std::fs::write(root.join("Other.txt"), "other")?;
+
+    let mut state = AppState::new(Config::try_parse_from([
+        "caseproxy",
+        "--root-path",
+        root.to_str().unwrap(),
+    ])?)?;
+    let shadow = root.join("shadow");
+    state.resolvedHook = Some(Box::new(move |file: &Path| {
+        let name = file.file_name()?;
+        match name.to_str()? {
+            "Private.txt" => None,
+            "File.txt" => Some(shadow.join(name)),
+            _ => Some(file.to_path_buf()),
+        }
+    }));
+    let state: &'static AppState = Box::leak(Box::new(state));
+
+    assert_eq!(test_get(state, "/file.txt").await?.body, b"shadow");
+    assert_eq!(test_get(state, "/other.TXT").await?.body, b"other");
+    let res = test_get(state, "/private.txt").await?;
+    assert_eq!(res.status, StatusCode::NOT_FOUND);
+    Ok(())
+}
+
+/// Answers requests whose target isn't a path, i.e. `CONNECT host:port` and
+/// `OPTIONS *`, which have nothing to resolve.
+fn non_origin_form_response(
+    config: &Config,
+    req: &Request<impl hyper::body::Body>,
+) -> Option<Response<ABody>> {
+    if req.method() == hyper::Method::CONNECT {
+        // not a proxy, whatever the name suggests
+        return Some(status_response(config, StatusCode::BAD_REQUEST));
+    }
+    if req.uri() == "*" {
+        if req.method() != hyper::Method::OPTIONS {
+            return Some(status_response(config, StatusCode::BAD_REQUEST));
+        }
+        let body = Full::new(Bytes::new()).map_err(|e| match e {}).boxed();
+        let mut res = Response::new(body);
+        *res.status_mut() = StatusCode::NO_CONTENT;
+        let allow = match config.webdav {
+            true => webdav::allowedMethods,
+            false => "GET, HEAD, OPTIONS",
+        };
+        res.headers_mut()
+            .insert(ALLOW, HeaderValue::from_static(allow));
+        return Some(res);
+    }
+    (!req.uri().path().starts_with('/')).then(|| status_response(config, StatusCode::BAD_REQUEST))
+}
+
+#[tokio::test]
+async fn test_non_origin_form() -> AResult<()> {
+    let (root, removeRoot) = make_temp_dir()?;
+    std::fs::write(root.join("file.txt"), "file")?;
+    let state = test_state(&root, &[]);
+    let send = |request: &'static str| send_test_request(state, request.as_bytes());
+
+    let res = send(
+        "CONNECT example.com:443 HTTP/1.1\r\nHost: example.com:443\r\nConnection: close\r\n\r\n",
+    )
+    .await?;
+    assert_eq!(res.status, StatusCode::BAD_REQUEST);
+    let res = send("OPTIONS * HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").await?;
+    assert_eq!(res.status, StatusCode::NO_CONTENT);
+    assert_eq!(res.headers[ALLOW], "GET, HEAD, OPTIONS");
+    let res = send("GET * HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").await?;
+    assert_eq!(res.status, StatusCode::BAD_REQUEST);
+
+    // absolute-form is still a path to serve
+    let res = send(
+        "GET http://localhost/FILE.txt HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+    )
+    .await?;
+    assert_eq!(res.body, b"file");
+
+    Ok(())
+}
+
+/// The URL to redirect to for `--redirect-dirs`, if the directories of
+/// `requested` (or `requested` itself, if a directory) are cased differently
+/// than those of the `resolved` file. Paths differing by more than case, as
+/// through `--alias` or a manifest, are left alone.
+async fn canonical_dir_location(
+    config: &Config,
+    req: &Request<impl hyper::body::Body>,
+    requested: &Path,
+    resolved: &Path,
+) -> AResult<Option<String>> {
+    let requested: Vec<_> = requested.strip_prefix(&config.rootPath)?.iter().collect();
+    let resolved: Vec<_> = resolved.strip_prefix(&config.rootPath)?.iter().collect();
+    if requested.len() != resolved.len() || requested.is_empty() {
+        return Ok(None);
+    }
+    let isDir = tokio::fs::metadata(config.rootPath.join(resolved.iter().collect::<PathBuf>()))
+        .await?
+        .is_dir();
+    let dirs = match isDir {
+        true => requested.len(),
+        false => requested.len() - 1,
+    };
+
+    let mut canonical = PathBuf::new();
+    let mut mismatched = false;
+    for (index, (requested, resolved)) in requested.iter().zip(&resolved).enumerate() {
+        if requested == resolved {
+            canonical.push(requested);
+            continue;
+        }
+        let insensitive = |name: &OsStr| InsensitiveOsString(name.to_os_string());
+        if insensitive(requested) != insensitive(resolved) {
+            return Ok(None);
+        }
+        if index < dirs {
+            mismatched = true;
+            canonical.push(resolved);
+        } else {
+            // the file keeps whatever case it was asked for with
+            canonical.push(requested);
+        }
+    }
+    if !mismatched {
+        return Ok(None);
+    }
+
+    let mut location = format!(
+        "{}{}",
+        config.urlPrefix,
+        percent_encode_path(canonical.as_os_str().as_encoded_bytes())
+    );
+    if req.uri().path().ends_with('/') {
+        location.push('/');
+    }
+    if let Some(query) = req.uri().query() {
+        location.push('?');
+        location.push_str(query);
+    }
+    Ok(Some(location))
+}
+
+/// Makes a redirect `location` absolute on the proxy the request came through,
+/// as described by `X-Forwarded-Proto` and `X-Forwarded-Host` under
+/// `--trust-proxy`. Otherwise, or without either header, it's left relative to
+/// whatever the client asked for.
+fn external_location(
+    config: &Config,
+    req: &Request<impl hyper::body::Body>,
+    location: &str,
+) -> String {
+    let proto = forwarded_header(req, "X-Forwarded-Proto");
+    let host = forwarded_header(req, "X-Forwarded-Host");
+    if !config.trustProxy || (proto.is_none() && host.is_none()) {
+        return location.to_string();
+    }
+    let proto = proto.filter(|proto| matches!(*proto, "http" | "https"));
+    let host = host.or_else(|| req.headers().get(HOST)?.to_str().ok());
+    let host = host.filter(|host| {
+        host.bytes()
+            .all(|byte| byte.is_ascii_alphanumeric() || b".-:[]".contains(&byte))
+    });
+    match host {
+        Some(host) => format!("{}://{host}{location}", proto.unwrap_or("http")),
+        None => location.to_string(),
+    }
+}
+
+#[test]
+fn test_external_location() -> AResult<()> {
+    let request = |headers: &[(&str, &str)]| {
+        let mut req = Request::builder().header(HOST, "backend:8080");
+        for (name, value) in headers {
+            req = req.header(*name, *value);
+        }
+        req.body(Full::new(Bytes::new())).unwrap()
+    };
+    let (root, removeRoot) = make_temp_dir()?;
+    let location = |args: &[&str], headers: &[(&str, &str)]| -> AResult<String> {
+        let state = test_state(&root, args);
+        Ok(external_location(&state.config, &request(headers), "/Dir/"))
+    };
+    let proxied = [
+        ("X-Forwarded-Proto", "https"),
+        ("X-Forwarded-Host", "example.com, backend"),
+    ];
+
+    assert_eq!(location(&[], &proxied)?, "/Dir/");
+    assert_eq!(location(&["--trust-proxy"], &[])?, "/Dir/");
+    assert_eq!(
+        location(&["--trust-proxy"], &proxied)?,
+        "https://example.com/Dir/"
+    );
+    assert_eq!(
+        location(&["--trust-proxy"], &[("X-Forwarded-Proto", "https")])?,
+        "https://backend:8080/Dir/"
+    );
+    assert_eq!(
+        location(&["--trust-proxy"], &[("X-Forwarded-Host", "[::1]:8443")])?,
+        "http://[::1]:8443/Dir/"
+    );
+    // nothing which could point the redirect elsewhere
+    assert_eq!(
+        location(&["--trust-proxy"], &[("X-Forwarded-Host", "evil.com/x?")])?,
+        "/Dir/"
+    );
+    assert_eq!(
+        location(
+            &["--trust-proxy"],
+            &[
+                ("X-Forwarded-Proto", "javascript"),
+                ("X-Forwarded-Host", "example.com")
+            ]
+        )?,
+        "http://example.com/Dir/"
+    );
+    Ok(())
+}
+
+fn redirect_response(location: &str) -> Response<ABody> {
+    let body = Full::new(Bytes::new()).map_err(|e| match e {}).boxed();
+    let mut res = Response::new(body);
+    *res.status_mut() = StatusCode::MOVED_PERMANENTLY;
+    if let Ok(location) = HeaderValue::from_str(location) {
+        res.headers_mut().insert(LOCATION, location);
+    }
+    res
+}
+
+#[tokio::test]
+async fn test_redirect_dirs() -> AResult<()> {
+    let (root, removeRoot) = make_temp_dir()?;
+    std::fs::create_dir_all(root.join("Dir/Sub Dir"))?;
+    std::fs::write(root.join("Dir/Sub Dir/File.txt"), "file")?;
+    let state = test_state(&root, &["--redirect-dirs", "--url-prefix", "/files"]);
+
+    for (path, location) in [
+        (
+            "/files/dir/sub%20dir/file.txt",
+            "/files/Dir/Sub%20Dir/file.txt",
+        ),
+        (
+            "/files/Dir/SUB%20DIR/File.txt?x=1",
+            "/files/Dir/Sub%20Dir/File.txt?x=1",
+        ),
+        // a directory itself
+        ("/files/dir", "/files/Dir"),
+        ("/files/dir/sub%20dir/", "/files/Dir/Sub%20Dir/"),
+    ] {
+        let res = test_get(state, path).await?;
+        assert_eq!(res.status, StatusCode::MOVED_PERMANENTLY, "{path}");
+        assert_eq!(res.headers[LOCATION], location, "{path}");
+    }
+
+    // hidden directories are refused without giving away their real case
+    std::fs::create_dir_all(root.join(".Secret"))?;
+    std::fs::write(root.join(".Secret/file.txt"), "secret")?;
+    let hiding = test_state(
+        &root,
+        &["--redirect-dirs", "--url-prefix", "/files", "--deny-hidden"],
+    );
+    let res = test_get(hiding, "/files/.secret/file.txt").await?;
+    assert_eq!(res.status, StatusCode::FORBIDDEN);
+    assert!(!res.headers.contains_key(LOCATION));
+
+    // only the file's case differs
+    let res = test_get(state, "/files/Dir/Sub%20Dir/FILE.TXT").await?;
+    assert_eq!(res.status, StatusCode::OK);
+    assert_eq!(res.body, b"file");
+
+    // absolute on the proxy, when it's trusted
+    let request = "GET /files/dir/ HTTP/1.1\r\nHost: localhost\r\nX-Forwarded-Proto: https\r\nX-Forwarded-Host: example.com\r\nConnection: close\r\n\r\n";
+    let res = send_test_request(state, request.as_bytes()).await?;
+    assert_eq!(res.headers[LOCATION], "/files/Dir/");
+    let trusting = test_state(
+        &root,
+        &["--redirect-dirs", "--url-prefix", "/files", "--trust-proxy"],
+    );
+    let res = send_test_request(trusting, request.as_bytes()).await?;
+    assert_eq!(res.headers[LOCATION], "https://example.com/files/Dir/");
+
+    let state = test_state(&root, &[]);
+    let res = test_get(state, "/dir/sub%20dir/file.txt").await?;
+    assert_eq!(res.status, StatusCode::OK);
+
+    Ok(())
+}
+
+const infoPath: &str = "/_caseproxy/info";
+
+fn info_response(config: &Config, req: &Request<impl hyper::body::Body>) -> Response<ABody> {
+    if !bearer_authorized(req, config.infoToken.as_deref()) {
+        return status_response(config, StatusCode::NOT_FOUND);
+    }
+    json_response(config.info_json())
+}
+
+/// Whether `req` carries `Authorization: Bearer` with `token`.
+fn bearer_authorized(req: &Request<impl hyper::body::Body>, token: Option<&str>) -> bool {
+    req.headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.as_bytes().strip_prefix(b"Bearer "))
+        .zip(token)
+        .is_some_and(|(given, token)| constant_time_eq(given, token.as_bytes()))
+}
+
+fn json_response(json: String) -> Response<ABody> {
+    let body = Full::new(Bytes::from(json)).map_err(|e| match e {}).boxed();
+    Response::builder()
+        .header(CONTENT_TYPE, "application/json")
+        .body(body)
+        .unwrap()
+}
+
+/// Compares without returning early, so timing doesn't reveal how much of a
+/// secret was guessed.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+impl Config {
+    /// Effective settings, keyed by their flag names, for `--info-endpoint`.
+    fn info_json(&self) -> String {
+        let offload = match (self.sendfile, &self.nginxUrl) {
+            (true, _) => json::quote("sendfile"),
+            (false, Some(_)) => json::quote("nginx"),
+            (false, None) => "null".to_string(),
+        };
+        let features = [
+            ("allow-tar", self.allowTar),
+            ("attachments", self.attachments),
+            ("autoindex", self.autoindex),
+            ("compress", self.compress),
+            ("deaccent", self.deaccent),
+            ("deny-hidden", self.denyHidden),
+            ("digest-header", self.digestHeader),
+            ("failed-at-header", self.failedAtHeader),
+            ("favicon-204", self.favicon204),
+            ("index-dirs", self.indexDirs),
+            ("insensitive-ext-only", self.insensitiveExtOnly),
+            ("insensitive-prefix", self.insensitivePrefix),
+            ("manifest-only", self.manifestOnly),
+            ("no-follow-file-links", self.noFollowFileLinks),
+            ("precompressed", self.precompressed),
+            ("prefer-exact", self.preferExact),
+            ("redirect-dirs", self.redirectDirs),
+            ("resolution-trailers", self.resolutionTrailers),
+            ("resolve-cache", self.resolveCache),
+            ("sniff-content", self.sniffContent),
+            ("strip-bom", self.stripBom),
+            ("suggest", self.suggest),
+            ("trim-trailing-dots", self.trimTrailingDots),
+            ("upstream-cache", self.upstreamCache),
+            ("webdav", self.webdav),
+        ];
+        let features: Vec<_> = features
+            .iter()
+            .filter(|(_, enabled)| *enabled)
+            .map(|(name, _)| json::quote(name))
+            .collect();
+        let limit =
+            |limit: Option<u64>| limit.map_or("null".to_string(), |limit| limit.to_string());
+        let path = |path: &Option<PathBuf>| {
+            path.as_ref().map_or("null".to_string(), |path| {
+                json::quote(&path.to_string_lossy())
+            })
+        };
+        let index: Vec<_> = self.index.iter().map(|name| json::quote(name)).collect();
+        format!(
+            concat!(
+                "{{\"version\":{},\"root-path\":{},\"url-prefix\":{},\"offload\":{},",
+                "\"on-ambiguous\":{},\"features\":[{}],\"index\":[{}],\"etag\":{},",
+                "\"mime-types\":{},\"favicon\":{},\"max-open-files\":{},\"max-inflight\":{},",
+                "\"max-queued\":{},\"max-rate\":{},\"resolve-timeout\":{}}}\n"
+            ),
+            json::quote(version),
+            json::quote(&self.rootPath.to_string_lossy()),
+            json::quote(&self.urlPrefix),
+            offload,
+            json::quote(self.onAmbiguous.to_possible_value().unwrap().get_name()),
+            features.join(","),
+            index.join(","),
+            json::quote(self.etag.to_possible_value().unwrap().get_name()),
+            path(&self.mimeTypes),
+            path(&self.favicon),
+            limit(self.maxOpenFiles.map(u64::from)),
+            limit(self.maxInflight.map(u64::from)),
+            limit(self.maxQueued.map(u64::from)),
+            limit(self.maxRate),
+            limit(self.resolveTimeout),
+        )
+    }
+}
+
+#[tokio::test]
+async fn test_info_endpoint() -> AResult<()> {
+    let (root, removeRoot) = make_temp_dir()?;
+    let state = test_state(
+        &root,
+        &[
+            "--info-endpoint",
+            "s3cret",
+            "--url-prefix",
+            "/files",
+            "--allow-tar",
+            "--index",
+            "index.html,default.htm",
+            "--max-inflight",
+            "4",
+        ],
+    );
+    let request = |token: &str| {
+        let authorization = match token {
+            "" => String::new(),
+            token => format!("Authorization: Bearer {token}\r\n"),
+        };
+        format!(
+            "GET {infoPath} HTTP/1.1\r\nHost: localhost\r\n{authorization}Connection: close\r\n\r\n"
+        )
+    };
+    let res = send_test_request(state, request("s3cret").as_bytes()).await?;
+    assert_eq!(res.status, StatusCode::OK);
+    assert_eq!(res.headers[CONTENT_TYPE], "application/json");
+    let info = String::from_utf8(res.body)?;
+    assert!(
+        info.contains(&format!(
+            "\"root-path\":{}",
+            json::quote(&state.config.rootPath.to_string_lossy())
+        )),
+        "{info}"
+    );
+    assert!(
+        info.contains(&format!(
+            "\"url-prefix\":{}",
+            json::quote(&state.config.urlPrefix)
+        )),
+        "{info}"
+    );
+    assert!(
+        info.contains(concat!(
+            "\"offload\":null,\"on-ambiguous\":\"first\",\"features\":[\"allow-tar\"],",
+            "\"index\":[\"index.html\",\"default.htm\"],\"etag\":\"mtime\",",
+            "\"mime-types\":null,\"favicon\":null,"
+        )),
+        "{info}"
+    );
+    assert!(
+        info.contains("\"max-inflight\":4,\"max-queued\":null,\"max-rate\":null"),
+        "{info}"
+    );
+
+    // not even loopback clients are trusted without the token
+    for token in ["", "wrong", "s3cre", "s3crett"] {
+        let res = send_test_request(state, request(token).as_bytes()).await?;
+        assert_eq!(res.status, StatusCode::NOT_FOUND, "{token}");
+    }
+
+    let state = test_state(&root, &[]);
+    let res = test_get(state, "/_caseproxy/info").await?;
+    assert_eq!(res.status, StatusCode::NOT_FOUND);
+    Ok(())
+}
+
+/// Answers `/favicon.ico` without resolving it, if configured to.
+fn favicon_response(state: &'static AppState) -> Option<Response<ABody>> {
+    let config = &state.config;
+    if let Some(favicon) = &state.favicon {
+        let contentType = sniff::sniff(favicon).unwrap_or("image/x-icon");
+        let body = Full::new(favicon.clone()).map_err(|e| match e {}).boxed();
+        let response = streamed_response(Some(favicon.len() as u64))
+            .header(CONTENT_TYPE, contentType)
+            .body(body);
+        return response.ok();
+    }
+    config
+        .favicon204
+        .then(|| status_response(config, StatusCode::NO_CONTENT))
+}
+
+#[tokio::test]
+async fn test_favicon() -> AResult<()> {
+    let (root, removeRoot) = make_temp_dir()?;
+    std::fs::create_dir(root.join("tree"))?;
+    std::fs::write(root.join("tree/favicon.ico"), "on disk")?;
+    std::fs::write(root.join("icon.png"), b"\x89PNG\r\n\x1a\nicon")?;
+
+    let state = test_state(
+        &root.join("tree"),
+        &["--favicon", root.join("icon.png").to_str().unwrap()],
+    );
+    // removing the file shows it's served from memory
+    std::fs::remove_file(root.join("icon.png"))?;
+    let res = test_get(state, "/favicon.ico").await?;
+    assert_eq!(res.status, StatusCode::OK);
+    assert_eq!(res.headers[CONTENT_TYPE], "image/png");
+    assert_eq!(res.body, b"\x89PNG\r\n\x1a\nicon");
+
+    let state = test_state(&root.join("tree"), &["--favicon-204"]);
+    let res = test_get(state, "/favicon.ico").await?;
+    assert_eq!(res.status, StatusCode::NO_CONTENT);
+    assert!(res.body.is_empty());
+    // only the root favicon is special
+    let res = test_get(state, "/dir/favicon.ico").await?;
+    assert_eq!(res.status, StatusCode::NOT_FOUND);
+
+    let state = test_state(&root.join("tree"), &[]);
+    assert_eq!(test_get(state, "/favicon.ico").await?.body, b"on disk");
+
+    Ok(())
+}
+
+/// The file a request resolved to, attached to its response.
+#[derive(Clone, Debug)]
+struct ResolvedFile(PathBuf);
+
+impl ResolvedFile {
+    /// The file relative to `root`, percent-encoded for use in headers.
+    fn relative(&self, root: &Path) -> String {
+        let relative = self.0.strip_prefix(root).unwrap_or(&self.0);
+        percent_encode_path(relative.as_os_str().as_encoded_bytes())
+    }
+}
+
+/// Lowercase, as hyper only sends trailers named exactly as in the `Trailer`
+/// header, and names trailers in lowercase.
+const resolutionTrailer: &str = "x-caseproxy-resolved-path";
+
+/// Whether the client said it can handle trailers, without which hyper won't
+/// send them.
+fn accepts_trailers(req: &Request<impl hyper::body::Body>) -> bool {
+    req.headers()
+        .get_all(TE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|coding| coding.trim().eq_ignore_ascii_case("trailers"))
+}
+
+/// Appends a trailer naming `file` to `res`'s body.
+fn with_resolution_trailer(res: Response<ABody>, file: &str) -> AResult<Response<ABody>> {
+    let (mut parts, body) = res.into_parts();
+    let mut trailers = hyper::HeaderMap::new();
+    trailers.insert(resolutionTrailer, HeaderValue::from_str(file)?);
+    // trailers are only sent with chunked encoding
+    parts.headers.remove(CONTENT_LENGTH);
+    parts
+        .headers
+        .insert(TRAILER, HeaderValue::from_static(resolutionTrailer));
+    let body = body.with_trailers(async { Some(Ok(trailers)) }).boxed();
+    Ok(Response::from_parts(parts, body))
+}
+
+#[tokio::test]
+async fn test_resolution_trailers() -> AResult<()> {
+    let (root, removeRoot) = make_temp_dir()?;
+    std::fs::create_dir(root.join("Dir"))?;
+    std::fs::write(root.join("Dir/File Name.txt"), "contents")?;
+    let state = test_state(&root, &["--resolution-trailers"]);
+
+    let request = |path: &str| {
+        format!(
+            "GET {path} HTTP/1.1\r\nHost: localhost\r\nTE: trailers\r\nConnection: close\r\n\r\n"
+        )
+    };
+    let res = send_test_request(state, request("/dir/file%20name.TXT").as_bytes()).await?;
+    assert_eq!(res.status, StatusCode::OK);
+    assert_eq!(res.headers["Transfer-Encoding"], "chunked");
+    assert_eq!(res.headers[TRAILER], resolutionTrailer);
+    assert!(!res.headers.contains_key(CONTENT_LENGTH));
+    assert_eq!(res.body, b"contents");
+    assert_eq!(res.trailers[resolutionTrailer], "Dir/File%20Name.txt");
+
+    // clients that didn't ask for trailers get the usual response
+    let res = test_get(state, "/dir/file%20name.TXT").await?;
+    assert_eq!(res.headers[CONTENT_LENGTH], "8");
+    assert!(!res.headers.contains_key(TRAILER));
+
+    // nor are errors given one
+    let res = send_test_request(state, request("/dir/missing.txt").as_bytes()).await?;
+    assert_eq!(res.status, StatusCode::NOT_FOUND);
+    assert!(res.trailers.is_empty());
+
+    Ok(())
+}
+
+/// Whether `file` mustn't be served, or its name revealed, being outside the
+/// root or hidden under `--deny-hidden`.
+fn is_forbidden(config: &Config, file: &Path) -> bool {
+    // being outside the root is technically impossible, as it's sufficiently
+    // handled by prefix stripping in `find_matching_files`, but just in case
+    // that ever changes
+    match file.strip_prefix(&config.rootPath) {
+        Ok(relative) => config.denyHidden && is_hidden(relative),
+        Err(_) => true,
+    }
+}
+
+async fn serve_file(
+    state: &'static AppState,
+    req: &Request<impl hyper::body::Body>,
+    file: PathBuf,
+) -> AResult<Response<ABody>> {
+    use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+    let config = &state.config;
+    // checked again for index files
+    if is_forbidden(config, &file) {
+        return Ok(status_response(config, StatusCode::FORBIDDEN));
+    }
+    if config.noFollowFileLinks && tokio::fs::symlink_metadata(&file).await?.is_symlink() {
+        return Ok(status_response(config, StatusCode::FORBIDDEN));
+    }
+
+    if config.allowTar && wants_tar(req) && tokio::fs::metadata(&file).await?.is_dir() {
+        return tar_response(file, config.denyHidden);
+    }
+    if config.webdav && req.method() == "PROPFIND" {
+        return propfind_response(state, req, file).await;
+    }
+
+    if let Some(mut response) = offload_response(config, &file)? {
+        if config.offloadContentLength && req.method() == hyper::Method::HEAD {
+            // with no body to leave out, the length can be given directly
+            let length = tokio::fs::metadata(&file).await?.len();
+            *response.status_mut() = StatusCode::OK;
+            response.headers_mut().insert(CONTENT_LENGTH, length.into());
+        }
+        return Ok(response);
+    }
+
+    let path = file;
+    // a sibling compressed ahead of time is sent in its place
+    let (served, encoding) = match precompressed_sibling(state, req, &path).await? {
+        Some((sibling, encoding)) => (sibling, Some(encoding)),
+        None => (path.clone(), None),
+    };
+    let Ok(permit) = state.admission.open_file() else {
+        return Ok(overloaded_response(config));
+    };
+    let mut file = tokio::fs::File::open(&served).await?;
+    let metadata = file.metadata().await?;
+    let mut length = metadata.len();
+    let contentType = match state.mimeTypes.get(&path) {
+        Some(contentType) => Some(contentType),
+        None if config.sniffContent && path.extension().is_none() => {
+            Some(sniff::sniff_file(&path).await?)
+        }
+        None => None,
+    };
+    let strippedBom = config.stripBom
+        && encoding.is_none()
+        && contentType.is_some_and(|contentType| contentType.starts_with("text/"))
+        && skip_bom(&mut file).await?;
+    if strippedBom {
+        length -= utf8Bom.len() as u64;
+    }
+    // only weak validators are given out, which `If-Range` can't use
+    let range = match req.headers().get(RANGE) {
+        Some(range)
+            if req.method() == hyper::Method::GET && !req.headers().contains_key(IF_RANGE) =>
+        {
+            parse_range(range.to_str().unwrap_or_default(), length)
+        }
+        _ => ByteRange::Full,
+    };
+    let compressible = config.compress
+        && contentType.is_some_and(is_compressible)
+        && !is_incompressible(config, &path);
+    // ranges are of the file as it is, so aren't compressed
+    let compress = compressible
+        && encoding.is_none()
+        && range == ByteRange::Full
+        && accepts_encoding(req, "gzip");
+    let mut etag = match config.etag {
+        ETagMode::Mtime => mtime_etag(&metadata),
+        ETagMode::Content => {
+            let digests = state.digestCache.as_ref().unwrap();
+            let served = served.clone();
+            let digest =
+                tokio::task::spawn_blocking(move || digests.get(&served, HashAlgorithm::Sha256))
+                    .await??;
+            format!("W/\"{}\"", base64(&digest))
+        }
+    };
+    // each encoding of the file is a different representation
+    if let Some(encoding) = compress.then_some("gzip").or(encoding) {
+        etag = format!("{}-{encoding}\"", etag.trim_end_matches('"'));
+    }
+    let modified = metadata.modified().ok();
+    let varies = compressible || config.precompressed;
+    if not_modified(req, &etag, modified) {
+        return Ok(not_modified_response(&etag, modified, varies));
+    }
+    let mut multipartType = None;
+    let (chunks, sentLength) = match &range {
+        ByteRange::Unsatisfiable => {
+            let mut res = status_response(config, StatusCode::RANGE_NOT_SATISFIABLE);
+            res.headers_mut()
+                .insert(CONTENT_RANGE, format!("bytes */{length}").parse()?);
+            return Ok(res);
+        }
+        ByteRange::Multiple(ranges) => {
+            let boundary = multipart_boundary();
+            let skipped = if strippedBom { utf8Bom.len() as u64 } else { 0 };
+            let (sentLength, parts) =
+                multipart_body(file, skipped, ranges, length, contentType, &boundary);
+            multipartType = Some(format!("multipart/byteranges; boundary={boundary}"));
+            (Either::Left(parts), sentLength)
+        }
+        ByteRange::Full | ByteRange::Partial(..) => {
+            let (start, end) = match range {
+                ByteRange::Partial(start, end) => (start, end),
+                _ => (0, length),
+            };
+            if start > 0 {
+                // relative to any skipped byte order mark
+                file.seek(std::io::SeekFrom::Current(start as i64)).await?;
+            }
+            (
+                Either::Right(ReaderStream::new(file.take(end - start))),
+                end - start,
+            )
+        }
+    };
+    let chunks = match compress {
+        true => Either::Left(gzip::compress_stream(chunks)),
+        false => Either::Right(chunks),
+    };
+    let chunks = match config.maxRate {
+        Some(rate) => Either::Left(throttle(chunks, rate)),
+        None => Either::Right(chunks),
+    };
+    // the permit is released once the body is done with
+    let fileStream = chunks.map_ok(move |chunk| {
+        let _ = &permit;
+        Frame::data(chunk)
+    });
+    let body = StreamBody::new(fileStream);
+    let reading = path.clone();
+    // keeps the `io::Error` for `classify_connection_error`
+    let body = BodyExt::map_err(body, move |err| {
+        anyhow::Error::new(err).context(format!("failed reading {reading:?}"))
+    })
+    .boxed();
+    let mut response = streamed_response((!compress).then_some(sentLength))
+        .header(ACCEPT_RANGES, "bytes")
+        .header(ETAG, &etag);
+    match (compress, encoding) {
+        (true, _) => response = response.header(CONTENT_ENCODING, "gzip"),
+        (false, Some(encoding)) => response = response.header(CONTENT_ENCODING, encoding),
+        (false, None) => {}
+    }
+    if varies {
+        response = response.header(VARY, "Accept-Encoding");
+    }
+    if let Some(modified) = modified {
+        response = response.header(LAST_MODIFIED, httpdate::fmt_http_date(modified));
+    }
+    match range {
+        ByteRange::Partial(start, end) => {
+            response = response
+                .status(StatusCode::PARTIAL_CONTENT)
+                .header(CONTENT_RANGE, format!("bytes {start}-{}/{length}", end - 1));
+        }
+        // each part has its own `Content-Range`
+        ByteRange::Multiple(_) => response = response.status(StatusCode::PARTIAL_CONTENT),
+        ByteRange::Full | ByteRange::Unsatisfiable => {}
+    }
+    // parts keep the file's own type
+    if let Some(contentType) = multipartType.as_deref().or(contentType) {
+        response = response.header(CONTENT_TYPE, contentType);
+    }
+    if let (true, Some(name)) = (config.attachments, path.file_name()) {
+        response = response.header(CONTENT_DISPOSITION, disposition::attachment(name));
+    }
+    // digests are of the whole file, so wouldn't match what's sent
+    let sentAsIs = !strippedBom && !compress && encoding.is_none();
+    if let (Some(digests), true, true) = (&state.digestCache, config.digestHeader, sentAsIs) {
+        let digest = tokio::task::spawn_blocking(move || digests.get(&path, HashAlgorithm::Sha256))
+            .await??;
+        response = response.header("Digest", format!("sha-256={}", base64(&digest)));
+    }
+    Ok(response.body(body)?)
+}
+
+const utf8Bom: &[u8] = b"\xef\xbb\xbf";
+
+/// Encodings of precompressed siblings, by extension, in order of preference
+/// among those the client likes equally.
+const precompressedEncodings: &[(&str, &str)] = &[("br", "br"), ("zst", "zstd"), ("gz", "gzip")];
+
+/// With `--precompressed`, a compressed copy of `file` beside it, e.g.
+/// `app.js.gz`, in the encoding the client prefers of those it accepts and
+/// that have one, along with that encoding. Its extension is matched
+/// case-insensitively.
+async fn precompressed_sibling(
+    state: &'static AppState,
+    req: &Request<impl hyper::body::Body>,
+    file: &Path,
+) -> AResult<Option<(PathBuf, &'static str)>> {
+    let config = &state.config;
+    if !config.precompressed {
+        return Ok(None);
+    }
+    let mut accepted: Vec<_> = precompressedEncodings
+        .iter()
+        .map(|encoding| (encoding, encoding_quality(req, encoding.1)))
+        .filter(|(_, q)| *q > 0.0)
+        .collect();
+    // stable, so ties keep the server's preference
+    accepted.sort_by(|(_, left), (_, right)| right.total_cmp(left));
+    let accepted: Vec<_> = accepted.into_iter().map(|(encoding, _)| encoding).collect();
+    let (Some(dir), Some(name), false) = (file.parent(), file.file_name(), accepted.is_empty())
+    else {
+        return Ok(None);
+    };
+    let (dir, name) = (dir.to_path_buf(), name.to_os_string());
+    tokio::task::spawn_blocking(move || {
+        let options = MatchOptions {
+            index: state.directoryIndex.as_ref(),
+            insensitiveExtOnly: config.insensitiveExtOnly,
+            deaccent: config.deaccent,
+            ..Default::default()
+        };
+        for (ext, encoding) in accepted {
+            let mut siblingName = name.clone();
+            siblingName.push(format!(".{ext}"));
+            let mut candidates = component_candidates(&dir, &siblingName, false, options)?;
+            candidates.sort();
+            let found = candidates
+                .into_iter()
+                .map(|candidate| dir.join(candidate))
+                .find(|candidate| {
+                    let metadata = match config.noFollowFileLinks {
+                        true => std::fs::symlink_metadata(candidate),
+                        false => std::fs::metadata(candidate),
+                    };
+                    metadata.is_ok_and(|metadata| metadata.is_file())
+                });
+            if let Some(found) = found {
+                return Ok(Some((found, *encoding)));
+            }
+        }
+        Ok(None)
+    })
+    .await?
+}
+
+#[tokio::test]
+async fn test_precompressed() -> AResult<()> {
+    let (root, removeRoot) = make_temp_dir()?;
+    std::fs::write(root.join("App.js"), "plain")?;
+    std::fs::write(root.join("App.js.GZ"), "gzipped")?;
+    std::fs::write(root.join("app.js.br"), "brotli")?;
+    std::fs::write(root.join("app.js.zst"), "zstd")?;
+    std::fs::write(root.join("style.css"), "plain style")?;
+    std::fs::write(root.join("style.css.gz"), "gzipped style")?;
+    std::fs::write(root.join("only.txt"), "only")?;
+    let state = test_state(&root, &["--precompressed", "--digest-header"]);
+    let request = |path: &str, accept: &str| {
+        format!("GET {path} HTTP/1.1\r\nHost: localhost\r\nAccept-Encoding: {accept}\r\nConnection: close\r\n\r\n")
+    };
+
+    // brotli is preferred, and the original's type kept
+    let res = send_test_request(state, request("/app.JS", "gzip, br").as_bytes()).await?;
+    assert_eq!(res.body, b"brotli");
+    assert_eq!(res.headers[CONTENT_ENCODING], "br");
+    assert_eq!(res.headers[CONTENT_TYPE], "text/javascript");
+    assert_eq!(res.headers[VARY], "Accept-Encoding");
+    assert!(!res.headers.contains_key("Digest"));
+    let res = send_test_request(state, request("/app.js", "gzip").as_bytes()).await?;
+    assert_eq!(res.body, b"gzipped");
+    assert_eq!(res.headers[CONTENT_ENCODING], "gzip");
+    let res = send_test_request(state, request("/STYLE.css", "br;q=0, gzip").as_bytes()).await?;
+    assert_eq!(res.body, b"gzipped style");
+    assert_eq!(res.headers[CONTENT_TYPE], "text/css");
+    // the client's preference comes before the server's
+    let res = send_test_request(state, request("/app.js", "br;q=0.5, gzip").as_bytes()).await?;
+    assert_eq!(res.body, b"gzipped");
+    let res = send_test_request(state, request("/app.js", "gzip;q=0.5, *").as_bytes()).await?;
+    assert_eq!(res.body, b"brotli");
+    let res =
+        send_test_request(state, request("/app.js", "gzip, zstd, br;q=0.9").as_bytes()).await?;
+    assert_eq!(res.body, b"zstd");
+    assert_eq!(res.headers[CONTENT_ENCODING], "zstd");
+
+    for (accept, expected) in [
+        ("br, zstd, gzip", "brotli"),
+        ("gzip, zstd", "zstd"),
+        ("GZIP", "gzipped"),
+        ("gzip;q=0.1, zstd;q=0.2", "zstd"),
+        ("br;q=0.5, zstd;q=0.5, gzip;q=0.5", "brotli"),
+        ("*;q=0.1, gzip", "gzipped"),
+        ("*, br;q=0", "zstd"),
+        ("br;q=2, gzip;q=0.1", "gzipped"),
+        ("deflate", "plain"),
+        ("identity", "plain"),
+        ("*;q=0", "plain"),
+        ("", "plain"),
+    ] {
+        let res = send_test_request(state, request("/app.js", accept).as_bytes()).await?;
+        assert_eq!(res.body, expected.as_bytes(), "{accept}");
+    }
+
+    // each encoding is tagged apart from the others
+    let mut tags = vec![];
+    for accept in ["br", "gzip", "identity"] {
+        let res = send_test_request(state, request("/app.js", accept).as_bytes()).await?;
+        tags.push(res.headers[ETAG].to_str()?.to_string());
+    }
+    let [brotli, gzipped, plain] = &tags[..] else {
+        unreachable!()
+    };
+    assert!(brotli.ends_with("-br\""), "{brotli}");
+    assert!(gzipped.ends_with("-gzip\""), "{gzipped}");
+    assert_ne!(plain, brotli);
+    assert_ne!(plain, gzipped);
+    let revalidate = format!(
+        "GET /app.js HTTP/1.1\r\nHost: localhost\r\nAccept-Encoding: br\r\nIf-None-Match: {brotli}\r\nConnection: close\r\n\r\n"
+    );
+    let res = send_test_request(state, revalidate.as_bytes()).await?;
+    assert_eq!(res.status, StatusCode::NOT_MODIFIED);
+    assert_eq!(res.headers[VARY], "Accept-Encoding");
+
+    // falling back to the original otherwise
+    let res = send_test_request(state, request("/style.css", "br").as_bytes()).await?;
+    assert_eq!(res.body, b"plain style");
+    assert!(!res.headers.contains_key(CONTENT_ENCODING));
+    let res = send_test_request(state, request("/only.txt", "gzip, br").as_bytes()).await?;
+    assert_eq!(res.body, b"only");
+    assert!(!res.headers.contains_key(CONTENT_ENCODING));
+    let res = test_get(state, "/app.js").await?;
+    assert_eq!(res.body, b"plain");
+    assert!(res.headers.contains_key("Digest"));
+
+    let state = test_state(&root, &[]);
+    let res = send_test_request(state, request("/app.js", "gzip, br").as_bytes()).await?;
+    assert_eq!(res.body, b"plain");
+    Ok(())
+}
+
+/// Whether files of `contentType` are worth compressing: text, and text-based
+/// formats. Most binary formats are compressed already.
+fn is_compressible(contentType: &str) -> bool {
+    let essence = contentType.split(';').next().unwrap_or_default().trim();
+    essence.starts_with("text/")
+        || essence.ends_with("+json")
+        || essence.ends_with("+xml")
+        || matches!(
+            essence,
+            "application/json" | "application/javascript" | "application/xml" | "image/svg+xml"
+        )
+}
+
+/// Whether `path` has one of the `--incompressible` extensions.
+fn is_incompressible(config: &Config, path: &Path) -> bool {
+    let Some(ext) = path.extension().and_then(OsStr::to_str) else {
+        return false;
+    };
+    config.incompressible.iter().any(|incompressible| {
+        incompressible
+            .trim_start_matches('.')
+            .eq_ignore_ascii_case(ext)
+    })
+}
+
+/// Whether `Accept-Encoding` allows `encoding`, by name or `*`, with a nonzero
+/// `q`.
+fn accepts_encoding(req: &Request<impl hyper::body::Body>, encoding: &str) -> bool {
+    encoding_quality(req, encoding) > 0.0
+}
+
+/// The `q` `Accept-Encoding` gives `encoding`, by name or else `*`, or 0 if it
+/// isn't listed. Items with a `q` that isn't a number from 0 to 1 are ignored.
+fn encoding_quality(req: &Request<impl hyper::body::Body>, encoding: &str) -> f32 {
+    let Some(header) = req
+        .headers()
+        .get(ACCEPT_ENCODING)
+        .and_then(|header| header.to_str().ok())
+    else {
+        return 0.0;
+    };
+    let mut wildcard = 0.0;
+    for item in header.split(',') {
+        let mut params = item.split(';');
+        let name = params.next().unwrap_or_default().trim();
+        let q = match params.find_map(|param| {
+            let (key, value) = param.split_once('=')?;
+            key.trim().eq_ignore_ascii_case("q").then_some(value)
+        }) {
+            Some(q) => match q.trim().parse::<f32>() {
+                std::result::Result::Ok(q) if (0.0..=1.0).contains(&q) => q,
+                _ => continue,
+            },
+            None => 1.0,
+        };
+        if name.eq_ignore_ascii_case(encoding) {
+            return q;
+        }
+        if name == "*" {
+            wildcard = q;
+        }
+    }
+    wildcard
+}
+
+#[test]
+fn test_accepts_encoding() {
+    let accepts = |header: &str| {
+        let req = Request::builder()
+            .header(ACCEPT_ENCODING, header)
+            .body(http_body_util::Empty::<Bytes>::new())
+            .unwrap();
+        accepts_encoding(&req, "gzip")
+    };
+    assert!(accepts("gzip"));
+    assert!(accepts("br, GZIP;q=0.5"));
+    assert!(accepts("*"));
+    assert!(!accepts("br"));
+    assert!(!accepts("gzip;q=0"));
+    assert!(!accepts("*, gzip;q=0"));
+    assert!(!accepts("identity"));
+
+    let quality = |header: &str, encoding: &str| {
+        let req = Request::builder()
+            .header(ACCEPT_ENCODING, header)
+            .body(http_body_util::Empty::<Bytes>::new())
+            .unwrap();
+        encoding_quality(&req, encoding)
+    };
+    assert_eq!(quality("gzip;q=0.8, br;q=0.3", "gzip"), 0.8);
+    assert_eq!(quality("gzip;q=0.8, br;q=0.3", "br"), 0.3);
+    assert_eq!(quality("gzip, *;q=0.2", "br"), 0.2);
+    assert_eq!(quality("gzip", "br"), 0.0);
+    assert_eq!(quality("gzip; Q=0.4", "gzip"), 0.4);
+    assert_eq!(quality("gzip;q=2, *;q=0.1", "gzip"), 0.1);
+    assert_eq!(quality("gzip;q=high", "gzip"), 0.0);
+    assert_eq!(quality("gzip;q=NaN", "gzip"), 0.0);
+}
+
+#[tokio::test]
+async fn test_compress() -> AResult<()> {
+    let (root, removeRoot) = make_temp_dir()?;
+    let text = "some text that repeats, ".repeat(1000);
+    std::fs::write(root.join("Page.html"), &text)?;
+    std::fs::write(root.join("image.png"), &text)?;
+    let state = test_state(&root, &["--compress", "--digest-header"]);
+    let request = |path: &str, headers: &str| {
+        format!("GET {path} HTTP/1.1\r\nHost: localhost\r\n{headers}Connection: close\r\n\r\n")
+    };
+
+    let gzip = "Accept-Encoding: gzip, br\r\n";
+    let res = send_test_request(state, request("/page.HTML", gzip).as_bytes()).await?;
+    assert_eq!(res.status, StatusCode::OK);
+    assert_eq!(res.headers[CONTENT_ENCODING], "gzip");
+    assert_eq!(res.headers[VARY], "Accept-Encoding");
+    assert!(!res.headers.contains_key(CONTENT_LENGTH));
+    assert!(!res.headers.contains_key("Digest"));
+    assert!(res.body.len() < text.len() / 10, "{}", res.body.len());
+    assert_eq!(gzip::decompress(&res.body)?, text.as_bytes());
+    let compressedTag = res.headers[ETAG].clone();
+
+    // the uncompressed version is tagged differently
+    let res = send_test_request(state, request("/page.html", "").as_bytes()).await?;
+    assert!(!res.headers.contains_key(CONTENT_ENCODING));
+    assert_eq!(res.headers[VARY], "Accept-Encoding");
+    assert_eq!(res.body, text.as_bytes());
+    assert_ne!(res.headers[ETAG], compressedTag);
+    let headers = format!("{gzip}If-None-Match: {}\r\n", compressedTag.to_str()?);
+    let res = send_test_request(state, request("/page.html", &headers).as_bytes()).await?;
+    assert_eq!(res.status, StatusCode::NOT_MODIFIED);
+    assert_eq!(res.headers[VARY], "Accept-Encoding");
+
+    // binary types and ranges are sent as they are
+    let res = send_test_request(state, request("/image.png", gzip).as_bytes()).await?;
+    assert!(!res.headers.contains_key(CONTENT_ENCODING));
+    assert!(!res.headers.contains_key(VARY));
+    let headers = format!("If-None-Match: {}\r\n", res.headers[ETAG].to_str()?);
+    let res = send_test_request(state, request("/image.png", &headers).as_bytes()).await?;
+    assert_eq!(res.status, StatusCode::NOT_MODIFIED);
+    assert!(!res.headers.contains_key(VARY));
+    let headers = format!("{gzip}Range: bytes=0-3\r\n");
+    let res = send_test_request(state, request("/page.html", &headers).as_bytes()).await?;
+    assert_eq!(res.status, StatusCode::PARTIAL_CONTENT);
+    assert!(!res.headers.contains_key(CONTENT_ENCODING));
+    assert_eq!(res.body, b"some");
+
+    // and nothing is compressed unless asked
+    let state = test_state(&root, &[]);
+    let res = send_test_request(state, request("/page.html", gzip).as_bytes()).await?;
+    assert!(!res.headers.contains_key(CONTENT_ENCODING));
+    assert_eq!(res.body, text.as_bytes());
+
+    // nor are files compressed already, whatever their type
+    std::fs::write(root.join("Icon.svgz"), &text)?;
+    std::fs::write(root.join("notes.log"), &text)?;
+    std::fs::write(root.join("types"), "image/svg+xml svgz\ntext/plain log\n")?;
+    let types = root.join("types");
+    let types = types.to_str().unwrap();
+    for (args, path, expected) in [
+        (&[][..], "/icon.svgz", false),
+        (&[], "/NOTES.LOG", true),
+        // the defaults are replaced
+        (&["--incompressible", "LOG,.txt"], "/icon.svgz", true),
+        (&["--incompressible", "LOG,.txt"], "/notes.log", false),
+        (&["--incompressible", ""], "/icon.svgz", true),
+    ] {
+        let state = test_state(
+            &root,
+            &[&["--compress", "--mime-types", types], args].concat(),
+        );
+        let res = send_test_request(state, request(path, gzip).as_bytes()).await?;
+        let compressed = res.headers.contains_key(CONTENT_ENCODING);
+        assert_eq!(compressed, expected, "{args:?} {path}");
+    }
+    Ok(())
+}
+
+/// Weak `ETag` from a file's size and mtime, which change whenever it's
+/// rewritten.
+fn mtime_etag(metadata: &std::fs::Metadata) -> String {
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|modified| modified.duration_since(SystemTime::UNIX_EPOCH).ok())
+        .unwrap_or_default();
+    format!("W/\"{:x}-{:x}\"", metadata.len(), modified.as_nanos())
+}
+
+/// Whether a conditional `GET` or `HEAD` can be answered with `304`, the
+/// client's copy having `etag` or being from after `modified`. `If-None-Match`
+/// takes precedence over `If-Modified-Since`, as it's more precise.
+fn not_modified(
+    req: &Request<impl hyper::body::Body>,
+    etag: &str,
+    modified: Option<SystemTime>,
+) -> bool {
+    if !matches!(*req.method(), hyper::Method::GET | hyper::Method::HEAD) {
+        return false;
+    }
+    let header = |name| {
+        req.headers()
+            .get(name)
+            .and_then(|value: &HeaderValue| value.to_str().ok())
+    };
+    if let Some(tags) = header(IF_NONE_MATCH) {
+        // compared weakly, ignoring any `W/`
+        let etag = etag.trim_start_matches("W/");
+        return tags
+            .split(',')
+            .map(str::trim)
+            .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag);
+    }
+    let since = header(IF_MODIFIED_SINCE).and_then(|since| httpdate::parse_http_date(since).ok());
+    let secs = |time: SystemTime| {
+        time.duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs())
+    };
+    match (since, modified) {
+        // HTTP-dates are to the second, so finer mtimes would always seem newer
+        (Some(since), Some(modified)) => secs(modified) <= secs(since),
+        _ => false,
+    }
+}
+
+/// A `304` for a representation tagged `etag`, which like the `200` it stands
+/// in for says whether it `varies` by `Accept-Encoding`.
+fn not_modified_response(
+    etag: &str,
+    modified: Option<SystemTime>,
+    varies: bool,
+) -> Response<ABody> {
+    let body = Full::new(Bytes::new()).map_err(|e| match e {}).boxed();
+    let mut response = Response::builder()
+        .status(StatusCode::NOT_MODIFIED)
+        .header(ETAG, etag);
+    if varies {
+        response = response.header(VARY, "Accept-Encoding");
+    }
+    if let Some(modified) = modified {
+        response = response.header(LAST_MODIFIED, httpdate::fmt_http_date(modified));
+    }
+    response.body(body).unwrap()
+}
+
+#[tokio::test]
+async fn test_conditional_index() -> AResult<()> {
+    let (root, removeRoot) = make_temp_dir()?;
+    std::fs::create_dir(root.join("Site"))?;
+    let index = root.join("Site/Index.html");
+    std::fs::write(&index, "index")?;
+    let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+    std::fs::File::options()
+        .write(true)
+        .open(&index)?
+        .set_modified(modified)?;
+    let state = test_state(&root, &["--index", "--autoindex"]);
+    let request = |headers: &str| {
+        format!("GET /site/ HTTP/1.1\r\nHost: localhost\r\n{headers}Connection: close\r\n\r\n")
+    };
+
+    // validated by the index file, not its directory
+    let res = test_get(state, "/site/").await?;
+    assert_eq!(res.body, b"index");
+    assert_eq!(res.headers[LAST_MODIFIED], "Tue, 14 Nov 2023 22:13:20 GMT");
+    let etag = res.headers[ETAG].to_str()?.to_string();
+    std::fs::write(root.join("Site/other.html"), "other")?;
+    let conditions = [
+        format!("If-None-Match: {etag}\r\n"),
+        "If-Modified-Since: Tue, 14 Nov 2023 22:13:20 GMT\r\n".into(),
+    ];
+    for headers in &conditions {
+        let res = send_test_request(state, request(headers).as_bytes()).await?;
+        assert_eq!(res.status, StatusCode::NOT_MODIFIED, "{headers}");
+    }
+
+    std::fs::write(&index, "new index")?;
+    for headers in &conditions {
+        let res = send_test_request(state, request(headers).as_bytes()).await?;
+        assert_eq!(res.status, StatusCode::OK, "{headers}");
+        assert_eq!(res.body, b"new index");
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_conditional_get() -> AResult<()> {
+    let (root, removeRoot) = make_temp_dir()?;
+    let path = root.join("File.txt");
+    std::fs::write(&path, "contents")?;
+    let modified = SystemTime::UNIX_EPOCH + Duration::new(1_700_000_000, 500_000_000);
+    std::fs::File::options()
+        .write(true)
+        .open(&path)?
+        .set_modified(modified)?;
+    let state = test_state(&root, &[]);
+    let request = |headers: &str| {
+        format!("GET /file.TXT HTTP/1.1\r\nHost: localhost\r\n{headers}Connection: close\r\n\r\n")
+    };
+
+    let res = test_get(state, "/file.txt").await?;
+    let etag = res.headers[ETAG].to_str()?.to_string();
+    assert!(etag.starts_with("W/\""), "{etag}");
+
+    for (headers, expected) in [
+        (
+            format!("If-None-Match: {etag}\r\n"),
+            StatusCode::NOT_MODIFIED,
+        ),
+        (
+            format!("If-None-Match: \"x\", {etag}\r\n"),
+            StatusCode::NOT_MODIFIED,
+        ),
+        ("If-None-Match: *\r\n".into(), StatusCode::NOT_MODIFIED),
+        ("If-None-Match: W/\"stale\"\r\n".into(), StatusCode::OK),
+        // in the same second as the mtime, despite it being later within it
+        (
+            "If-Modified-Since: Tue, 14 Nov 2023 22:13:20 GMT\r\n".into(),
+            StatusCode::NOT_MODIFIED,
+        ),
+        (
+            "If-Modified-Since: Tue, 14 Nov 2023 22:13:19 GMT\r\n".into(),
+            StatusCode::OK,
+        ),
+        // a mismatched tag overrides the date
+        (
+            "If-None-Match: \"x\"\r\nIf-Modified-Since: Tue, 14 Nov 2023 22:13:20 GMT\r\n".into(),
+            StatusCode::OK,
+        ),
+    ] {
+        let res = send_test_request(state, request(&headers).as_bytes()).await?;
+        assert_eq!(res.status, expected, "{headers}");
+        assert_eq!(res.headers[ETAG], etag.as_str(), "{headers}");
+        match expected {
+            StatusCode::OK => assert_eq!(res.body, b"contents", "{headers}"),
+            _ => assert!(res.body.is_empty(), "{headers}"),
+        }
+    }
+
+    // a client revalidating with the date it was given
+    let res = test_get(state, "/file.txt").await?;
+    let lastModified = res.headers[LAST_MODIFIED].to_str()?.to_string();
+    assert_eq!(lastModified, "Tue, 14 Nov 2023 22:13:20 GMT");
+    let headers = format!("If-Modified-Since: {lastModified}\r\n");
+    let res = send_test_request(state, request(&headers).as_bytes()).await?;
+    assert_eq!(res.status, StatusCode::NOT_MODIFIED);
+    assert_eq!(res.headers[LAST_MODIFIED], lastModified.as_str());
+    let head = "HEAD /FILE.txt HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n";
+    let res = send_test_request(state, head.as_bytes()).await?;
+    assert_eq!(res.headers[LAST_MODIFIED], lastModified.as_str());
+
+    // changes once the file does
+    std::fs::write(&path, "new contents")?;
+    let res = send_test_request(
+        state,
+        request(&format!("If-None-Match: {etag}\r\n")).as_bytes(),
+    )
+    .await?;
+    assert_eq!(res.status, StatusCode::OK);
+    assert_ne!(res.headers[ETAG], etag.as_str());
+
+    // left to the httpd when offloading
+    let state = test_state(&root, &["--sendfile"]);
+    assert!(!test_get(state, "/file.txt")
+        .await?
+        .headers
+        .contains_key(ETAG));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_content_etag() -> AResult<()> {
+    let (root, removeRoot) = make_temp_dir()?;
+    std::fs::write(root.join("a.txt"), "same")?;
+    std::fs::write(root.join("b.txt"), "same")?;
+    std::fs::write(root.join("c.txt"), "different")?;
+    for (name, secs) in [("a.txt", 1_000), ("b.txt", 2_000), ("c.txt", 1_000)] {
+        std::fs::File::options()
+            .write(true)
+            .open(root.join(name))?
+            .set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(secs))?;
+    }
+    async fn etag(state: &'static AppState, path: &str) -> AResult<HeaderValue> {
+        Ok(test_get(state, path).await?.headers[ETAG].clone())
+    }
+
+    // identical content has the same tag whatever its mtime
+    let state = test_state(&root, &["--etag", "content"]);
+    assert_eq!(etag(state, "/A.txt").await?, etag(state, "/B.txt").await?);
+    assert_ne!(etag(state, "/A.txt").await?, etag(state, "/C.txt").await?);
+    assert!(!test_get(state, "/a.txt")
+        .await?
+        .headers
+        .contains_key("Digest"));
+
+    // rebuilding a file unchanged keeps its tag, changing it doesn't
+    let before = etag(state, "/a.txt").await?;
+    std::fs::write(root.join("a.txt"), "same")?;
+    std::fs::File::options()
+        .write(true)
+        .open(root.join("a.txt"))?
+        .set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(3_000))?;
+    assert_eq!(etag(state, "/a.txt").await?, before);
+    std::fs::write(root.join("a.txt"), "changed")?;
+    assert_ne!(etag(state, "/a.txt").await?, before);
+
+    let state = test_state(&root, &[]);
+    assert_ne!(etag(state, "/A.txt").await?, etag(state, "/B.txt").await?);
+    Ok(())
+}
+
+/// What to send of a file in answer to a `Range` header.
+#[derive(Debug, PartialEq, Eq)]
+enum ByteRange {
+    /// The whole file, as the header isn't understood or asks for too many
+    /// ranges.
+    Full,
+    /// Bytes `start..end`.
+    Partial(u64, u64),
+    /// Several `start..end` ranges, in the order asked for, sent as
+    /// `multipart/byteranges`.
+    Multiple(Vec<(u64, u64)>),
+    /// None of it, as the ranges lie past its end.
+    Unsatisfiable,
+}
+
+/// Most ranges answered at once. Requests for more get the whole file, so a
+/// short header can't ask for a response that's mostly part headers.
+const maxRanges: usize = 16;
+
+/// Parses a `Range` header for a file of `length` bytes. Ranges past the end
+/// are left out, and only if that leaves none is it unsatisfiable.
+fn parse_range(header: &str, length: u64) -> ByteRange {
+    let Some(specs) = header.trim().strip_prefix("bytes=") else {
+        return ByteRange::Full;
+    };
+    let mut ranges = vec![];
+    for spec in specs.split(',') {
+        let Some((first, last)) = spec.split_once('-') else {
+            return ByteRange::Full;
+        };
+        let (first, last) = (first.trim(), last.trim());
+        let (start, end) = match (first.parse::<u64>(), last.parse::<u64>()) {
+            (Ok(first), Ok(last)) if first <= last => (first, last.saturating_add(1).min(length)),
+            (Ok(first), Err(_)) if last.is_empty() => (first, length),
+            // the last `suffix` bytes
+            (Err(_), Ok(suffix)) if first.is_empty() => (length.saturating_sub(suffix), length),
+            _ => return ByteRange::Full,
+        };
+        if start < end {
+            ranges.push((start, end));
+        }
+    }
+    match ranges[..] {
+        [] => ByteRange::Unsatisfiable,
+        [(start, end)] => ByteRange::Partial(start, end),
+        _ if ranges.len() > maxRanges => ByteRange::Full,
+        _ => ByteRange::Multiple(ranges),
+    }
+}
+
+/// A boundary for `multipart/byteranges`, random so it's as good as certain
+/// not to turn up in the file.
+fn multipart_boundary() -> String {
+    use std::{
+        collections::hash_map::RandomState,
+        hash::{BuildHasher, Hasher},
+    };
+
+    let random = RandomState::new().build_hasher().finish();
+    format!("caseproxy-{random:016x}")
+}
+
+/// A `multipart/byteranges` body of `ranges` of `file`, which is `length`
+/// bytes long from `skipped` bytes in (past any byte order mark), along with
+/// the body's length.
+fn multipart_body(
+    file: tokio::fs::File,
+    skipped: u64,
+    ranges: &[(u64, u64)],
+    length: u64,
+    contentType: Option<&str>,
+    boundary: &str,
+) -> (u64, impl Stream<Item = std::io::Result<Bytes>>) {
+    use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+    const chunkSize: u64 = 64 * 1024;
+
+    let mut parts = VecDeque::new();
+    let mut bodyLength = 0;
+    for (index, &(start, end)) in ranges.iter().enumerate() {
+        // the line break before a delimiter belongs to it, not the part
+        let mut head = match index {
+            0 => format!("--{boundary}\r\n"),
+            _ => format!("\r\n--{boundary}\r\n"),
+        };
+        if let Some(contentType) = contentType {
+            head.push_str(&format!("Content-Type: {contentType}\r\n"));
+        }
+        head.push_str(&format!(
+            "Content-Range: bytes {start}-{}/{length}\r\n\r\n",
+            end - 1
+        ));
+        bodyLength += head.len() as u64 + (end - start);
+        parts.push_back((Bytes::from(head), start, end));
+    }
+    let closing = Bytes::from(format!("\r\n--{boundary}--\r\n"));
+    bodyLength += closing.len() as u64;
+
+    // the part being sent, as the range of it still to read
+    let current: Option<(u64, u64)> = None;
+    let body = futures_util::stream::try_unfold(
+        (file, parts, current, Some(closing)),
+        move |(mut file, mut parts, current, mut closing)| async move {
+            if let Some((start, end)) = current.filter(|(start, end)| start < end) {
+                let mut chunk = vec![0; (end - start).min(chunkSize) as usize];
+                file.read_exact(&mut chunk).await?;
+                let current = Some((start + chunk.len() as u64, end));
+                return Ok(Some((Bytes::from(chunk), (file, parts, current, closing))));
+            }
+            if let Some((head, start, end)) = parts.pop_front() {
+                file.seek(std::io::SeekFrom::Start(skipped + start)).await?;
+                return Ok(Some((head, (file, parts, Some((start, end)), closing))));
+            }
+            let closing = closing.take();
+            Ok(closing.map(|closing| (closing, (file, parts, None, None))))
+        },
+    );
+    (bodyLength, body)
+}
+
+#[test]
+fn test_parse_range() {
+    assert_eq!(parse_range("bytes=0-9", 100), ByteRange::Partial(0, 10));
+    assert_eq!(
+        parse_range("bytes=90-200", 100),
+        ByteRange::Partial(90, 100)
+    );
+    assert_eq!(parse_range("bytes=50-", 100), ByteRange::Partial(50, 100));
+    assert_eq!(parse_range("bytes=-10", 100), ByteRange::Partial(90, 100));
+    assert_eq!(parse_range("bytes=-200", 100), ByteRange::Partial(0, 100));
+    assert_eq!(parse_range("bytes=100-", 100), ByteRange::Unsatisfiable);
+    assert_eq!(parse_range("bytes=-0", 100), ByteRange::Unsatisfiable);
+    assert_eq!(parse_range("bytes=0-", 0), ByteRange::Unsatisfiable);
+    assert_eq!(
+        parse_range("bytes=0-1, 5-6", 100),
+        ByteRange::Multiple(vec![(0, 2), (5, 7)])
+    );
+    assert_eq!(
+        parse_range("bytes=-10,0-0", 100),
+        ByteRange::Multiple(vec![(90, 100), (0, 1)])
+    );
+    assert_eq!(parse_range("bytes=0-1,200-", 100), ByteRange::Partial(0, 2));
+    assert_eq!(
+        parse_range("bytes=200-,300-", 100),
+        ByteRange::Unsatisfiable
+    );
+    assert_eq!(parse_range("bytes=0-1,x", 100), ByteRange::Full);
+    let many = vec!["0-0"; maxRanges + 1].join(",");
+    assert_eq!(parse_range(&format!("bytes={many}"), 100), ByteRange::Full);
+    assert_eq!(parse_range("bytes=9-0", 100), ByteRange::Full);
+    assert_eq!(parse_range("bytes=x-y", 100), ByteRange::Full);
+    assert_eq!(parse_range("lines=0-9", 100), ByteRange::Full);
+}
+
+#[tokio::test]
+async fn test_range() -> AResult<()> {
+    let (root, removeRoot) = make_temp_dir()?;
+    std::fs::write(root.join("File.txt"), "0123456789")?;
+    let state = test_state(&root, &[]);
+    let request = |headers: &str| {
+        format!("GET /file.TXT HTTP/1.1\r\nHost: localhost\r\n{headers}Connection: close\r\n\r\n")
+    };
+
+    let res = test_get(state, "/file.txt").await?;
+    assert_eq!(res.status, StatusCode::OK);
+    assert_eq!(res.headers[ACCEPT_RANGES], "bytes");
+
+    let res = send_test_request(state, request("Range: bytes=2-5\r\n").as_bytes()).await?;
+    assert_eq!(res.status, StatusCode::PARTIAL_CONTENT);
+    assert_eq!(res.headers[CONTENT_RANGE], "bytes 2-5/10");
+    assert_eq!(res.headers[CONTENT_LENGTH], "4");
+    assert_eq!(res.body, b"2345");
+
+    let res = send_test_request(state, request("Range: bytes=-3\r\n").as_bytes()).await?;
+    assert_eq!(res.status, StatusCode::PARTIAL_CONTENT);
+    assert_eq!(res.headers[CONTENT_RANGE], "bytes 7-9/10");
+    assert_eq!(res.body, b"789");
+
+    let res = send_test_request(state, request("Range: bytes=10-\r\n").as_bytes()).await?;
+    assert_eq!(res.status, StatusCode::RANGE_NOT_SATISFIABLE);
+    assert_eq!(res.headers[CONTENT_RANGE], "bytes */10");
+
+    // several ranges are sent as parts, each with its own `Content-Range`
+    let multipart = |res: &TestResponse| -> AResult<String> {
+        let contentType = res.headers[CONTENT_TYPE].to_str()?;
+        let boundary = contentType
+            .strip_prefix("multipart/byteranges; boundary=")
+            .ok_or_else(|| anyhow!("not multipart: {contentType}"))?;
+        assert_eq!(
+            res.headers[CONTENT_LENGTH].to_str()?,
+            res.body.len().to_string()
+        );
+        Ok(String::from_utf8(res.body.clone())?.replace(boundary, "BOUNDARY"))
+    };
+    let res = send_test_request(state, request("Range: bytes=0-1,-3\r\n").as_bytes()).await?;
+    assert_eq!(res.status, StatusCode::PARTIAL_CONTENT);
+    assert!(!res.headers.contains_key(CONTENT_RANGE));
+    assert_eq!(
+        multipart(&res)?,
+        concat!(
+            "--BOUNDARY\r\nContent-Type: text/plain\r\nContent-Range: bytes 0-1/10\r\n\r\n",
+            "01",
+            "\r\n--BOUNDARY\r\nContent-Type: text/plain\r\nContent-Range: bytes 7-9/10\r\n\r\n",
+            "789",
+            "\r\n--BOUNDARY--\r\n",
+        )
+    );
+
+    // of the file past any stripped byte order mark
+    std::fs::write(root.join("Bom.txt"), b"\xef\xbb\xbfabcdef")?;
+    let state = test_state(&root, &["--strip-bom"]);
+    let bomRequest = "GET /bom.txt HTTP/1.1\r\nHost: localhost\r\nRange: bytes=1-2,4-\r\nConnection: close\r\n\r\n";
+    let res = send_test_request(state, bomRequest.as_bytes()).await?;
+    assert_eq!(
+        multipart(&res)?,
+        concat!(
+            "--BOUNDARY\r\nContent-Type: text/plain\r\nContent-Range: bytes 1-2/6\r\n\r\n",
+            "bc",
+            "\r\n--BOUNDARY\r\nContent-Type: text/plain\r\nContent-Range: bytes 4-5/6\r\n\r\n",
+            "ef",
+            "\r\n--BOUNDARY--\r\n",
+        )
+    );
+
+    // too many ranges, or one conditional on a validator, get the whole file
+    let state = test_state(&root, &[]);
+    let tooMany = format!("Range: bytes={}\r\n", vec!["0-0"; maxRanges + 1].join(","));
+    for headers in [tooMany.as_str(), "Range: bytes=2-5\r\nIf-Range: \"x\"\r\n"] {
+        let res = send_test_request(state, request(headers).as_bytes()).await?;
+        assert_eq!(res.status, StatusCode::OK, "{headers}");
+        assert_eq!(res.body, b"0123456789", "{headers}");
+    }
+    Ok(())
+}
+
+/// Delays `chunks` so they're passed on at `rate` bytes per second on average,
+/// allowing a burst of one chunk.
+fn throttle<E>(
+    chunks: impl Stream<Item = Result<Bytes, E>>,
+    rate: u64,
+) -> impl Stream<Item = Result<Bytes, E>> {
+    use futures_util::StreamExt;
+
+    let start = tokio::time::Instant::now();
+    futures_util::stream::unfold(
+        (Box::pin(chunks), 0),
+        move |(mut chunks, sent)| async move {
+            let due = start + Duration::from_secs_f64(sent as f64 / rate as f64);
+            tokio::time::sleep_until(due).await;
+            let chunk = chunks.next().await?;
+            let sent = sent + chunk.as_ref().map_or(0, |chunk| chunk.len() as u64);
+            Some((chunk, (chunks, sent)))
+        },
+    )
+}
+
+#[tokio::test]
+async fn test_max_rate() -> AResult<()> {
+    let (root, removeRoot) = make_temp_dir()?;
+    let data = vec![7; 64 * 1024];
+    std::fs::write(root.join("file.bin"), &data)?;
+
+    let state = test_state(&root, &["--max-rate", &(128 * 1024).to_string()]);
+    let started = std::time::Instant::now();
+    let res = test_get(state, "/FILE.bin").await?;
+    let elapsed = started.elapsed();
+    assert_eq!(res.body, data);
+    // half a second, less the last chunk which isn't waited on
+    assert!(
+        elapsed >= Duration::from_millis(400) && elapsed < Duration::from_secs(2),
+        "took {elapsed:?}"
+    );
+
+    let state = test_state(&root, &[]);
+    let started = std::time::Instant::now();
+    assert_eq!(test_get(state, "/file.bin").await?.body, data);
+    assert!(started.elapsed() < Duration::from_millis(400));
+    Ok(())
+}
+
+/// Hands `file` off to the httpd in front, if configured to with `--sendfile`
+/// or `--nginx`.
+fn offload_response(config: &Config, file: &Path) -> AResult<Option<Response<ABody>>> {
+    if config.sendfile {
+        let file = file.canonicalize()?;
+        let body = Bytes::new();
+        let body = Full::new(body).map_err(|e| match e {}).boxed();
+        let response = Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .header(
+                "X-Sendfile",
+                HeaderValue::from_bytes(file.as_os_str().as_encoded_bytes())?,
+            )
+            .body(body)?;
+        Ok(Some(response))
+    } else if let Some(nginxUrl) = &config.nginxUrl {
+        let file = file.strip_prefix(&config.rootPath)?;
+        let body = Bytes::new();
+        let body = Full::new(body).map_err(|e| match e {}).boxed();
+        let mut fullUrl = Vec::new();
+        fullUrl.extend(nginxUrl.as_bytes());
+        fullUrl.extend(file.as_os_str().as_encoded_bytes());
+        let response = Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .header("X-Accel-Redirect", HeaderValue::from_bytes(&fullUrl)?)
+            .body(body)?;
+        Ok(Some(response))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Moves past a UTF-8 byte order mark at the start of `file`, if it has one,
+/// otherwise leaving it at the start.
+async fn skip_bom(file: &mut tokio::fs::File) -> AResult<bool> {
+    use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+    let mut start = Vec::with_capacity(utf8Bom.len());
+    (&mut *file)
+        .take(utf8Bom.len() as u64)
+        .read_to_end(&mut start)
+        .await?;
+    let found = start == utf8Bom;
+    if !found {
+        file.rewind().await?;
+    }
+    Ok(found)
+}
+
+#[tokio::test]
+async fn test_strip_bom() -> AResult<()> {
+    let (root, removeRoot) = make_temp_dir()?;
+    std::fs::write(root.join("bom"), b"\xef\xbb\xbfhello")?;
+    std::fs::write(root.join("plain"), b"hello")?;
+    std::fs::write(root.join("bom.bin"), b"\xef\xbb\xbfhello")?;
+    let state = test_state(
+        &root,
+        &["--sniff-content", "--strip-bom", "--digest-header"],
+    );
+
+    let res = test_get(state, "/BOM").await?;
+    assert_eq!(res.headers[CONTENT_TYPE], "text/plain; charset=utf-8");
+    assert_eq!(res.headers[CONTENT_LENGTH], "5");
+    assert_eq!(res.body, b"hello");
+    assert!(!res.headers.contains_key("Digest"));
+
+    let res = test_get(state, "/plain").await?;
+    assert_eq!(res.headers[CONTENT_LENGTH], "5");
+    assert_eq!(res.body, b"hello");
+    assert!(res.headers.contains_key("Digest"));
+
+    // not known to be text
+    let res = test_get(state, "/bom.bin").await?;
+    assert_eq!(res.headers[CONTENT_LENGTH], "8");
+    assert_eq!(res.body, b"\xef\xbb\xbfhello");
+
+    // and left alone unless asked
+    let state = test_state(&root, &["--sniff-content"]);
+    let res = test_get(state, "/bom").await?;
+    assert_eq!(res.headers[CONTENT_LENGTH], "8");
+    assert_eq!(res.body, b"\xef\xbb\xbfhello");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_attachments() -> AResult<()> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let (root, removeRoot) = make_temp_dir()?;
+    // decomposed, as macOS stores names
+    std::fs::write(root.join("Cafe\u{301}.txt"), "coffee")?;
+    std::fs::write(root.join(OsStr::from_bytes(b"Caf\xe9.bin")), "latin-1")?;
+    std::fs::create_dir(root.join("Docs"))?;
+    std::fs::write(root.join("Docs/a.txt"), "")?;
+
+    let state = test_state(&root, &["--attachments", "--allow-tar"]);
+    let res = test_get(state, "/CAFE%CC%81.TXT").await?;
+    assert_eq!(res.body, b"coffee");
+    assert_eq!(
+        res.headers[CONTENT_DISPOSITION],
+        "attachment; filename=\"Cafe.txt\"; filename*=UTF-8''Caf%C3%A9.txt"
+    );
+    let res = test_get(state, "/caf%E9.BIN").await?;
+    assert_eq!(
+        res.headers[CONTENT_DISPOSITION],
+        "attachment; filename=\"Caf_.bin\""
+    );
+    let res = test_get(state, "/docs?format=tar").await?;
+    assert_eq!(
+        res.headers[CONTENT_DISPOSITION],
+        "attachment; filename=\"Docs.tar\""
+    );
+
+    let state = test_state(&root, &[]);
+    let res = test_get(state, "/cafe%CC%81.txt").await?;
+    assert!(!res.headers.contains_key(CONTENT_DISPOSITION));
+    Ok(())
+}
+
+/// Starts a `200 OK` response with a streamed body, declaring its length only
+/// if known upfront. Otherwise hyper sends the body with chunked encoding, or
+/// to HTTP/1.0 clients, ends it by closing the connection.
+fn streamed_response(length: Option<u64>) -> hyper::http::response::Builder {
+    let response = Response::builder().status(StatusCode::OK);
+    match length {
+        Some(length) => response.header("Content-Length", format!("{length}")),
+        None => response,
+    }
+}
+
+#[tokio::test]
+async fn test_unknown_length_chunked() -> AResult<()> {
+    let (root, removeRoot) = make_temp_dir()?;
+    std::fs::create_dir(root.join("Dir"))?;
+    // several times hyper's write buffer, so it goes out in many chunks
+    let data: Vec<u8> = (0..1 << 20).map(|i: u32| (i % 251) as u8).collect();
+    std::fs::write(root.join("Dir/data.bin"), &data)?;
+    let state = test_state(&root, &["--allow-tar"]);
+
+    let res = test_get(state, "/dir?format=tar").await?;
+    assert_eq!(res.status, StatusCode::OK);
+    assert!(!res.headers.contains_key("Content-Length"));
+    assert_eq!(res.headers["Transfer-Encoding"], "chunked");
+    // headers, then the data padded to a block, then the end of archive marker
+    assert_eq!(res.body.len(), 512 + 512 + data.len() + 1024);
+    assert_eq!(&res.body[1024..1024 + data.len()], data);
+
+    // HTTP/1.0 has no chunked encoding, so the body ends with the connection
+    let res = send_test_request(state, b"GET /dir?format=tar HTTP/1.0\r\n\r\n").await?;
+    assert_eq!(res.status, StatusCode::OK);
+    assert!(!res.headers.contains_key("Content-Length"));
+    assert!(!res.headers.contains_key("Transfer-Encoding"));
+    assert_eq!(&res.body[1024..1024 + data.len()], data);
+
+    Ok(())
+}
+
+fn overloaded_response(config: &Config) -> Response<ABody> {
+    let mut res = status_response(config, StatusCode::SERVICE_UNAVAILABLE);
+    res.headers_mut()
+        .insert("Retry-After", HeaderValue::from_static("1"));
+    res
+}
+
+#[tokio::test]
+async fn test_max_open_files() -> AResult<()> {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let (root, removeRoot) = make_temp_dir()?;
+    std::fs::write(root.join("big.bin"), vec![0; 1 << 20])?;
+    std::fs::write(root.join("small.txt"), "small")?;
+    let state = test_state(&root, &["--max-open-files", "1"]);
+
+    // a slow client stalls partway through the download, holding its file open
+    let (mut slowClient, server) = tokio::io::duplex(1 << 12);
+    tokio::task::spawn(http1::Builder::new().serve_connection(
+        TokioIo::new(server),
+        service_fn(|req| handle_request(state, None, req)),
+    ));
+    slowClient
+        .write_all(b"GET /big.bin HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+        .await?;
+    let mut head = [0; 12];
+    slowClient.read_exact(&mut head).await?;
+    assert_eq!(&head, b"HTTP/1.1 200");
+
+    let res = test_get(state, "/small.txt").await?;
+    assert_eq!(res.status, StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(res.headers["Retry-After"], "1");
+
+    drop(slowClient);
+    // the connection task notices the client is gone asynchronously
+    let mut attempts = 0;
+    let res = loop {
+        let res = test_get(state, "/small.txt").await?;
+        attempts += 1;
+        if res.status != StatusCode::SERVICE_UNAVAILABLE || attempts == 50 {
+            break res;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    };
+    assert_eq!(res.status, StatusCode::OK);
+    assert_eq!(res.body, b"small");
+
+    Ok(())
+}
+
+/// Merges runs of `/` in a request path, as most servers do. Otherwise a
+/// leading `//` would make the path absolute once joined onto the root.
+fn collapse_slashes(path: &str) -> std::borrow::Cow<'_, str> {
+    if !path.contains("//") {
+        return path.into();
+    }
+    let mut res = String::with_capacity(path.len());
+    for char in path.chars() {
+        if !(char == '/' && res.ends_with('/')) {
+            res.push(char);
+        }
+    }
+    res.into()
+}
+
+#[test]
+fn test_collapse_slashes() {
+    assert_eq!(collapse_slashes("/dir/file.txt"), "/dir/file.txt");
+    assert_eq!(collapse_slashes("//file.txt"), "/file.txt");
+    assert_eq!(
+        collapse_slashes("/files//sub///file.txt"),
+        "/files/sub/file.txt"
+    );
+    assert_eq!(collapse_slashes("/dir//"), "/dir/");
+    assert_eq!(collapse_slashes("///"), "/");
+}
+
+#[tokio::test]
+async fn test_repeated_slashes() -> AResult<()> {
+    let (root, removeRoot) = make_temp_dir()?;
+    std::fs::create_dir(root.join("Sub"))?;
+    std::fs::write(root.join("Sub/File.txt"), "sub")?;
+    std::fs::write(root.join("top.txt"), "top")?;
+    let state = test_state(&root, &["--url-prefix", "/files/"]);
+
+    for path in [
+        "/files/sub/file.txt",
+        "/files//sub/file.txt",
+        "/files//sub///file.txt",
+        "//files/sub//file.txt",
+    ] {
+        let res = test_get(state, path).await?;
+        assert_eq!(res.status, StatusCode::OK, "{path}");
+        assert_eq!(res.body, b"sub", "{path}");
+    }
+    // would otherwise be /top.txt from the filesystem root
+    let res = test_get(state, "/files//top.txt").await?;
+    assert_eq!(res.body, b"top");
+    Ok(())
+}
+
+/// Strips the (normalized) `prefix` from a request path, treating the prefix
+/// without its trailing slash as a request for the root.
+fn strip_url_prefix<'a>(path: &'a str, prefix: &str) -> Option<&'a Path> {
+    let relative = match path.strip_prefix(prefix) {
+        Some(relative) => relative,
+        None if path == prefix.trim_end_matches('/') => "",
+        None => return None,
+    };
+    Some(Path::new(relative))
+}
+
+#[test]
+fn test_strip_url_prefix() {
+    let strip = |path| strip_url_prefix(path, "/files/").map(Path::to_path_buf);
+    assert_eq!(strip("/files"), Some(PathBuf::new()));
+    assert_eq!(strip("/files/"), Some(PathBuf::new()));
+    assert_eq!(strip("/files/foo"), Some(PathBuf::from("foo")));
+    assert_eq!(strip("/files/foo/bar"), Some(PathBuf::from("foo/bar")));
+    assert_eq!(strip("/filesfoo"), None);
+    assert_eq!(strip("/other/files"), None);
+    assert_eq!(strip("/"), None);
+
+    assert_eq!(strip_url_prefix("/", "/"), Some(Path::new("")));
+    assert_eq!(strip_url_prefix("/foo", "/"), Some(Path::new("foo")));
+}
+
+/// Like `strip_url_prefix`, but comparing the prefix as paths are resolved.
+fn strip_url_prefix_insensitive<'a>(path: &'a str, prefix: &str) -> Option<&'a Path> {
+    let matches = |part: &str, prefix: &str| {
+        InsensitiveOsString(part.into()) == InsensitiveOsString(prefix.into())
+    };
+    // the prefix always ends in a slash, so can only end after one
+    for (index, _) in path.match_indices('/') {
+        if matches(&path[..=index], prefix) {
+            return Some(Path::new(&path[index + 1..]));
+        }
+    }
+    matches(path, prefix.trim_end_matches('/')).then(|| Path::new(""))
+}
+
+#[test]
+fn test_strip_url_prefix_insensitive() {
+    let strip = |path| strip_url_prefix_insensitive(path, "/files/").map(Path::to_path_buf);
+    assert_eq!(strip("/Files"), Some(PathBuf::new()));
+    assert_eq!(strip("/FILES/"), Some(PathBuf::new()));
+    assert_eq!(strip("/fiLes/Foo/bar"), Some(PathBuf::from("Foo/bar")));
+    assert_eq!(strip("/FilesFoo"), None);
+    assert_eq!(strip("/other/files/foo"), None);
+    assert_eq!(strip("/"), None);
+
+    // lowercasing changes the length of some
+    let strip = |path| strip_url_prefix_insensitive(path, "/i\u{307}/").map(Path::to_path_buf);
+    assert_eq!(strip("/\u{130}/foo"), Some(PathBuf::from("foo")));
+    assert_eq!(
+        strip_url_prefix_insensitive("/Foo", "/"),
+        Some(Path::new("Foo"))
+    );
+}
+
+#[tokio::test]
+async fn test_insensitive_prefix() -> AResult<()> {
+    let (root, removeRoot) = make_temp_dir()?;
+    std::fs::write(root.join("foo.txt"), "foo")?;
+
+    let state = test_state(&root, &["--url-prefix", "/Files/", "--insensitive-prefix"]);
+    for path in ["/files/FOO.txt", "/FILES/foo.txt", "/Files/foo.txt"] {
+        assert_eq!(test_get(state, path).await?.body, b"foo", "{path}");
+    }
+
+    let state = test_state(&root, &["--url-prefix", "/Files/"]);
+    let res = test_get(state, "/files/foo.txt").await?;
+    assert_eq!(res.status, StatusCode::NOT_FOUND);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_bare_prefix() -> AResult<()> {
+    let (root, removeRoot) = make_temp_dir()?;
+    std::fs::write(root.join("foo.txt"), "foo")?;
+    let state = test_state(&root, &["--url-prefix", "/files/", "--allow-tar"]);
+
+    let res = test_get(state, "/files/Foo.txt").await?;
+    assert_eq!(res.status, StatusCode::OK);
+    assert_eq!(res.body, b"foo");
+
+    // both resolve to the root directory itself
+    for path in ["/files?format=tar", "/files/?format=tar"] {
+        let res = test_get(state, path).await?;
+        assert_eq!(res.status, StatusCode::OK, "{path}");
+        assert_eq!(res.headers[CONTENT_TYPE], "application/x-tar", "{path}");
+    }
+
+    for path in ["/filesfoo.txt", "/foo.txt", "/"] {
+        let res = test_get(state, path).await?;
+        assert_eq!(res.status, StatusCode::NOT_FOUND, "{path}");
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_empty_file() -> AResult<()> {
+    let (root, removeRoot) = make_temp_dir()?;
+    std::fs::write(root.join("empty.txt"), "")?;
+    let state = test_state(&root, &[]);
+
+    let res = test_get(state, "/Empty.TXT").await?;
+    assert_eq!(res.status, StatusCode::OK);
+    assert_eq!(res.headers["Content-Length"], "0");
+    assert!(res.body.is_empty());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_offload_range() -> AResult<()> {
+    let (root, removeRoot) = make_temp_dir()?;
+    std::fs::write(root.join("file.txt"), "0123456789")?;
+
+    // the httpd serves ranges from the original request, so offload responses
+    // must be identical with or without one, and not claim a length of their own
+    for (args, header) in [
+        (&["--sendfile"][..], "X-Sendfile"),
+        (&["--nginx", "/_caseproxied/"][..], "X-Accel-Redirect"),
+    ] {
+        let state = test_state(&root, args);
+        let mut full = test_get(state, "/FILE.txt").await?;
+        let request = "GET /FILE.txt HTTP/1.1\r\nHost: localhost\r\nRange: bytes=2-5\r\nIf-Range: \"x\"\r\nConnection: close\r\n\r\n";
+        let mut ranged = send_test_request(state, request.as_bytes()).await?;
+        full.headers.remove("Date");
+        ranged.headers.remove("Date");
+
+        assert_eq!(ranged.status, StatusCode::NO_CONTENT, "{header}");
+        assert_eq!(ranged.headers, full.headers, "{header}");
+        assert!(ranged.body.is_empty(), "{header}");
+        assert!(ranged.headers[header].to_str()?.ends_with("file.txt"));
+        assert!(!ranged.headers.contains_key("Content-Range"));
+        assert!(ranged
+            .headers
+            .get("Content-Length")
+            .is_none_or(|length| length == "0"));
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_offload_content_length() -> AResult<()> {
+    let (root, removeRoot) = make_temp_dir()?;
+    std::fs::write(root.join("file.txt"), "0123456789")?;
+    let head = "HEAD /FILE.txt HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n";
+
+    for (args, header) in [
+        (&["--sendfile"][..], "X-Sendfile"),
+        (&["--nginx", "/_caseproxied/"][..], "X-Accel-Redirect"),
+    ] {
+        let state = test_state(&root, &[args, &["--offload-content-length"]].concat());
+        let res = send_test_request(state, head.as_bytes()).await?;
+        assert_eq!(res.status, StatusCode::OK, "{header}");
+        assert_eq!(res.headers[CONTENT_LENGTH], "10", "{header}");
+        assert!(res.headers.contains_key(header));
+        assert!(res.body.is_empty());
+
+        // a GET is still left to the httpd entirely
+        let res = test_get(state, "/FILE.txt").await?;
+        assert_eq!(res.status, StatusCode::NO_CONTENT, "{header}");
+
+        let state = test_state(&root, args);
+        let res = send_test_request(state, head.as_bytes()).await?;
+        assert_eq!(res.status, StatusCode::NO_CONTENT, "{header}");
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_expect_continue() -> AResult<()> {
+    let (root, removeRoot) = make_temp_dir()?;
+    std::fs::write(root.join("file.txt"), "file")?;
+    let state = test_state(&root, &[]);
+
+    // request bodies are never read, so no `100 Continue` is sent and the
+    // response doesn't wait on a body the client is holding back
+    for request in [
+        "GET /File.txt HTTP/1.1\r\nHost: localhost\r\nExpect: 100-continue\r\nConnection: close\r\n\r\n",
+        "GET /File.txt HTTP/1.1\r\nHost: localhost\r\nExpect: 100-continue\r\nContent-Length: 5\r\nConnection: close\r\n\r\n",
+    ] {
+        let res = send_test_request(state, request.as_bytes()).await?;
+        assert_eq!(res.status, StatusCode::OK);
+        assert_eq!(res.body, b"file");
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_sniff_content() -> AResult<()> {
+    let (root, removeRoot) = make_temp_dir()?;
+    std::fs::write(root.join("image"), b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR")?;
+    std::fs::write(root.join("document"), b"%PDF-1.7\n%\xe2\xe3\xcf\xd3\n")?;
+    std::fs::write(root.join("unknown"), "plain")?;
+    std::fs::write(root.join("image.dat"), b"\x89PNG\r\n\x1a\n")?;
+
+    let state = test_state(&root, &["--sniff-content"]);
+    for (path, expected) in [
+        ("/Image", Some("image/png")),
+        ("/DOCUMENT", Some("application/pdf")),
+        ("/unknown", Some(sniff::defaultContentType)),
+        // only files without an extension are sniffed
+        ("/image.dat", None),
+    ] {
+        let res = test_get(state, path).await?;
+        assert_eq!(
+            res.headers
+                .get(CONTENT_TYPE)
+                .map(HeaderValue::to_str)
+                .transpose()?,
+            expected,
+            "{path}"
+        );
+    }
+
+    let state = test_state(&root, &[]);
+    assert!(!test_get(state, "/image")
+        .await?
+        .headers
+        .contains_key(CONTENT_TYPE));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_content_type_by_extension() -> AResult<()> {
+    let (root, removeRoot) = make_temp_dir()?;
+    std::fs::write(root.join("Index.HTML"), "<!DOCTYPE html>")?;
+    std::fs::write(root.join("notes.txt"), "notes")?;
+    std::fs::write(root.join("data.cst"), "custom")?;
+    std::fs::write(root.join("image"), b"\x89PNG\r\n\x1a\n")?;
+    let typesFile = root.join("mime.types");
+    std::fs::write(
+        &typesFile,
+        "# overrides\ntext/x-custom cst\ntext/x-notes TXT\n",
+    )?;
+
+    let contentType = |res: &TestResponse| res.headers.get(CONTENT_TYPE).cloned();
+    let state = test_state(&root, &["--sniff-content"]);
+    let res = test_get(state, "/index.html").await?;
+    assert_eq!(contentType(&res).unwrap(), "text/html");
+    assert_eq!(
+        contentType(&test_get(state, "/notes.txt").await?).unwrap(),
+        "text/plain"
+    );
+    assert_eq!(contentType(&test_get(state, "/data.cst").await?), None);
+    // files without an extension are still sniffed
+    assert_eq!(
+        contentType(&test_get(state, "/image").await?).unwrap(),
+        "image/png"
+    );
+
+    let typesFile = typesFile.to_str().unwrap();
+    let state = test_state(&root, &["--mime-types", typesFile]);
+    assert_eq!(
+        contentType(&test_get(state, "/DATA.cst").await?).unwrap(),
+        "text/x-custom"
+    );
+    assert_eq!(
+        contentType(&test_get(state, "/notes.txt").await?).unwrap(),
+        "text/x-notes"
+    );
+    assert_eq!(
+        contentType(&test_get(state, "/index.html").await?).unwrap(),
+        "text/html"
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_digest_header() -> AResult<()> {
+    let (root, removeRoot) = make_temp_dir()?;
+    let path = root.join("file.txt");
+    std::fs::write(&path, "abc")?;
+    let state = test_state(&root, &["--digest-header"]);
+    let abcDigest = "sha-256=ungWv48Bz+pBQUDeXa4iI7ADYaOWF3qctBD/YfIAFa0=";
+
+    let res = test_get(state, "/File.txt").await?;
+    assert_eq!(res.headers["Digest"], abcDigest);
+
+    // same size and mtime, so the cached digest is reused
+    let modified = std::fs::metadata(&path)?.modified()?;
+    std::fs::write(&path, "xyz")?;
+    std::fs::File::options()
+        .write(true)
+        .open(&path)?
+        .set_modified(modified)?;
+    let res = test_get(state, "/File.txt").await?;
+    assert_eq!(res.body, b"xyz");
+    assert_eq!(res.headers["Digest"], abcDigest);
+
+    std::fs::write(&path, "")?;
+    let res = test_get(state, "/File.txt").await?;
+    assert_eq!(
+        res.headers["Digest"],
+        "sha-256=47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU="
+    );
+
+    let state = test_state(&root, &[]);
+    assert!(!test_get(state, "/File.txt")
+        .await?
+        .headers
+        .contains_key("Digest"));
+
+    Ok(())
+}
+
+fn webdav_options_response() -> Response<ABody> {
+    let body = Full::new(Bytes::new()).map_err(|e| match e {}).boxed();
+    let mut res = Response::new(body);
+    res.headers_mut()
+        .insert(ALLOW, HeaderValue::from_static(webdav::allowedMethods));
+    // class 1: no locking, which a read-only share has no need for
+    res.headers_mut()
+        .insert("DAV", HeaderValue::from_static("1"));
+    res
+}
+
+async fn propfind_response(
+    state: &'static AppState,
+    req: &Request<impl hyper::body::Body>,
+    file: PathBuf,
+) -> AResult<Response<ABody>> {
+    let config = &state.config;
+    let depth = req
+        .headers()
+        .get("Depth")
+        .and_then(|depth| depth.to_str().ok())
+        .unwrap_or("infinity")
+        .to_string();
+    let body = tokio::task::spawn_blocking(move || {
+        webdav::propfind(
+            &config.rootPath,
+            &file,
+            &config.urlPrefix,
+            &depth,
+            config.denyHidden,
+        )
+    })
+    .await??;
+    let body = Full::new(Bytes::from(body)).map_err(|e| match e {}).boxed();
+    let response = Response::builder()
+        .status(StatusCode::MULTI_STATUS)
+        .header(CONTENT_TYPE, "application/xml; charset=utf-8")
+        .body(body)?;
+    Ok(response)
+}
+
+#[tokio::test]
+async fn test_webdav() -> AResult<()> {
+    let (root, removeRoot) = make_temp_dir()?;
+    std::fs::create_dir_all(root.join("Dir/Sub"))?;
+    std::fs::write(root.join("Dir/File & Co.txt"), "hello")?;
+    std::fs::write(root.join("Dir/.hidden"), "")?;
+    let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+    std::fs::File::open(root.join("Dir/File & Co.txt"))?.set_modified(modified)?;
+    std::fs::File::open(root.join("Dir/Sub"))?.set_modified(modified)?;
+    std::fs::File::open(root.join("Dir"))?.set_modified(modified)?;
+    let state = test_state(
+        &root,
+        &["--webdav", "--deny-hidden", "--url-prefix", "/dav"],
+    );
+    let propfind = |path: &str, depth: &str| {
+        let request = format!(
+            "PROPFIND {path} HTTP/1.1\r\nHost: localhost\r\nDepth: {depth}\r\nConnection: close\r\n\r\n"
+        );
+        async move { send_test_request(state, request.as_bytes()).await }
+    };
+    let date = "Tue, 14 Nov 2023 22:13:20 GMT";
+
+    let res = propfind("/dav/dir", "1").await?;
+    assert_eq!(res.status, StatusCode::MULTI_STATUS);
+    assert_eq!(res.headers[CONTENT_TYPE], "application/xml; charset=utf-8");
+    let expected = format!(
+        r#"<?xml version="1.0" encoding="utf-8"?>
+<D:multistatus xmlns:D="DAV:">
+<D:response>
+<D:href>/dav/Dir/</D:href>
+<D:propstat>
+<D:prop>
+<D:displayname>Dir</D:displayname>
+<D:resourcetype><D:collection/></D:resourcetype>
+<D:getlastmodified>{date}</D:getlastmodified>
+</D:prop>
+<D:status>HTTP/1.1 200 OK</D:status>
+</D:propstat>
+</D:response>
+<D:response>
+<D:href>/dav/Dir/File%20%26%20Co.txt</D:href>
+<D:propstat>
+<D:prop>
+<D:displayname>File &amp; Co.txt</D:displayname>
+<D:resourcetype/>
+<D:getcontentlength>5</D:getcontentlength>
+<D:getlastmodified>{date}</D:getlastmodified>
+</D:prop>
+<D:status>HTTP/1.1 200 OK</D:status>
+</D:propstat>
+</D:response>
+<D:response>
+<D:href>/dav/Dir/Sub/</D:href>
+<D:propstat>
+<D:prop>
+<D:displayname>Sub</D:displayname>
+<D:resourcetype><D:collection/></D:resourcetype>
+<D:getlastmodified>{date}</D:getlastmodified>
+</D:prop>
+<D:status>HTTP/1.1 200 OK</D:status>
+</D:propstat>
+</D:response>
+</D:multistatus>
+"#
+    );
+    assert_eq!(String::from_utf8(res.body)?, expected);
+
+    // only the directory itself
+    let res = propfind("/dav/DIR/", "0").await?;
+    assert_eq!(
+        String::from_utf8(res.body)?.matches("<D:response>").count(),
+        1
+    );
+    let res = propfind("/dav/dir/file%20&%20co.TXT", "1").await?;
+    assert_eq!(res.status, StatusCode::MULTI_STATUS);
+    assert!(String::from_utf8(res.body)?.contains("<D:getcontentlength>5<"));
+    assert_eq!(
+        propfind("/dav/missing", "1").await?.status,
+        StatusCode::NOT_FOUND
+    );
+
+    let res = send_test_request(
+        state,
+        b"OPTIONS /dav/ HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+    )
+    .await?;
+    assert_eq!(res.headers["DAV"], "1");
+    for method in ["PUT", "DELETE", "MKCOL", "MOVE", "LOCK", "POST"] {
+        let request = format!(
+            "{method} /dav/dir/file%20&%20co.txt HTTP/1.1\r\nHost: localhost\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+        );
+        let res = send_test_request(state, request.as_bytes()).await?;
+        assert_eq!(res.status, StatusCode::METHOD_NOT_ALLOWED, "{method}");
+        assert_eq!(res.headers[ALLOW], webdav::allowedMethods);
+    }
+    assert_eq!(
+        test_get(state, "/dav/dir/file%20&%20co.txt").await?.body,
+        b"hello"
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_no_follow_file_links() -> AResult<()> {
+    let (root, removeRoot) = make_temp_dir()?;
+    std::fs::create_dir(root.join("Real"))?;
+    std::fs::write(root.join("Real/File.txt"), "real")?;
+    std::os::unix::fs::symlink("File.txt", root.join("Real/Link.txt"))?;
+
+    let state = test_state(&root, &[]);
+    assert_eq!(test_get(state, "/real/link.txt").await?.body, b"real");
+
+    let state = test_state(&root, &["--no-follow-file-links"]);
+    let res = test_get(state, "/real/link.txt").await?;
+    assert_eq!(res.status, StatusCode::FORBIDDEN);
+    assert_eq!(test_get(state, "/real/file.txt").await?.body, b"real");
+
+    Ok(())
+}
+
+/// Whether any component of `path` (relative to the root) is a dotfile, other
+/// than a leading `.well-known` which must stay reachable for e.g. ACME challenges.
+fn is_hidden(path: &Path) -> bool {
+    let mut components = path.components().peekable();
+    if components
+        .peek()
+        .is_some_and(|first| first.as_os_str() == ".well-known")
+    {
+        components.next();
+    }
+    components.any(|component| component.as_os_str().as_encoded_bytes().starts_with(b"."))
+}
+
+#[tokio::test]
+async fn test_deny_hidden() -> AResult<()> {
+    let (root, removeRoot) = make_temp_dir()?;
+    std::fs::create_dir_all(root.join(".well-known/acme-challenge"))?;
+    std::fs::create_dir_all(root.join(".git"))?;
+    std::fs::create_dir_all(root.join("dir"))?;
+    std::fs::write(root.join(".well-known/acme-challenge/token"), "token")?;
+    std::fs::write(root.join(".well-known/.secret"), "")?;
+    std::fs::write(root.join(".git/config"), "")?;
+    std::fs::write(root.join("dir/.env"), "")?;
+    std::fs::write(root.join("dir/visible.txt"), "")?;
+
+    let state = test_state(&root, &["--deny-hidden", "--allow-tar"]);
+    let res = test_get(state, "/.well-known/acme-challenge/token").await?;
+    assert_eq!(res.status, StatusCode::OK);
+    assert_eq!(res.body, b"token");
+    for path in [
+        "/.well-known/.secret",
+        "/.git/config",
+        "/dir/.env",
+        "/dir/.ENV",
+    ] {
+        assert_eq!(test_get(state, path).await?.status, StatusCode::FORBIDDEN);
+    }
+    assert_eq!(
+        test_get(state, "/dir/visible.txt").await?.status,
+        StatusCode::OK
+    );
+
+    let res = test_get(state, "/dir?format=tar").await?;
+    let listing = String::from_utf8_lossy(&res.body);
+    assert!(listing.contains("dir/visible.txt"));
+    assert!(!listing.contains(".env"));
+
+    let state = test_state(&root, &[]);
+    assert_eq!(test_get(state, "/dir/.env").await?.status, StatusCode::OK);
+
+    Ok(())
+}
+
+/// Whether the client asked for a directory to be sent as a tar archive.
+fn wants_tar(req: &Request<impl hyper::body::Body>) -> bool {
+    let accepted = req
+        .headers()
+        .get_all(ACCEPT)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|mediaRange| mediaRange.split(';').next())
+        .any(|mediaType| mediaType.trim().eq_ignore_ascii_case("application/x-tar"));
+    let queried = req
+        .uri()
+        .query()
+        .is_some_and(|query| query.split('&').any(|param| param == "format=tar"));
+    accepted || queried
+}
+
+fn tar_response(dir: PathBuf, skipHidden: bool) -> AResult<Response<ABody>> {
+    // archives are generated as they're sent, so their length isn't known
+    let mut headers = streamed_response(None).header(CONTENT_TYPE, "application/x-tar");
+    if let Some(name) = dir.file_name() {
+        let mut name = name.to_os_string();
+        name.push(".tar");
+        headers = headers.header(CONTENT_DISPOSITION, disposition::attachment(&name));
+    }
+
+    let (sink, chunks) = tokio::sync::mpsc::channel(8);
+    tokio::task::spawn(tar::write_archive(dir, skipHidden, sink));
+    let chunks = futures_util::stream::unfold(chunks, |mut chunks| async move {
+        chunks.recv().await.map(|chunk| (chunk, chunks))
+    });
+    let body = StreamBody::new(chunks.map_ok(Frame::data)).boxed();
+    Ok(headers.body(body)?)
+}
+
+#[tokio::test]
+async fn test_tar_download() -> AResult<()> {
+    let (root, removeRoot) = make_temp_dir()?;
+    std::fs::create_dir_all(root.join("Dir/sub"))?;
+    std::fs::write(root.join("Dir/b.txt"), "bee")?;
+    std::fs::write(root.join("Dir/a.txt"), "a".repeat(600))?;
+    std::fs::write(root.join("Dir/sub/c.txt"), "")?;
+
+    let read_tar = |mut data: &[u8]| -> Vec<(String, Vec<u8>)> {
+        let mut entries = vec![];
+        while data[..512].iter().any(|&byte| byte != 0) {
+            let field = |range: std::ops::Range<usize>| {
+                let field = &data[range];
+                let end = field
+                    .iter()
+                    .position(|&byte| byte == 0)
+                    .unwrap_or(field.len());
+                String::from_utf8(field[..end].to_vec()).unwrap()
+            };
+            let name = field(0..100);
+            let size = u64::from_str_radix(&field(124..135), 8).unwrap() as usize;
+            entries.push((name, data[512..512 + size].to_vec()));
+            data = &data[512 + size + tar::padding(size as u64)..];
+        }
+        assert_eq!(data, [0; 1024]);
+        entries
+    };
+    let expected = vec![
+        ("Dir/".to_string(), vec![]),
+        ("Dir/a.txt".to_string(), "a".repeat(600).into_bytes()),
+        ("Dir/b.txt".to_string(), b"bee".to_vec()),
+        ("Dir/sub/".to_string(), vec![]),
+        ("Dir/sub/c.txt".to_string(), vec![]),
+    ];
+
+    let state = test_state(&root, &["--allow-tar"]);
+    let res = test_get(state, "/dir?format=tar").await?;
+    assert_eq!(res.status, StatusCode::OK);
+    assert_eq!(res.headers[CONTENT_TYPE], "application/x-tar");
+    assert_eq!(
+        res.headers[CONTENT_DISPOSITION],
+        "attachment; filename=\"Dir.tar\""
+    );
+    assert_eq!(read_tar(&res.body), expected);
+
+    let request = "GET /DIR HTTP/1.1\r\nHost: localhost\r\nAccept: text/html, application/x-tar;q=0.9\r\nConnection: close\r\n\r\n";
+    let res = send_test_request(state, request.as_bytes()).await?;
+    assert_eq!(read_tar(&res.body), expected);
+
+    // files are served as usual
+    let res = test_get(state, "/dir/b.txt?format=tar").await?;
+    assert_eq!(res.body, b"bee");
+
+    // without the flag the query is ignored, and the directory listed as usual
+    let state = test_state(&root, &["--autoindex"]);
+    let res = test_get(state, "/dir/?format=tar").await?;
+    assert_eq!(res.status, StatusCode::OK);
+    assert_eq!(res.headers[CONTENT_TYPE], "text/html; charset=utf-8");
+
+    Ok(())
+}
+
+/// Counts how requests were resolved, to show how often clients get the case
+/// of paths wrong.
+#[derive(Default)]
+struct ResolutionStats {
+    exact: AtomicU64,
+    insensitive: AtomicU64,
+}
+
+impl ResolutionStats {
+    fn record(&self, exact: bool) {
+        let counter = match exact {
+            true => &self.exact,
+            false => &self.insensitive,
+        };
+        counter.fetch_add(1, AtomicOrdering::Relaxed);
+    }
+
+    fn summary(&self) -> String {
+        let exact = self.exact.load(AtomicOrdering::Relaxed);
+        let insensitive = self.insensitive.load(AtomicOrdering::Relaxed);
+        let rate = match exact + insensitive {
+            0 => 0.0,
+            total => exact as f64 / total as f64 * 100.0,
+        };
+        format!("resolved {exact} requests exactly and {insensitive} case-insensitively ({rate:.1}% exact)")
+    }
+}
+
+async fn resolve_path(state: &'static AppState, path: InsensitivePath) -> AResult<PathBuf> {
+    let requested = path.0.clone();
+    let file = find_file(state, path).await?;
+    let exact = file == requested;
+    log!(
+        LogLevel::Debug,
+        "resolved {requested:?} {}",
+        match exact {
+            true => "exactly".to_string(),
+            false => format!("case-insensitively to {file:?}"),
+        }
+    );
+    state.resolutionStats.record(exact);
+    Ok(file)
+}
+
+#[tokio::test]
+async fn test_resolution_stats() -> AResult<()> {
+    let (root, removeRoot) = make_temp_dir()?;
+    std::fs::create_dir(root.join("Dir"))?;
+    std::fs::write(root.join("Dir/file.txt"), "lower")?;
+    std::fs::write(root.join("Dir/FILE.txt"), "upper")?;
+    let counts = |state: &AppState| {
+        let stats = &state.resolutionStats;
+        (
+            stats.exact.load(AtomicOrdering::Relaxed),
+            stats.insensitive.load(AtomicOrdering::Relaxed),
+        )
+    };
+
+    let state = test_state(&root, &["--prefer-exact"]);
+    assert_eq!(test_get(state, "/Dir/file.txt").await?.body, b"lower");
+    assert_eq!(counts(state), (1, 0));
+    assert_eq!(test_get(state, "/Dir/FILE.txt").await?.body, b"upper");
+    assert_eq!(counts(state), (2, 0));
+    assert_eq!(test_get(state, "/dir/File.txt").await?.body, b"upper");
+    assert_eq!(counts(state), (2, 1));
+    // misses aren't counted
+    test_get(state, "/dir/missing.txt").await?;
+    assert_eq!(counts(state), (2, 1));
+    assert_eq!(
+        state.resolutionStats.summary(),
+        "resolved 2 requests exactly and 1 case-insensitively (66.7% exact)"
+    );
+
+    // without preferring exact matches, the first variant wins regardless
+    let state = test_state(&root, &[]);
+    assert_eq!(test_get(state, "/Dir/file.txt").await?.body, b"upper");
+    assert_eq!(counts(state), (0, 1));
+    assert_eq!(test_get(state, "/Dir/FILE.txt").await?.body, b"upper");
+    assert_eq!(counts(state), (1, 1));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_prefer_exact_stays_in_root() -> AResult<()> {
+    let (tmp, removeTmp) = make_temp_dir()?;
+    let root = tmp.join("root");
+    std::fs::create_dir(&root)?;
+    std::fs::create_dir(tmp.join("outside"))?;
+    std::fs::write(tmp.join("outside/secret.txt"), "secret")?;
+    let state = test_state(&root, &["--prefer-exact", "--autoindex"]);
+
+    for escape in [
+        "/../outside/secret.txt",
+        "/../outside/",
+        "/%2E%2E/outside/secret.txt",
+        "/a/../../outside/secret.txt",
+    ] {
+        let res = test_get(state, escape).await?;
+        assert_eq!(res.status, StatusCode::NOT_FOUND, "{escape}");
+    }
+    for escape in ["/..%2Foutside/secret.txt", "/%2F..%2Foutside%2F"] {
+        let res = test_get(state, escape).await?;
+        assert_eq!(res.status, StatusCode::BAD_REQUEST, "{escape}");
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_deaccent_flag() -> AResult<()> {
+    let (root, removeRoot) = make_temp_dir()?;
+    std::fs::create_dir(root.join("R\u{e9}sum\u{e9}"))?;
+    std::fs::write(root.join("R\u{e9}sum\u{e9}/Caf\u{e9}.txt"), "cafe")?;
+
+    for args in [&["--deaccent"][..], &["--deaccent", "--index-dirs"]] {
+        let state = test_state(&root, args);
+        let res = test_get(state, "/resume/CAFE.txt").await?;
+        assert_eq!(res.body, b"cafe", "{args:?}");
+    }
+    let state = test_state(&root, &["--index-dirs"]);
+    let res = test_get(state, "/resume/CAFE.txt").await?;
+    assert_eq!(res.status, StatusCode::NOT_FOUND);
+    let res = test_get(state, "/R%C3%89SUM%C3%89/CAF%C3%89.txt").await?;
+    assert_eq!(res.body, b"cafe");
+    Ok(())
+}
+
+/// Finds the file `path` refers to, by whichever means are enabled.
+async fn find_file(state: &'static AppState, path: InsensitivePath) -> AResult<PathBuf> {
+    let config = &state.config;
+    // paths relative to the working directory may omit the leading `./`
+    let relative = path.strip_prefix(&config.rootPath).unwrap_or(&path);
+    if relative.as_os_str().is_empty() {
+        return Ok(config.rootPath.clone());
+    }
+    if let Some(manifest) = &state.manifest {
+        if let Some(real) = manifest.get(relative) {
+            return Ok(config.rootPath.join(real));
+        }
+        if config.manifestOnly {
+            return Err(anyhow!("not found in manifest"));
+        }
+    }
+
+    if config.preferExact && tokio::fs::try_exists(&path.0).await? {
+        // the walk never leaves the root, so neither may the shortcut past it
+        if !path.starts_with(&config.rootPath) {
+            return Err(anyhow!("{:?} is outside the root", path.0));
+        }
+        return Ok(path.0);
+    }
+    if let Some(cache) = &state.resolveCache {
+        if let Some(file) = cache.get(&path) {
+            if tokio::fs::try_exists(&file).await? {
+                cache.hits.fetch_add(1, AtomicOrdering::Relaxed);
+                return Ok(file);
+            }
+            cache.remove(&path);
+        }
+        cache.misses.fetch_add(1, AtomicOrdering::Relaxed);
+    }
+
+    let strategy = ambiguity_for(config, relative);
+    let cacheKey = state.resolveCache.as_ref().map(|_| path.clone());
+    let cancelled = Arc::new(AtomicBool::new(false));
+    let walk = tokio::task::spawn_blocking({
+        let cancelled = cancelled.clone();
+        move || {
+            #[cfg(test)]
+            std::thread::sleep(state.resolveDelay);
+            let options = MatchOptions {
+                index: state.directoryIndex.as_ref(),
+                insensitiveExtOnly: config.insensitiveExtOnly,
+                trimTrailingDots: config.trimTrailingDots,
+                cancelled: Some(&cancelled),
+                deaccent: config.deaccent,
+            };
+            match strategy {
+                Ambiguity::First => {
+                    path.find_first_matching_file_with(Some(&config.rootPath), options)
+                }
+                strategy => {
+                    let files = path.find_matching_files_with(Some(&config.rootPath), options)?;
+                    select_match(strategy, &path, files)
+                }
+            }
+        }
+    });
+    let file = match config.resolveTimeout {
+        Some(timeout) => match tokio::time::timeout(Duration::from_millis(timeout), walk).await {
+            Ok(file) => file,
+            Err(_) => {
+                cancelled.store(true, AtomicOrdering::Relaxed);
+                return Err(anyhow!(ResolveTimeout));
+            }
+        },
+        None => walk.await,
+    }??;
+    let file = file.ok_or_else(|| anyhow!("not found"))?;
+    if let (Some(cache), Some(key)) = (&state.resolveCache, cacheKey) {
+        cache.insert(key, file.clone());
+    }
+    Ok(file)
+}
+
+/// Files request paths recently resolved to, with `--resolve-cache`. Keyed by
+/// `InsensitivePath`, so one entry serves a path in any case.
+struct ResolveCache {
+    limit: usize,
+    ttl: Duration,
+    entries: RwLock<CacheEntries>,
+    /// Requests answered from the cache, and those which resolved afresh, for
+    /// `--cache-endpoint`.
+    hits: AtomicU64,
+    misses: AtomicU64,
+    /// Insertion of the last entry `sweep` checked.
+    sweepCursor: AtomicU64,
+}
+
+#[derive(Default)]
+struct CacheEntries {
+    /// Each path's file, when it was resolved, and which insertion that was.
+    files: HashMap<InsensitivePath, (PathBuf, Instant, u64)>,
+    /// Paths oldest first, which all expire in turn. Paths inserted again or
+    /// removed leave their earlier places behind, told apart by insertion.
+    order: VecDeque<(InsensitivePath, u64)>,
+    insertions: u64,
+}
+
+impl ResolveCache {
+    fn new(limit: usize, ttl: Duration) -> Self {
+        Self {
+            limit,
+            ttl,
+            entries: RwLock::new(CacheEntries::default()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            sweepCursor: AtomicU64::new(0),
+        }
+    }
+
+    /// What `path` resolved to, unless that's expired.
+    fn get(&self, path: &InsensitivePath) -> Option<PathBuf> {
+        let entries = self.entries.read().unwrap();
+        let (file, resolved, _) = entries.files.get(path)?;
+        (resolved.elapsed() < self.ttl).then(|| file.clone())
+    }
+
+    /// Remembers that `path` resolved to `file`, evicting the oldest entry to
+    /// make room, which is also the first to expire.
+    fn insert(&self, path: InsensitivePath, file: PathBuf) {
+        let mut entries = self.entries.write().unwrap();
+        let CacheEntries {
+            files,
+            order,
+            insertions,
+        } = &mut *entries;
+        if files.len() >= self.limit && !files.contains_key(&path) {
+            while let Some((oldest, insertion)) = order.pop_front() {
+                if files.get(&oldest).is_some_and(|entry| entry.2 == insertion) {
+                    files.remove(&oldest);
+                    break;
+                }
+            }
+        }
+        *insertions += 1;
+        files.insert(path.clone(), (file, Instant::now(), *insertions));
+        order.push_back((path, *insertions));
+        // drops places left behind, rarely enough to cost O(1) per insertion
+        if order.len() > self.limit * 2 {
+            order.retain(|(path, insertion)| {
+                files.get(path).is_some_and(|entry| entry.2 == *insertion)
+            });
+        }
+    }
+
+    fn remove(&self, path: &InsensitivePath) {
+        self.entries.write().unwrap().files.remove(path);
+    }
+
+    /// Checks up to `batch` entries oldest first, carrying on from where the
+    /// last sweep stopped, and drops those that expired or whose file is no
+    /// longer named as it was under `root`. Files are checked without holding
+    /// the lock. Returns how many entries were dropped.
+    fn sweep(&self, root: &Path, batch: usize) -> usize {
+        let cursor = self.sweepCursor.load(AtomicOrdering::Relaxed);
+        let checking: Vec<_> = {
+            let entries = self.entries.read().unwrap();
+            entries
+                .order
+                .iter()
+                .filter(|(_, insertion)| *insertion > cursor)
+                .filter_map(|(path, insertion)| {
+                    let (file, resolved, _) = entries
+                        .files
+                        .get(path)
+                        .filter(|entry| entry.2 == *insertion)?;
+                    Some((path.clone(), file.clone(), *resolved, *insertion))
+                })
+                .take(batch)
+                .collect()
+        };
+        // starting over once the newest entry has been checked
+        let next = match checking.len() < batch {
+            true => 0,
+            false => checking.last().map_or(0, |entry| entry.3),
+        };
+        self.sweepCursor.store(next, AtomicOrdering::Relaxed);
+
+        let stale: Vec<_> = checking
+            .into_iter()
+            .filter(|(_, file, resolved, _)| {
+                let renamed = |relative: &Path| {
+                    !matches!(canonical_case(relative, root), Ok(Some(real)) if real == *file)
+                };
+                resolved.elapsed() >= self.ttl || file.strip_prefix(root).map_or(true, renamed)
+            })
+            .collect();
+        let mut entries = self.entries.write().unwrap();
+        for (path, _, _, insertion) in &stale {
+            // unless resolved again meanwhile
+            if entries
+                .files
+                .get(path)
+                .is_some_and(|entry| entry.2 == *insertion)
+            {
+                entries.files.remove(path);
+            }
+        }
+        stale.len()
+    }
+
+    /// Size, hits and misses, and up to `recent` of the newest entries with
+    /// paths relative to `root`, for `--cache-endpoint`.
+    fn stats_json(&self, root: &Path, recent: usize) -> String {
+        let entries = self.entries.read().unwrap();
+        let relative =
+            |path: &Path| json::quote(&path.strip_prefix(root).unwrap_or(path).to_string_lossy());
+        let newest: Vec<_> = entries
+            .order
+            .iter()
+            .rev()
+            .filter_map(|(path, insertion)| {
+                let (file, resolved, _) = entries
+                    .files
+                    .get(path)
+                    .filter(|entry| entry.2 == *insertion)?;
+                Some(format!(
+                    "{{\"path\":{},\"file\":{},\"age-ms\":{}}}",
+                    relative(&path.0),
+                    relative(file),
+                    resolved.elapsed().as_millis()
+                ))
+            })
+            .take(recent)
+            .collect();
+        format!(
+            "{{\"size\":{},\"limit\":{},\"ttl\":{},\"hits\":{},\"misses\":{},\"recent\":[{}]}}\n",
+            entries.files.len(),
+            self.limit,
+            self.ttl.as_secs(),
+            self.hits.load(AtomicOrdering::Relaxed),
+            self.misses.load(AtomicOrdering::Relaxed),
+            newest.join(",")
+        )
+    }
+}
+
+/// How many entries each `--cache-sweep-interval` sweep checks.
+const sweepBatch: usize = 256;
+
+/// Sweeps `--resolve-cache` every `--cache-sweep-interval`, until the server
+/// stops.
+async fn sweep_resolve_cache(state: &'static AppState) {
+    let (Some(cache), Some(interval)) = (&state.resolveCache, state.config.cacheSweepInterval)
+    else {
+        return;
+    };
+    let mut ticks = tokio::time::interval(Duration::from_secs(interval));
+    // the first tick is immediate, with nothing cached yet
+    ticks.tick().await;
+    loop {
+        ticks.tick().await;
+        let sweep = tokio::task::spawn_blocking(|| cache.sweep(&state.config.rootPath, sweepBatch));
+        match sweep.await {
+            Ok(0) => {}
+            Ok(swept) => log!(LogLevel::Debug, "swept {swept} stale resolve cache entries"),
+            Err(err) => log!(LogLevel::Error, "resolve cache sweep failed: {err}"),
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_cache_sweep() -> AResult<()> {
+    let (root, removeRoot) = make_temp_dir()?;
+    std::fs::create_dir(root.join("Dir"))?;
+    for name in ["A.txt", "B.txt", "C.txt"] {
+        std::fs::write(root.join("Dir").join(name), name)?;
+    }
+    let state = test_state(&root, &["--resolve-cache", "--cache-sweep-interval", "1"]);
+    let cache = state.resolveCache.as_ref().unwrap();
+    let cached = |path: &str| cache.get(&InsensitivePath(root.join(path)));
+    for path in ["/dir/a.txt", "/dir/b.txt", "/dir/c.txt"] {
+        assert_eq!(test_get(state, path).await?.status, StatusCode::OK);
+    }
+
+    // batches carry on where the last left off
+    std::fs::remove_file(root.join("Dir/C.txt"))?;
+    std::fs::rename(root.join("Dir/B.txt"), root.join("Dir/b.txt"))?;
+    assert_eq!(cache.sweep(&root, 1), 0);
+    assert_eq!(cache.sweep(&root, 1), 1);
+    assert_eq!(cached("dir/b.txt"), None);
+    assert!(cached("dir/c.txt").is_some());
+    assert_eq!(cache.sweep(&root, 1), 1);
+    assert_eq!(cached("dir/c.txt"), None);
+    assert_eq!(cache.sweep(&root, 1), 0);
+    assert_eq!(cached("dir/a.txt"), Some(root.join("Dir/A.txt")));
+
+    // and run in the background
+    assert_eq!(test_get(state, "/dir/b.txt").await?.status, StatusCode::OK);
+    tokio::spawn(sweep_resolve_cache(state));
+    std::fs::remove_file(root.join("Dir/b.txt"))?;
+    let started = Instant::now();
+    while cached("dir/b.txt").is_some() {
+        assert!(started.elapsed() < Duration::from_secs(5), "never swept");
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    assert!(cached("dir/a.txt").is_some());
+
+    assert!(Config::try_parse_from(["caseproxy", "--cache-sweep-interval", "1"]).is_err());
+    Ok(())
+}
+
+const cachePath: &str = "/_caseproxy/cache";
+
+/// Answers `--cache-endpoint`, without resolving anything.
+fn cache_response(
+    state: &'static AppState,
+    req: &Request<impl hyper::body::Body>,
+) -> Response<ABody> {
+    let config = &state.config;
+    let Some(cache) = &state.resolveCache else {
+        return status_response(config, StatusCode::NOT_FOUND);
+    };
+    if !bearer_authorized(req, config.cacheToken.as_deref()) {
+        return status_response(config, StatusCode::NOT_FOUND);
+    }
+    json_response(cache.stats_json(&config.rootPath, 10))
+}
+
+#[tokio::test]
+async fn test_cache_endpoint() -> AResult<()> {
+    let (root, removeRoot) = make_temp_dir()?;
+    std::fs::create_dir(root.join("Dir"))?;
+    std::fs::write(root.join("Dir/A.txt"), "a")?;
+    std::fs::write(root.join("Dir/B.txt"), "b")?;
+    let state = test_state(&root, &["--resolve-cache", "--cache-endpoint", "s3cret"]);
+    let request = |token: &str| {
+        let authorization = match token {
+            "" => String::new(),
+            token => format!("Authorization: Bearer {token}\r\n"),
+        };
+        format!(
+            "GET {cachePath} HTTP/1.1\r\nHost: localhost\r\n{authorization}Connection: close\r\n\r\n"
+        )
+    };
+
+    for path in ["/dir/a.txt", "/DIR/A.TXT", "/dir/b.txt", "/missing.txt"] {
+        test_get(state, path).await?;
+    }
+    let res = send_test_request(state, request("s3cret").as_bytes()).await?;
+    assert_eq!(res.status, StatusCode::OK);
+    assert_eq!(res.headers[CONTENT_TYPE], "application/json");
+    let stats = String::from_utf8(res.body)?;
+    assert!(
+        stats.starts_with(concat!(
+            "{\"size\":2,\"limit\":10000,\"ttl\":60,\"hits\":1,\"misses\":3,",
+            "\"recent\":[{\"path\":\"dir/b.txt\",\"file\":\"Dir/B.txt\",\"age-ms\":"
+        )),
+        "{stats}"
+    );
+    assert!(
+        stats.contains("{\"path\":\"dir/a.txt\",\"file\":\"Dir/A.txt\",\"age-ms\":"),
+        "{stats}"
+    );
+    assert!(stats.ends_with("}]}\n"), "{stats}");
+    // answering isn't itself a resolution
+    let res = send_test_request(state, request("s3cret").as_bytes()).await?;
+    assert!(String::from_utf8(res.body)?.contains("\"hits\":1,\"misses\":3,"));
+
+    for token in ["", "wrong"] {
+        let res = send_test_request(state, request(token).as_bytes()).await?;
+        assert_eq!(res.status, StatusCode::NOT_FOUND, "{token}");
+    }
+    let state = test_state(&root, &["--resolve-cache"]);
+    let res = send_test_request(state, request("s3cret").as_bytes()).await?;
+    assert_eq!(res.status, StatusCode::NOT_FOUND);
+    Ok(())
+}
+
+#[test]
+fn test_resolve_cache() {
+    let path = |path: &str| InsensitivePath(path.into());
+    let cache = ResolveCache::new(2, Duration::from_millis(100));
+    cache.insert(path("/a/FILE"), "/A/file".into());
+    assert_eq!(cache.get(&path("/A/file")), Some("/A/file".into()));
+    assert_eq!(cache.get(&path("/a/file")), Some("/A/file".into()));
+    assert_eq!(cache.get(&path("/b")), None);
+
+    // the oldest entry makes way, even if used since
+    cache.insert(path("/b"), "/B".into());
+    cache.insert(path("/c"), "/C".into());
+    assert_eq!(cache.entries.read().unwrap().files.len(), 2);
+    assert_eq!(cache.get(&path("/a/file")), None);
+    assert_eq!(cache.get(&path("/b")), Some("/B".into()));
+    assert_eq!(cache.get(&path("/c")), Some("/C".into()));
+    // inserting again makes an entry the newest
+    cache.insert(path("/B"), "/B".into());
+    cache.insert(path("/d"), "/D".into());
+    assert_eq!(cache.get(&path("/c")), None);
+    assert_eq!(cache.get(&path("/b")), Some("/B".into()));
+    // and removed entries leave room without evicting anything
+    cache.remove(&path("/b"));
+    cache.insert(path("/e"), "/E".into());
+    assert_eq!(cache.get(&path("/d")), Some("/D".into()));
+    assert_eq!(cache.get(&path("/e")), Some("/E".into()));
+
+    std::thread::sleep(Duration::from_millis(150));
+    assert_eq!(cache.get(&path("/e")), None);
+
+    // places left behind by churn don't accumulate
+    for _ in 0..100 {
+        cache.insert(path("/f"), "/F".into());
+    }
+    let order = cache.entries.read().unwrap().order.len();
+    assert!(order <= 4, "{order}");
+}
+
+#[tokio::test]
+async fn test_resolve_cache_invalidation() -> AResult<()> {
+    let (root, removeRoot) = make_temp_dir()?;
+    std::fs::create_dir(root.join("Dir"))?;
+    std::fs::write(root.join("Dir/File.txt"), "first")?;
+    let state = test_state(&root, &["--resolve-cache"]);
+    let cache = state.resolveCache.as_ref().unwrap();
+
+    assert_eq!(test_get(state, "/dir/file.txt").await?.body, b"first");
+    assert_eq!(
+        cache.get(&InsensitivePath(root.join("DIR/FILE.TXT"))),
+        Some(root.join("Dir/File.txt"))
+    );
+    // requests are answered from the cache
+    cache.insert(
+        InsensitivePath(root.join("alias.txt")),
+        root.join("Dir/File.txt"),
+    );
+    assert_eq!(test_get(state, "/ALIAS.txt").await?.body, b"first");
+
+    // until the file it gives is gone
+    std::fs::rename(root.join("Dir/File.txt"), root.join("Dir/file.TXT"))?;
+    assert_eq!(test_get(state, "/DIR/File.txt").await?.body, b"first");
+    assert_eq!(
+        cache.get(&InsensitivePath(root.join("dir/file.txt"))),
+        Some(root.join("Dir/file.TXT"))
+    );
+    assert_eq!(
+        test_get(state, "/alias.txt").await?.status,
+        StatusCode::NOT_FOUND
+    );
+    assert_eq!(cache.get(&InsensitivePath(root.join("alias.txt"))), None);
+
+    assert!(
+        Config::try_parse_from(["caseproxy", "--resolve-cache", "--insensitive-ext-only"]).is_err()
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_on_ambiguous_shortest() -> AResult<()> {
+    let (root, removeRoot) = make_temp_dir()?;
+    // `İ` lowercases to `i` and a combining dot, so these match each other,
+    // and the longer decomposed spelling sorts first
+    std::fs::create_dir_all(root.join("Dir"))?;
+    std::fs::create_dir_all(root.join("dir"))?;
+    std::fs::write(root.join("Dir/i\u{307}ndex.txt"), "decomposed")?;
+    std::fs::write(root.join("dir/\u{130}ndex.txt"), "precomposed")?;
+    std::fs::write(root.join("dir/Other.txt"), "other")?;
+
+    let state = test_state(&root, &[]);
+    let res = test_get(state, "/DIR/%C4%B0NDEX.txt").await?;
+    assert_eq!(res.body, b"decomposed");
+
+    let state = test_state(&root, &["--on-ambiguous", "shortest"]);
+    let res = test_get(state, "/DIR/%C4%B0NDEX.txt").await?;
+    assert_eq!(res.body, b"precomposed");
+    let res = test_get(state, "/DIR/I%CC%87ndex.txt").await?;
+    assert_eq!(res.body, b"precomposed");
+    assert_eq!(test_get(state, "/DIR/other.txt").await?.body, b"other");
+    let res = test_get(state, "/DIR/missing.txt").await?;
+    assert_eq!(res.status, StatusCode::NOT_FOUND);
+    Ok(())
+}
+
+/// Picks which of `files`, every match for `requested` in sorted order, to
+/// serve by `strategy`.
+fn select_match(
+    strategy: Ambiguity,
+    requested: &Path,
+    files: Vec<PathBuf>,
+) -> AResult<Option<PathBuf>> {
+    let first = |files: Vec<PathBuf>| files.into_iter().next();
+    Ok(match strategy {
+        Ambiguity::First => first(files),
+        Ambiguity::Shortest => files
+            .into_iter()
+            .enumerate()
+            .min_by_key(|(index, file)| {
+                (file.as_os_str().to_string_lossy().chars().count(), *index)
+            })
+            .map(|(_, file)| file),
+        Ambiguity::ExactPreferred => match files.iter().position(|file| file == requested) {
+            Some(exact) => Some(files.into_iter().nth(exact).unwrap()),
+            None => first(files),
+        },
+        Ambiguity::Newest => files
+            .into_iter()
+            .enumerate()
+            .map(|(index, file)| {
+                let modified = std::fs::metadata(&file).and_then(|metadata| metadata.modified());
+                let modified = modified.unwrap_or(SystemTime::UNIX_EPOCH);
+                // the first of equally new files, as `max_by_key` gives the last
+                (modified, std::cmp::Reverse(index), file)
+            })
+            .max_by_key(|(modified, index, _)| (*modified, *index))
+            .map(|(_, _, file)| file),
+        Ambiguity::Error => match files.len() {
+            0 | 1 => first(files),
+            count => return Err(anyhow!(AmbiguousMatch(count))),
+        },
+    })
+}
+
+/// Which strategy chooses between matches for `relative`, a request path
+/// relative to the root: that of the deepest `--on-ambiguous-under` directory
+/// containing it, or else `--on-ambiguous`.
+fn ambiguity_for(config: &Config, relative: &Path) -> Ambiguity {
+    relative
+        .ancestors()
+        .find_map(|ancestor| {
+            let ancestor = InsensitivePath(ancestor.to_path_buf());
+            config
+                .ambiguityPrefixes
+                .iter()
+                .find(|(dir, _)| InsensitivePath(dir.clone()) == ancestor)
+        })
+        .map_or(config.onAmbiguous, |(_, strategy)| *strategy)
+}
+
+/// Parses an `--on-ambiguous-under`, as a directory relative to the URL prefix
+/// and a strategy.
+fn parse_ambiguity_prefix(arg: &str) -> AResult<(PathBuf, Ambiguity)> {
+    let (dir, strategy) = arg
+        .split_once('=')
+        .ok_or_else(|| anyhow!("expected `/dir=STRATEGY`"))?;
+    let dir = resolve_parents(&Path::new("/").join(dir.trim()));
+    let strategy = Ambiguity::from_str(strategy.trim(), true).map_err(|err| anyhow!(err))?;
+    Ok((dir.strip_prefix("/")?.to_path_buf(), strategy))
+}
+
+#[tokio::test]
+async fn test_ambiguity_prefixes() -> AResult<()> {
+    let (root, removeRoot) = make_temp_dir()?;
+    for dir in ["", "Legacy/", "Legacy/Pinned/", "Static/"] {
+        std::fs::create_dir_all(root.join(dir))?;
+        for (name, secs) in [("README.txt", 1_000), ("readme.txt", 2_000)] {
+            let path = root.join(dir).join(name);
+            std::fs::write(&path, format!("{dir}{name}"))?;
+            std::fs::File::options()
+                .write(true)
+                .open(path)?
+                .set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(secs))?;
+        }
+    }
+    let state = test_state(
+        &root,
+        &[
+            "--url-prefix",
+            "/files",
+            "--on-ambiguous",
+            "shortest",
+            "--on-ambiguous-under",
+            "/legacy=newest",
+            "--on-ambiguous-under",
+            "legacy/pinned/=first",
+            "--on-ambiguous-under",
+            "/STATIC=error",
+        ],
+    );
+
+    let body = |res: TestResponse| String::from_utf8(res.body).unwrap();
+    assert_eq!(
+        body(test_get(state, "/files/readme.txt").await?),
+        "README.txt"
+    );
+    // the deepest directory containing the request wins, in any case
+    assert_eq!(
+        body(test_get(state, "/files/LEGACY/README.txt").await?),
+        "Legacy/readme.txt"
+    );
+    assert_eq!(
+        body(test_get(state, "/files/legacy/pinned/readme.txt").await?),
+        "Legacy/Pinned/README.txt"
+    );
+    assert_eq!(
+        test_get(state, "/files/static/readme.txt").await?.status,
+        StatusCode::CONFLICT
+    );
+
+    let strategy = |path: &str| ambiguity_for(&state.config, Path::new(path));
+    assert_eq!(strategy("legacy"), Ambiguity::Newest);
+    assert_eq!(strategy("legacyfiles/x"), Ambiguity::Shortest);
+    assert_eq!(strategy("Legacy/Pinned/deeper/x"), Ambiguity::First);
+    assert_eq!(strategy(""), Ambiguity::Shortest);
+
+    assert!(parse_ambiguity_prefix("/legacy").is_err());
+    assert!(parse_ambiguity_prefix("/legacy=bogus").is_err());
+    assert_eq!(
+        parse_ambiguity_prefix("/a/./b/=error")?,
+        (PathBuf::from("a/b"), Ambiguity::Error)
+    );
+    assert!(AppState::new(Config::try_parse_from([
+        "caseproxy",
+        "--resolve-cache",
+        "--on-ambiguous-under",
+        "/legacy=newest",
+    ])?)
+    .is_err());
+    Ok(())
+}
+
+/// Several files matched a request under `--on-ambiguous error`.
+#[derive(Debug)]
+struct AmbiguousMatch(usize);
+
+impl std::fmt::Display for AmbiguousMatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} files match path", self.0)
+    }
+}
+
+#[tokio::test]
+async fn test_resolve_strategies() -> AResult<()> {
+    let (root, removeRoot) = make_temp_dir()?;
+    std::fs::write(root.join("README.txt"), "upper")?;
+    std::fs::write(root.join("Readme.txt"), "title")?;
+    std::fs::write(root.join("readme.txt"), "lower")?;
+    std::fs::write(root.join("Only.txt"), "only")?;
+    // the newest isn't first or last in sorted order
+    let setModified = |name: &str, secs: u64| -> AResult<()> {
+        let file = std::fs::File::options().write(true).open(root.join(name))?;
+        file.set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(secs))?;
+        Ok(())
+    };
+    setModified("README.txt", 1_000)?;
+    setModified("Readme.txt", 3_000)?;
+    setModified("readme.txt", 2_000)?;
+
+    let state = test_state(&root, &["--resolve-strategy", "first"]);
+    assert_eq!(test_get(state, "/readme.txt").await?.body, b"upper");
+
+    let state = test_state(&root, &["--on-ambiguous", "exact-preferred"]);
+    assert_eq!(test_get(state, "/readme.txt").await?.body, b"lower");
+    assert_eq!(test_get(state, "/Readme.txt").await?.body, b"title");
+    assert_eq!(test_get(state, "/README.TXT").await?.body, b"upper");
+
+    let state = test_state(&root, &["--resolve-strategy", "newest"]);
+    assert_eq!(test_get(state, "/README.TXT").await?.body, b"title");
+
+    let state = test_state(&root, &["--resolve-strategy", "error"]);
+    let res = test_get(state, "/readme.txt").await?;
+    assert_eq!(res.status, StatusCode::CONFLICT);
+    assert_eq!(test_get(state, "/only.TXT").await?.body, b"only");
+    // an exact match settles it with `--prefer-exact`
+    let state = test_state(&root, &["--resolve-strategy", "error", "--prefer-exact"]);
+    assert_eq!(test_get(state, "/Readme.txt").await?.body, b"title");
+    assert_eq!(
+        test_get(state, "/readMe.txt").await?.status,
+        StatusCode::CONFLICT
+    );
+    Ok(())
+}
+
+/// Resolution outlasted `--resolve-timeout`.
+#[derive(Debug)]
+struct ResolveTimeout;
+
+impl std::fmt::Display for ResolveTimeout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("timed out resolving path")
+    }
+}
+
+#[tokio::test]
+async fn test_resolve_timeout() -> AResult<()> {
+    let (root, removeRoot) = make_temp_dir()?;
+    std::fs::write(root.join("File.txt"), "contents")?;
+    let slowState = |args: &[&str]| -> AResult<&'static AppState> {
+        let config = Config::try_parse_from(
+            ["caseproxy", "--root-path", root.to_str().unwrap()]
+                .iter()
+                .chain(args),
+        )?;
+        let mut state = AppState::new(config)?;
+        state.resolveDelay = Duration::from_millis(200);
+        Ok(Box::leak(Box::new(state)))
+    };
+
+    let state = slowState(&["--resolve-timeout", "20"])?;
+    let res = test_get(state, "/file.txt").await?;
+    assert_eq!(res.status, StatusCode::GATEWAY_TIMEOUT);
+    // exact paths don't need resolving with `--prefer-exact`
+    let state = slowState(&["--resolve-timeout", "20", "--prefer-exact"])?;
+    let res = test_get(state, "/File.txt").await?;
+    assert_eq!(res.body, b"contents");
+
+    let state = slowState(&["--resolve-timeout", "5000"])?;
+    assert_eq!(test_get(state, "/file.txt").await?.body, b"contents");
+    let state = slowState(&[])?;
+    assert_eq!(test_get(state, "/file.txt").await?.body, b"contents");
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_trim_trailing_dots() -> AResult<()> {
+    let (root, removeRoot) = make_temp_dir()?;
+    std::fs::write(root.join("Report.txt"), "report")?;
+    std::fs::write(root.join("notes. "), "notes")?;
+
+    let state = test_state(&root, &["--trim-trailing-dots"]);
+    assert_eq!(test_get(state, "/report.txt.").await?.body, b"report");
+    assert_eq!(test_get(state, "/REPORT.TXT%20.").await?.body, b"report");
+    assert_eq!(test_get(state, "/Notes").await?.body, b"notes");
+
+    let state = test_state(&root, &[]);
+    let res = test_get(state, "/report.txt.").await?;
+    assert_eq!(res.status, StatusCode::NOT_FOUND);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_relative_root() -> AResult<()> {
+    // tests run from the package root
+    let absolute = test_state(Path::new(env!("CARGO_MANIFEST_DIR")), &[]);
+    let expected = test_get(absolute, "/src/LIB.rs").await?;
+    assert_eq!(expected.status, StatusCode::OK);
+
+    for root in [".", "./", "./src/..", "src/.."] {
+        let state = test_state(Path::new(root), &[]);
+        let res = test_get(state, "/src/LIB.rs").await?;
+        assert_eq!(res.status, StatusCode::OK, "root {root:?}");
+        assert_eq!(res.body, expected.body, "root {root:?}");
+
+        let res = test_get(state, "/../Cargo.toml").await?;
+        assert_eq!(res.status, StatusCode::OK, "root {root:?}");
+    }
+
+    let state = test_state(Path::new("./src"), &[]);
+    assert_eq!(test_get(state, "/LIB.rs").await?.body, expected.body);
+    assert_eq!(
+        test_get(state, "/../Cargo.toml").await?.status,
+        StatusCode::NOT_FOUND
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_manifest() -> AResult<()> {
+    let (root, removeRoot) = make_temp_dir()?;
+    std::fs::create_dir_all(root.join("Docs"))?;
+    std::fs::write(root.join("Docs/README.md"), "readme")?;
+    std::fs::write(root.join("unlisted.txt"), "unlisted")?;
+    std::fs::write(root.join("real.txt"), "real")?;
+    let manifestPath = root.join("manifest.json");
+    std::fs::write(
+        &manifestPath,
+        r#"{"docs/readme.md": "Docs/README.md", "alias.txt": "real.txt"}"#,
+    )?;
+    let manifestPath = manifestPath.to_str().unwrap();
+
+    let state = test_state(&root, &["--manifest", manifestPath]);
+    assert_eq!(test_get(state, "/docs/ReadMe.md").await?.body, b"readme");
+    // only resolvable through the manifest
+    assert_eq!(test_get(state, "/Alias.txt").await?.body, b"real");
+    assert_eq!(test_get(state, "/UNLISTED.txt").await?.body, b"unlisted");
+
+    let state = test_state(&root, &["--manifest", manifestPath, "--manifest-only"]);
+    assert_eq!(test_get(state, "/docs/ReadMe.md").await?.body, b"readme");
+    let res = test_get(state, "/UNLISTED.txt").await?;
+    assert_eq!(res.status, StatusCode::NOT_FOUND);
+
+    Ok(())
+}
+
+/// Builds the response for a `path` not found under the root, trying
+/// `--upstream` and then `--suggest` if enabled.
+async fn not_found_response(state: &'static AppState, path: &Path) -> AResult<Response<ABody>> {
+    let config = &state.config;
+    if let Some(upstream) = &config.upstream {
+        // paths relative to the working directory may omit the leading `./`
+        let relative = path.strip_prefix(&config.rootPath).unwrap_or(path);
+        if !(config.denyHidden && is_hidden(relative)) {
+            match upstream_response(state, upstream, relative).await {
+                Ok(Some(response)) => return Ok(response),
+                Ok(None) => {}
+                Err(err) => log!(
+                    LogLevel::Warn,
+                    "fetching {relative:?} from upstream failed: {err:#}"
+                ),
+            }
+        }
+    }
+
+    if config.suggest {
+        suggestion_response(state, path).await
+    } else {
+        Ok(status_response(config, StatusCode::NOT_FOUND))
+    }
+}
+
+/// Fetches `path` (relative to the root) from `upstream`, caching it into the
+/// root if enabled. Returns `None` if upstream doesn't have it either.
+async fn upstream_response(
+    state: &'static AppState,
+    upstream: &Upstream,
+    path: &Path,
+) -> AResult<Option<Response<ABody>>> {
+    let Some(mut urlPath) = path.to_str().map(str::to_string) else {
+        return Ok(None);
+    };
+    let timeout = Duration::from_millis(state.config.upstreamTimeout);
+    let mut res = upstream
+        .get(&percent_encode_path(urlPath.as_bytes()), timeout)
+        .await?;
+    if res.status == StatusCode::NOT_FOUND {
+        let lowercase = urlPath.to_lowercase();
+        if lowercase != urlPath {
+            urlPath = lowercase;
+            res = upstream
+                .get(&percent_encode_path(urlPath.as_bytes()), timeout)
+                .await?;
+        }
+    }
+    if res.status != StatusCode::OK {
+        log!(
+            LogLevel::Debug,
+            "upstream responded {} for {urlPath:?}",
+            res.status
+        );
+        return Ok(None);
+    }
+
+    let mut response = streamed_response(res.contentLength);
+    if let Some(contentType) = &res.contentType {
+        response = response.header(CONTENT_TYPE, contentType);
+    }
+
+    let mut body = res.body;
+    if let (Some(budget), Some(length)) = (&state.upstreamCache, res.contentLength) {
+        match cache_destination(state, Path::new(&urlPath)).await {
+            Ok(Some(dest)) if budget.reserve(length) => {
+                // only now there's room for the file are its directories made
+                let dir = dest.parent().unwrap_or(&dest);
+                match tokio::fs::create_dir_all(dir).await {
+                    Ok(()) => body = upstream::cache_body(body, dest, length).await?,
+                    Err(err) => {
+                        budget.release(length);
+                        log!(LogLevel::Warn, "can't cache {urlPath:?}: {err}");
+                    }
+                }
+            }
+            Ok(Some(dest)) => log!(LogLevel::Warn, "cache is full, not caching {dest:?}"),
+            Ok(None) => {}
+            Err(err) => log!(LogLevel::Warn, "can't cache {urlPath:?}: {err:#}"),
+        }
+    }
+    let body = StreamBody::new(body.map_ok(Frame::data)).boxed();
+    Ok(Some(response.body(body)?))
+}
+
+/// Where to cache `path` (relative to the root), reusing the casing of
+/// whichever of its parent directories already exist so as not to create
+/// directories that differ only in case. Missing directories are left to the
+/// caller to create.
+async fn cache_destination(state: &'static AppState, path: &Path) -> AResult<Option<PathBuf>> {
+    let config = &state.config;
+    let (Some(parent), Some(name)) = (path.parent(), path.file_name()) else {
+        return Ok(None);
+    };
+    for existing in parent.ancestors() {
+        let fullPath = resolve_parents(&config.rootPath.join(existing));
+        let Ok(realPath) = resolve_path(state, InsensitivePath(fullPath)).await else {
+            continue;
+        };
+        if !tokio::fs::metadata(&realPath).await?.is_dir() {
+            return Ok(None);
+        }
+        let dir = realPath.join(parent.strip_prefix(existing)?);
+        return Ok(Some(dir.join(name)));
+    }
+    Ok(None)
+}
+
+#[tokio::test]
+async fn test_upstream() -> AResult<()> {
+    let (root, removeRoot) = make_temp_dir()?;
+    std::fs::create_dir(root.join("Assets"))?;
+
+    let origin = TcpListener::bind("127.0.0.1:0").await?;
+    let originUrl = format!("http://{}/base", origin.local_addr()?);
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = origin.accept().await {
+            let service = service_fn(|req: Request<hyper::body::Incoming>| async move {
+                let full = |body: &'static str| Full::new(Bytes::from_static(body.as_bytes()));
+                let mut res = match req.uri().path() {
+                    "/base/assets/style.css" => Response::new(full("body {}").boxed()),
+                    "/base/new/dir/file.txt" => Response::new(full("new").boxed()),
+                    "/base/big.txt" | "/base/big/dir/file.txt" => {
+                        Response::new(full("big file").boxed())
+                    }
+                    "/base/chunked.txt" => {
+                        let chunks =
+                            ["chun", "ked"].map(|chunk| Ok(Frame::data(Bytes::from(chunk))));
+                        Response::new(StreamBody::new(futures_util::stream::iter(chunks)).boxed())
+                    }
+                    "/base/stalled-head.txt" => {
+                        tokio::time::sleep(Duration::from_secs(60)).await;
+                        Response::new(full("").boxed())
+                    }
+                    "/base/stalled-body.txt" => {
+                        let part =
+                            futures_util::stream::iter([Ok(Frame::data(Bytes::from("part")))]);
+                        let chunks =
+                            futures_util::StreamExt::chain(part, futures_util::stream::pending());
+                        let mut res = Response::new(StreamBody::new(chunks).boxed());
+                        res.headers_mut()
+                            .insert(CONTENT_LENGTH, HeaderValue::from_static("10"));
+                        res
+                    }
+                    _ => {
+                        let mut res = Response::new(full("").boxed());
+                        *res.status_mut() = StatusCode::NOT_FOUND;
+                        res
+                    }
+                };
+                res.headers_mut()
+                    .insert(CONTENT_TYPE, HeaderValue::from_static("text/x-test"));
+                Ok::<_, Infallible>(res)
+            });
+            tokio::spawn(http1::Builder::new().serve_connection(TokioIo::new(stream), service));
+        }
+    });
+    let state = test_state(
+        &root,
+        &[
+            "--upstream",
+            &originUrl,
+            "--upstream-cache",
+            "--upstream-cache-size",
+            "10",
+            "--upstream-timeout",
+            "200",
+        ],
+    );
+
+    // found upstream after retrying in lowercase, and cached into the existing directory
+    let res = test_get(state, "/Assets/Style.CSS").await?;
+    assert_eq!(res.status, StatusCode::OK);
+    assert_eq!(res.headers[CONTENT_TYPE], "text/x-test");
+    assert_eq!(res.body, b"body {}");
+    assert_eq!(std::fs::read(root.join("Assets/style.css"))?, b"body {}");
+    assert!(!root.join("assets").exists());
+
+    // missing directories are created
+    let res = test_get(state, "/new/dir/file.txt").await?;
+    assert_eq!(res.body, b"new");
+    assert_eq!(std::fs::read(root.join("new/dir/file.txt"))?, b"new");
+
+    // streamed, but too big for what's left of the cache
+    let res = test_get(state, "/big.txt").await?;
+    assert_eq!(res.body, b"big file");
+    assert!(!root.join("big.txt").exists());
+    // nor are directories made for it
+    let res = test_get(state, "/big/dir/file.txt").await?;
+    assert_eq!(res.body, b"big file");
+    assert!(!root.join("big").exists());
+
+    // a stalled upstream is given up on, before or after it starts responding
+    let res = test_get(state, "/stalled-head.txt").await?;
+    assert_eq!(res.status, StatusCode::NOT_FOUND);
+    let res = test_get(state, "/stalled-body.txt").await?;
+    assert_eq!(res.status, StatusCode::OK);
+    assert_eq!(res.body, b"part");
+
+    // passed through but not cached without a known length
+    let res = test_get(state, "/chunked.txt").await?;
+    assert_eq!(res.body, b"chunked");
+    assert!(!root.join("chunked.txt").exists());
+
+    let res = test_get(state, "/missing.txt").await?;
+    assert_eq!(res.status, StatusCode::NOT_FOUND);
+
+    // no partial files are left behind
+    let names: Vec<_> = std::fs::read_dir(&root)?
+        .map(|entry| Ok(entry?.file_name()))
+        .collect::<AResult<_>>()?;
+    assert_eq!(names.len(), 2, "{names:?}");
+
+    Ok(())
+}
+
+/// URL of the first component of `path` that doesn't resolve, under its
+/// deepest existing directory, and why it doesn't. Paths outside the root, or
+/// through hidden names under `--deny-hidden`, get no URL.
+async fn failed_at(
+    state: &'static AppState,
+    path: &Path,
+) -> AResult<Option<(String, ResolveError)>> {
+    let config = &state.config;
+    let search = InsensitivePath(path.to_path_buf());
+    let reason = tokio::task::spawn_blocking(move || {
+        let options = MatchOptions {
+            index: state.directoryIndex.as_ref(),
+            insensitiveExtOnly: config.insensitiveExtOnly,
+            trimTrailingDots: config.trimTrailingDots,
+            deaccent: config.deaccent,
+            ..Default::default()
+        };
+        search.unresolved(Some(&config.rootPath), options)
+    })
+    .await?;
+    let std::result::Result::Ok(Some(reason)) = reason else {
+        return Ok(None);
+    };
+
+    // paths relative to the working directory may omit the leading `./`
+    let relative = path.strip_prefix(&config.rootPath).unwrap_or(path);
+    let Some(failed) = relative.components().nth(reason.depth()) else {
+        return Ok(None);
+    };
+    let deepest = match &reason {
+        ResolveError::NoMatch { dir, .. } => dir.as_path(),
+        ResolveError::NotADirectory { file, .. } => file.parent().unwrap_or(file),
+    };
+    let deepest = deepest.strip_prefix(&config.rootPath).unwrap_or(deepest);
+    let failed = deepest.join(failed);
+    if config.denyHidden && is_hidden(&failed) {
+        return Ok(None);
+    }
+    let encoded = percent_encode_path(failed.as_os_str().as_encoded_bytes());
+    Ok(Some((format!("{}{encoded}", config.urlPrefix), reason)))
+}
+
+#[tokio::test]
+async fn test_failed_at_header() -> AResult<()> {
+    let (root, removeRoot) = make_temp_dir()?;
+    std::fs::create_dir_all(root.join("Docs/Guides"))?;
+    std::fs::write(root.join("Docs/Guides/Intro.txt"), "intro")?;
+
+    let state = test_state(&root, &["--failed-at-header", "--url-prefix", "/files"]);
+    let failedAt = |res: &TestResponse| {
+        res.headers
+            .get("X-Caseproxy-Failed-At")
+            .map(|value| value.to_str().unwrap().to_string())
+    };
+    let res = test_get(state, "/files/docs/missing/deeper/intro.txt").await?;
+    assert_eq!(res.status, StatusCode::NOT_FOUND);
+    assert_eq!(failedAt(&res).as_deref(), Some("/files/Docs/missing"));
+    let res = test_get(state, "/files/docs/guides/outro.txt").await?;
+    assert_eq!(
+        failedAt(&res).as_deref(),
+        Some("/files/Docs/Guides/outro.txt")
+    );
+    let res = test_get(state, "/files/nowhere%20near.txt").await?;
+    assert_eq!(failedAt(&res).as_deref(), Some("/files/nowhere%20near.txt"));
+    assert_eq!(res.headers["X-Caseproxy-Failure"], "no match");
+    // a file where a directory is expected
+    let res = test_get(state, "/files/docs/guides/intro.txt/more").await?;
+    assert_eq!(res.status, StatusCode::NOT_FOUND);
+    assert_eq!(
+        failedAt(&res).as_deref(),
+        Some("/files/Docs/Guides/intro.txt")
+    );
+    assert_eq!(res.headers["X-Caseproxy-Failure"], "not a directory");
+    let res = test_get(state, "/files/docs/guides/intro.txt").await?;
+    assert_eq!(res.status, StatusCode::OK);
+    assert_eq!(failedAt(&res), None);
+
+    let state = test_state(&root, &["--url-prefix", "/files"]);
+    let res = test_get(state, "/files/docs/missing/intro.txt").await?;
+    assert_eq!(failedAt(&res), None);
+
+    // hidden directories aren't confirmed to exist
+    std::fs::create_dir_all(root.join(".Secret/Inner"))?;
+    let state = test_state(&root, &["--failed-at-header", "--deny-hidden"]);
+    for path in [
+        "/.secret/inner/missing.txt",
+        "/.secret/missing.txt",
+        "/docs/.missing",
+    ] {
+        let res = test_get(state, path).await?;
+        assert_eq!(res.status, StatusCode::NOT_FOUND, "{path}");
+        assert_eq!(failedAt(&res), None, "{path}");
+    }
+    assert_eq!(
+        failedAt(&test_get(state, "/docs/missing.txt").await?).as_deref(),
+        Some("/Docs/missing.txt")
+    );
+    Ok(())
+}
+
+const maxSuggestions: usize = 5;
+// bounds the cost of suggesting names in huge directories
+const maxSuggestionEntries: usize = 10_000;
+
+/// Builds a `404` response listing files named similarly to the missing `path`.
+async fn suggestion_response(state: &'static AppState, path: &Path) -> AResult<Response<ABody>> {
+    let config = &state.config;
+    let notFound = || Ok(status_response(config, StatusCode::NOT_FOUND));
+
+    let (Some(parent), Some(name)) = (path.parent(), path.file_name()) else {
+        return notFound();
+    };
+    if !parent.starts_with(&config.rootPath) {
+        return notFound();
+    }
+    let dir = if parent == config.rootPath {
+        parent.to_path_buf()
+    } else {
+        match resolve_path(state, InsensitivePath(parent.to_path_buf())).await {
+            Ok(dir) => dir,
+            Err(_) => return notFound(),
+        }
+    };
+
+    let name = name.to_os_string();
+    let maxDistance = (name.len() / 4).clamp(1, 3);
+    let searchDir = dir.clone();
+    let similar = tokio::task::spawn_blocking(move || {
+        find_similar_names(
+            &searchDir,
+            &name,
+            maxDistance,
+            maxSuggestions,
+            maxSuggestionEntries,
+        )
+    })
+    .await?;
+    let similar = match similar {
+        Ok(similar) if !similar.is_empty() => similar,
+        _ => return notFound(),
+    };
+
+    let relativeDir = dir.strip_prefix(&config.rootPath)?;
+    let urls: Vec<_> = similar
+        .iter()
+        .map(|name| {
+            let relative = relativeDir.join(name);
+            let encoded = percent_encode_path(relative.as_os_str().as_encoded_bytes());
+            format!("{}{encoded}", config.urlPrefix)
+        })
+        .collect();
+
+    let mut response = error_response(config.errorFormat, StatusCode::NOT_FOUND, &urls);
+    response.headers_mut().insert(
+        "X-Caseproxy-Suggestions",
+        HeaderValue::from_str(&urls.join(", "))?,
+    );
+    Ok(response)
+}
+
+#[tokio::test]
+async fn test_suggestions() -> AResult<()> {
+    let (root, removeRoot) = make_temp_dir()?;
+    std::fs::create_dir_all(root.join("Docs"))?;
+    std::fs::write(root.join("Docs/Report.txt"), "")?;
+    std::fs::write(root.join("Docs/report 1.txt"), "")?;
+    std::fs::write(root.join("Docs/unrelated.txt"), "")?;
+    std::fs::write(root.join("index.html"), "")?;
+
+    let state = test_state(&root, &["--suggest", "--url-prefix", "/files"]);
+    let res = test_get(state, "/files/docs/report1.txt").await?;
+    assert_eq!(res.status, StatusCode::NOT_FOUND);
+    assert_eq!(
+        res.headers["X-Caseproxy-Suggestions"],
+        "/files/Docs/report%201.txt, /files/Docs/Report.txt"
+    );
+    assert_eq!(
+        String::from_utf8(res.body)?,
+        "Not Found\n\nDid you mean:\n/files/Docs/report%201.txt\n/files/Docs/Report.txt\n"
+    );
+
+    let res = test_get(state, "/files/index.htm").await?;
+    assert_eq!(res.headers["X-Caseproxy-Suggestions"], "/files/index.html");
+
+    let res = test_get(state, "/files/docs/nothing-like-it").await?;
+    assert_eq!(res.status, StatusCode::NOT_FOUND);
+    assert!(res.headers.get("X-Caseproxy-Suggestions").is_none());
+
+    let res = test_get(state, "/files/missing/report.txt").await?;
+    assert_eq!(res.status, StatusCode::NOT_FOUND);
+    assert!(res.headers.get("X-Caseproxy-Suggestions").is_none());
+
+    let state = test_state(&root, &["--url-prefix", "/files"]);
+    let res = test_get(state, "/files/docs/reprot.txt").await?;
+    assert_eq!(res.status, StatusCode::NOT_FOUND);
+    assert!(res.headers.get("X-Caseproxy-Suggestions").is_none());
+
+    Ok(())
+}
+
+/// Percent-encodes `path` for use in a URL, leaving `/` separators intact.
+fn percent_encode_path(path: &[u8]) -> String {
+    let mut res = String::with_capacity(path.len());
+    for &byte in path {
+        if byte.is_ascii_alphanumeric() || b"-._~/".contains(&byte) {
+            res.push(byte as char);
+        } else {
+            res.push_str(&format!("%{byte:02X}"));
+        }
+    }
+    res
+}
+
+fn parse_http_version(name: &str) -> AResult<hyper::Version> {
+    match name.trim() {
+        "1.0" => Ok(hyper::Version::HTTP_10),
+        "1.1" => Ok(hyper::Version::HTTP_11),
+        "2" | "2.0" | "3" | "3.0" => Err(anyhow!("only HTTP/1 is supported")),
+        _ => Err(anyhow!("unknown HTTP version")),
+    }
+}
+
+#[tokio::test]
+async fn test_http_versions() -> AResult<()> {
+    let (root, removeRoot) = make_temp_dir()?;
+    std::fs::write(root.join("file.txt"), "file")?;
+    let request = |httpVersion| {
+        format!("GET /file.txt HTTP/{httpVersion}\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+    };
+
+    let state = test_state(&root, &[]);
+    for httpVersion in ["1.0", "1.1"] {
+        let res = send_test_request(state, request(httpVersion).as_bytes()).await?;
+        assert_eq!(res.status, StatusCode::OK, "{httpVersion}");
+    }
+
+    let state = test_state(&root, &["--http-versions", "1.1"]);
+    let res = send_test_request(state, request("1.0").as_bytes()).await?;
+    assert_eq!(res.status, StatusCode::HTTP_VERSION_NOT_SUPPORTED);
+    let res = send_test_request(state, request("1.1").as_bytes()).await?;
+    assert_eq!(res.body, b"file");
+
+    for invalid in ["2", "1.1,2", "0.9", ""] {
+        assert!(
+            Config::try_parse_from(["caseproxy", "--http-versions", invalid]).is_err(),
+            "{invalid}"
+        );
+    }
+    Ok(())
+}
+
+/// Parses an `--alias`, as paths relative to the URL prefix and root.
+fn parse_alias(alias: &str) -> AResult<(PathBuf, PathBuf)> {
+    let (from, to) = alias
+        .split_once('=')
+        .ok_or_else(|| anyhow!("expected `/from=/to`"))?;
+    let relative = |path: &str| -> AResult<PathBuf> {
+        let path = resolve_parents(&Path::new("/").join(path.trim()));
+        let relative = path.strip_prefix("/")?;
+        if relative.as_os_str().is_empty() {
+            return Err(anyhow!("alias paths can't be the root"));
+        }
+        Ok(relative.to_path_buf())
+    };
+    Ok((relative(from)?, relative(to)?))
+}
+
+/// Rewrites `path` (relative to the URL prefix) to its `--alias` target, if
+/// it has one.
+fn resolve_alias(config: &Config, path: PathBuf) -> PathBuf {
+    let requested = InsensitivePath(resolve_parents(&Path::new("/").join(&path)));
+    for (from, to) in &config.aliases {
+        if requested == InsensitivePath(Path::new("/").join(from)) {
+            log!(LogLevel::Debug, "aliased {path:?} to {to:?}");
+            return to.clone();
+        }
+    }
+    path
+}
+
+#[tokio::test]
+async fn test_alias() -> AResult<()> {
+    let (root, removeRoot) = make_temp_dir()?;
+    std::fs::create_dir_all(root.join("tree/Releases"))?;
+    std::fs::write(root.join("tree/Releases/v2.3.1.zip"), "2.3.1")?;
+    std::fs::write(root.join("tree/latest.zip"), "shadowed")?;
+    std::fs::write(root.join("tree/other.txt"), "other")?;
+    std::fs::write(root.join("secret.txt"), "secret")?;
+    let state = test_state(
+        &root.join("tree"),
+        &[
+            "--alias",
+            "/latest.zip=/releases/V2.3.1.zip",
+            "--alias",
+            "docs/=/missing/",
+            "--alias",
+            "/escape.txt=/../secret.txt",
+            "--url-prefix",
+            "/files",
+        ],
+    );
+
+    // the target resolves case-insensitively, shadowing any file at the alias
+    for path in [
+        "/files/latest.zip",
+        "/files/LATEST.zip",
+        "/files/./x/../latest.zip",
+    ] {
+        let res = test_get(state, path).await?;
+        assert_eq!(res.status, StatusCode::OK, "{path}");
+        assert_eq!(res.body, b"2.3.1");
+    }
+    assert_eq!(test_get(state, "/files/other.txt").await?.body, b"other");
+    assert_eq!(
+        test_get(state, "/files/docs").await?.status,
+        StatusCode::NOT_FOUND
+    );
+    assert_eq!(
+        test_get(state, "/files/escape.txt").await?.status,
+        StatusCode::NOT_FOUND
+    );
+
+    assert!(parse_alias("/no-target").is_err());
+    assert!(parse_alias("/=/root").is_err());
+    Ok(())
+}
+
+/// Percent-decodes a request path. Malformed escapes, and NUL or other control
+/// characters (which have no business in a filename), are rejected, as are
+/// escaped slashes, which would otherwise make an absolute path or a `..`
+/// that survives `collapse_slashes`.
+///
+/// Colons are left alone, being ordinary characters in Unix filenames: there
+/// are no NTFS alternate data streams for `name:stream` to open, so such a
+/// path only ever finds a file with that literal name.
+fn decode_request_path(path: &[u8]) -> Option<PathBuf> {
+    let mut res = Vec::with_capacity(path.len());
+    let mut bytes = path.iter();
+    while let Some(&byte) = bytes.next() {
+        let byte = if byte == b'%' {
+            let hex = [*bytes.next()?, *bytes.next()?];
+            u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16)
+                .ok()
+                .filter(|&byte| byte != b'/')?
+        } else {
+            byte
+        };
+        if byte.is_ascii_control() {
+            return None;
+        }
+        res.push(byte);
+    }
+    Some(PathBuf::from(OsString::from_vec(res)))
+}
+
+#[test]
+fn test_decode_request_path() {
+    let decode = |path: &[u8]| decode_request_path(path).map(PathBuf::into_os_string);
+    assert_eq!(decode(b"dir/file.txt").unwrap(), "dir/file.txt");
+    assert_eq!(decode(b"a%20b/c%3fd").unwrap(), "a b/c?d");
+    assert_eq!(decode(b"caf%C3%A9").unwrap(), "caf\u{e9}");
+    assert_eq!(decode(b"%FF").unwrap(), OsString::from_vec(vec![0xff]));
+    assert_eq!(
+        decode(percent_encode_path(b"a b/%?#").as_bytes()).unwrap(),
+        "a b/%?#"
+    );
+
+    for invalid in [&b"%00"[..], b"a%0Ab", b"%1f", b"%7F", b"\x01", b"\t"] {
+        assert_eq!(decode(invalid), None, "{invalid:?}");
+    }
+    for malformed in [&b"%"[..], b"%4", b"%zz", b"%+1"] {
+        assert_eq!(decode(malformed), None, "{malformed:?}");
+    }
+    for escaped in [&b"%2Fetc/passwd"[..], b"..%2F..%2Fetc", b"a%2fb"] {
+        assert_eq!(decode(escaped), None, "{escaped:?}");
+    }
+}
+
+#[tokio::test]
+async fn test_colons_are_literal() -> AResult<()> {
+    let (root, removeRoot) = make_temp_dir()?;
+    std::fs::write(root.join("file.txt"), "file")?;
+    std::fs::write(root.join("Notes:Draft.txt"), "draft")?;
+    let state = test_state(&root, &[]);
+
+    for stream in [
+        "/file.txt:$DATA",
+        "/file.txt::$DATA",
+        "/FILE.TXT%3A%24DATA",
+        "/file.txt:",
+    ] {
+        let res = test_get(state, stream).await?;
+        assert_eq!(res.status, StatusCode::NOT_FOUND, "{stream}");
+    }
+    assert_eq!(test_get(state, "/notes:draft.TXT").await?.body, b"draft");
+    assert_eq!(test_get(state, "/notes%3Adraft.txt").await?.body, b"draft");
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_control_characters_rejected() -> AResult<()> {
+    let (root, removeRoot) = make_temp_dir()?;
+    std::fs::write(root.join("file.txt"), "file")?;
+    std::fs::write(root.join("with space.txt"), "space")?;
+    let state = test_state(&root, &[]);
+
+    let res = test_get(state, "/With%20Space.txt").await?;
+    assert_eq!(res.status, StatusCode::OK);
+    assert_eq!(res.body, b"space");
+
+    for path in [
+        "/file.txt%00",
+        "/file.txt%00.png",
+        "/%0Afile.txt",
+        "/file%7F.txt",
+        "/%",
+    ] {
+        let res = test_get(state, path).await?;
+        assert_eq!(res.status, StatusCode::BAD_REQUEST, "{path}");
+    }
+
+    // rejected by hyper before reaching us
+    let res = send_test_request(
+        state,
+        b"GET /file\x01.txt HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+    )
+    .await?;
+    assert_eq!(res.status, StatusCode::BAD_REQUEST);
+
+    Ok(())
+}
+
+#[test]
+fn test_percent_encode_path() {
+    assert_eq!(percent_encode_path(b"dir/file.txt"), "dir/file.txt");
+    assert_eq!(percent_encode_path(b"a b/c?d#e%"), "a%20b/c%3Fd%23e%25");
+    assert_eq!(percent_encode_path("caf\u{e9}".as_bytes()), "caf%C3%A9");
+    assert_eq!(percent_encode_path(b"\xff"), "%FF");
+}
+
+fn status_response(config: &Config, code: StatusCode) -> Response<ABody> {
+    error_response(config.errorFormat, code, &[])
+}
+
+/// Builds a response describing an error `code`, with a list of suggested URLs
+/// to try instead.
+fn error_response(
+    format: ErrorFormat,
+    code: StatusCode,
+    suggestions: &[String],
+) -> Response<ABody> {
+    let message = code.canonical_reason().unwrap_or("unknown");
+    let (contentType, body) = match format {
+        ErrorFormat::Text => {
+            let mut body = message.to_string();
+            if !suggestions.is_empty() {
+                body.push_str(&format!("\n\nDid you mean:\n{}\n", suggestions.join("\n")));
+            }
+            ("text/plain; charset=utf-8", body)
+        }
+        ErrorFormat::Html => {
+            let title = html_escape(&format!("{} {message}", code.as_u16()));
+            let mut body = format!(
+                "<!DOCTYPE html>\n<html><head><title>{title}</title></head>\n<body><h1>{title}</h1>\n"
+            );
+            if !suggestions.is_empty() {
+                body.push_str("<p>Did you mean:</p>\n<ul>\n");
+                for url in suggestions {
+                    let url = html_escape(url);
+                    body.push_str(&format!("<li><a href=\"{url}\">{url}</a></li>\n"));
+                }
+                body.push_str("</ul>\n");
+            }
+            body.push_str("</body></html>\n");
+            ("text/html; charset=utf-8", body)
+        }
+        ErrorFormat::Json => {
+            let mut body = format!(
+                "{{\"error\":{},\"status\":{}",
+                json::quote(message),
+                code.as_u16()
+            );
+            if !suggestions.is_empty() {
+                let suggestions: Vec<_> = suggestions.iter().map(|url| json::quote(url)).collect();
+                body.push_str(&format!(",\"suggestions\":[{}]", suggestions.join(",")));
+            }
+            body.push('}');
+            ("application/json", body)
+        }
+    };
+
+    let body = Full::new(Bytes::from(body)).map_err(|e| match e {}).boxed();
+    let mut res = Response::new(body);
+    *res.status_mut() = code;
+    res.headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static(contentType));
+    res
+}
+
+#[test]
+fn test_error_response() -> AResult<()> {
+    let body = |res: Response<ABody>| -> AResult<(String, String)> {
+        let contentType = res.headers()[CONTENT_TYPE].to_str()?.to_string();
+        let body = futures_util::FutureExt::now_or_never(res.into_body().collect())
+            .unwrap()?
+            .to_bytes();
+        Ok((contentType, String::from_utf8(body.to_vec())?))
+    };
+    let suggestions = ["/a.txt".to_string(), "/b&c.txt".to_string()];
+
+    let res = error_response(ErrorFormat::Text, StatusCode::NOT_FOUND, &[]);
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    assert_eq!(
+        body(res)?,
+        ("text/plain; charset=utf-8".into(), "Not Found".into())
+    );
+
+    let res = error_response(ErrorFormat::Json, StatusCode::NOT_FOUND, &[]);
+    assert_eq!(
+        body(res)?,
+        (
+            "application/json".into(),
+            r#"{"error":"Not Found","status":404}"#.into()
+        )
+    );
+    let res = error_response(ErrorFormat::Json, StatusCode::FORBIDDEN, &suggestions);
+    assert_eq!(
+        body(res)?.1,
+        r#"{"error":"Forbidden","status":403,"suggestions":["/a.txt","/b&c.txt"]}"#
+    );
+
+    let res = error_response(ErrorFormat::Html, StatusCode::NOT_FOUND, &[]);
+    let (contentType, html) = body(res)?;
+    assert_eq!(contentType, "text/html; charset=utf-8");
+    assert!(html.contains("<title>404 Not Found</title>"));
+    let res = error_response(ErrorFormat::Html, StatusCode::NOT_FOUND, &suggestions);
+    assert!(body(res)?
+        .1
+        .contains(r#"<li><a href="/b&amp;c.txt">/b&amp;c.txt</a></li>"#));
+
+    Ok(())
+}
+
+fn html_escape(str: &str) -> String {
+    let mut res = String::with_capacity(str.len());
+    for char in str.chars() {
+        match char {
+            '&' => res.push_str("&amp;"),
+            '<' => res.push_str("&lt;"),
+            '>' => res.push_str("&gt;"),
+            '"' => res.push_str("&quot;"),
+            '\'' => res.push_str("&#39;"),
+            _ => res.push(char),
+        }
+    }
+    res
+}
+
+#[cfg(test)]
+use caseproxy::make_temp_dir;
+
+/// Builds server state as if the given arguments were passed on the command line.
+#[cfg(test)]
+fn test_state(rootPath: &Path, args: &[&str]) -> &'static AppState {
+    let mut fullArgs = vec![
+        "caseproxy".into(),
+        "--root-path".into(),
+        rootPath.as_os_str().to_owned(),
+    ];
+    fullArgs.extend(args.iter().map(Into::into));
+    let mut config = Config::try_parse_from(fullArgs).unwrap();
+    config.normalize();
+    Box::leak(Box::new(AppState::new(config).unwrap()))
+}
+
+#[cfg(test)]
+#[derive(Debug)]
+struct TestResponse {
+    status: StatusCode,
+    headers: hyper::HeaderMap,
+    body: Vec<u8>,
+    trailers: hyper::HeaderMap,
+}
+
+/// Sends a raw HTTP/1.1 request to a connection served by `handle_request`,
+/// returning the response once the server closes the connection.
+#[cfg(test)]
+async fn send_test_request(state: &'static AppState, request: &[u8]) -> AResult<TestResponse> {
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let (mut client, server) = tokio::io::duplex(1 << 16);
+    tokio::task::spawn(http1::Builder::new().serve_connection(
+        TokioIo::new(server),
+        service_fn(|req| handle_request(state, None, req)),
+    ));
+    client.write_all(request).await?;
+
+    let mut raw = Vec::new();
+    tokio::time::timeout(Duration::from_secs(5), client.read_to_end(&mut raw))
+        .await
+        .context("timed out waiting for response")??;
+
+    let mut headers = [httparse::EMPTY_HEADER; 64];
+    let mut parsed = httparse::Response::new(&mut headers);
+    let httparse::Status::Complete(headerLength) = parsed.parse(&raw)? else {
+        return Err(anyhow!("incomplete response: {raw:?}"));
+    };
+    let mut response = TestResponse {
+        status: StatusCode::from_u16(parsed.code.unwrap())?,
+        headers: hyper::HeaderMap::new(),
+        body: raw[headerLength..].to_vec(),
+        trailers: hyper::HeaderMap::new(),
+    };
+    for header in parsed.headers {
+        response.headers.append(
+            hyper::header::HeaderName::from_bytes(header.name.as_bytes())?,
+            HeaderValue::from_bytes(header.value)?,
+        );
+    }
+    if response
+        .headers
+        .get("Transfer-Encoding")
+        .is_some_and(|v| v == "chunked")
+    {
+        (response.body, response.trailers) = decode_chunked(&response.body)?;
+    }
+    Ok(response)
+}
+
+#[cfg(test)]
+fn decode_chunked(mut raw: &[u8]) -> AResult<(Vec<u8>, hyper::HeaderMap)> {
+    let mut body = Vec::new();
+    loop {
+        let httparse::Status::Complete((offset, size)) =
+            httparse::parse_chunk_size(raw).map_err(|_| anyhow!("invalid chunk size"))?
+        else {
+            return Err(anyhow!("truncated chunked body"));
+        };
+        let size = size as usize;
+        if size == 0 {
+            raw = &raw[offset..];
+            break;
+        }
+        body.extend(&raw[offset..offset + size]);
+        raw = &raw[offset + size + 2..];
+    }
+
+    let mut trailers = hyper::HeaderMap::new();
+    let mut headers = [httparse::EMPTY_HEADER; 16];
+    let httparse::Status::Complete((_, parsed)) = httparse::parse_headers(raw, &mut headers)?
+    else {
+        return Err(anyhow!("truncated trailers"));
+    };
+    for header in parsed {
+        trailers.append(
+            HeaderName::from_bytes(header.name.as_bytes())?,
+            HeaderValue::from_bytes(header.value)?,
+        );
+    }
+    Ok((body, trailers))
+}
+
+#[cfg(test)]
+async fn test_get(state: &'static AppState, path: &str) -> AResult<TestResponse> {
+    let request = format!("GET {path} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n");
+    send_test_request(state, request.as_bytes()).await
 }
@@ -0,0 +1,360 @@
+//! Prebuilt mappings from case-folded paths to their casing on disk, letting
+//! immutable deployments resolve paths without walking the filesystem.
+
+use std::{
+    collections::{btree_map::Entry, BTreeMap, HashMap, VecDeque},
+    fmt::Write,
+    fs::read_dir,
+    path::{Component, Path, PathBuf},
+};
+
+use anyhow::{anyhow, Context, Ok};
+
+use crate::{json, AResult, InsensitivePath};
+
+/// Leads manifests in `manifest_to_binary`'s format, telling them apart from JSON.
+pub const binaryMagic: &[u8; 8] = b"CPMANIF1";
+
+/// Maps case-folded paths relative to the root to their real relative paths.
+pub struct Manifest(Entries);
+
+enum Entries {
+    Map(HashMap<String, PathBuf>),
+    Binary(BinaryManifest),
+}
+
+impl Manifest {
+    /// Loads a manifest in either format.
+    pub fn load(path: &Path) -> AResult<Self> {
+        let data =
+            std::fs::read(path).with_context(|| format!("couldn't read manifest {path:?}"))?;
+        let manifest = match data.starts_with(binaryMagic) {
+            true => Self::from_binary(data),
+            false => String::from_utf8(data)
+                .map_err(anyhow::Error::from)
+                .and_then(|json| Self::from_json(&json)),
+        };
+        manifest.with_context(|| format!("invalid manifest {path:?}"))
+    }
+
+    pub fn from_json(json: &str) -> AResult<Self> {
+        let entries = json::parse_string_map(json)?
+            .into_iter()
+            .map(|(key, path)| (key, PathBuf::from(path)))
+            .collect();
+        Ok(Self(Entries::Map(entries)))
+    }
+
+    /// Checks `data` is a well-formed binary manifest, which is then looked up
+    /// in place rather than parsed into a map.
+    pub fn from_binary(data: Vec<u8>) -> AResult<Self> {
+        Ok(Self(Entries::Binary(BinaryManifest::new(data)?)))
+    }
+
+    /// Looks up the real casing of `path`, relative to the root.
+    pub fn get(&self, path: &Path) -> Option<&Path> {
+        let key = manifest_key(path)?;
+        match &self.0 {
+            Entries::Map(entries) => entries.get(&key).map(PathBuf::as_path),
+            Entries::Binary(manifest) => manifest.get(&key),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match &self.0 {
+            Entries::Map(entries) => entries.len(),
+            Entries::Binary(manifest) => manifest.len,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// A binary manifest: `binaryMagic`, the entry count as a `u64`, a `u64` offset
+/// for each entry, then the entries, each a `u32` length and bytes of its key
+/// followed by the same for its path. All integers are little-endian, and
+/// entries are sorted by key so they can be binary searched.
+struct BinaryManifest {
+    data: Vec<u8>,
+    len: usize,
+}
+
+impl BinaryManifest {
+    const headerSize: usize = binaryMagic.len() + 8;
+
+    fn new(data: Vec<u8>) -> AResult<Self> {
+        if !data.starts_with(binaryMagic) {
+            return Err(anyhow!("not a binary manifest"));
+        }
+        let len = read_u64(&data, binaryMagic.len())
+            .and_then(|len| usize::try_from(len).ok())
+            .ok_or_else(|| anyhow!("truncated header"))?;
+        if len.saturating_mul(8) > data.len() - Self::headerSize {
+            return Err(anyhow!("truncated offsets"));
+        }
+
+        let manifest = Self { data, len };
+        let mut previous: Option<&str> = None;
+        for index in 0..len {
+            let (key, _) = manifest
+                .entry(index)
+                .ok_or_else(|| anyhow!("invalid entry {index}"))?;
+            if previous.is_some_and(|previous| previous >= key) {
+                return Err(anyhow!("entries aren't sorted by key"));
+            }
+            previous = Some(key);
+        }
+        Ok(manifest)
+    }
+
+    /// The key and path of the `index`th entry, if it's in bounds and valid UTF-8.
+    fn entry(&self, index: usize) -> Option<(&str, &str)> {
+        let offset = read_u64(&self.data, Self::headerSize + index * 8)?;
+        let (key, rest) = read_bytes(&self.data, usize::try_from(offset).ok()?)?;
+        let (path, _) = read_bytes(&self.data, rest)?;
+        Some((
+            std::str::from_utf8(key).ok()?,
+            std::str::from_utf8(path).ok()?,
+        ))
+    }
+
+    fn get(&self, key: &str) -> Option<&Path> {
+        let (mut low, mut high) = (0, self.len);
+        while low < high {
+            let middle = low + (high - low) / 2;
+            let (entryKey, path) = self.entry(middle)?;
+            match entryKey.cmp(key) {
+                std::cmp::Ordering::Less => low = middle + 1,
+                std::cmp::Ordering::Greater => high = middle,
+                std::cmp::Ordering::Equal => return Some(Path::new(path)),
+            }
+        }
+        None
+    }
+}
+
+fn read_u64(data: &[u8], offset: usize) -> Option<u64> {
+    let bytes = data.get(offset..offset.checked_add(8)?)?;
+    Some(u64::from_le_bytes(bytes.try_into().ok()?))
+}
+
+/// Reads a length-prefixed byte string at `offset`, returning it and the offset
+/// just past it.
+fn read_bytes(data: &[u8], offset: usize) -> Option<(&[u8], usize)> {
+    let length = data.get(offset..offset.checked_add(4)?)?;
+    let length = u32::from_le_bytes(length.try_into().ok()?) as usize;
+    let start = offset + 4;
+    let end = start.checked_add(length)?;
+    Some((data.get(start..end)?, end))
+}
+
+/// The key `path` (relative to the root) is stored under: its components
+/// case-folded and joined with `/`. Paths which aren't valid UTF-8, or include
+/// anything but plain names, have no key.
+pub fn manifest_key(path: &Path) -> Option<String> {
+    let mut key = String::new();
+    for component in path.components() {
+        let Component::Normal(name) = component else {
+            return None;
+        };
+        if !key.is_empty() {
+            key.push('/');
+        }
+        key.extend(name.to_str()?.chars().flat_map(char::to_lowercase));
+    }
+    (!key.is_empty()).then_some(key)
+}
+
+#[test]
+fn test_manifest_key() {
+    assert_eq!(
+        manifest_key(Path::new("Foo/BAR.txt")).unwrap(),
+        "foo/bar.txt"
+    );
+    assert_eq!(manifest_key(Path::new("foo//bar/")).unwrap(), "foo/bar");
+    assert_eq!(manifest_key(Path::new("\u{130}")).unwrap(), "i\u{307}");
+    assert_eq!(manifest_key(Path::new("/foo")), None);
+    assert_eq!(manifest_key(Path::new("../foo")), None);
+    assert_eq!(manifest_key(Path::new("")), None);
+}
+
+pub struct BuiltManifest {
+    /// Manifest keys mapped to real paths, relative to the root.
+    pub entries: BTreeMap<String, String>,
+    /// Paths left out because another path differing only in case took their key.
+    pub collisions: Vec<PathBuf>,
+    /// Paths left out because they aren't valid UTF-8.
+    pub skipped: Vec<PathBuf>,
+}
+
+/// Walks `root`, recording every file and directory under it. Where several
+/// paths differ only in case, the first in sorted order is recorded.
+pub fn build_manifest(root: &Path) -> AResult<BuiltManifest> {
+    let mut paths = vec![];
+    let mut queue = VecDeque::new();
+    queue.push_back(PathBuf::new());
+    while let Some(dir) = queue.pop_front() {
+        for entry in read_dir(root.join(&dir))? {
+            let entry = entry?;
+            let path = dir.join(entry.file_name());
+            if entry.file_type()?.is_dir() {
+                queue.push_back(path.clone());
+            }
+            paths.push(InsensitivePath(path));
+        }
+    }
+    paths.sort_by(|l, r| l.cmp(r).then_with(|| l.0.cmp(&r.0)));
+
+    let mut manifest = BuiltManifest {
+        entries: BTreeMap::new(),
+        collisions: vec![],
+        skipped: vec![],
+    };
+    for InsensitivePath(path) in paths {
+        let (Some(key), Some(real)) = (manifest_key(&path), path.to_str()) else {
+            manifest.skipped.push(path);
+            continue;
+        };
+        match manifest.entries.entry(key) {
+            Entry::Occupied(_) => manifest.collisions.push(path),
+            Entry::Vacant(entry) => {
+                entry.insert(real.to_string());
+            }
+        }
+    }
+    Ok(manifest)
+}
+
+/// Formats manifest entries as a JSON object, one entry per line.
+pub fn manifest_to_json(entries: &BTreeMap<String, String>) -> String {
+    let mut res = String::from("{\n");
+    for (index, (key, path)) in entries.iter().enumerate() {
+        let separator = if index + 1 < entries.len() { "," } else { "" };
+        writeln!(
+            &mut res,
+            "  {}: {}{separator}",
+            json::quote(key),
+            json::quote(path)
+        )
+        .unwrap();
+    }
+    res.push_str("}\n");
+    res
+}
+
+/// Encodes manifest entries in the binary format `Manifest::load` also accepts,
+/// which loads without parsing, for trees with millions of paths.
+pub fn manifest_to_binary(entries: &BTreeMap<String, String>) -> Vec<u8> {
+    let mut res = binaryMagic.to_vec();
+    res.extend((entries.len() as u64).to_le_bytes());
+    let mut offset = res.len() + entries.len() * 8;
+    for (key, path) in entries {
+        res.extend((offset as u64).to_le_bytes());
+        offset += 8 + key.len() + path.len();
+    }
+    for (key, path) in entries {
+        for bytes in [key, path] {
+            res.extend((bytes.len() as u32).to_le_bytes());
+            res.extend(bytes.as_bytes());
+        }
+    }
+    res
+}
+
+#[test]
+fn test_binary_manifest() -> AResult<()> {
+    let entries: BTreeMap<String, String> = [
+        ("docs", "Docs"),
+        ("docs/readme.md", "Docs/README.md"),
+        ("\u{e9}t\u{e9}.txt", "\u{c9}t\u{e9}.txt"),
+        ("top.txt", "top.TXT"),
+    ]
+    .into_iter()
+    .map(|(key, path)| (key.to_string(), path.to_string()))
+    .collect();
+    let data = manifest_to_binary(&entries);
+
+    let manifest = Manifest::from_binary(data.clone())?;
+    assert_eq!(manifest.len(), 4);
+    for (key, path) in &entries {
+        assert_eq!(manifest.get(Path::new(key)), Some(Path::new(path)));
+    }
+    assert_eq!(
+        manifest.get(Path::new("DOCS/Readme.md")),
+        Some(Path::new("Docs/README.md"))
+    );
+    assert_eq!(manifest.get(Path::new("a")), None);
+    assert_eq!(manifest.get(Path::new("docs/missing")), None);
+    assert_eq!(manifest.get(Path::new("zzz")), None);
+
+    let empty = Manifest::from_binary(manifest_to_binary(&BTreeMap::new()))?;
+    assert!(empty.is_empty());
+    assert_eq!(empty.get(Path::new("docs")), None);
+
+    // truncated anywhere
+    for length in 0..data.len() {
+        assert!(Manifest::from_binary(data[..length].to_vec()).is_err());
+    }
+    // offsets pointing past the end
+    let mut corrupt = data.clone();
+    corrupt[BinaryManifest::headerSize + 8] = 0xff;
+    assert!(Manifest::from_binary(corrupt).is_err());
+    // entries out of order
+    let mut swapped = data.clone();
+    let offsets = BinaryManifest::headerSize..BinaryManifest::headerSize + 16;
+    swapped[offsets.clone()].rotate_left(8);
+    assert!(Manifest::from_binary(swapped).is_err());
+    Ok(())
+}
+
+#[test]
+fn test_build_manifest() -> AResult<()> {
+    use crate::make_temp_dir;
+
+    let (tempdir, removeTempdir) = make_temp_dir()?;
+
+    let file = |path: &str| -> AResult<()> {
+        let fullPath = tempdir.join(path);
+        std::fs::create_dir_all(fullPath.parent().unwrap())?;
+        std::fs::write(fullPath, "")?;
+        Ok(())
+    };
+    file("Docs/README.md")?;
+    file("docs/readme.md")?;
+    file("top.TXT")?;
+
+    let built = build_manifest(&tempdir)?;
+    assert_eq!(
+        built.entries.iter().collect::<Vec<_>>(),
+        vec![
+            (&"docs".to_string(), &"Docs".to_string()),
+            (&"docs/readme.md".to_string(), &"Docs/README.md".to_string()),
+            (&"top.txt".to_string(), &"top.TXT".to_string()),
+        ]
+    );
+    assert_eq!(
+        built.collisions,
+        vec![PathBuf::from("docs"), PathBuf::from("docs/readme.md")]
+    );
+
+    let manifest = Manifest::from_json(&manifest_to_json(&built.entries))?;
+    assert_eq!(manifest.len(), 3);
+    assert_eq!(
+        manifest.get(Path::new("DOCS/Readme.md")),
+        Some(Path::new("Docs/README.md"))
+    );
+    assert_eq!(manifest.get(Path::new("missing")), None);
+
+    let manifestPath = tempdir.join("manifest.bin");
+    std::fs::write(&manifestPath, manifest_to_binary(&built.entries))?;
+    let manifest = Manifest::load(&manifestPath)?;
+    assert_eq!(manifest.len(), 3);
+    assert_eq!(
+        manifest.get(Path::new("DOCS/Readme.md")),
+        Some(Path::new("Docs/README.md"))
+    );
+
+    Ok(())
+}
@@ -0,0 +1,110 @@
+//! `Content-Type` by file extension, from a built-in table of common types
+//! which a `mime.types` file can add to or override.
+
+use std::{collections::HashMap, ffi::OsStr, path::Path};
+
+use anyhow::Context;
+use caseproxy::{AResult, InsensitiveOsString};
+
+const builtinTypes: &[(&str, &str)] = &[
+    ("html", "text/html"),
+    ("htm", "text/html"),
+    ("css", "text/css"),
+    ("js", "text/javascript"),
+    ("mjs", "text/javascript"),
+    ("json", "application/json"),
+    ("xml", "application/xml"),
+    ("txt", "text/plain"),
+    ("md", "text/markdown"),
+    ("csv", "text/csv"),
+    ("png", "image/png"),
+    ("jpg", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("gif", "image/gif"),
+    ("webp", "image/webp"),
+    ("svg", "image/svg+xml"),
+    ("ico", "image/vnd.microsoft.icon"),
+    ("wasm", "application/wasm"),
+    ("pdf", "application/pdf"),
+    ("zip", "application/zip"),
+    ("gz", "application/gzip"),
+    ("tar", "application/x-tar"),
+    ("woff", "font/woff"),
+    ("woff2", "font/woff2"),
+    ("mp3", "audio/mpeg"),
+    ("ogg", "audio/ogg"),
+    ("wav", "audio/wav"),
+    ("mp4", "video/mp4"),
+    ("webm", "video/webm"),
+];
+
+/// Types by extension, matched case-insensitively as names are.
+pub struct MimeTypes(HashMap<InsensitiveOsString, String>);
+
+impl MimeTypes {
+    /// The built-in table, extended by the `mime.types` file `typesFile`.
+    pub fn new(typesFile: Option<&Path>) -> AResult<Self> {
+        let mut types: HashMap<_, _> = builtinTypes
+            .iter()
+            .map(|(ext, contentType)| (InsensitiveOsString(ext.into()), contentType.to_string()))
+            .collect();
+        if let Some(path) = typesFile {
+            let contents = std::fs::read_to_string(path)
+                .with_context(|| format!("couldn't read types file {path:?}"))?;
+            types.extend(parse_types(&contents));
+        }
+        Ok(Self(types))
+    }
+
+    /// Type of `path`, by its extension.
+    pub fn get(&self, path: &Path) -> Option<&str> {
+        let ext = path.extension()?;
+        self.0
+            .get(&InsensitiveOsString(ext.to_os_string()))
+            .map(String::as_str)
+    }
+}
+
+/// Parses `mime.types` lines, each a type followed by its extensions, with
+/// `#` starting comments.
+fn parse_types(contents: &str) -> impl Iterator<Item = (InsensitiveOsString, String)> + '_ {
+    contents.lines().flat_map(|line| {
+        let line = line.split('#').next().unwrap_or_default();
+        let mut fields = line.split_whitespace();
+        let contentType = fields.next().unwrap_or_default();
+        fields.map(move |ext| {
+            let ext = OsStr::new(ext.trim_start_matches('.')).to_os_string();
+            (InsensitiveOsString(ext), contentType.to_string())
+        })
+    })
+}
+
+#[test]
+fn test_mime_types() -> AResult<()> {
+    let types = MimeTypes::new(None)?;
+    assert_eq!(types.get(Path::new("a/index.html")), Some("text/html"));
+    assert_eq!(types.get(Path::new("a/INDEX.HTML")), Some("text/html"));
+    assert_eq!(types.get(Path::new("app.Wasm")), Some("application/wasm"));
+    assert_eq!(
+        types.get(Path::new("archive.tar.gz")),
+        Some("application/gzip")
+    );
+    assert_eq!(types.get(Path::new("data.unknown")), None);
+    assert_eq!(types.get(Path::new("README")), None);
+    assert_eq!(types.get(Path::new(".html")), None);
+
+    let parsed: Vec<_> = parse_types(
+        "# comment\ntext/x-custom  cst  .CST2\n\napplication/x-empty\ntext/plain txt # text\n",
+    )
+    .map(|(ext, contentType)| (ext.0.into_string().unwrap(), contentType))
+    .collect();
+    assert_eq!(
+        parsed,
+        [
+            ("cst".into(), "text/x-custom".into()),
+            ("CST2".into(), "text/x-custom".into()),
+            ("txt".into(), "text/plain".into()),
+        ]
+    );
+    Ok(())
+}
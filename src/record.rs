@@ -0,0 +1,119 @@
+//! Recording how requests resolve, and replaying recordings against a running
+//! server to catch resolution changing after the tree does.
+//!
+//! Only built with the `record` feature.
+
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, Write},
+    path::Path,
+    sync::Mutex,
+    time::Duration,
+};
+
+use anyhow::{anyhow, Context};
+use caseproxy::{json, AResult};
+use futures_util::StreamExt;
+
+use crate::upstream::Upstream;
+
+/// Name of the recording written to the `--record` directory.
+pub const recordingName: &str = "requests.jsonl";
+
+/// Header telling `--replay` which file (relative to the root) a response served.
+pub const resolvedFileHeader: &str = "X-Caseproxy-File";
+
+/// How long `--replay` waits on the server before giving up on a request.
+const replayTimeout: Duration = Duration::from_secs(30);
+
+pub struct Recorder {
+    file: Mutex<File>,
+}
+
+impl Recorder {
+    pub fn open(dir: &Path) -> AResult<Self> {
+        std::fs::create_dir_all(dir)?;
+        let path = dir.join(recordingName);
+        let file = File::options()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .with_context(|| format!("couldn't open recording {path:?}"))?;
+        Ok(Self {
+            file: Mutex::new(file),
+        })
+    }
+
+    /// Appends a request for `path` (as requested, still percent-encoded),
+    /// which got `status` and served `file` (relative to the root), if any.
+    pub fn record(&self, method: &str, path: &str, status: u16, file: Option<&str>) -> AResult<()> {
+        let line = format!(
+            "{{\"method\":{},\"path\":{},\"status\":\"{status}\",\"file\":{}}}\n",
+            json::quote(method),
+            json::quote(path),
+            json::quote(file.unwrap_or_default())
+        );
+        self.file.lock().unwrap().write_all(line.as_bytes())?;
+        Ok(())
+    }
+}
+
+pub struct Recorded {
+    pub method: String,
+    pub path: String,
+    pub status: u16,
+    pub file: String,
+}
+
+pub fn read_recording(recording: &Path) -> AResult<Vec<Recorded>> {
+    let reader = BufReader::new(
+        File::open(recording).with_context(|| format!("couldn't open {recording:?}"))?,
+    );
+    let mut res = vec![];
+    for (index, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let parse = || -> AResult<Recorded> {
+            let mut entry = json::parse_string_map(&line)?;
+            let mut field = |name| {
+                entry
+                    .remove(name)
+                    .ok_or_else(|| anyhow!("missing {name:?}"))
+            };
+            Ok(Recorded {
+                method: field("method")?,
+                path: field("path")?,
+                status: field("status")?.parse()?,
+                file: field("file")?,
+            })
+        };
+        res.push(parse().with_context(|| format!("invalid entry on line {}", index + 1))?);
+    }
+    Ok(res)
+}
+
+/// Re-issues each recorded `GET` against `server`, printing those that no
+/// longer get the same status and file. Returns how many differed.
+pub async fn replay(recording: &Path, server: &Upstream) -> AResult<usize> {
+    let mut mismatches = 0;
+    for entry in read_recording(recording)? {
+        if entry.method != "GET" {
+            continue;
+        }
+        let res = server.get(&entry.path, replayTimeout).await?;
+        let file = res.resolvedFile.unwrap_or_default();
+        // drain the body so the connection closes cleanly
+        res.body.for_each(|_| async {}).await;
+
+        if res.status != entry.status || file != entry.file {
+            mismatches += 1;
+            println!(
+                "{}: recorded {} {:?}, now {} {file:?}",
+                entry.path, entry.status, entry.file, res.status
+            );
+        }
+    }
+    Ok(mismatches)
+}
@@ -0,0 +1,71 @@
+//! Content type detection from the leading bytes of a file, for files whose
+//! name doesn't say what they are.
+
+use std::path::Path;
+
+use caseproxy::AResult;
+use tokio::io::AsyncReadExt;
+
+/// How much of a file is read for sniffing.
+pub const sniffLength: usize = 512;
+
+/// Type used when sniffing doesn't recognize anything.
+pub const defaultContentType: &str = "application/octet-stream";
+
+const signatures: &[(&[u8], &str)] = &[
+    (b"\x89PNG\r\n\x1a\n", "image/png"),
+    (b"\xff\xd8\xff", "image/jpeg"),
+    (b"GIF87a", "image/gif"),
+    (b"GIF89a", "image/gif"),
+    (b"%PDF-", "application/pdf"),
+    (b"PK\x03\x04", "application/zip"),
+    (b"\x1f\x8b", "application/gzip"),
+    (b"\0asm", "application/wasm"),
+    (b"wOFF", "font/woff"),
+    (b"wOF2", "font/woff2"),
+    (b"OggS", "audio/ogg"),
+    (b"ID3", "audio/mpeg"),
+    (b"\x1aE\xdf\xa3", "video/webm"),
+    // a UTF-8 byte order mark
+    (b"\xef\xbb\xbf", "text/plain; charset=utf-8"),
+];
+
+/// Guesses the content type of a file beginning with `data`.
+pub fn sniff(data: &[u8]) -> Option<&'static str> {
+    if let Some((_, contentType)) = signatures
+        .iter()
+        .find(|(signature, _)| data.starts_with(signature))
+    {
+        return Some(contentType);
+    }
+    // formats with a container header before their signature
+    match data {
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P', ..] => Some("image/webp"),
+        [_, _, _, _, b'f', b't', b'y', b'p', ..] => Some("video/mp4"),
+        _ => None,
+    }
+}
+
+#[test]
+fn test_sniff() {
+    assert_eq!(sniff(b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR"), Some("image/png"));
+    assert_eq!(sniff(b"%PDF-1.7\n"), Some("application/pdf"));
+    assert_eq!(sniff(b"RIFF\x24\0\0\0WEBPVP8 "), Some("image/webp"));
+    assert_eq!(sniff(b"RIFF\x24\0\0\0WAVEfmt "), None);
+    assert_eq!(sniff(b"\0\0\0\x18ftypmp42"), Some("video/mp4"));
+    assert_eq!(
+        sniff(b"\xef\xbb\xbfhello"),
+        Some("text/plain; charset=utf-8")
+    );
+    assert_eq!(sniff(b"\x89PN"), None);
+    assert_eq!(sniff(b"plain text"), None);
+    assert_eq!(sniff(b""), None);
+}
+
+/// Sniffs the content type of `path`, reading at most `sniffLength` bytes.
+pub async fn sniff_file(path: &Path) -> AResult<&'static str> {
+    let file = tokio::fs::File::open(path).await?;
+    let mut data = Vec::with_capacity(sniffLength);
+    file.take(sniffLength as u64).read_to_end(&mut data).await?;
+    Ok(sniff(&data).unwrap_or(defaultContentType))
+}
@@ -0,0 +1,272 @@
+//! Minimal writer for streaming directories as ustar archives.
+
+use std::{
+    ffi::OsString,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use anyhow::anyhow;
+use caseproxy::{log, AResult, InsensitiveOsString, LogLevel};
+use futures_util::StreamExt;
+use hyper::body::Bytes;
+use tokio::{io::AsyncReadExt, sync::mpsc};
+use tokio_util::io::ReaderStream;
+
+const blockSize: usize = 512;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EntryKind {
+    File,
+    Directory,
+}
+
+impl EntryKind {
+    fn typeflag(self) -> u8 {
+        match self {
+            EntryKind::File => b'0',
+            EntryKind::Directory => b'5',
+        }
+    }
+}
+
+/// Builds the header for an archive entry. Names too long for a ustar header
+/// are preceded by a GNU long name entry.
+pub fn entry_header(
+    name: &[u8],
+    kind: EntryKind,
+    size: u64,
+    mode: u32,
+    mtime: u64,
+) -> AResult<Vec<u8>> {
+    let mut res = Vec::with_capacity(blockSize);
+    let (prefix, name) = match split_name(name) {
+        Some(split) => split,
+        None => {
+            let mut longName = name.to_vec();
+            longName.push(0);
+            res.extend(header_block(
+                b"././@LongLink",
+                b"",
+                b'L',
+                longName.len() as u64,
+                0o644,
+                0,
+            )?);
+            let padLength = padding(longName.len() as u64);
+            res.extend(longName);
+            res.resize(res.len() + padLength, 0);
+            (&b""[..], &name[..100])
+        }
+    };
+    res.extend(header_block(
+        name,
+        prefix,
+        kind.typeflag(),
+        size,
+        mode,
+        mtime,
+    )?);
+    Ok(res)
+}
+
+/// Splits `name` into the prefix and name fields of a ustar header, if it fits.
+fn split_name(name: &[u8]) -> Option<(&[u8], &[u8])> {
+    if name.len() <= 100 {
+        return Some((b"", name));
+    }
+
+    // ignore a trailing slash, the name field can't be empty
+    let searchable = &name[..name.len() - 1];
+    searchable
+        .iter()
+        .enumerate()
+        .filter(|&(index, &byte)| byte == b'/' && index <= 155 && name.len() - index - 1 <= 100)
+        .map(|(index, _)| (&name[..index], &name[index + 1..]))
+        .next()
+}
+
+fn header_block(
+    name: &[u8],
+    prefix: &[u8],
+    typeflag: u8,
+    size: u64,
+    mode: u32,
+    mtime: u64,
+) -> AResult<[u8; blockSize]> {
+    let mut block = [0u8; blockSize];
+    block[..name.len()].copy_from_slice(name);
+    write_octal(&mut block[100..108], mode as u64)?;
+    write_octal(&mut block[108..116], 0)?; // uid
+    write_octal(&mut block[116..124], 0)?; // gid
+    write_size(&mut block[124..136], size);
+    write_octal(&mut block[136..148], mtime)?;
+    block[156] = typeflag;
+    block[257..263].copy_from_slice(b"ustar\0");
+    block[263..265].copy_from_slice(b"00");
+    block[345..345 + prefix.len()].copy_from_slice(prefix);
+
+    // checksum is computed with its own field filled with spaces
+    block[148..156].fill(b' ');
+    let checksum: u32 = block.iter().map(|&byte| byte as u32).sum();
+    write_octal(&mut block[148..155], checksum as u64)?;
+    Ok(block)
+}
+
+/// Writes `value` as zero-padded, NUL-terminated octal filling `field`.
+fn write_octal(field: &mut [u8], value: u64) -> AResult<()> {
+    let digits = field.len() - 1;
+    let octal = format!("{value:0digits$o}");
+    if octal.len() > digits {
+        return Err(anyhow!("{value} doesn't fit in a {digits} digit tar field"));
+    }
+    field[..digits].copy_from_slice(octal.as_bytes());
+    field[digits] = 0;
+    Ok(())
+}
+
+fn write_size(field: &mut [u8], size: u64) {
+    if write_octal(field, size).is_err() {
+        // GNU base-256 extension for files of 8GiB and up
+        field.fill(0);
+        field[0] = 0x80;
+        let len = field.len();
+        field[len - 8..].copy_from_slice(&size.to_be_bytes());
+    }
+}
+
+/// Number of zero bytes needed to pad `size` bytes of data to a whole block.
+pub fn padding(size: u64) -> usize {
+    (blockSize - (size % blockSize as u64) as usize) % blockSize
+}
+
+#[test]
+fn test_entry_header() -> AResult<()> {
+    let header = entry_header(b"dir/file.txt", EntryKind::File, 1234, 0o644, 1700000000)?;
+    assert_eq!(header.len(), blockSize);
+    assert_eq!(&header[..12], b"dir/file.txt");
+    assert_eq!(&header[124..136], b"00000002322\0");
+    assert_eq!(header[156], b'0');
+    assert_eq!(&header[257..263], b"ustar\0");
+
+    let checksum: u32 = header
+        .iter()
+        .enumerate()
+        .map(|(index, &byte)| if (148..156).contains(&index) { b' ' } else { byte } as u32)
+        .sum();
+    assert_eq!(&header[148..156], format!("{checksum:06o}\0 ").as_bytes());
+
+    // split across prefix and name fields
+    let name = format!("{}/{}", "a".repeat(120), "b".repeat(90));
+    let header = entry_header(name.as_bytes(), EntryKind::File, 0, 0o644, 0)?;
+    assert_eq!(header.len(), blockSize);
+    assert_eq!(&header[..90], "b".repeat(90).as_bytes());
+    assert_eq!(&header[345..465], "a".repeat(120).as_bytes());
+
+    // too long for ustar
+    let name = "c".repeat(300);
+    let header = entry_header(name.as_bytes(), EntryKind::Directory, 0, 0o755, 0)?;
+    assert_eq!(header.len(), blockSize * 3);
+    assert_eq!(header[156], b'L');
+    assert_eq!(&header[blockSize..blockSize + 300], name.as_bytes());
+    assert_eq!(header[2 * blockSize + 156], b'5');
+
+    assert_eq!(padding(0), 0);
+    assert_eq!(padding(1), 511);
+    assert_eq!(padding(512), 0);
+
+    Ok(())
+}
+
+/// Streams an archive of `dir` and everything under it into `sink`, with
+/// entries named relative to the parent of `dir`.
+///
+/// Only regular files and directories are included, symlinks and special files
+/// are skipped, as are dotfiles if `skipHidden` is set.
+pub async fn write_archive(dir: PathBuf, skipHidden: bool, sink: mpsc::Sender<AResult<Bytes>>) {
+    if let Err(err) = write_archive_entries(&dir, skipHidden, &sink).await {
+        // the client may be gone by now, in which case there's nobody to tell
+        let _ = sink.send(Err(err)).await;
+    }
+}
+
+async fn write_archive_entries(
+    dir: &Path,
+    skipHidden: bool,
+    sink: &mpsc::Sender<AResult<Bytes>>,
+) -> AResult<()> {
+    let send = |chunk: Bytes| async move {
+        sink.send(Ok(chunk))
+            .await
+            .map_err(|_| anyhow!("archive receiver went away"))
+    };
+
+    let baseName = dir
+        .file_name()
+        .ok_or_else(|| anyhow!("can't archive {dir:?} without a name"))?;
+    let mut stack = vec![(dir.to_path_buf(), baseName.to_os_string())];
+    while let Some((path, name)) = stack.pop() {
+        let metadata = tokio::fs::symlink_metadata(&path).await?;
+        let mode = metadata.permissions().mode() & 0o7777;
+        let mtime = metadata
+            .modified()?
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|time| time.as_secs())
+            .unwrap_or(0);
+
+        if metadata.is_dir() {
+            let mut dirName = name.as_encoded_bytes().to_vec();
+            dirName.push(b'/');
+            send(entry_header(&dirName, EntryKind::Directory, 0, mode, mtime)?.into()).await?;
+
+            let mut entries = vec![];
+            let mut reader = tokio::fs::read_dir(&path).await?;
+            while let Some(entry) = reader.next_entry().await? {
+                let name = entry.file_name();
+                if skipHidden && name.as_encoded_bytes().starts_with(b".") {
+                    continue;
+                }
+                entries.push(name);
+            }
+            entries.sort_by(|l, r| {
+                InsensitiveOsString(l.clone())
+                    .cmp(&InsensitiveOsString(r.clone()))
+                    .then_with(|| l.cmp(r))
+            });
+
+            // reversed so entries are popped in order
+            for entry in entries.into_iter().rev() {
+                let mut entryName = OsString::from(&name);
+                entryName.push("/");
+                entryName.push(&entry);
+                stack.push((path.join(entry), entryName));
+            }
+        } else if metadata.is_file() {
+            let size = metadata.len();
+            let name = name.as_encoded_bytes();
+            send(entry_header(name, EntryKind::File, size, mode, mtime)?.into()).await?;
+
+            // the file may change size while being read, but the header is already sent
+            let file = tokio::fs::File::open(&path).await?;
+            let mut stream = ReaderStream::new(file.take(size));
+            let mut written = 0;
+            while let Some(chunk) = stream.next().await {
+                let chunk = chunk?;
+                written += chunk.len() as u64;
+                send(chunk).await?;
+            }
+            let padLength = (size - written) as usize + padding(size);
+            send(vec![0; padLength].into()).await?;
+        } else {
+            log!(
+                LogLevel::Debug,
+                "not archiving {path:?}, not a regular file or directory"
+            );
+        }
+    }
+
+    // end of archive marker
+    send(vec![0; blockSize * 2].into()).await?;
+    Ok(())
+}
@@ -0,0 +1,391 @@
+//! Minimal HTTP/1.1 client for fetching files missing locally from an upstream
+//! origin, and caching them into the root as they're streamed.
+
+use std::{
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Context};
+use caseproxy::{log, AResult, LogLevel};
+use futures_util::{Stream, StreamExt};
+use hyper::body::Bytes;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::TcpStream,
+};
+
+const connectTimeout: Duration = Duration::from_secs(10);
+const maxHeaderSize: usize = 64 * 1024;
+const maxChunkSize: u64 = 64 * 1024;
+
+pub type BodyStream = Pin<Box<dyn Stream<Item = AResult<Bytes>> + Send + Sync>>;
+
+/// An `http://` origin to fetch from, and the path requests are relative to.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Upstream {
+    host: String,
+    port: u16,
+    basePath: String,
+}
+
+impl Upstream {
+    pub fn parse(url: &str) -> AResult<Self> {
+        let rest = url
+            .strip_prefix("http://")
+            .ok_or_else(|| anyhow!("only http:// upstreams are supported"))?;
+        let (authority, basePath) = match rest.find('/') {
+            Some(index) => rest.split_at(index),
+            None => (rest, "/"),
+        };
+        let (host, port) = match authority.rsplit_once(':') {
+            // IPv6 literals have colons of their own
+            Some((host, port)) if !port.contains(']') => (host, port.parse()?),
+            _ => (authority, 80),
+        };
+        if host.is_empty() {
+            return Err(anyhow!("upstream URL has no host"));
+        }
+
+        let mut basePath = basePath.to_string();
+        if !basePath.ends_with('/') {
+            basePath.push('/');
+        }
+        Ok(Self {
+            host: host.to_string(),
+            port,
+            basePath,
+        })
+    }
+
+    /// Fetches `path`, relative to the base path. `path` is sent as is, so
+    /// should already be percent-encoded.
+    ///
+    /// Upstream gets `timeout` to send the response head, and then again for
+    /// each read of the body, so a stalled origin can't hold a request forever.
+    pub async fn get(&self, path: &str, timeout: Duration) -> AResult<UpstreamResponse> {
+        let address = (
+            self.host.trim_start_matches('[').trim_end_matches(']'),
+            self.port,
+        );
+        let stream = tokio::time::timeout(connectTimeout, TcpStream::connect(address))
+            .await
+            .map_err(|_| anyhow!("timed out connecting to upstream"))??;
+        let mut reader = BufReader::new(stream);
+
+        let hostHeader = match self.port {
+            80 => self.host.clone(),
+            port => format!("{}:{port}", self.host),
+        };
+        let request = format!(
+            "GET {}{} HTTP/1.1\r\nHost: {hostHeader}\r\nUser-Agent: caseproxy\r\nConnection: close\r\n\r\n",
+            self.basePath,
+            path.trim_start_matches('/'),
+        );
+        reader.get_mut().write_all(request.as_bytes()).await?;
+
+        let mut head = vec![];
+        let readHead = async {
+            while !head.ends_with(b"\r\n\r\n") {
+                if reader.read_until(b'\n', &mut head).await? == 0 {
+                    return Err(anyhow!("upstream closed connection before sending headers"));
+                }
+                if head.len() > maxHeaderSize {
+                    return Err(anyhow!("upstream response headers too large"));
+                }
+            }
+            Ok(())
+        };
+        tokio::time::timeout(timeout, readHead)
+            .await
+            .map_err(|_| anyhow!("timed out waiting for upstream response"))??;
+
+        let mut headers = [httparse::EMPTY_HEADER; 64];
+        let mut parsed = httparse::Response::new(&mut headers);
+        parsed.parse(&head).context("invalid upstream response")?;
+        let status = parsed.code.unwrap_or_default();
+        let header = |name: &str| {
+            parsed
+                .headers
+                .iter()
+                .find(|header| header.name.eq_ignore_ascii_case(name))
+                .and_then(|header| std::str::from_utf8(header.value).ok())
+                .map(str::trim)
+        };
+
+        let contentType = header("Content-Type").map(str::to_string);
+        let resolvedFile = header("X-Caseproxy-File").map(str::to_string);
+        let chunked = header("Transfer-Encoding")
+            .is_some_and(|encoding| encoding.eq_ignore_ascii_case("chunked"));
+        let contentLength = match header("Content-Length") {
+            Some(length) if !chunked => Some(length.parse().context("invalid Content-Length")?),
+            _ => None,
+        };
+        let framing = match (chunked, contentLength) {
+            (true, _) => Framing::Chunked { remaining: 0 },
+            (false, Some(length)) => Framing::Length(length),
+            (false, None) => Framing::Eof,
+        };
+
+        let body = futures_util::stream::unfold(Some((reader, framing)), move |state| async move {
+            let (mut reader, mut framing) = state?;
+            let chunk = tokio::time::timeout(timeout, framing.next_chunk(&mut reader))
+                .await
+                .unwrap_or_else(|_| Err(anyhow!("upstream stalled mid-body")));
+            match chunk {
+                Ok(Some(chunk)) => Some((Ok(chunk), Some((reader, framing)))),
+                Ok(None) => None,
+                Err(err) => Some((Err(err), None)),
+            }
+        });
+        Ok(UpstreamResponse {
+            status,
+            contentType,
+            contentLength,
+            resolvedFile,
+            body: Box::pin(body),
+        })
+    }
+}
+
+#[test]
+fn test_parse_upstream() -> AResult<()> {
+    assert_eq!(
+        Upstream::parse("http://example.com")?,
+        Upstream {
+            host: "example.com".into(),
+            port: 80,
+            basePath: "/".into()
+        }
+    );
+    assert_eq!(
+        Upstream::parse("http://127.0.0.1:8080/assets")?,
+        Upstream {
+            host: "127.0.0.1".into(),
+            port: 8080,
+            basePath: "/assets/".into()
+        }
+    );
+    assert_eq!(Upstream::parse("http://[::1]/")?.host, "[::1]");
+    assert_eq!(Upstream::parse("http://[::1]:81/")?.port, 81);
+    assert!(Upstream::parse("https://example.com").is_err());
+    assert!(Upstream::parse("http://:80/").is_err());
+    Ok(())
+}
+
+pub struct UpstreamResponse {
+    pub status: u16,
+    pub contentType: Option<String>,
+    pub contentLength: Option<u64>,
+    /// The file a caseproxy origin reports having served, see `--record`.
+    pub resolvedFile: Option<String>,
+    pub body: BodyStream,
+}
+
+/// How the end of a response body is found.
+enum Framing {
+    Length(u64),
+    /// `remaining` is what's left of the current chunk.
+    Chunked {
+        remaining: u64,
+    },
+    Eof,
+    Done,
+}
+
+impl Framing {
+    async fn next_chunk(&mut self, reader: &mut BufReader<TcpStream>) -> AResult<Option<Bytes>> {
+        let toRead = match self {
+            Framing::Done => return Ok(None),
+            Framing::Length(0) => {
+                *self = Framing::Done;
+                return Ok(None);
+            }
+            Framing::Length(remaining) => *remaining,
+            Framing::Chunked { remaining: 0 } => {
+                let mut line = String::new();
+                reader.read_line(&mut line).await?;
+                let size = line.split(';').next().unwrap_or_default().trim();
+                let size = u64::from_str_radix(size, 16)
+                    .with_context(|| format!("invalid chunk size {size:?}"))?;
+                if size == 0 {
+                    // skip trailers
+                    while !matches!(line.as_str(), "\r\n" | "\n" | "") {
+                        line.clear();
+                        reader.read_line(&mut line).await?;
+                    }
+                    *self = Framing::Done;
+                    return Ok(None);
+                }
+                *self = Framing::Chunked { remaining: size };
+                size
+            }
+            Framing::Chunked { remaining } => *remaining,
+            Framing::Eof => maxChunkSize,
+        };
+
+        let mut buf = vec![0; toRead.min(maxChunkSize) as usize];
+        let read = reader.read(&mut buf).await?;
+        buf.truncate(read);
+        match self {
+            _ if read > 0 => {}
+            Framing::Eof => {
+                *self = Framing::Done;
+                return Ok(None);
+            }
+            _ => return Err(anyhow!("upstream closed connection mid-body")),
+        }
+
+        match self {
+            Framing::Length(remaining) => *remaining -= read as u64,
+            Framing::Chunked { remaining } => {
+                *remaining -= read as u64;
+                if *remaining == 0 {
+                    let mut crlf = String::new();
+                    reader.read_line(&mut crlf).await?;
+                }
+            }
+            _ => {}
+        }
+        Ok(Some(buf.into()))
+    }
+}
+
+/// Bounds the total size of files cached from upstream while running.
+pub struct CacheBudget {
+    used: AtomicU64,
+    limit: u64,
+}
+
+impl CacheBudget {
+    pub fn new(limit: u64) -> Self {
+        Self {
+            used: AtomicU64::new(0),
+            limit,
+        }
+    }
+
+    /// Reserves room for `length` more bytes, if it fits.
+    pub fn reserve(&self, length: u64) -> bool {
+        self.used
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |used| {
+                used.checked_add(length)
+                    .filter(|&total| total <= self.limit)
+            })
+            .is_ok()
+    }
+
+    /// Gives back a reservation which turned out not to be needed.
+    pub fn release(&self, length: u64) {
+        self.used.fetch_sub(length, Ordering::SeqCst);
+    }
+}
+
+#[test]
+fn test_cache_budget() {
+    let budget = CacheBudget::new(10);
+    assert!(budget.reserve(4));
+    assert!(budget.reserve(6));
+    assert!(!budget.reserve(1));
+    assert!(budget.reserve(0));
+    budget.release(6);
+    assert!(budget.reserve(5));
+    assert!(!budget.reserve(2));
+    assert!(!CacheBudget::new(10).reserve(u64::MAX));
+}
+
+/// Passes `body` through while writing it to `dest`. The file is only moved
+/// into place once exactly `length` bytes have been written, so a failed or
+/// abandoned download never leaves a truncated file behind.
+pub async fn cache_body(body: BodyStream, dest: PathBuf, length: u64) -> AResult<BodyStream> {
+    static counter: AtomicU64 = AtomicU64::new(0);
+    let name = dest
+        .file_name()
+        .ok_or_else(|| anyhow!("can't cache to {dest:?}"))?
+        .to_string_lossy();
+    let partial = dest.with_file_name(format!(
+        ".{name}.{}-{}.partial",
+        std::process::id(),
+        counter.fetch_add(1, Ordering::Relaxed)
+    ));
+    let file = tokio::fs::File::create(&partial).await?;
+    let partial = PartialFile {
+        path: partial,
+        file: Some(file),
+        written: 0,
+    };
+
+    let body = futures_util::stream::unfold(Some((body, partial)), move |state| {
+        let dest = dest.clone();
+        async move {
+            let (mut body, mut partial) = state?;
+            let chunk = body.next().await;
+            match &chunk {
+                Some(Ok(chunk)) => {
+                    partial.write(chunk).await;
+                    // hyper stops polling once it has sent `Content-Length` bytes
+                    if partial.written >= length {
+                        partial.persist(&dest, length).await;
+                    }
+                }
+                Some(Err(_)) => partial.file = None,
+                None => partial.persist(&dest, length).await,
+            }
+            chunk.map(|chunk| (chunk, Some((body, partial))))
+        }
+    });
+    Ok(Box::pin(body))
+}
+
+/// A file being cached, removed on drop unless persisted.
+struct PartialFile {
+    path: PathBuf,
+    /// `None` once caching has been abandoned.
+    file: Option<tokio::fs::File>,
+    written: u64,
+}
+
+impl PartialFile {
+    async fn write(&mut self, chunk: &[u8]) {
+        let Some(file) = &mut self.file else {
+            return;
+        };
+        if let Err(err) = file.write_all(chunk).await {
+            log!(LogLevel::Warn, "failed writing to {:?}: {err}", self.path);
+            self.file = None;
+        }
+        self.written += chunk.len() as u64;
+    }
+
+    async fn persist(&mut self, dest: &Path, length: u64) {
+        let Some(mut file) = self.file.take() else {
+            return;
+        };
+        if self.written != length {
+            log!(
+                LogLevel::Warn,
+                "upstream sent {} bytes instead of {length}, not caching {dest:?}",
+                self.written
+            );
+            return;
+        }
+        let res = async {
+            file.flush().await?;
+            drop(file);
+            tokio::fs::rename(&self.path, dest).await
+        };
+        match res.await {
+            Ok(()) => log!(LogLevel::Debug, "cached {dest:?}"),
+            Err(err) => log!(LogLevel::Warn, "failed to cache {dest:?}: {err}"),
+        }
+    }
+}
+
+impl Drop for PartialFile {
+    fn drop(&mut self) {
+        // a no-op once renamed into place
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
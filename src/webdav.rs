@@ -0,0 +1,104 @@
+//! Read-only WebDAV, answering `PROPFIND` with the properties of a file or the
+//! entries of a directory, so the tree can be mounted by file managers.
+
+use std::{
+    fmt::Write,
+    fs::Metadata,
+    path::{Path, PathBuf},
+};
+
+use caseproxy::AResult;
+
+use crate::{html_escape, is_hidden, percent_encode_path};
+
+/// Methods allowed on a read-only share, for `Allow` headers.
+pub const allowedMethods: &str = "GET, HEAD, OPTIONS, PROPFIND";
+
+/// Whether a method only reads, and so is allowed under `--webdav`.
+pub fn is_read_only(method: &str) -> bool {
+    matches!(method, "GET" | "HEAD" | "OPTIONS" | "PROPFIND")
+}
+
+/// Builds the `207 Multi-Status` body for `path`, relative to `root` and served
+/// under `urlPrefix`. Its entries are included too if it's a directory and
+/// `depth` isn't `0`; deeper listings are never made.
+pub fn propfind(
+    root: &Path,
+    path: &Path,
+    urlPrefix: &str,
+    depth: &str,
+    skipHidden: bool,
+) -> AResult<String> {
+    let relative = path.strip_prefix(root)?;
+    let metadata = std::fs::metadata(path)?;
+    let mut href = format!(
+        "{urlPrefix}{}",
+        percent_encode_path(relative.as_os_str().as_encoded_bytes())
+    );
+    if metadata.is_dir() && !href.ends_with('/') {
+        href.push('/');
+    }
+
+    let mut res = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n");
+    res.push_str("<D:multistatus xmlns:D=\"DAV:\">\n");
+    write_response(&mut res, &href, relative, &metadata);
+
+    if metadata.is_dir() && depth != "0" {
+        let mut entries: Vec<(PathBuf, Metadata)> = vec![];
+        for entry in std::fs::read_dir(path)? {
+            let entry = entry?;
+            let relative = relative.join(entry.file_name());
+            if skipHidden && is_hidden(&relative) {
+                continue;
+            }
+            // follows symlinks, as serving them would
+            let Ok(metadata) = std::fs::metadata(entry.path()) else {
+                continue;
+            };
+            entries.push((relative, metadata));
+        }
+        entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+        for (relative, metadata) in entries {
+            let name = relative.file_name().unwrap_or_default();
+            let mut entryHref = href.clone();
+            entryHref.push_str(&percent_encode_path(name.as_encoded_bytes()));
+            if metadata.is_dir() {
+                entryHref.push('/');
+            }
+            write_response(&mut res, &entryHref, &relative, &metadata);
+        }
+    }
+
+    res.push_str("</D:multistatus>\n");
+    Ok(res)
+}
+
+fn write_response(out: &mut String, href: &str, relative: &Path, metadata: &Metadata) {
+    let name = relative.file_name().unwrap_or_default().to_string_lossy();
+    out.push_str("<D:response>\n");
+    writeln!(out, "<D:href>{}</D:href>", html_escape(href)).unwrap();
+    out.push_str("<D:propstat>\n<D:prop>\n");
+    writeln!(out, "<D:displayname>{}</D:displayname>", html_escape(&name)).unwrap();
+    if metadata.is_dir() {
+        out.push_str("<D:resourcetype><D:collection/></D:resourcetype>\n");
+    } else {
+        out.push_str("<D:resourcetype/>\n");
+        writeln!(
+            out,
+            "<D:getcontentlength>{}</D:getcontentlength>",
+            metadata.len()
+        )
+        .unwrap();
+    }
+    if let Ok(modified) = metadata.modified() {
+        writeln!(
+            out,
+            "<D:getlastmodified>{}</D:getlastmodified>",
+            httpdate::fmt_http_date(modified)
+        )
+        .unwrap();
+    }
+    out.push_str("</D:prop>\n<D:status>HTTP/1.1 200 OK</D:status>\n</D:propstat>\n");
+    out.push_str("</D:response>\n");
+}